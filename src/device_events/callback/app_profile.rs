@@ -0,0 +1,264 @@
+//! Application profiles.
+
+use super::{remap_verdict, FocusCallback, HandlerId, KeyBinding, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_events::event_loop::{focused_window, watch_focus};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use {Key, KeyEvent, WindowInfo, WindowSelector};
+
+/// Keymap applied while the windows matching a selector have the focus, e.g. game bindings
+/// that only apply in the game. Profiles are switched by a `ProfileSelector`.
+///
+/// Profiles can be serialized, e.g. to be loaded from a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppProfile {
+    pub name: String,
+    pub window: WindowSelector,
+    /// Keys emitted in place of each remapped key: pressed in order, released in reverse order.
+    pub remaps: BTreeMap<Key, Vec<Key>>,
+    /// Swallow the keys that aren't remapped instead of letting them through.
+    #[serde(default)]
+    pub block_unmapped: bool,
+}
+
+impl AppProfile {
+    pub fn new(name: &str, window: WindowSelector) -> Self {
+        AppProfile {
+            name: name.to_string(),
+            window,
+            remaps: BTreeMap::new(),
+            block_unmapped: false,
+        }
+    }
+
+    /// Emit `keys` in place of `key`. Remapping to no key swallows it.
+    pub fn remap(mut self, key: Key, keys: &[Key]) -> Self {
+        self.remaps.insert(key, keys.to_vec());
+        self
+    }
+
+    /// Swallow the keys that aren't remapped.
+    pub fn block_unmapped(mut self) -> Self {
+        self.block_unmapped = true;
+        self
+    }
+
+    /// Start building a hotkey bound to `key` while the windows of the profile have the focus.
+    /// Hotkeys take precedence over the remaps of the profile.
+    pub fn on(&self, key: Key) -> KeyBinding {
+        KeyBinding::new(key).window(self.window.clone())
+    }
+}
+
+struct State {
+    profiles: Vec<AppProfile>,
+    /// Codes of the keys emitted for each remapped key held down, so that they are released
+    /// even if the profile changed in the meantime.
+    remapped: HashMap<u32, Vec<u32>>,
+}
+
+impl State {
+    fn active(&self) -> Option<&AppProfile> {
+        self.profile_for(&focused_window()?)
+    }
+
+    /// First profile whose selector matches `window`.
+    fn profile_for(&self, window: &WindowInfo) -> Option<&AppProfile> {
+        self.profiles.iter().find(|profile| profile.window.matches(window))
+    }
+}
+
+/// Applies the first of its profiles whose selector matches the focused window, switching
+/// profiles as the focus moves. Keys are left alone while no profile matches.
+///
+/// ```no_run
+/// use key_director::{AppProfile, DeviceState, Key, ProfileSelector, WindowSelector};
+///
+/// let _device_state = DeviceState::new();
+/// let game = AppProfile::new("game", WindowSelector::Class("factorio".to_string()))
+///     .remap(Key::CapsLock, &[Key::LControl]);
+/// let ide = AppProfile::new("ide", WindowSelector::Title("Visual Studio Code".to_string()))
+///     .remap(Key::F13, &[Key::LControl, Key::LShift, Key::P]);
+/// let _hotkey = game.on(Key::F1).pressed().block(|_| println!("F1 in the game"));
+/// let _selector = ProfileSelector::install(vec![game, ide]);
+/// ```
+///
+/// It runs after the other `Stage::Normal` handlers, like the device profiles, and is removed
+/// when dropped.
+pub struct ProfileSelector {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+    /// Keeps the focused window polled while the selector is installed.
+    _focus_watch: Arc<FocusCallback>,
+}
+
+impl ProfileSelector {
+    pub fn install(profiles: Vec<AppProfile>) -> ProfileSelector {
+        let focus_watch = watch_focus();
+        let state = Arc::new(Mutex::new(State {
+            profiles,
+            remapped: HashMap::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "profile selector",
+                Stage::Normal,
+                i32::MIN,
+                None,
+                Arc::new(move |event| match handler_state.lock() {
+                    Ok(mut state) => filter(&mut state, event),
+                    Err(_) => Verdict::Pass,
+                }),
+            );
+        ProfileSelector {
+            id,
+            state,
+            _focus_watch: focus_watch,
+        }
+    }
+
+    /// Profile applied to the focused window, if any.
+    pub fn active(&self) -> Option<AppProfile> {
+        self.lock().active().cloned()
+    }
+
+    pub fn profiles(&self) -> Vec<AppProfile> {
+        self.lock().profiles.clone()
+    }
+
+    /// Replace the profiles. Remapped keys held down are still released as they were pressed.
+    pub fn set_profiles(&self, profiles: Vec<AppProfile>) {
+        self.lock().profiles = profiles;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the profile selector state")
+    }
+}
+
+impl Drop for ProfileSelector {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+/// Releases follow their press: a key remapped when pressed releases the keys it was remapped
+/// to, and the chain settles the fate of the others.
+fn filter(state: &mut State, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    if !event.is_pressed {
+        return match state.remapped.remove(&event.key_code) {
+            Some(codes) => Verdict::Replace(
+                codes
+                    .into_iter()
+                    .rev()
+                    .map(|code| KeyEvent::new(None, code, 0, false, true))
+                    .collect(),
+            ),
+            None => Verdict::Pass,
+        };
+    }
+
+    let verdict = match state.active() {
+        Some(profile) => remap_verdict(&profile.remaps, profile.block_unmapped, event),
+        None => return Verdict::Pass,
+    };
+    if let Verdict::Replace(ref events) = verdict {
+        state
+            .remapped
+            .insert(event.key_code, events.iter().map(|event| event.key_code).collect());
+    }
+    verdict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Key, is_pressed: bool) -> KeyEvent {
+        KeyEvent::new(None, key.code().unwrap(), 0, is_pressed, false)
+    }
+
+    fn window(class: &str, title: &str) -> WindowInfo {
+        WindowInfo {
+            class: class.to_string(),
+            title: title.to_string(),
+            pid: Some(42),
+        }
+    }
+
+    fn state() -> State {
+        State {
+            profiles: vec![
+                AppProfile::new("game", WindowSelector::Class("factorio".to_string()))
+                    .remap(Key::CapsLock, &[Key::LControl]),
+                AppProfile::new("ide", WindowSelector::Title("visual studio code".to_string()))
+                    .remap(Key::F13, &[Key::LControl, Key::LShift, Key::P])
+                    .block_unmapped(),
+                AppProfile::new("process", WindowSelector::Pid(42)),
+            ],
+            remapped: HashMap::new(),
+        }
+    }
+
+    fn name(profile: Option<&AppProfile>) -> Option<&str> {
+        profile.map(|profile| profile.name.as_str())
+    }
+
+    #[test]
+    fn the_first_profile_matching_the_window_applies() {
+        let state = state();
+        assert_eq!(name(state.profile_for(&window("Factorio", "Factorio 1.1"))), Some("game"));
+        assert_eq!(name(state.profile_for(&window("code", "main.rs - Visual Studio Code"))), Some("ide"));
+        assert_eq!(name(state.profile_for(&window("firefox", "Mozilla Firefox"))), Some("process"));
+        let other_process = WindowInfo {
+            pid: None,
+            ..window("firefox", "Mozilla Firefox")
+        };
+        assert_eq!(name(state.profile_for(&other_process)), None);
+    }
+
+    #[test]
+    fn profiles_remap_and_block_keys() {
+        let state = state();
+        let ide = state.profile_for(&window("code", "Visual Studio Code")).unwrap();
+        let codes = |verdict: Verdict| -> Vec<(u32, bool)> {
+            match verdict {
+                Verdict::Replace(events) => events.iter().map(|event| (event.key_code, event.is_pressed)).collect(),
+                verdict => panic!("unexpected {:?}", verdict),
+            }
+        };
+        let code = |key: Key| key.code().unwrap();
+        assert_eq!(
+            codes(remap_verdict(&ide.remaps, ide.block_unmapped, &key(Key::F13, true))),
+            vec![(code(Key::LControl), true), (code(Key::LShift), true), (code(Key::P), true)]
+        );
+        assert_eq!(remap_verdict(&ide.remaps, ide.block_unmapped, &key(Key::A, true)), Verdict::Block);
+    }
+
+    #[test]
+    fn remapped_keys_are_released_as_they_were_pressed() {
+        let mut state = state();
+        let code = |key: Key| key.code().unwrap();
+        state.remapped.insert(code(Key::F13), vec![code(Key::LControl), code(Key::P)]);
+        // Whatever has the focus now.
+        state.profiles.clear();
+        assert_eq!(
+            filter(&mut state, &key(Key::F13, false)),
+            Verdict::Replace(vec![
+                KeyEvent::new(None, code(Key::P), 0, false, true),
+                KeyEvent::new(None, code(Key::LControl), 0, false, true),
+            ])
+        );
+        assert_eq!(filter(&mut state, &key(Key::F13, false)), Verdict::Pass);
+    }
+}
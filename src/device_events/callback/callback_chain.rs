@@ -0,0 +1,885 @@
+//! Ordered chain of blocking keyboard callbacks.
+
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use device_events::event_loop::focused_window;
+use device_state::emit_resumed;
+use std::sync::{Arc, Mutex};
+use keymap::DeviceKey;
+use {DeviceSelector, Key, KeyEvent, Modifiers, WindowSelector};
+
+/// Stage of the callback chain a handler runs in. Stages are dispatched in declaration order,
+/// so every pre-filter runs before any regular handler, whatever their priorities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    /// Runs first, e.g. for input cleaning that user handlers should never see.
+    PreFilter,
+    /// Regular handlers.
+    Normal,
+    /// Runs last, e.g. for logging what got through.
+    PostFilter,
+}
+
+/// Identifier of a registered handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HandlerId(u64);
+
+/// Description of a registered handler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerInfo {
+    pub id: HandlerId,
+    pub name: String,
+    pub stage: Stage,
+    pub priority: i32,
+    /// Key the handler is bound to, for handlers registered with `DeviceState::on`.
+    pub key: Option<Key>,
+    /// Devices the handler is scoped to. It runs for events of every device if `None`.
+    pub device: Option<DeviceSelector>,
+    /// Windows the handler is scoped to. It runs whatever window has the focus if `None`.
+    pub window: Option<WindowSelector>,
+}
+
+impl HandlerInfo {
+    /// Handlers are dispatched in ascending order of this key.
+    fn dispatch_order(&self) -> (Stage, Reverse<i32>, HandlerId) {
+        (self.stage, Reverse(self.priority), self.id)
+    }
+}
+
+/// What a handler decides to do with a key event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// Let the event through to the next handler.
+    Pass,
+    /// Swallow the event and stop the chain.
+    Block,
+    /// Swallow the event, stop the chain and emit these events in its place, in order.
+    Replace(Vec<KeyEvent>),
+    /// Continue the chain with this event instead. If it still differs from the original once
+    /// the chain is done, the original is swallowed and this one is emitted in its place.
+    Modify(KeyEvent),
+}
+
+impl From<bool> for Verdict {
+    fn from(pass: bool) -> Self {
+        if pass {
+            Verdict::Pass
+        } else {
+            Verdict::Block
+        }
+    }
+}
+
+/// Blocking keyboard callback. Returning `false` blocks the event and stops the chain.
+pub type BlockingCallback = dyn Fn(&KeyEvent) -> bool + Sync + Send + 'static;
+
+/// Keyboard handler deciding the fate of each event with a `Verdict`.
+pub type VerdictCallback = dyn Fn(&KeyEvent) -> Verdict + Sync + Send + 'static;
+
+/// Conditions an event must meet to reach a handler.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct BindingFilter {
+    /// Modifiers that must be held, exactly. Any modifiers if `None`.
+    pub modifiers: Option<Modifiers>,
+    /// Only presses or only releases. Both if `None`.
+    pub is_pressed: Option<bool>,
+    pub include_simulated: bool,
+    /// Only events of these devices. Events of any device, or of none, if `None`.
+    pub device: Option<DeviceSelector>,
+    /// Only while one of these windows has the focus. Whatever window has it if `None`.
+    pub window: Option<WindowSelector>,
+}
+
+impl BindingFilter {
+    /// Filter of handlers that aren't bound to a key, which see simulated events too.
+    pub fn unbound(device: Option<DeviceSelector>) -> Self {
+        BindingFilter {
+            include_simulated: true,
+            device,
+            ..BindingFilter::default()
+        }
+    }
+
+    fn matches(&self, event: &KeyEvent, modifiers: Modifiers) -> bool {
+        if let Some(ref selector) = self.device {
+            match event.device {
+                Some(ref device) if selector.matches(device) => {}
+                _ => return false,
+            }
+        }
+        if let Some(ref selector) = self.window {
+            match focused_window() {
+                Some(ref window) if selector.matches(window) => {}
+                _ => return false,
+            }
+        }
+        if self.modifiers.is_some() && self.modifiers != Some(modifiers) {
+            return false;
+        }
+        if self.is_pressed.is_some() && self.is_pressed != Some(event.is_pressed) {
+            return false;
+        }
+        self.include_simulated || !event.is_simulated
+    }
+}
+
+#[derive(Clone)]
+struct Handler {
+    info: HandlerInfo,
+    filter: BindingFilter,
+    callback: Arc<VerdictCallback>,
+}
+
+fn insert_sorted(handlers: &mut Vec<Handler>, handler: Handler) {
+    let order = handler.info.dispatch_order();
+    let position = handlers.partition_point(|other| other.info.dispatch_order() < order);
+    handlers.insert(position, handler);
+}
+
+/// Handlers sorted in dispatch order: by stage, then by descending priority, then by
+/// registration order. Key bindings are indexed by key so that only the ones bound to the
+/// key of an event are considered for it.
+#[derive(Default)]
+pub(crate) struct CallbackChain {
+    handlers: Vec<Handler>,
+    bindings: HashMap<Key, Vec<Handler>>,
+    held_modifiers: Vec<Key>,
+    /// Physical keys whose last press reached the OS, by device and key code.
+    passed_presses: HashSet<DeviceKey>,
+    /// Physical keys whose press was swallowed, by device and key code.
+    blocked_presses: HashSet<DeviceKey>,
+    next_id: u64,
+}
+
+impl CallbackChain {
+    fn next_info(
+        &mut self,
+        name: &str,
+        stage: Stage,
+        priority: i32,
+        key: Option<Key>,
+        filter: &BindingFilter,
+    ) -> HandlerInfo {
+        let id = HandlerId(self.next_id);
+        self.next_id += 1;
+        HandlerInfo {
+            id,
+            name: name.to_string(),
+            stage,
+            priority,
+            key,
+            device: filter.device.clone(),
+            window: filter.window.clone(),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        name: &str,
+        stage: Stage,
+        priority: i32,
+        device: Option<DeviceSelector>,
+        callback: Arc<VerdictCallback>,
+    ) -> HandlerId {
+        let filter = BindingFilter::unbound(device);
+        let info = self.next_info(name, stage, priority, None, &filter);
+        let id = info.id;
+        insert_sorted(
+            &mut self.handlers,
+            Handler {
+                info,
+                filter,
+                callback,
+            },
+        );
+        id
+    }
+
+    pub fn insert_binding(
+        &mut self,
+        name: &str,
+        key: Key,
+        stage: Stage,
+        priority: i32,
+        filter: BindingFilter,
+        callback: Arc<VerdictCallback>,
+    ) -> HandlerId {
+        let info = self.next_info(name, stage, priority, Some(key), &filter);
+        let id = info.id;
+        insert_sorted(
+            self.bindings.entry(key).or_default(),
+            Handler {
+                info,
+                filter,
+                callback,
+            },
+        );
+        id
+    }
+
+    /// Chain with the same handlers, and its own state as if no key were held.
+    fn snapshot(&self) -> CallbackChain {
+        CallbackChain {
+            handlers: self.handlers.clone(),
+            bindings: self.bindings.clone(),
+            next_id: self.next_id,
+            ..CallbackChain::default()
+        }
+    }
+
+    pub fn remove(&mut self, id: HandlerId) -> bool {
+        let len = self.handlers.len();
+        self.handlers.retain(|handler| handler.info.id != id);
+        if self.handlers.len() != len {
+            return true;
+        }
+
+        let mut removed = false;
+        self.bindings.retain(|_, bindings| {
+            let len = bindings.len();
+            bindings.retain(|binding| binding.info.id != id);
+            removed |= bindings.len() != len;
+            !bindings.is_empty()
+        });
+        removed
+    }
+
+    pub fn handlers(&self) -> Vec<HandlerInfo> {
+        let mut handlers: Vec<HandlerInfo> = self
+            .handlers
+            .iter()
+            .chain(self.bindings.values().flatten())
+            .map(|handler| handler.info.clone())
+            .collect();
+        handlers.sort_by_key(HandlerInfo::dispatch_order);
+        handlers
+    }
+
+    /// Modifiers currently held down.
+    pub fn modifiers(&self) -> Modifiers {
+        self.held_modifiers
+            .iter()
+            .filter_map(|key| key.modifier())
+            .fold(Modifiers::NONE, |modifiers, modifier| modifiers | modifier)
+    }
+
+    /// Records the modifier state change caused by `event` and returns the modifiers held
+    /// alongside its key.
+    fn track_modifiers(&mut self, event: &KeyEvent, key: Option<Key>) -> Modifiers {
+        if let Some(key) = key.filter(|key| key.modifier().is_some()) {
+            if !event.is_pressed {
+                self.held_modifiers.retain(|held| *held != key);
+            } else if !self.held_modifiers.contains(&key) {
+                self.held_modifiers.push(key);
+            }
+        }
+        self.modifiers_besides(key)
+    }
+
+    /// Modifiers held down, not counting `key`.
+    fn modifiers_besides(&self, key: Option<Key>) -> Modifiers {
+        self.held_modifiers
+            .iter()
+            .filter(|held| Some(**held) != key)
+            .filter_map(|held| held.modifier())
+            .fold(Modifiers::NONE, |modifiers, modifier| modifiers | modifier)
+    }
+
+    /// Makes the fate of a physical key release follow the one of its press, so that the OS
+    /// neither sees a release without a press nor keeps a key pressed forever.
+    fn settle(&mut self, event: &KeyEvent, verdict: Verdict) -> Verdict {
+        if event.is_simulated {
+            return verdict;
+        }
+
+        let key = event.device_key();
+        if event.is_pressed {
+            if verdict == Verdict::Pass {
+                self.blocked_presses.remove(&key);
+                self.passed_presses.insert(key);
+            } else if !self.passed_presses.contains(&key) {
+                self.blocked_presses.insert(key);
+            }
+            return verdict;
+        }
+
+        if self.passed_presses.remove(&key) {
+            self.blocked_presses.remove(&key);
+            return match verdict {
+                Verdict::Block => Verdict::Pass,
+                Verdict::Replace(mut events) => {
+                    events.push(event.clone());
+                    Verdict::Replace(events)
+                }
+                Verdict::Modify(new_event) => Verdict::Replace(vec![new_event, event.clone()]),
+                Verdict::Pass => Verdict::Pass,
+            };
+        }
+        if self.blocked_presses.remove(&key) && verdict == Verdict::Pass {
+            return Verdict::Block;
+        }
+        verdict
+    }
+
+    /// Callbacks to run for `event`, in dispatch order, with the modifiers held alongside it.
+    fn callbacks(&self, event: &KeyEvent, modifiers: Modifiers) -> Vec<Callback> {
+        let mut bindings = Key::from_code(event.key_code)
+            .and_then(|key| self.bindings.get(&key))
+            .into_iter()
+            .flatten()
+            .filter(|binding| binding.filter.matches(event, modifiers))
+            .peekable();
+        let mut callbacks = Vec::new();
+        let handlers = self
+            .handlers
+            .iter()
+            .filter(|handler| handler.filter.matches(event, modifiers));
+        for handler in handlers {
+            let order = handler.info.dispatch_order();
+            while let Some(binding) = bindings.next_if(|binding| binding.info.dispatch_order() < order) {
+                callbacks.push(Callback::new(binding));
+            }
+            callbacks.push(Callback::new(handler));
+        }
+        callbacks.extend(bindings.map(Callback::new));
+        callbacks
+    }
+}
+
+/// Snapshot of a handler taken for the dispatch of one event.
+struct Callback {
+    id: HandlerId,
+    stage: Stage,
+    callback: Arc<VerdictCallback>,
+}
+
+impl Callback {
+    fn new(handler: &Handler) -> Self {
+        Callback {
+            id: handler.info.id,
+            stage: handler.info.stage,
+            callback: handler.callback.clone(),
+        }
+    }
+}
+
+lazy_static! {
+    pub(crate) static ref GLOBAL_CALLBACKS: Mutex<CallbackChain> = Mutex::new(CallbackChain::default());
+}
+
+thread_local! {
+    /// Chain of the simulation running on the thread, if any, standing in for the global one.
+    static SIMULATED_CALLBACKS: RefCell<Option<Arc<Mutex<CallbackChain>>>> = const { RefCell::new(None) };
+
+    /// Events resumed during the simulated dispatch running on the thread, in place of being
+    /// emitted.
+    static SIMULATED_RESUMES: RefCell<Vec<KeyEvent>> = const { RefCell::new(Vec::new()) };
+
+    /// Handler whose callback is running on the thread, if any.
+    static RUNNING_HANDLER: Cell<Option<HandlerId>> = const { Cell::new(None) };
+}
+
+/// Chain the events of the current thread go through.
+fn current_chain() -> Option<Arc<Mutex<CallbackChain>>> {
+    SIMULATED_CALLBACKS.with(|simulated| simulated.borrow().clone())
+}
+
+/// Modifiers currently held down, as seen by the chain dispatching the events of the current
+/// thread. Handlers asking for the modifiers should use this rather than `GLOBAL_CALLBACKS`,
+/// so that they behave the same in a `Simulation`.
+pub(crate) fn held_modifiers() -> Modifiers {
+    let simulated = current_chain();
+    let chain = simulated.as_deref().unwrap_or(&GLOBAL_CALLBACKS);
+    chain.lock().map(|chain| chain.modifiers()).unwrap_or(Modifiers::NONE)
+}
+
+/// Copy of the global chain with the same handlers but a state of its own, e.g. which
+/// modifiers are held, so that events can be run through the handlers without disturbing the
+/// dispatch of real input.
+pub(crate) struct Simulation {
+    chain: Arc<Mutex<CallbackChain>>,
+}
+
+/// Puts back the chain that was current before a simulated dispatch, even if a handler panics.
+struct Restore(Option<Arc<Mutex<CallbackChain>>>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        SIMULATED_CALLBACKS.with(|simulated| *simulated.borrow_mut() = self.0.take());
+    }
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        let chain = match GLOBAL_CALLBACKS.lock() {
+            Ok(chain) => chain.snapshot(),
+            Err(_) => CallbackChain::default(),
+        };
+        Simulation {
+            chain: Arc::new(Mutex::new(chain)),
+        }
+    }
+
+    /// Simulation of `chain` alone, rather than of the global one.
+    #[cfg(test)]
+    pub(crate) fn of(chain: CallbackChain) -> Self {
+        Simulation {
+            chain: Arc::new(Mutex::new(chain)),
+        }
+    }
+
+    /// Run `event` through the handlers like `dispatch`, on the current thread. The events that
+    /// handlers resume meanwhile are returned as replacing it, before what its own verdict
+    /// emits.
+    pub fn dispatch(&self, event: &KeyEvent) -> Verdict {
+        let verdict = {
+            let _restore = Restore(SIMULATED_CALLBACKS.with(|simulated| simulated.replace(Some(self.chain.clone()))));
+            dispatch(event)
+        };
+        let mut events = SIMULATED_RESUMES.with(|resumed| resumed.take());
+        if events.is_empty() {
+            return verdict;
+        }
+        match verdict {
+            Verdict::Pass => events.push(event.clone()),
+            Verdict::Block => {}
+            Verdict::Replace(replacements) => events.extend(replacements),
+            Verdict::Modify(new_event) => events.push(new_event),
+        }
+        Verdict::Replace(events)
+    }
+}
+
+/// Runs `event` through the global chain. Never returns `Verdict::Modify` with an event equal
+/// to the original one, so backends only need to re-emit events that actually changed.
+///
+/// The chain is snapshotted first so callbacks can register or remove handlers themselves.
+/// Whatever the callbacks decide, the release of a physical key reaches the OS if and only if
+/// its press did. Pre-filters are exempt: they clean up the physical input, so they may
+/// swallow a release, e.g. a chattering one, as long as they let the real one through.
+pub(crate) fn dispatch(event: &KeyEvent) -> Verdict {
+    let simulated = current_chain();
+    let chain = simulated.as_deref().unwrap_or(&GLOBAL_CALLBACKS);
+    let callbacks = match chain.lock() {
+        Ok(mut chain) => {
+            let modifiers = chain.track_modifiers(event, Key::from_code(event.key_code));
+            chain.callbacks(event, modifiers)
+        }
+        Err(_) => return Verdict::Pass,
+    };
+    finish(chain, event, run(&callbacks, event))
+}
+
+/// Runs an event that the pre-filter `after` swallowed through the handlers that come after
+/// it, then emits the outcome like a backend would have. The emitted input is marked so that
+/// the hooks let it through without running the callbacks a second time. Lets pre-filters
+/// delay events, e.g. until they know whether a key is chattering.
+///
+/// If `after` isn't in the chain, e.g. a filter removed while its timer resumed an event, the
+/// event goes through the handlers of the stages after the pre-filters.
+///
+/// May be called from a pre-filter, see `resume_replacement`. In a `Simulation`, the events go
+/// through its chain and are returned by its dispatch instead of being emitted.
+pub(crate) fn resume(event: &KeyEvent, after: HandlerId) {
+    let simulated = current_chain();
+    let chain = simulated.as_deref().unwrap_or(&GLOBAL_CALLBACKS);
+    let callbacks = match chain.lock() {
+        Ok(chain) => {
+            let modifiers = chain.modifiers_besides(Key::from_code(event.key_code));
+            chain.callbacks(event, modifiers)
+        }
+        Err(_) => return,
+    };
+
+    let start = match callbacks.iter().position(|callback| callback.id == after) {
+        Some(position) => position + 1,
+        None => callbacks.partition_point(|callback| callback.stage == Stage::PreFilter),
+    };
+    let remaining = &callbacks[start..];
+    let events = match finish(chain, event, run(remaining, event)) {
+        Verdict::Pass => vec![event.clone()],
+        Verdict::Block => return,
+        Verdict::Replace(events) => events,
+        Verdict::Modify(new_event) => vec![new_event],
+    };
+    if simulated.is_some() {
+        SIMULATED_RESUMES.with(|resumed| resumed.borrow_mut().extend(events));
+    } else {
+        emit_resumed(&events);
+    }
+}
+
+/// Lets a physical event that a pre-filter replaces with events around it, such as text typed
+/// before it, go on through the handlers after the filter: emitted as part of a
+/// `Verdict::Replace`, it would come back as simulated input, which key bindings ignore, or not
+/// at all on Linux. When `verdict` replaces `event` with events including it, they are resumed
+/// in order after the handler running on the thread, and the event is swallowed. Other
+/// verdicts are returned as they are.
+pub(crate) fn resume_replacement(event: &KeyEvent, verdict: Verdict) -> Verdict {
+    let after = match RUNNING_HANDLER.with(Cell::get) {
+        Some(after) if !event.is_simulated => after,
+        _ => return verdict,
+    };
+    match verdict {
+        Verdict::Replace(ref events) if events.contains(event) => {
+            for replacement in events {
+                resume(replacement, after);
+            }
+            Verdict::Block
+        }
+        verdict => verdict,
+    }
+}
+
+/// Marks the handler whose callback runs on the thread until dropped.
+struct Running(Option<HandlerId>);
+
+impl Running {
+    fn enter(id: HandlerId) -> Self {
+        Running(RUNNING_HANDLER.with(|running| running.replace(Some(id))))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING_HANDLER.with(|running| running.set(self.0));
+    }
+}
+
+fn finish(chain: &Mutex<CallbackChain>, event: &KeyEvent, (verdict, stage): (Verdict, Option<Stage>)) -> Verdict {
+    let verdict = if event.is_pressed && event.is_repeat {
+        repeated(verdict)
+    } else {
+        verdict
+    };
+    if stage == Some(Stage::PreFilter) {
+        return verdict;
+    }
+    match chain.lock() {
+        Ok(mut chain) => chain.settle(event, verdict),
+        Err(_) => verdict,
+    }
+}
+
+/// Flags the presses standing for a repeat as repeats too, so that a remapped key repeats the
+/// keys it is remapped to.
+fn repeated(verdict: Verdict) -> Verdict {
+    match verdict {
+        Verdict::Replace(mut events) => {
+            for event in events.iter_mut() {
+                event.is_repeat = event.is_pressed && event.key_code != KeyEvent::UNICODE_KEY_CODE;
+            }
+            Verdict::Replace(events)
+        }
+        Verdict::Modify(mut event) => {
+            event.is_repeat = event.is_pressed;
+            Verdict::Modify(event)
+        }
+        verdict => verdict,
+    }
+}
+
+/// Returns the verdict of the callbacks, with the stage of the callback that stopped the
+/// chain, if any.
+fn run(callbacks: &[Callback], event: &KeyEvent) -> (Verdict, Option<Stage>) {
+    let mut modified: Option<KeyEvent> = None;
+    for callback in callbacks.iter() {
+        let _running = Running::enter(callback.id);
+        match (callback.callback)(modified.as_ref().unwrap_or(event)) {
+            Verdict::Pass => {}
+            Verdict::Modify(new_event) => modified = Some(new_event),
+            verdict => return (verdict, Some(callback.stage)),
+        }
+    }
+    match modified {
+        Some(new_event) if new_event != *event => (Verdict::Modify(new_event), None),
+        _ => (Verdict::Pass, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Key, is_pressed: bool, is_simulated: bool) -> KeyEvent {
+        KeyEvent::new(None, key.code().unwrap(), 0, is_pressed, is_simulated)
+    }
+
+    /// Callback recording the events it sees in `seen`, and letting them through.
+    fn recorder(seen: &Arc<Mutex<Vec<KeyEvent>>>) -> Arc<VerdictCallback> {
+        let seen = seen.clone();
+        Arc::new(move |event| {
+            seen.lock().unwrap().push(event.clone());
+            Verdict::Pass
+        })
+    }
+
+    /// Callback returning `verdict` for every event.
+    fn verdict(verdict: Verdict) -> Arc<VerdictCallback> {
+        Arc::new(move |_| verdict.clone())
+    }
+
+    /// Callback logging `name` in `log`, and letting events through.
+    fn logger(log: &Arc<Mutex<Vec<&'static str>>>, name: &'static str) -> Arc<VerdictCallback> {
+        let log = log.clone();
+        Arc::new(move |_| {
+            log.lock().unwrap().push(name);
+            Verdict::Pass
+        })
+    }
+
+    #[test]
+    fn handlers_run_by_stage_then_priority_then_registration() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = CallbackChain::default();
+        chain.insert("post", Stage::PostFilter, 100, None, logger(&log, "post"));
+        chain.insert("normal low", Stage::Normal, -5, None, logger(&log, "normal low"));
+        chain.insert("normal first", Stage::Normal, 10, None, logger(&log, "normal first"));
+        chain.insert("normal second", Stage::Normal, 10, None, logger(&log, "normal second"));
+        chain.insert("pre", Stage::PreFilter, -100, None, logger(&log, "pre"));
+        let names: Vec<String> = chain.handlers().into_iter().map(|handler| handler.name).collect();
+        Simulation::of(chain).dispatch(&key(Key::A, true, false));
+
+        let order = vec!["pre", "normal first", "normal second", "normal low", "post"];
+        assert_eq!(*log.lock().unwrap(), order);
+        assert_eq!(names, order);
+    }
+
+    #[test]
+    fn a_blocking_handler_stops_the_chain() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = CallbackChain::default();
+        chain.insert("first", Stage::Normal, 1, None, logger(&log, "first"));
+        chain.insert("blocker", Stage::Normal, 0, None, verdict(Verdict::Block));
+        chain.insert("post", Stage::PostFilter, 0, None, logger(&log, "post"));
+
+        assert_eq!(Simulation::of(chain).dispatch(&key(Key::A, true, false)), Verdict::Block);
+        assert_eq!(*log.lock().unwrap(), vec!["first"]);
+    }
+
+    #[test]
+    fn removed_handlers_no_longer_run() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = CallbackChain::default();
+        let handler = chain.insert("handler", Stage::Normal, 0, None, logger(&log, "handler"));
+        let binding = chain.insert_binding("A", Key::A, Stage::Normal, 0, BindingFilter::default(), logger(&log, "A"));
+        assert!(chain.remove(handler));
+        assert!(chain.remove(binding));
+        assert!(!chain.remove(binding));
+        assert!(chain.handlers().is_empty());
+
+        Simulation::of(chain).dispatch(&key(Key::A, true, false));
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bindings_interleave_with_handlers_in_dispatch_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = CallbackChain::default();
+        chain.insert("high", Stage::Normal, 10, None, logger(&log, "high"));
+        chain.insert("low", Stage::Normal, -10, None, logger(&log, "low"));
+        let filter = BindingFilter::default;
+        chain.insert_binding("A", Key::A, Stage::Normal, 0, filter(), logger(&log, "A"));
+        chain.insert_binding("A tie", Key::A, Stage::Normal, -10, filter(), logger(&log, "A tie"));
+        chain.insert_binding("B", Key::B, Stage::Normal, 0, filter(), logger(&log, "B"));
+        let simulation = Simulation::of(chain);
+
+        simulation.dispatch(&key(Key::A, true, false));
+        assert_eq!(*log.lock().unwrap(), vec!["high", "A", "low", "A tie"]);
+        log.lock().unwrap().clear();
+        simulation.dispatch(&key(Key::C, true, false));
+        assert_eq!(*log.lock().unwrap(), vec!["high", "low"]);
+    }
+
+    #[test]
+    fn bindings_skip_simulated_events_unless_asked() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = CallbackChain::default();
+        chain.insert("handler", Stage::Normal, 0, None, logger(&log, "handler"));
+        chain.insert_binding("A", Key::A, Stage::Normal, 0, BindingFilter::default(), logger(&log, "A"));
+        let simulated = BindingFilter {
+            include_simulated: true,
+            ..BindingFilter::default()
+        };
+        chain.insert_binding("A simulated", Key::A, Stage::Normal, 0, simulated, logger(&log, "A simulated"));
+        let simulation = Simulation::of(chain);
+
+        simulation.dispatch(&key(Key::A, true, true));
+        assert_eq!(*log.lock().unwrap(), vec!["handler", "A simulated"]);
+    }
+
+    #[test]
+    fn bindings_match_the_exact_modifiers_and_the_direction() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = CallbackChain::default();
+        let ctrl_press = BindingFilter {
+            modifiers: Some(Modifiers::CTRL),
+            is_pressed: Some(true),
+            ..BindingFilter::default()
+        };
+        chain.insert_binding("Ctrl+A", Key::A, Stage::Normal, 0, ctrl_press, logger(&log, "Ctrl+A"));
+        let simulation = Simulation::of(chain);
+
+        simulation.dispatch(&key(Key::A, true, false));
+        simulation.dispatch(&key(Key::A, false, false));
+        simulation.dispatch(&key(Key::LControl, true, false));
+        simulation.dispatch(&key(Key::A, true, false));
+        simulation.dispatch(&key(Key::A, false, false));
+        simulation.dispatch(&key(Key::LShift, true, false));
+        simulation.dispatch(&key(Key::A, true, false));
+        assert_eq!(*log.lock().unwrap(), vec!["Ctrl+A"]);
+    }
+
+    #[test]
+    fn modified_events_go_on_through_the_chain() {
+        let mut chain = CallbackChain::default();
+        chain.insert("A to B", Stage::PreFilter, 0, None, verdict(Verdict::Modify(key(Key::B, true, false))));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        chain.insert("logger", Stage::Normal, 0, None, recorder(&seen));
+        let simulation = Simulation::of(chain);
+
+        assert_eq!(simulation.dispatch(&key(Key::A, true, false)), Verdict::Modify(key(Key::B, true, false)));
+        // Modified back into the original event, it goes through as is.
+        assert_eq!(simulation.dispatch(&key(Key::B, true, false)), Verdict::Pass);
+        assert_eq!(*seen.lock().unwrap(), vec![key(Key::B, true, false), key(Key::B, true, false)]);
+    }
+
+    #[test]
+    fn replacing_an_event_stops_the_chain_in_any_stage() {
+        for stage in [Stage::PreFilter, Stage::Normal, Stage::PostFilter] {
+            let mut chain = CallbackChain::default();
+            let replacement = vec![key(Key::B, true, true), key(Key::B, false, true)];
+            chain.insert("A to B", stage, 0, None, verdict(Verdict::Replace(replacement.clone())));
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            chain.insert("after", stage, -1, None, recorder(&seen));
+            let simulation = Simulation::of(chain);
+
+            assert_eq!(simulation.dispatch(&key(Key::A, true, false)), Verdict::Replace(replacement));
+            assert!(seen.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn releases_follow_the_fate_of_their_press() {
+        let blocked_presses = Arc::new(Mutex::new(true));
+        let mut chain = CallbackChain::default();
+        let handler_blocked_presses = blocked_presses.clone();
+        chain.insert(
+            "blocker",
+            Stage::Normal,
+            0,
+            None,
+            Arc::new(move |event| {
+                let blocked = *handler_blocked_presses.lock().unwrap() == event.is_pressed;
+                Verdict::from(!blocked)
+            }),
+        );
+        let simulation = Simulation::of(chain);
+
+        // The release of a blocked press is blocked, although the handler lets it through.
+        assert_eq!(simulation.dispatch(&key(Key::A, true, false)), Verdict::Block);
+        assert_eq!(simulation.dispatch(&key(Key::A, false, false)), Verdict::Block);
+        // The handler now blocks releases, but not the one of a press that went through.
+        *blocked_presses.lock().unwrap() = false;
+        assert_eq!(simulation.dispatch(&key(Key::A, true, false)), Verdict::Pass);
+        assert_eq!(simulation.dispatch(&key(Key::A, false, false)), Verdict::Pass);
+        // Simulated events aren't settled.
+        assert_eq!(simulation.dispatch(&key(Key::A, false, true)), Verdict::Block);
+    }
+
+    #[test]
+    fn releases_of_passed_presses_are_replaced_around() {
+        let mut chain = CallbackChain::default();
+        chain.insert(
+            "remap releases",
+            Stage::Normal,
+            0,
+            None,
+            Arc::new(|event| match (event.is_pressed, Key::from_code(event.key_code)) {
+                (true, _) => Verdict::Pass,
+                (false, Some(Key::A)) => Verdict::Replace(vec![key(Key::C, false, true)]),
+                (false, _) => Verdict::Modify(key(Key::D, false, false)),
+            }),
+        );
+        let simulation = Simulation::of(chain);
+
+        assert_eq!(simulation.dispatch(&key(Key::A, true, false)), Verdict::Pass);
+        assert_eq!(
+            simulation.dispatch(&key(Key::A, false, false)),
+            Verdict::Replace(vec![key(Key::C, false, true), key(Key::A, false, false)])
+        );
+        assert_eq!(simulation.dispatch(&key(Key::B, true, false)), Verdict::Pass);
+        assert_eq!(
+            simulation.dispatch(&key(Key::B, false, false)),
+            Verdict::Replace(vec![key(Key::D, false, false), key(Key::B, false, false)])
+        );
+    }
+
+    #[test]
+    fn pre_filters_may_swallow_the_release_of_a_passed_press() {
+        let mut chain = CallbackChain::default();
+        chain.insert(
+            "chatter",
+            Stage::PreFilter,
+            0,
+            None,
+            Arc::new(|event| Verdict::from(event.is_pressed)),
+        );
+        let simulation = Simulation::of(chain);
+
+        assert_eq!(simulation.dispatch(&key(Key::A, true, false)), Verdict::Pass);
+        assert_eq!(simulation.dispatch(&key(Key::A, false, false)), Verdict::Block);
+    }
+
+    #[test]
+    fn replacements_of_repeats_are_repeats() {
+        let mut chain = CallbackChain::default();
+        chain.insert(
+            "A to B",
+            Stage::Normal,
+            0,
+            None,
+            verdict(Verdict::Replace(vec![key(Key::B, true, true), KeyEvent::unicode('b', true)])),
+        );
+        let simulation = Simulation::of(chain);
+
+        let mut repeat = key(Key::A, true, false);
+        repeat.is_repeat = true;
+        match simulation.dispatch(&repeat) {
+            Verdict::Replace(events) => {
+                assert!(events[0].is_repeat);
+                assert!(!events[1].is_repeat);
+            }
+            verdict => panic!("unexpected {:?}", verdict),
+        }
+    }
+
+    #[test]
+    fn events_replaced_around_by_a_pre_filter_go_on_as_physical_input() {
+        let mut chain = CallbackChain::default();
+        chain.insert(
+            "release A first",
+            Stage::PreFilter,
+            0,
+            None,
+            Arc::new(|event| {
+                let verdict = Verdict::Replace(vec![key(Key::A, false, true), event.clone()]);
+                resume_replacement(event, verdict)
+            }),
+        );
+        let bound = Arc::new(Mutex::new(Vec::new()));
+        chain.insert_binding("D", Key::D, Stage::Normal, 0, BindingFilter::default(), recorder(&bound));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        chain.insert("logger", Stage::PostFilter, 0, None, recorder(&seen));
+
+        let simulation = Simulation::of(chain);
+        let press = key(Key::D, true, false);
+        assert_eq!(
+            simulation.dispatch(&press),
+            Verdict::Replace(vec![key(Key::A, false, true), press.clone()])
+        );
+        assert_eq!(*bound.lock().unwrap(), vec![press.clone()]);
+        assert_eq!(*seen.lock().unwrap(), vec![key(Key::A, false, true), press]);
+    }
+}
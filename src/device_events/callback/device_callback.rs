@@ -0,0 +1,64 @@
+use device_state::InputDevice;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Device callback.
+pub type DeviceCallback = dyn Fn(&InputDevice) + Sync + Send + 'static;
+
+/// Device callbacks.
+#[derive(Default)]
+pub(crate) struct DeviceCallbacks {
+    added: Mutex<Vec<Weak<DeviceCallback>>>,
+    removed: Mutex<Vec<Weak<DeviceCallback>>>,
+}
+
+fn is_empty(callbacks: &Mutex<Vec<Weak<DeviceCallback>>>) -> bool {
+    match callbacks.lock() {
+        Ok(mut callbacks) => {
+            callbacks.retain(|callback| callback.strong_count() > 0);
+            callbacks.is_empty()
+        }
+        Err(_) => true,
+    }
+}
+
+fn run(callbacks: &Mutex<Vec<Weak<DeviceCallback>>>, device: &InputDevice) {
+    let callbacks: Vec<Arc<DeviceCallback>> = match callbacks.lock() {
+        Ok(mut callbacks) => {
+            callbacks.retain(|callback| callback.strong_count() > 0);
+            callbacks.iter().filter_map(Weak::upgrade).collect()
+        }
+        Err(_) => return,
+    };
+    for callback in callbacks {
+        callback(device);
+    }
+}
+
+impl DeviceCallbacks {
+    pub fn push_added(&self, callback: Arc<DeviceCallback>) {
+        if let Ok(mut added) = self.added.lock() {
+            let callback = Arc::downgrade(&callback);
+            added.push(callback)
+        }
+    }
+
+    pub fn push_removed(&self, callback: Arc<DeviceCallback>) {
+        if let Ok(mut removed) = self.removed.lock() {
+            let callback = Arc::downgrade(&callback);
+            removed.push(callback)
+        }
+    }
+
+    /// Whether every callback was dropped.
+    pub fn is_empty(&self) -> bool {
+        is_empty(&self.added) && is_empty(&self.removed)
+    }
+
+    pub fn run_added(&self, device: &InputDevice) {
+        run(&self.added, device);
+    }
+
+    pub fn run_removed(&self, device: &InputDevice) {
+        run(&self.removed, device);
+    }
+}
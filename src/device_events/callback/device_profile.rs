@@ -0,0 +1,89 @@
+//! Device profiles.
+
+use super::{KeyBinding, Verdict};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use {DeviceSelector, Key, KeyEvent};
+
+/// Keymap applied to the events of the devices matching a selector only, e.g. to turn a cheap
+/// numpad into a macro board while the main keyboard stays untouched. Installed with
+/// `DeviceState::add_profile`.
+///
+/// ```no_run
+/// use key_director::{DeviceProfile, DeviceSelector, DeviceState, Key};
+///
+/// let device_state = DeviceState::new();
+/// let profile = DeviceProfile::new("macro pad", DeviceSelector::Usb { vendor: 0x1c4f, product: 0x0002 })
+///     .remap(Key::Numpad1, &[Key::F13])
+///     .remap(Key::Numpad2, &[Key::LControl, Key::C])
+///     .block_unmapped();
+/// let _id = device_state.add_profile(&profile);
+/// let _hotkey = profile.on(Key::Numpad0).pressed().block(|_| println!("Numpad0 on the pad"));
+/// ```
+///
+/// Profiles can be serialized, e.g. to be loaded from a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    pub name: String,
+    pub device: DeviceSelector,
+    /// Keys emitted in place of each remapped key: pressed in order, released in reverse order.
+    pub remaps: BTreeMap<Key, Vec<Key>>,
+    /// Swallow the keys that aren't remapped instead of letting them through.
+    #[serde(default)]
+    pub block_unmapped: bool,
+}
+
+impl DeviceProfile {
+    pub fn new(name: &str, device: DeviceSelector) -> Self {
+        DeviceProfile {
+            name: name.to_string(),
+            device,
+            remaps: BTreeMap::new(),
+            block_unmapped: false,
+        }
+    }
+
+    /// Emit `keys` in place of `key`. Remapping to no key swallows it.
+    pub fn remap(mut self, key: Key, keys: &[Key]) -> Self {
+        self.remaps.insert(key, keys.to_vec());
+        self
+    }
+
+    /// Swallow the keys that aren't remapped.
+    pub fn block_unmapped(mut self) -> Self {
+        self.block_unmapped = true;
+        self
+    }
+
+    /// Start building a hotkey bound to `key` on the devices of the profile. Hotkeys take
+    /// precedence over the remaps of the profile.
+    pub fn on(&self, key: Key) -> KeyBinding {
+        KeyBinding::new(key).device(self.device.clone())
+    }
+
+    /// What the profile does with an event of one of its devices.
+    pub(crate) fn verdict(&self, event: &KeyEvent) -> Verdict {
+        remap_verdict(&self.remaps, self.block_unmapped, event)
+    }
+}
+
+/// What a keymap does with an event: emit the keys the key of the event is remapped to, or
+/// block it if it isn't remapped and `block_unmapped`.
+pub(crate) fn remap_verdict(remaps: &BTreeMap<Key, Vec<Key>>, block_unmapped: bool, event: &KeyEvent) -> Verdict {
+    let keys = match Key::from_code(event.key_code).and_then(|key| remaps.get(&key)) {
+        Some(keys) => keys,
+        None if block_unmapped => return Verdict::Block,
+        None => return Verdict::Pass,
+    };
+
+    let mut codes: Vec<u32> = keys.iter().filter_map(|key| key.code()).collect();
+    if !event.is_pressed {
+        codes.reverse();
+    }
+    Verdict::Replace(
+        codes
+            .into_iter()
+            .map(|code| KeyEvent::new(None, code, 0, event.is_pressed, true))
+            .collect(),
+    )
+}
@@ -0,0 +1,44 @@
+use device_state::WindowInfo;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Focus callback.
+pub type FocusCallback = dyn Fn(Option<&WindowInfo>) + Sync + Send + 'static;
+
+/// Focus callbacks.
+#[derive(Default)]
+pub(crate) struct FocusCallbacks {
+    changed: Mutex<Vec<Weak<FocusCallback>>>,
+}
+
+impl FocusCallbacks {
+    pub fn push_changed(&self, callback: Arc<FocusCallback>) {
+        if let Ok(mut changed) = self.changed.lock() {
+            let callback = Arc::downgrade(&callback);
+            changed.push(callback)
+        }
+    }
+
+    /// Whether every callback was dropped.
+    pub fn is_empty(&self) -> bool {
+        match self.changed.lock() {
+            Ok(mut callbacks) => {
+                callbacks.retain(|callback| callback.strong_count() > 0);
+                callbacks.is_empty()
+            }
+            Err(_) => true,
+        }
+    }
+
+    pub fn run_changed(&self, window: Option<&WindowInfo>) {
+        let callbacks: Vec<Arc<FocusCallback>> = match self.changed.lock() {
+            Ok(mut callbacks) => {
+                callbacks.retain(|callback| callback.strong_count() > 0);
+                callbacks.iter().filter_map(Weak::upgrade).collect()
+            }
+            Err(_) => return,
+        };
+        for callback in callbacks {
+            callback(window);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// Idle callback.
+pub type IdleCallback = dyn Fn() + Sync + Send + 'static;
+
+/// Activity callback, called with how long the user was idle.
+pub type ActiveCallback = dyn Fn(Duration) + Sync + Send + 'static;
+
+struct IdleTimeout {
+    timeout: Duration,
+    callback: Weak<IdleCallback>,
+    /// Whether the callback ran since the user was last active.
+    fired: bool,
+}
+
+/// Idle and activity callbacks.
+#[derive(Default)]
+pub(crate) struct IdleCallbacks {
+    idle: Mutex<Vec<IdleTimeout>>,
+    active: Mutex<Vec<Weak<ActiveCallback>>>,
+}
+
+impl IdleCallbacks {
+    pub fn push_idle(&self, timeout: Duration, callback: Arc<IdleCallback>) {
+        if let Ok(mut idle) = self.idle.lock() {
+            let callback = Arc::downgrade(&callback);
+            idle.push(IdleTimeout {
+                timeout,
+                callback,
+                fired: false,
+            })
+        }
+    }
+
+    pub fn push_active(&self, callback: Arc<ActiveCallback>) {
+        if let Ok(mut active) = self.active.lock() {
+            let callback = Arc::downgrade(&callback);
+            active.push(callback)
+        }
+    }
+
+    /// Whether every callback was dropped.
+    pub fn is_empty(&self) -> bool {
+        let idle_empty = match self.idle.lock() {
+            Ok(mut timeouts) => {
+                timeouts.retain(|timeout| timeout.callback.strong_count() > 0);
+                timeouts.is_empty()
+            }
+            Err(_) => true,
+        };
+        let active_empty = match self.active.lock() {
+            Ok(mut callbacks) => {
+                callbacks.retain(|callback| callback.strong_count() > 0);
+                callbacks.is_empty()
+            }
+            Err(_) => true,
+        };
+        idle_empty && active_empty
+    }
+
+    /// Run the idle callbacks whose timeout `idle_time` just reached.
+    pub fn run_idle(&self, idle_time: Duration) {
+        let callbacks: Vec<Arc<IdleCallback>> = match self.idle.lock() {
+            Ok(mut timeouts) => {
+                timeouts.retain(|timeout| timeout.callback.strong_count() > 0);
+                timeouts
+                    .iter_mut()
+                    .filter_map(|timeout| {
+                        let reached = idle_time >= timeout.timeout;
+                        let fire = reached && !timeout.fired;
+                        timeout.fired = reached;
+                        if fire {
+                            timeout.callback.upgrade()
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            }
+            Err(_) => return,
+        };
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    pub fn run_active(&self, idle_time: Duration) {
+        let callbacks: Vec<Arc<ActiveCallback>> = match self.active.lock() {
+            Ok(mut callbacks) => {
+                callbacks.retain(|callback| callback.strong_count() > 0);
+                callbacks.iter().filter_map(Weak::upgrade).collect()
+            }
+            Err(_) => return,
+        };
+        for callback in callbacks {
+            callback(idle_time);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Key bindings.
+
+use super::{BindingFilter, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_events::event_loop::watch_focus;
+use std::sync::Arc;
+use {DeviceSelector, Key, KeyEvent, Modifiers, WindowSelector};
+
+/// Builder of a handler bound to a single key, created with `DeviceState::on`.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Key, Modifiers};
+///
+/// let device_state = DeviceState::new();
+/// let _id = device_state
+///     .on(Key::F13)
+///     .with(Modifiers::CTRL)
+///     .pressed()
+///     .block(|event| println!("Ctrl+F13: {:?}", event));
+/// ```
+#[derive(Debug, Clone)]
+pub struct KeyBinding {
+    key: Key,
+    name: Option<String>,
+    stage: Stage,
+    priority: i32,
+    filter: BindingFilter,
+}
+
+impl KeyBinding {
+    pub(crate) fn new(key: Key) -> Self {
+        KeyBinding {
+            key,
+            name: None,
+            stage: Stage::Normal,
+            priority: 0,
+            filter: BindingFilter::default(),
+        }
+    }
+
+    /// Only run while exactly these modifiers are held. Modifiers are ignored by default.
+    pub fn with(mut self, modifiers: Modifiers) -> Self {
+        self.filter.modifiers = Some(modifiers);
+        self
+    }
+
+    /// Only run when the key is pressed.
+    pub fn pressed(mut self) -> Self {
+        self.filter.is_pressed = Some(true);
+        self
+    }
+
+    /// Only run when the key is released.
+    pub fn released(mut self) -> Self {
+        self.filter.is_pressed = Some(false);
+        self
+    }
+
+    /// Also run for simulated events, which are ignored by default.
+    pub fn simulated(mut self) -> Self {
+        self.filter.include_simulated = true;
+        self
+    }
+
+    /// Only run for events of the devices matching `selector`. Events from devices the
+    /// backend can't tell apart never match.
+    pub fn device(mut self, selector: DeviceSelector) -> Self {
+        self.filter.device = Some(selector);
+        self
+    }
+
+    /// Only run while a window matching `selector` has the focus, e.g. for the hotkeys of a
+    /// single application. The focused window is polled while the handler is registered, so
+    /// the scope follows focus changes within a fifth of a second.
+    pub fn window(mut self, selector: WindowSelector) -> Self {
+        self.filter.window = Some(selector);
+        self
+    }
+
+    /// Name shown by `DeviceState::handlers`. Defaults to the name of the key.
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Stage to run in, `Stage::Normal` by default.
+    pub fn stage(mut self, stage: Stage) -> Self {
+        self.stage = stage;
+        self
+    }
+
+    /// Priority within the stage, 0 by default.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Run `callback` and block the event.
+    pub fn block<F>(self, callback: F) -> HandlerId
+    where
+        F: Fn(&KeyEvent) + Send + Sync + 'static,
+    {
+        self.handle(move |event| {
+            callback(event);
+            Verdict::Block
+        })
+    }
+
+    /// Run `callback` and let the event through.
+    pub fn observe<F>(self, callback: F) -> HandlerId
+    where
+        F: Fn(&KeyEvent) + Send + Sync + 'static,
+    {
+        self.handle(move |event| {
+            callback(event);
+            Verdict::Pass
+        })
+    }
+
+    /// Let `handler` decide what happens to the event.
+    pub fn handle<F>(self, handler: F) -> HandlerId
+    where
+        F: Fn(&KeyEvent) -> Verdict + Send + Sync + 'static,
+    {
+        let key = self.key;
+        let name = self.name.unwrap_or_else(|| format!("{:?}", key));
+        // Held by the handler, for the focus to be polled until it is removed.
+        let focus_watch = self.filter.window.as_ref().map(|_| watch_focus());
+        let handler = move |event: &KeyEvent| {
+            let _ = &focus_watch;
+            handler(event)
+        };
+        GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert_binding(
+                &name,
+                key,
+                self.stage,
+                self.priority,
+                self.filter,
+                Arc::new(handler),
+            )
+    }
+}
@@ -0,0 +1,44 @@
+use device_state::LockState;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Lock state callback.
+pub type LockCallback = dyn Fn(LockState) + Sync + Send + 'static;
+
+/// Lock state callbacks.
+#[derive(Default)]
+pub(crate) struct LockCallbacks {
+    changed: Mutex<Vec<Weak<LockCallback>>>,
+}
+
+impl LockCallbacks {
+    pub fn push_changed(&self, callback: Arc<LockCallback>) {
+        if let Ok(mut changed) = self.changed.lock() {
+            let callback = Arc::downgrade(&callback);
+            changed.push(callback)
+        }
+    }
+
+    /// Whether every callback was dropped.
+    pub fn is_empty(&self) -> bool {
+        match self.changed.lock() {
+            Ok(mut callbacks) => {
+                callbacks.retain(|callback| callback.strong_count() > 0);
+                callbacks.is_empty()
+            }
+            Err(_) => true,
+        }
+    }
+
+    pub fn run_changed(&self, lock_state: LockState) {
+        let callbacks: Vec<Arc<LockCallback>> = match self.changed.lock() {
+            Ok(mut callbacks) => {
+                callbacks.retain(|callback| callback.strong_count() > 0);
+                callbacks.iter().filter_map(Weak::upgrade).collect()
+            }
+            Err(_) => return,
+        };
+        for callback in callbacks {
+            callback(lock_state);
+        }
+    }
+}
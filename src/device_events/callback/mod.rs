@@ -1,5 +1,21 @@
+mod app_profile;
+mod callback_chain;
 mod callback_guard;
+mod device_callback;
+mod device_profile;
+mod focus_callback;
+mod idle_callback;
+mod key_binding;
 mod keyboard_callback;
+mod lock_callback;
 
+pub use self::app_profile::*;
+pub use self::callback_chain::*;
 pub use self::callback_guard::*;
+pub use self::device_callback::*;
+pub use self::device_profile::*;
+pub use self::focus_callback::*;
+pub use self::idle_callback::*;
+pub use self::key_binding::*;
 pub use self::keyboard_callback::*;
+pub use self::lock_callback::*;
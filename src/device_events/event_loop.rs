@@ -1,33 +1,238 @@
-use super::{CallbackGuard, KeyboardCallbacks};
-use std::sync::{Arc, Mutex, Weak};
-use std::thread::{sleep, spawn, JoinHandle};
-use std::time::Duration;
+use super::{CallbackGuard, DeviceCallbacks, FocusCallback, FocusCallbacks, IdleCallbacks, KeyboardCallbacks, LockCallbacks};
+use device_state::{
+    active_window, idle_time, input_devices, lock_state, polled_lock_state, DeviceMonitor, InputDevice, LockKey,
+    LockState, WindowInfo,
+};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
 use KeyEvent;
 
+/// How often the lock state is checked for changes, where the OS doesn't report them.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the focused window is checked for changes.
+const FOCUS_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How often the idle time is checked.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Shortest pause in the input reported to the activity callbacks.
+const MIN_IDLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Drift of the time of the last input, computed from idle counters in whole milliseconds.
+const IDLE_JITTER: Duration = Duration::from_millis(10);
+
+/// Changes polled for by a background thread, while some callbacks want them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Poll {
+    Devices,
+    Locks,
+    Focus,
+    Idle,
+}
+
 pub(crate) struct EventLoop {
     keyboard_callbacks: Arc<KeyboardCallbacks>,
-    _keyboard_thread: JoinHandle<()>,
+    device_callbacks: Arc<DeviceCallbacks>,
+    lock_callbacks: Arc<LockCallbacks>,
+    focus_callbacks: Arc<FocusCallbacks>,
+    idle_callbacks: Arc<IdleCallbacks>,
+    /// Changes a thread is polling for.
+    polling: HashSet<Poll>,
+    /// Lock state last reported to the lock callbacks, while there are some.
+    lock_state: Option<LockState>,
+}
+
+fn device_thread(callbacks: Arc<DeviceCallbacks>) {
+    spawn(move || {
+        let monitor = DeviceMonitor::new();
+        let mut known = input_devices();
+        loop {
+            monitor.wait(Duration::from_millis(1000));
+            if !keep_polling(Poll::Devices) {
+                break;
+            }
+
+            let devices = input_devices();
+            let is_known = |devices: &[InputDevice], device: &InputDevice| {
+                devices.iter().any(|other| other.id == device.id)
+            };
+            for device in devices.iter().filter(|device| !is_known(&known, device)) {
+                callbacks.run_added(device);
+            }
+            for device in known.iter().filter(|device| !is_known(&devices, device)) {
+                callbacks.run_removed(device);
+            }
+            known = devices;
+        }
+    });
+}
+
+/// Poll the lock state where the OS doesn't report its changes. They are reported through
+/// `lock_changed` otherwise.
+fn lock_thread() {
+    spawn(move || loop {
+        sleep(LOCK_POLL_INTERVAL);
+        if !keep_polling(Poll::Locks) {
+            break;
+        }
+        if let Some(current) = polled_lock_state() {
+            update_lock_state(|lock_state| *lock_state = current);
+        }
+    });
+}
+
+fn focus_thread(callbacks: Arc<FocusCallbacks>) {
+    spawn(move || {
+        let mut known = active_window();
+        set_focused_window(known.clone());
+        loop {
+            sleep(FOCUS_POLL_INTERVAL);
+            if !keep_polling(Poll::Focus) {
+                break;
+            }
+
+            let current = active_window();
+            if current != known {
+                known = current;
+                set_focused_window(known.clone());
+                callbacks.run_changed(known.as_ref());
+            }
+        }
+    });
 }
 
-fn keyboard_thread(callbacks: Weak<KeyboardCallbacks>) -> JoinHandle<()> {
+fn idle_thread(callbacks: Arc<IdleCallbacks>) {
     spawn(move || {
+        let mut last_input = Instant::now() - idle_time();
         loop {
-            sleep(Duration::from_millis(50));
-            if callbacks.upgrade().is_none() {
+            sleep(IDLE_POLL_INTERVAL);
+            if !keep_polling(Poll::Idle) {
                 break;
             }
+
+            let idle = idle_time();
+            let input = Instant::now() - idle;
+            if input > last_input + IDLE_JITTER {
+                let pause = input - last_input;
+                if pause >= MIN_IDLE_PERIOD {
+                    callbacks.run_active(pause);
+                }
+                last_input = input;
+            }
+            callbacks.run_idle(idle);
+        }
+    });
+}
+
+/// Whether the thread polling for `poll` should go on, some callbacks still wanting the
+/// changes. It is forgotten otherwise, under the event loop lock, for the next callback
+/// registered to start a new thread.
+fn keep_polling(poll: Poll) -> bool {
+    let mut event_loop = match EVENT_LOOP.lock() {
+        Ok(event_loop) => event_loop,
+        Err(_) => return false,
+    };
+    if event_loop.is_wanted(poll) {
+        return true;
+    }
+    event_loop.polling.remove(&poll);
+    match poll {
+        Poll::Locks => event_loop.lock_state = None,
+        Poll::Focus => set_focused_window(None),
+        Poll::Devices | Poll::Idle => {}
+    }
+    false
+}
+
+/// Run the lock callbacks if `update` changes the lock state last reported to them.
+fn update_lock_state<F: FnOnce(&mut LockState)>(update: F) {
+    let (callbacks, lock_state) = {
+        let mut event_loop = match EVENT_LOOP.lock() {
+            Ok(event_loop) => event_loop,
+            Err(_) => return,
+        };
+        let known = match event_loop.lock_state {
+            Some(known) => known,
+            None => return,
+        };
+        let mut lock_state = known;
+        update(&mut lock_state);
+        if lock_state == known {
+            return;
         }
-    })
+        event_loop.lock_state = Some(lock_state);
+        (event_loop.lock_callbacks.clone(), lock_state)
+    };
+    callbacks.run_changed(lock_state);
+}
+
+/// Report a lock turned on or off, for platforms noticing it from their events rather than by
+/// polling.
+pub(crate) fn lock_changed(key: LockKey, on: bool) {
+    update_lock_state(|lock_state| lock_state.set(key, on));
+}
+
+fn set_focused_window(window: Option<WindowInfo>) {
+    if let Ok(mut focused_window) = FOCUSED_WINDOW.lock() {
+        *focused_window = window;
+    }
+}
+
+/// Window that had the focus when it was last polled, for handlers that can't wait for a
+/// query. Only polled while there are focus callbacks, see `watch_focus`.
+pub(crate) fn focused_window() -> Option<WindowInfo> {
+    FOCUSED_WINDOW.lock().ok()?.clone()
+}
+
+/// Start polling the focused window for `focused_window`, if it isn't yet. Polling goes on
+/// until the returned focus callback, which does nothing, is dropped along with the others.
+pub(crate) fn watch_focus() -> Arc<FocusCallback> {
+    let watch: Arc<FocusCallback> = Arc::new(|_: Option<&WindowInfo>| {});
+    let mut event_loop = EVENT_LOOP.lock().expect("Couldn't lock EVENT_LOOP");
+    event_loop.focus_callbacks.push_changed(watch.clone());
+    event_loop.poll(Poll::Focus);
+    watch
 }
 
 impl EventLoop {
     pub fn new() -> Self {
-        let keyboard_callbacks = Arc::new(KeyboardCallbacks::default());
-        let keyboard_thread = keyboard_thread(Arc::downgrade(&keyboard_callbacks));
-        
         Self {
-            keyboard_callbacks,
-            _keyboard_thread: keyboard_thread,
+            keyboard_callbacks: Arc::new(KeyboardCallbacks::default()),
+            device_callbacks: Arc::new(DeviceCallbacks::default()),
+            lock_callbacks: Arc::new(LockCallbacks::default()),
+            focus_callbacks: Arc::new(FocusCallbacks::default()),
+            idle_callbacks: Arc::new(IdleCallbacks::default()),
+            polling: HashSet::new(),
+            lock_state: None,
+        }
+    }
+
+    /// Whether some callbacks want the changes `poll` looks for.
+    fn is_wanted(&self, poll: Poll) -> bool {
+        match poll {
+            Poll::Devices => !self.device_callbacks.is_empty(),
+            Poll::Locks => !self.lock_callbacks.is_empty(),
+            Poll::Focus => !self.focus_callbacks.is_empty(),
+            Poll::Idle => !self.idle_callbacks.is_empty(),
+        }
+    }
+
+    /// Start a thread polling for `poll`, unless one already is.
+    fn poll(&mut self, poll: Poll) {
+        if !self.polling.insert(poll) {
+            return;
+        }
+        match poll {
+            Poll::Devices => device_thread(self.device_callbacks.clone()),
+            Poll::Locks => {
+                self.lock_state = Some(lock_state());
+                lock_thread();
+            }
+            Poll::Focus => focus_thread(self.focus_callbacks.clone()),
+            Poll::Idle => idle_thread(self.idle_callbacks.clone()),
         }
     }
 
@@ -49,6 +254,67 @@ impl EventLoop {
         CallbackGuard { _callback }
     }
 
+    pub fn on_device_added<Callback: Fn(&InputDevice) + Send + Sync + 'static>(
+        &mut self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        let _callback = Arc::new(callback);
+        self.device_callbacks.push_added(_callback.clone());
+        self.poll(Poll::Devices);
+        CallbackGuard { _callback }
+    }
+
+    pub fn on_device_removed<Callback: Fn(&InputDevice) + Send + Sync + 'static>(
+        &mut self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        let _callback = Arc::new(callback);
+        self.device_callbacks.push_removed(_callback.clone());
+        self.poll(Poll::Devices);
+        CallbackGuard { _callback }
+    }
+
+    pub fn on_lock_change<Callback: Fn(LockState) + Send + Sync + 'static>(
+        &mut self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        let _callback = Arc::new(callback);
+        self.lock_callbacks.push_changed(_callback.clone());
+        self.poll(Poll::Locks);
+        CallbackGuard { _callback }
+    }
+
+    pub fn on_focus_change<Callback: Fn(Option<&WindowInfo>) + Send + Sync + 'static>(
+        &mut self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        let _callback = Arc::new(callback);
+        self.focus_callbacks.push_changed(_callback.clone());
+        self.poll(Poll::Focus);
+        CallbackGuard { _callback }
+    }
+
+    pub fn on_idle<Callback: Fn() + Send + Sync + 'static>(
+        &mut self,
+        timeout: Duration,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        let _callback = Arc::new(callback);
+        self.idle_callbacks.push_idle(timeout, _callback.clone());
+        self.poll(Poll::Idle);
+        CallbackGuard { _callback }
+    }
+
+    pub fn on_active<Callback: Fn(Duration) + Send + Sync + 'static>(
+        &mut self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        let _callback = Arc::new(callback);
+        self.idle_callbacks.push_active(_callback.clone());
+        self.poll(Poll::Idle);
+        CallbackGuard { _callback }
+    }
+
     pub fn on_keys<F>(&mut self, callback: F) -> CallbackGuard<F>
     where
         F: Fn(Vec<KeyEvent>) -> bool + Send + Sync + 'static,
@@ -61,4 +327,47 @@ impl EventLoop {
 
 lazy_static! {
     pub(crate) static ref EVENT_LOOP: Arc<Mutex<EventLoop>> = Arc::new(Mutex::new(EventLoop::new()));
+    static ref FOCUSED_WINDOW: Mutex<Option<WindowInfo>> = Mutex::new(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(class: &str, title: &str) -> WindowInfo {
+        WindowInfo {
+            class: class.to_string(),
+            title: title.to_string(),
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn the_focus_is_polled_while_focus_callbacks_are_alive() {
+        let event_loop = EventLoop::new();
+        assert!(!event_loop.is_wanted(Poll::Focus));
+        let callback: Arc<FocusCallback> = Arc::new(|_: Option<&WindowInfo>| {});
+        event_loop.focus_callbacks.push_changed(callback.clone());
+        assert!(event_loop.is_wanted(Poll::Focus));
+        drop(callback);
+        assert!(!event_loop.is_wanted(Poll::Focus));
+    }
+
+    #[test]
+    fn focus_changes_reach_the_callbacks_alive() {
+        let callbacks = FocusCallbacks::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let callback: Arc<FocusCallback> =
+            Arc::new(move |window: Option<&WindowInfo>| recorder.lock().unwrap().push(window.cloned()));
+        callbacks.push_changed(callback.clone());
+
+        let editor = window("code", "main.rs - Visual Studio Code");
+        callbacks.run_changed(Some(&editor));
+        callbacks.run_changed(None);
+        drop(callback);
+        callbacks.run_changed(Some(&editor));
+        assert_eq!(*seen.lock().unwrap(), vec![Some(editor), None]);
+        assert!(callbacks.is_empty());
+    }
 }
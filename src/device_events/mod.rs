@@ -6,8 +6,9 @@ pub(crate) mod event_loop;
 pub use self::callback::*;
 use self::event_loop::*;
 
+use std::time::Duration;
 use {DeviceQuery, KeyEvent};
-use DeviceState;
+use {DeviceState, InputDevice, LockState, WindowInfo};
 
 /// All the supported devices events.
 pub trait DeviceEvents: DeviceQuery {
@@ -22,6 +23,50 @@ pub trait DeviceEvents: DeviceQuery {
         &self,
         callback: Callback,
     ) -> CallbackGuard<Callback>;
+
+    /// Register a callback for input devices being plugged in.
+    fn on_device_added<Callback: Fn(&InputDevice) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback>;
+
+    /// Register a callback for input devices being unplugged.
+    fn on_device_removed<Callback: Fn(&InputDevice) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback>;
+
+    /// Register a callback for Caps Lock, Num Lock or Scroll Lock being turned on or off,
+    /// called with the new lock state. Changes are noticed within a tenth of a second, or as
+    /// the keyboard LEDs change when the keyboards are read through evdev.
+    fn on_lock_change<Callback: Fn(LockState) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback>;
+
+    /// Register a callback for the focused window changing, or its title, called with the
+    /// new focused window if any. Changes are noticed within a fifth of a second.
+    fn on_focus_change<Callback: Fn(Option<&WindowInfo>) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback>;
+
+    /// Register a callback for the user being idle for `timeout`: without physical key input
+    /// nor, where the OS keeps an idle counter (XScreenSaver on X11), any other input. Input
+    /// injected by this crate doesn't count. Called once per idle period, within a quarter
+    /// of a second.
+    fn on_idle<Callback: Fn() + Sync + Send + 'static>(
+        &self,
+        timeout: Duration,
+        callback: Callback,
+    ) -> CallbackGuard<Callback>;
+
+    /// Register a callback for input resuming after the user was idle for at least a second,
+    /// called with how long they were idle.
+    fn on_active<Callback: Fn(Duration) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback>;
 }
 
 impl DeviceEvents for DeviceState {
@@ -44,4 +89,65 @@ impl DeviceEvents for DeviceState {
             .expect("Couldn't lock EVENT_LOOP")
             .on_key_up(callback)
     }
+
+    fn on_device_added<Callback: Fn(&InputDevice) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        EVENT_LOOP
+            .lock()
+            .expect("Couldn't lock EVENT_LOOP")
+            .on_device_added(callback)
+    }
+
+    fn on_device_removed<Callback: Fn(&InputDevice) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        EVENT_LOOP
+            .lock()
+            .expect("Couldn't lock EVENT_LOOP")
+            .on_device_removed(callback)
+    }
+
+    fn on_lock_change<Callback: Fn(LockState) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        EVENT_LOOP
+            .lock()
+            .expect("Couldn't lock EVENT_LOOP")
+            .on_lock_change(callback)
+    }
+
+    fn on_focus_change<Callback: Fn(Option<&WindowInfo>) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        EVENT_LOOP
+            .lock()
+            .expect("Couldn't lock EVENT_LOOP")
+            .on_focus_change(callback)
+    }
+
+    fn on_idle<Callback: Fn() + Sync + Send + 'static>(
+        &self,
+        timeout: Duration,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        EVENT_LOOP
+            .lock()
+            .expect("Couldn't lock EVENT_LOOP")
+            .on_idle(timeout, callback)
+    }
+
+    fn on_active<Callback: Fn(Duration) + Sync + Send + 'static>(
+        &self,
+        callback: Callback,
+    ) -> CallbackGuard<Callback> {
+        EVENT_LOOP
+            .lock()
+            .expect("Couldn't lock EVENT_LOOP")
+            .on_active(callback)
+    }
 }
@@ -0,0 +1,60 @@
+//! Idle time of the user.
+
+use super::os_idle_time;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Input the OS got this close to an injection is taken for the injected input.
+const INJECTION_SLACK: Duration = Duration::from_millis(100);
+
+struct Activity {
+    /// Last physical key event seen by the callbacks.
+    input: Instant,
+    /// Last time this crate injected or emitted input.
+    injection: Option<Instant>,
+}
+
+lazy_static! {
+    static ref ACTIVITY: Mutex<Activity> = Mutex::new(Activity {
+        input: Instant::now(),
+        injection: None,
+    });
+}
+
+/// Record physical input.
+pub(crate) fn record_input() {
+    if let Ok(mut activity) = ACTIVITY.lock() {
+        activity.input = Instant::now();
+    }
+}
+
+/// Record that input was just injected, so that the idle counter of the OS isn't trusted
+/// about it.
+pub(crate) fn record_injection() {
+    if let Ok(mut activity) = ACTIVITY.lock() {
+        activity.injection = Some(Instant::now());
+    }
+}
+
+fn is_near(instant: Instant, other: Instant) -> bool {
+    let distance = if instant > other { instant - other } else { other - instant };
+    distance <= INJECTION_SLACK
+}
+
+/// Time since the last physical input: the last key event seen by the callbacks, or the last
+/// input the OS got if it is later, e.g. from the mouse, unless it is close enough to an
+/// injection to be the injected input. The OS doesn't tell injected input apart, so physical
+/// input made while this crate injects input may go unnoticed.
+pub(crate) fn idle_time() -> Duration {
+    let now = Instant::now();
+    let (mut input, injection) = match ACTIVITY.lock() {
+        Ok(activity) => (activity.input, activity.injection),
+        Err(_) => return Duration::from_secs(0),
+    };
+    if let Some(os_input) = os_idle_time().and_then(|idle| now.checked_sub(idle)) {
+        if os_input > input && !injection.is_some_and(|injection| is_near(os_input, injection)) {
+            input = os_input;
+        }
+    }
+    now.saturating_duration_since(input)
+}
@@ -0,0 +1,421 @@
+//! Ordered input injection.
+
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Mouse buttons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+/// Input injected by `DeviceState::send`. Injected input is flagged as simulated when the
+/// callbacks see it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum InputAction {
+    /// Press the key with this platform key code.
+    KeyDown(u32),
+    /// Release the key with this platform key code.
+    KeyUp(u32),
+    /// Repeat the press of the held key with this platform key code, flagged as a repeat.
+    KeyRepeat(u32),
+    /// Move the pointer relatively to its current position.
+    MouseMove { dx: i32, dy: i32 },
+    MouseDown(MouseButton),
+    MouseUp(MouseButton),
+    /// Turn the wheels by notches: up for a positive `dy`, right for a positive `dx`.
+    Scroll { dx: i32, dy: i32 },
+}
+
+/// Backend function injecting a batch of actions, in order.
+pub(crate) type Sink = fn(&[InputAction]);
+
+/// How often the worker looks for stuck keys while idle, when a timeout is set.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct Queue {
+    batches: VecDeque<(u64, Vec<InputAction>)>,
+    /// Ticket of the last queued batch.
+    queued: u64,
+    /// Ticket of the last injected batch.
+    injected: u64,
+    stuck_key_timeout: Option<Duration>,
+    /// Number of calls to `release_all` so far.
+    epoch: u64,
+}
+
+/// Keys and buttons pressed by injected input and not released yet, with the time of their
+/// last press and whether it was queued rather than emitted by a backend.
+#[derive(Default)]
+struct Held {
+    presses: Vec<(InputAction, Instant, bool)>,
+}
+
+impl Held {
+    fn record(&mut self, actions: &[InputAction], queued: bool) {
+        for action in actions {
+            let press = match *action {
+                InputAction::KeyDown(_) | InputAction::MouseDown(_) => action.clone(),
+                InputAction::KeyUp(key) => InputAction::KeyDown(key),
+                InputAction::MouseUp(button) => InputAction::MouseDown(button),
+                InputAction::KeyRepeat(_) | InputAction::MouseMove { .. } | InputAction::Scroll { .. } => continue,
+            };
+            self.presses.retain(|(held, _, _)| *held != press);
+            if press == *action {
+                self.presses.push((press, Instant::now(), queued));
+            }
+        }
+    }
+
+    /// Forget the presses made before `deadline`, or all of them, and return the actions
+    /// releasing them.
+    fn take_releases(&mut self, deadline: Option<Instant>) -> Vec<InputAction> {
+        let mut releases = Vec::new();
+        self.presses.retain(|(press, time, _)| {
+            if let Some(deadline) = deadline {
+                if *time >= deadline {
+                    return true;
+                }
+            }
+            releases.push(match *press {
+                InputAction::MouseDown(button) => InputAction::MouseUp(button),
+                InputAction::KeyDown(key) => InputAction::KeyUp(key),
+                _ => unreachable!("only presses are held"),
+            });
+            false
+        });
+        releases
+    }
+}
+
+thread_local! {
+    /// Whether the thread is running the sink, so that a panic of the backend doesn't make
+    /// `release_all` wait for the batch the thread is injecting itself.
+    static INJECTING: Cell<bool> = const { Cell::new(false) };
+}
+
+thread_local! {
+    /// Input queued by the thread while `Injector::capture` runs, instead of being injected.
+    static CAPTURED: RefCell<Option<Vec<InputAction>>> = const { RefCell::new(None) };
+}
+
+static RELEASE_ON_PANIC: Once = Once::new();
+
+struct Shared {
+    sink: Sink,
+    queue: Mutex<Queue>,
+    held: Mutex<Held>,
+    /// Held while input is injected and recorded, so that `release_all` can't run in between
+    /// and miss a press.
+    injecting: Mutex<()>,
+    changed: Condvar,
+}
+
+impl Shared {
+    /// Inject `actions` and record the keys and buttons they leave pressed, in one go.
+    fn inject(&self, actions: &[InputAction], queued: bool) {
+        let _injecting = self.injecting.lock().unwrap_or_else(PoisonError::into_inner);
+        INJECTING.with(|injecting| injecting.set(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| (self.sink)(actions)));
+        INJECTING.with(|injecting| injecting.set(false));
+        // Part of a batch may be injected when the backend panics: releasing keys that weren't
+        // pressed is harmless, leaving pressed keys behind isn't.
+        self.lock_held().record(actions, queued);
+        if let Err(panic) = result {
+            panic::resume_unwind(panic);
+        }
+    }
+
+    fn lock_queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_held(&self) -> MutexGuard<'_, Held> {
+        self.held.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Single worker thread injecting batches of input in the order they were queued, so that a
+/// release can never overtake the press before it.
+///
+/// Waiting on the injector from a callback deadlocks until the OS gives up on the hook, since
+/// the injected input has to go through the callbacks too. Callbacks should return a `Verdict`
+/// or only `queue` input instead.
+#[derive(Clone)]
+pub struct Injector {
+    shared: Arc<Shared>,
+}
+
+impl Injector {
+    pub(crate) fn new(sink: Sink) -> Self {
+        let shared = Arc::new(Shared {
+            sink,
+            queue: Mutex::new(Queue::default()),
+            held: Mutex::new(Held::default()),
+            injecting: Mutex::new(()),
+            changed: Condvar::new(),
+        });
+
+        let worker = shared.clone();
+        thread::spawn(move || loop {
+            let (ticket, actions) = {
+                let mut queue = worker.lock_queue();
+                loop {
+                    if let Some(batch) = queue.batches.pop_front() {
+                        break batch;
+                    }
+                    queue = match queue.stuck_key_timeout {
+                        Some(timeout) => {
+                            // Nothing can have been held for longer than the time since boot.
+                            let stuck = match Instant::now().checked_sub(timeout) {
+                                Some(deadline) => worker.lock_held().take_releases(Some(deadline)),
+                                None => Vec::new(),
+                            };
+                            if !stuck.is_empty() {
+                                queue.queued += 1;
+                                break (queue.queued, stuck);
+                            }
+                            worker
+                                .changed
+                                .wait_timeout(queue, WATCHDOG_INTERVAL)
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .0
+                        }
+                        None => worker.changed.wait(queue).unwrap_or_else(PoisonError::into_inner),
+                    };
+                }
+            };
+
+            // A panicking backend loses its batch, but mustn't take the worker down with it:
+            // everything waiting on the injector would block forever.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| worker.inject(&actions, true)));
+
+            let mut queue = worker.lock_queue();
+            queue.injected = queue.injected.max(ticket);
+            worker.changed.notify_all();
+        });
+
+        Injector { shared }
+    }
+
+    /// Queue `actions` to be injected after everything queued before them, without waiting.
+    /// Returns a ticket to pass to `wait_for`.
+    pub fn queue(&self, actions: Vec<InputAction>) -> u64 {
+        let actions = match CAPTURED.with(|captured| match *captured.borrow_mut() {
+            Some(ref mut captured) => {
+                captured.extend(actions);
+                None
+            }
+            None => Some(actions),
+        }) {
+            Some(actions) => actions,
+            // Nothing to wait for.
+            None => return 0,
+        };
+        let mut queue = self.shared.lock_queue();
+        queue.queued += 1;
+        let ticket = queue.queued;
+        queue.batches.push_back((ticket, actions));
+        self.shared.changed.notify_all();
+        ticket
+    }
+
+    /// Block until the batch with this ticket has been injected.
+    pub fn wait_for(&self, ticket: u64) {
+        let mut queue = self.shared.lock_queue();
+        while queue.injected < ticket {
+            queue = self.shared.changed.wait(queue).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Inject `actions` after everything queued before them and block until they are injected.
+    pub fn send(&self, actions: &[InputAction]) {
+        let ticket = self.queue(actions.to_vec());
+        self.wait_for(ticket);
+    }
+
+    /// Block until everything queued so far has been injected.
+    pub fn flush(&self) {
+        let ticket = self.shared.lock_queue().queued;
+        self.wait_for(ticket);
+    }
+
+    /// Block until nothing is left to inject, including input queued while waiting. Returns
+    /// `false` if the queue still wasn't empty after `timeout`.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.lock_queue();
+        while queue.injected < queue.queued {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            queue = self
+                .shared
+                .changed
+                .wait_timeout(queue, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+
+    /// Release every key and mouse button that injected input pressed and didn't release yet.
+    /// Input still waiting in the queue is discarded, so that it can't press anything again.
+    ///
+    /// Waits for the batch being injected, if any, but not for the rest of the queue. Like
+    /// `send`, it deadlocks when called from a callback while a batch is being injected.
+    pub fn release_all(&self) {
+        if INJECTING.with(Cell::get) {
+            // The backend panicked while injecting: the batch can't be waited for.
+            return;
+        }
+        {
+            let mut queue = self.shared.lock_queue();
+            queue.batches.clear();
+            queue.injected = queue.queued;
+            queue.epoch += 1;
+        }
+        {
+            let _injecting = self.shared.injecting.lock().unwrap_or_else(PoisonError::into_inner);
+            let releases = self.shared.lock_held().take_releases(None);
+            if !releases.is_empty() {
+                INJECTING.with(|injecting| injecting.set(true));
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| (self.shared.sink)(&releases)));
+                INJECTING.with(|injecting| injecting.set(false));
+            }
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// Call `release_all` whenever a thread panics, before the previous panic hook runs, so
+    /// that a crashing application doesn't leave keys pressed behind. Installed once, however
+    /// often it is called.
+    ///
+    /// The hook is global and also runs for panics that are caught, e.g. by `catch_unwind`.
+    pub(crate) fn release_all_on_panic(&self) {
+        let releaser = self.clone();
+        RELEASE_ON_PANIC.call_once(move || {
+            let previous_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                releaser.release_all();
+                previous_hook(info);
+            }));
+        });
+    }
+
+    /// Release injected keys and buttons automatically once they have been held for
+    /// `timeout` without being pressed again. Disabled with `None`, the default.
+    pub fn set_stuck_key_timeout(&self, timeout: Option<Duration>) {
+        self.shared.lock_queue().stuck_key_timeout = timeout;
+        self.shared.changed.notify_all();
+    }
+
+    /// Run `simulate`, returning what it returns and the input queued by the current thread
+    /// meanwhile, which isn't injected.
+    pub(crate) fn capture<T, F: FnOnce() -> T>(simulate: F) -> (T, Vec<InputAction>) {
+        let outer = CAPTURED.with(|captured| captured.replace(Some(Vec::new())));
+        let result = panic::catch_unwind(AssertUnwindSafe(simulate));
+        let actions = CAPTURED.with(|captured| captured.replace(outer)).unwrap_or_default();
+        match result {
+            Ok(result) => (result, actions),
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+
+    /// Changes on every call to `release_all`, so that features injecting input on their own
+    /// can tell when to stop.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.shared.lock_queue().epoch
+    }
+
+    /// Record input emitted by a backend without going through the queue.
+    pub(crate) fn record(&self, actions: &[InputAction]) {
+        self.shared.lock_held().record(actions, false);
+    }
+
+    /// Keys pressed by queued input and not released yet, with the time of their last press.
+    pub(crate) fn queued_keys(&self) -> Vec<(u32, Instant)> {
+        self.shared
+            .lock_held()
+            .presses
+            .iter()
+            .filter_map(|(press, time, queued)| match *press {
+                InputAction::KeyDown(key) if *queued => Some((key, *time)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn panicking_sink(actions: &[InputAction]) {
+        if actions.contains(&InputAction::KeyDown(0)) {
+            panic!("backend failure");
+        }
+    }
+
+    lazy_static! {
+        static ref SLOW_SINK_LOG: Mutex<Vec<InputAction>> = Mutex::new(Vec::new());
+    }
+
+    fn slow_sink(actions: &[InputAction]) {
+        thread::sleep(Duration::from_millis(50));
+        SLOW_SINK_LOG.lock().unwrap().extend_from_slice(actions);
+    }
+
+    #[test]
+    fn release_all_releases_the_batch_being_injected() {
+        let injector = Injector::new(slow_sink);
+        injector.queue(vec![InputAction::KeyDown(2)]);
+        thread::sleep(Duration::from_millis(10));
+        injector.release_all();
+        assert!(injector.queued_keys().is_empty());
+        assert_eq!(
+            *SLOW_SINK_LOG.lock().unwrap(),
+            vec![InputAction::KeyDown(2), InputAction::KeyUp(2)]
+        );
+    }
+
+    fn null_sink(_: &[InputAction]) {}
+
+    #[test]
+    fn watchdog_releases_keys_held_past_the_timeout() {
+        let injector = Injector::new(null_sink);
+        injector.set_stuck_key_timeout(Some(Duration::from_millis(10)));
+        injector.send(&[InputAction::KeyDown(3)]);
+        thread::sleep(WATCHDOG_INTERVAL * 3);
+        assert!(injector.queued_keys().is_empty());
+    }
+
+    #[test]
+    fn watchdog_keeps_keys_when_the_timeout_is_longer_than_the_uptime() {
+        let injector = Injector::new(null_sink);
+        injector.set_stuck_key_timeout(Some(Duration::from_secs(u64::MAX / 2)));
+        injector.send(&[InputAction::KeyDown(3)]);
+        thread::sleep(WATCHDOG_INTERVAL * 2);
+        assert!(injector.queued_keys().iter().any(|(key, _)| *key == 3));
+    }
+
+    #[test]
+    fn worker_survives_a_panicking_sink() {
+        let injector = Injector::new(panicking_sink);
+        injector.send(&[InputAction::KeyDown(0)]);
+        injector.send(&[InputAction::KeyDown(1)]);
+        assert!(injector.wait_idle(Duration::from_secs(1)));
+        assert!(injector.queued_keys().iter().any(|(key, _)| *key == 1));
+    }
+}
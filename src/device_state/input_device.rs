@@ -0,0 +1,85 @@
+//! Input device descriptions.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+lazy_static! {
+    /// Numbers given to the devices seen so far, by id.
+    static ref DEVICE_NUMBERS: Mutex<HashMap<String, u32>> = Mutex::new(HashMap::new());
+}
+
+/// Bus an input device is attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Bus {
+    Usb,
+    Bluetooth,
+    /// PS/2, I2C, SPI and other buses internal to the machine.
+    Internal,
+    /// Devices created by software, such as uinput devices.
+    Virtual,
+    Unknown,
+}
+
+/// What an input device can do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub keyboard: bool,
+    pub mouse: bool,
+    pub touch: bool,
+    pub leds: bool,
+}
+
+/// An input device attached to the machine.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct InputDevice {
+    /// Identifier of the device that stays the same as long as it is plugged into the same
+    /// port, such as `usb-0000:00:14.0-2/input0/Foo Keyboard` on Linux or the Raw Input device
+    /// path on Windows.
+    pub id: String,
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+    pub bus: Bus,
+    /// Physical location of the device, such as `usb-0000:00:14.0-2/input0`, when known.
+    pub path: Option<String>,
+    pub capabilities: Capabilities,
+}
+
+impl InputDevice {
+    /// Number of the device for the life of the process, standing for its id where devices
+    /// are looked up on every event, since it is cheaper to hash and copy.
+    pub(crate) fn number(&self) -> u32 {
+        let mut numbers = DEVICE_NUMBERS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(number) = numbers.get(&self.id) {
+            return *number;
+        }
+        let number = numbers.len() as u32;
+        numbers.insert(self.id.clone(), number);
+        number
+    }
+}
+
+/// Selects input devices, e.g. to scope handlers to the events of a single device.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DeviceSelector {
+    /// The device with this `InputDevice::id`.
+    Id(String),
+    /// Devices whose name contains this text, ignoring case.
+    Name(String),
+    /// Devices with these USB vendor and product ids.
+    Usb { vendor: u16, product: u16 },
+    /// Devices at this physical location, see `InputDevice::path`.
+    Path(String),
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, device: &InputDevice) -> bool {
+        match *self {
+            DeviceSelector::Id(ref id) => device.id == *id,
+            DeviceSelector::Name(ref name) => device.name.to_lowercase().contains(&name.to_lowercase()),
+            DeviceSelector::Usb { vendor, product } => device.vendor == vendor && device.product == product,
+            DeviceSelector::Path(ref path) => device.path.as_ref() == Some(path),
+        }
+    }
+}
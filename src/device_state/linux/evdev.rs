@@ -0,0 +1,679 @@
+//! evdev input devices and key events.
+
+use super::emit;
+use super::uinput::{input_event, VirtualDevice, LED_CAPSL, LED_NUML, LED_SCROLLL, VIRTUAL_DEVICE, VIRTUAL_DEVICE_NAME};
+use device_events::event_loop::lock_changed;
+use device_events::{dispatch, Verdict};
+use device_state::{track_key, Bus, Capabilities, InputDevice, LockKey, LockState};
+use libc;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::raw::c_ulong;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use KeyEvent;
+
+const DEVICE_DIR: &str = "/dev/input";
+const SYSFS_DIR: &str = "/sys/class/input";
+
+// Reference: https://github.com/torvalds/linux/blob/master/include/uapi/linux/input-event-codes.h
+const EV_SYN: usize = 0x00;
+const EV_KEY: usize = 0x01;
+const EV_REL: usize = 0x02;
+const EV_ABS: usize = 0x03;
+const EV_MSC: usize = 0x04;
+const EV_LED: usize = 0x11;
+const SYN_REPORT: u16 = 0x00;
+const MSC_SCAN: u16 = 0x04;
+const BTN_MISC: u16 = 0x100;
+const BTN_LEFT: usize = 0x110;
+const BTN_TOUCH: usize = 0x14a;
+const KEY_OK: u16 = 0x160;
+const BTN_DPAD_UP: u16 = 0x220;
+
+// Reference: https://github.com/torvalds/linux/blob/master/include/uapi/linux/input.h
+const BUS_USB: u16 = 0x03;
+const BUS_BLUETOOTH: u16 = 0x05;
+const BUS_VIRTUAL: u16 = 0x06;
+const BUS_I8042: u16 = 0x11;
+const BUS_I2C: u16 = 0x18;
+const BUS_SPI: u16 = 0x1C;
+const EVIOCGKEY: u32 = 0x8060_4518; // _IOR('E', 0x18, [u8; (KEY_MAX + 1) / 8])
+const EVIOCGRAB: u32 = 0x4004_4590; // _IOW('E', 0x90, int)
+const EVIOCGLED: u32 = 0x8008_4519; // _IOR('E', 0x19, [u8; 8])
+
+/// Whether the reader has a keyboard with LEDs open, whose events tell the lock changes.
+static READS_LEDS: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Last LED event the OS sent to the virtual device, for each LED.
+    static ref LEDS: Mutex<Vec<libc::input_event>> = Mutex::new(Vec::new());
+
+    /// Reader shared by every `DeviceState` alive, if any.
+    static ref READER: Mutex<Weak<Reader>> = Mutex::new(Weak::new());
+}
+
+/// Capability bitmap as printed by sysfs: hexadecimal `unsigned long` words, most significant
+/// first.
+struct Bitmap(Vec<c_ulong>);
+
+impl Bitmap {
+    fn parse(text: &str) -> Self {
+        Bitmap(
+            text.split_whitespace()
+                .rev()
+                .map(|word| c_ulong::from_str_radix(word, 16).unwrap_or(0))
+                .collect(),
+        )
+    }
+
+    fn contains(&self, bit: usize) -> bool {
+        let word_bits = 8 * std::mem::size_of::<c_ulong>();
+        match self.0.get(bit / word_bits) {
+            Some(word) => word & (1 << (bit % word_bits)) != 0,
+            None => false,
+        }
+    }
+}
+
+fn bus(bus_type: u16) -> Bus {
+    match bus_type {
+        BUS_USB => Bus::Usb,
+        BUS_BLUETOOTH => Bus::Bluetooth,
+        BUS_VIRTUAL => Bus::Virtual,
+        BUS_I8042 | BUS_I2C | BUS_SPI => Bus::Internal,
+        _ => Bus::Unknown,
+    }
+}
+
+/// Describes the device behind the `eventN` node, from sysfs.
+fn read_device(node: &str) -> Option<InputDevice> {
+    describe_device(&Path::new(SYSFS_DIR).join(node).join("device"), node)
+}
+
+/// Describes the device of `node` from its sysfs directory, `device_dir`.
+fn describe_device(device_dir: &Path, node: &str) -> Option<InputDevice> {
+    let read = |file: &str| {
+        fs::read_to_string(device_dir.join(file))
+            .ok()
+            .map(|text| text.trim().to_string())
+    };
+    let read_hex = |file: &str| {
+        read(file)
+            .and_then(|text| u16::from_str_radix(&text, 16).ok())
+            .unwrap_or(0)
+    };
+    let read_bitmap = |file: &str| Bitmap::parse(&read(file).unwrap_or_default());
+
+    let events = read_bitmap("capabilities/ev");
+    let keys = read_bitmap("capabilities/key");
+    let capabilities = Capabilities {
+        keyboard: events.contains(EV_KEY) && keys.contains(super::kernel_key::KEY_A as usize),
+        mouse: events.contains(EV_REL) && keys.contains(BTN_LEFT),
+        touch: events.contains(EV_ABS) && keys.contains(BTN_TOUCH),
+        leds: events.contains(EV_LED),
+    };
+
+    let name = read("name")?;
+    let path = read("phys").filter(|path| !path.is_empty());
+    // Event nodes are numbered in plugging order, but a device keeps its physical location.
+    // Several nodes can share one location, so the name is part of the identifier too.
+    let id = match path {
+        Some(ref path) => format!("{}/{}", path, name),
+        None => node_path(node),
+    };
+    Some(InputDevice {
+        id,
+        name,
+        vendor: read_hex("id/vendor"),
+        product: read_hex("id/product"),
+        bus: bus(read_hex("id/bustype")),
+        path,
+        capabilities,
+    })
+}
+
+fn node_path(node: &str) -> String {
+    format!("{}/{}", DEVICE_DIR, node)
+}
+
+/// Names of the evdev nodes, such as `event3`, in node order.
+fn event_nodes() -> Vec<String> {
+    let mut nodes: Vec<(u32, String)> = match fs::read_dir(SYSFS_DIR) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter_map(|node| Some((node.strip_prefix("event")?.parse().ok()?, node)))
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    nodes.sort();
+    nodes.into_iter().map(|(_, node)| node).collect()
+}
+
+/// Input devices with an evdev node, in node order.
+pub(crate) fn input_devices() -> Vec<InputDevice> {
+    event_nodes().iter().filter_map(|node| read_device(node)).collect()
+}
+
+/// Which locks are on, from the LEDs of the first keyboard having them. Grabbed keyboards
+/// mirror the LEDs of the virtual device, so every keyboard shows the state of the OS.
+pub(crate) fn lock_state() -> Option<LockState> {
+    event_nodes()
+        .iter()
+        .filter(|node| {
+            read_device(node).is_some_and(|device| device.capabilities.keyboard && device.capabilities.leds)
+        })
+        .filter_map(|node| File::open(node_path(node)).ok())
+        .filter_map(|file| {
+            let mut leds = [0u8; 8];
+            if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGLED as libc::Ioctl, leds.as_mut_ptr()) } < 0 {
+                return None;
+            }
+            let is_on = |led: u16| leds[led as usize / 8] & (1 << (led % 8)) != 0;
+            Some(LockState {
+                caps_lock: is_on(LED_CAPSL),
+                num_lock: is_on(LED_NUML),
+                scroll_lock: is_on(LED_SCROLLL),
+            })
+        })
+        .next()
+}
+
+/// Whether the lock changes are reported from the LED events of a keyboard, see `lock_changed`.
+pub(crate) fn reads_leds() -> bool {
+    READS_LEDS.load(Ordering::Relaxed)
+}
+
+/// Report the lock change an LED event tells.
+fn report_led(event: &libc::input_event) {
+    let key = match event.code {
+        LED_CAPSL => LockKey::CapsLock,
+        LED_NUML => LockKey::NumLock,
+        LED_SCROLLL => LockKey::ScrollLock,
+        _ => return,
+    };
+    lock_changed(key, event.value != 0);
+}
+
+/// Wakes up when evdev nodes are created, removed or get their permissions changed, using
+/// inotify on `/dev/input`.
+pub(crate) struct DeviceMonitor {
+    fd: libc::c_int,
+}
+
+impl DeviceMonitor {
+    pub fn new() -> Self {
+        let directory = CString::new(DEVICE_DIR).expect("Device directory contains a nul byte");
+        unsafe {
+            let fd = libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK);
+            if fd >= 0
+                && libc::inotify_add_watch(
+                    fd,
+                    directory.as_ptr(),
+                    libc::IN_CREATE | libc::IN_DELETE | libc::IN_ATTRIB,
+                ) < 0
+            {
+                libc::close(fd);
+                return DeviceMonitor { fd: -1 };
+            }
+            DeviceMonitor { fd }
+        }
+    }
+
+    /// Block until something changed in `/dev/input` or `timeout` elapsed.
+    pub fn wait(&self, timeout: Duration) {
+        if self.fd < 0 {
+            thread::sleep(timeout);
+            return;
+        }
+
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) } > 0 {
+            self.drain();
+        }
+    }
+
+    fn drain(&self) {
+        // Give udev a moment to finish setting up the node, then drain the events.
+        thread::sleep(Duration::from_millis(50));
+        let mut buffer = [0u8; 4096];
+        unsafe {
+            while libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) > 0 {}
+        }
+    }
+}
+
+impl Drop for DeviceMonitor {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+/// Whether an `EV_KEY` code is a key rather than a mouse, joystick or gamepad button.
+fn is_key(code: u16) -> bool {
+    code < BTN_MISC || (KEY_OK..BTN_DPAD_UP).contains(&code)
+}
+
+/// Open evdev node of a keyboard.
+///
+/// Keyboards are grabbed when the virtual device could be created, so that their events only
+/// reach the OS if the callbacks let them through. Devices with touch input aren't grabbed,
+/// since the virtual device can't reproduce it.
+struct Source {
+    node: String,
+    file: File,
+    device: Arc<InputDevice>,
+    grabbed: bool,
+    /// Scan code reported for the key event of the current frame.
+    scan_code: u32,
+    /// Events of the current frame to re-emit once it is complete.
+    frame: Vec<libc::input_event>,
+}
+
+impl Source {
+    fn open(node: &str) -> Option<Source> {
+        let device = read_device(node)
+            .filter(|device| device.capabilities.keyboard && device.name != VIRTUAL_DEVICE_NAME)?;
+        // Writing is only needed to set the LEDs.
+        let open = |write: bool| {
+            OpenOptions::new()
+                .read(true)
+                .write(write)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(node_path(node))
+        };
+        let file = open(true).or_else(|_| open(false)).ok()?;
+        let mut source = Source {
+            node: node.to_string(),
+            file,
+            device: Arc::new(device),
+            grabbed: false,
+            scan_code: 0,
+            frame: Vec::new(),
+        };
+        source.grab();
+        Some(source)
+    }
+
+    fn should_grab(&self) -> bool {
+        !self.grabbed && !self.device.capabilities.touch && VIRTUAL_DEVICE.is_some()
+    }
+
+    /// Grab the device once none of its keys is held, or the OS would never see their release.
+    fn grab(&mut self) {
+        if !self.should_grab() {
+            return;
+        }
+        let fd = self.file.as_raw_fd();
+        let mut held_keys = [0u8; 96];
+        unsafe {
+            if libc::ioctl(fd, EVIOCGKEY as libc::Ioctl, held_keys.as_mut_ptr()) < 0
+                || held_keys.iter().any(|byte| *byte != 0)
+            {
+                return;
+            }
+            self.grabbed = libc::ioctl(fd, EVIOCGRAB as libc::Ioctl, 1 as libc::c_int) >= 0;
+        }
+        if self.grabbed {
+            if let Ok(leds) = LEDS.lock() {
+                self.set_leds(&leds);
+            }
+        }
+    }
+
+    /// Light the LEDs of a grabbed device like `leds` tell, since the OS only sets the ones of
+    /// the virtual device.
+    fn set_leds(&self, leds: &[libc::input_event]) {
+        if !self.grabbed || leds.is_empty() {
+            return;
+        }
+        let mut frame = leds.to_vec();
+        frame.push(input_event(EV_SYN as u16, SYN_REPORT, 0));
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                frame.as_ptr() as *const u8,
+                frame.len() * mem::size_of::<libc::input_event>(),
+            )
+        };
+        let _ = (&self.file).write_all(bytes);
+    }
+
+    /// Re-emit the events of the frame that were let through.
+    fn flush(&mut self) {
+        if let Some(ref virtual_device) = *VIRTUAL_DEVICE {
+            if !self.frame.is_empty() {
+                virtual_device.emit(&self.frame);
+            }
+        }
+        self.frame.clear();
+    }
+
+    /// Dispatch the pending key events of the device. Returns `false` once the device is gone.
+    fn read(&mut self) -> bool {
+        let mut events: [libc::input_event; 64] = unsafe { mem::zeroed() };
+        loop {
+            let read = unsafe {
+                libc::read(
+                    self.file.as_raw_fd(),
+                    events.as_mut_ptr() as *mut libc::c_void,
+                    mem::size_of_val(&events),
+                )
+            };
+            if read <= 0 {
+                return read < 0 && io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock;
+            }
+            for event in &events[..read as usize / mem::size_of::<libc::input_event>()] {
+                self.handle(event);
+            }
+        }
+    }
+
+    fn handle(&mut self, event: &libc::input_event) {
+        match event.type_ as usize {
+            EV_MSC if event.code == MSC_SCAN => self.scan_code = event.value as u32,
+            EV_SYN if event.code == SYN_REPORT => {
+                self.scan_code = 0;
+                self.flush();
+                self.grab();
+            }
+            EV_KEY if is_key(event.code) => {
+                // Auto-repeats (value 2) are reported as presses flagged as repeats, like on the
+                // other platforms.
+                let mut key_event = KeyEvent::new(None, event.code as u32, self.scan_code, event.value != 0, false);
+                key_event.device = Some(self.device.clone());
+                key_event.is_repeat = event.value == 2;
+                track_key(&mut key_event);
+
+                // A panicking callback mustn't stop the reader while the keyboard is grabbed:
+                // the event goes through as if nobody had seen it.
+                let verdict = panic::catch_unwind(AssertUnwindSafe(|| dispatch(&key_event))).unwrap_or(Verdict::Pass);
+                // Verdicts can only keep the events of grabbed devices from the OS.
+                if !self.grabbed {
+                    return;
+                }
+                match verdict {
+                    Verdict::Pass => self.frame.push(*event),
+                    Verdict::Block => {}
+                    Verdict::Replace(events) => {
+                        self.flush();
+                        emit(&events);
+                    }
+                    Verdict::Modify(key_event) => {
+                        self.flush();
+                        emit(&[key_event]);
+                    }
+                }
+            }
+            // LED events echo the state set on the device, and aren't input. The OS sets the
+            // LEDs of the virtual device rather than those of grabbed devices.
+            EV_LED if !self.grabbed => report_led(event),
+            EV_SYN | EV_MSC | EV_LED => {}
+            _ if self.grabbed => self.frame.push(*event),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Source {
+    /// Ungrab the device, releasing the keys it holds on the virtual device first, since the
+    /// OS only sees their releases on the device itself from now on.
+    fn drop(&mut self) {
+        if !self.grabbed {
+            return;
+        }
+        let fd = self.file.as_raw_fd();
+        let mut held_keys = [0u8; 96];
+        unsafe {
+            if libc::ioctl(fd, EVIOCGKEY as libc::Ioctl, held_keys.as_mut_ptr()) >= 0 {
+                if let Some(ref virtual_device) = *VIRTUAL_DEVICE {
+                    let mut releases: Vec<libc::input_event> = (0..held_keys.len() * 8)
+                        .filter(|code| held_keys[code / 8] & (1 << (code % 8)) != 0)
+                        .map(|code| input_event(EV_KEY as u16, code as u16, 0))
+                        .collect();
+                    if !releases.is_empty() {
+                        releases.push(input_event(EV_SYN as u16, SYN_REPORT, 0));
+                        virtual_device.emit(&releases);
+                    }
+                }
+            }
+            libc::ioctl(fd, EVIOCGRAB as libc::Ioctl, 0 as libc::c_int);
+        }
+    }
+}
+
+/// Open the keyboards that aren't open yet and that the process has access to.
+fn open_new_keyboards(sources: &mut Vec<Source>) {
+    for node in event_nodes() {
+        if !sources.iter().any(|source| source.node == node) {
+            sources.extend(Source::open(&node));
+        }
+    }
+}
+
+/// Pass the LED changes of the virtual device on to the grabbed keyboards.
+fn mirror_leds(virtual_device: &VirtualDevice, sources: &[Source]) {
+    let leds = virtual_device.read_leds();
+    if leds.is_empty() {
+        return;
+    }
+    if let Ok(mut last) = LEDS.lock() {
+        for led in leds.iter() {
+            report_led(led);
+            last.retain(|other| other.code != led.code);
+            last.push(*led);
+        }
+    }
+    for source in sources {
+        source.set_leds(&leds);
+    }
+}
+
+/// Dispatch the key events of the keyboards until `stop` is readable. The keyboards are
+/// ungrabbed when it returns, or unwinds.
+fn read_keyboards(stop: libc::c_int) {
+    let monitor = DeviceMonitor::new();
+    let mut sources = Vec::new();
+    open_new_keyboards(&mut sources);
+    loop {
+        READS_LEDS.store(
+            sources.iter().any(|source| source.device.capabilities.leds),
+            Ordering::Relaxed,
+        );
+        let mut poll_fds: Vec<libc::pollfd> = sources
+            .iter()
+            .map(|source| source.file.as_raw_fd())
+            .chain(Some(stop))
+            .chain(Some(monitor.fd))
+            .chain(VIRTUAL_DEVICE.as_ref().map(VirtualDevice::fd))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // Without inotify, look for new keyboards every second.
+        let timeout = if monitor.fd < 0 { 1000 } else { -1 };
+        if unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, timeout) } < 0 {
+            continue;
+        }
+
+        let mut ready = poll_fds.iter().map(|poll_fd| poll_fd.revents != 0);
+        sources.retain_mut(|source| !ready.next().unwrap_or(false) || source.read());
+        if ready.next().unwrap_or(false) {
+            READS_LEDS.store(false, Ordering::Relaxed);
+            return;
+        }
+        if ready.next().unwrap_or(false) {
+            monitor.drain();
+            open_new_keyboards(&mut sources);
+        } else if monitor.fd < 0 {
+            open_new_keyboards(&mut sources);
+        }
+        if ready.next().unwrap_or(false) {
+            if let Some(ref virtual_device) = *VIRTUAL_DEVICE {
+                mirror_leds(virtual_device, &sources);
+            }
+        }
+    }
+}
+
+/// Background thread dispatching the key events of the keyboards, stopped when dropped.
+#[derive(Debug)]
+pub(crate) struct Reader {
+    /// eventfd waking the thread up to stop.
+    stop: libc::c_int,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reader {
+    /// Start dispatching the key events of every keyboard whose evdev node the process can
+    /// read, usually requiring membership of the `input` group, from a background thread.
+    /// Keyboards plugged in later are picked up too. Returns the running reader if there is
+    /// one.
+    ///
+    /// The keyboards are grabbed if `/dev/uinput` is writable too, so that verdicts apply. They
+    /// are ungrabbed once every handle on the reader is dropped.
+    pub(crate) fn start() -> Arc<Reader> {
+        let mut reader = READER.lock().expect("Couldn't lock the evdev reader");
+        if let Some(running) = reader.upgrade() {
+            return running;
+        }
+        let stop = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        let started = Arc::new(Reader {
+            stop,
+            thread: Some(thread::spawn(move || read_keyboards(stop))),
+        });
+        *reader = Arc::downgrade(&started);
+        started
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let one: u64 = 1;
+        unsafe {
+            libc::write(self.stop, &one as *const u64 as *const libc::c_void, mem::size_of::<u64>());
+        }
+        // The last handle can be dropped by a callback, on the reader thread itself.
+        if let Some(thread) = self.thread.take() {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+        unsafe {
+            libc::close(self.stop);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::Instant;
+
+    #[test]
+    fn bitmaps_are_read_least_significant_word_last() {
+        let bitmap = Bitmap::parse("120013");
+        let bits: Vec<usize> = (0..32).filter(|bit| bitmap.contains(*bit)).collect();
+        assert_eq!(bits, vec![0, 1, 4, 17, 20]);
+
+        let word_bits = 8 * mem::size_of::<c_ulong>();
+        let bitmap = Bitmap::parse("2 0");
+        assert!(bitmap.contains(word_bits + 1));
+        assert!(!bitmap.contains(1));
+        assert!(!bitmap.contains(4 * word_bits));
+        assert!(!Bitmap::parse("").contains(0));
+    }
+
+    #[test]
+    fn buttons_arent_keys() {
+        assert!(is_key(super::super::kernel_key::KEY_A));
+        assert!(is_key(KEY_OK));
+        assert!(!is_key(BTN_LEFT as u16));
+        assert!(!is_key(BTN_TOUCH as u16));
+        assert!(!is_key(BTN_DPAD_UP));
+    }
+
+    #[test]
+    fn devices_are_described_from_sysfs() {
+        let device_dir = env::temp_dir().join(format!("key_director-sysfs-{}", process::id()));
+        let write = |file: &str, text: &str| {
+            let path = device_dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        };
+        write("name", "Foo Keyboard\n");
+        write("phys", "usb-0000:00:14.0-2/input0\n");
+        write("id/vendor", "046d\n");
+        write("id/product", "c31c\n");
+        write("id/bustype", "0003\n");
+        // EV_SYN, EV_KEY, EV_MSC, EV_LED and EV_REP; KEY_A only.
+        write("capabilities/ev", "120013\n");
+        write("capabilities/key", "40000000\n");
+
+        let device = describe_device(&device_dir, "event3").unwrap();
+        assert_eq!(device.id, "usb-0000:00:14.0-2/input0/Foo Keyboard");
+        assert_eq!(device.name, "Foo Keyboard");
+        assert_eq!((device.vendor, device.product), (0x046d, 0xc31c));
+        assert_eq!(device.bus, Bus::Usb);
+        assert_eq!(device.path.as_deref(), Some("usb-0000:00:14.0-2/input0"));
+        assert_eq!(
+            device.capabilities,
+            Capabilities {
+                keyboard: true,
+                mouse: false,
+                touch: false,
+                leds: true,
+            }
+        );
+
+        // Devices without a physical location are told apart by their node.
+        write("phys", "\n");
+        let device = describe_device(&device_dir, "event3").unwrap();
+        assert_eq!(device.id, "/dev/input/event3");
+        assert_eq!(device.path, None);
+
+        fs::remove_dir_all(&device_dir).unwrap();
+        assert_eq!(describe_device(&device_dir, "event3"), None);
+    }
+
+    #[test]
+    fn the_virtual_device_is_listed() {
+        // Only where the process may create uinput devices.
+        if VIRTUAL_DEVICE.is_none() {
+            return;
+        }
+        let started = Instant::now();
+        let device = loop {
+            let listed = input_devices().into_iter().find(|device| device.name == VIRTUAL_DEVICE_NAME);
+            match listed {
+                Some(device) => break device,
+                None if started.elapsed() < Duration::from_secs(2) => thread::sleep(Duration::from_millis(50)),
+                None => panic!("The virtual device isn't listed"),
+            }
+        };
+        assert_eq!(device.bus, Bus::Virtual);
+        assert!(device.capabilities.keyboard && device.capabilities.mouse && device.capabilities.leds);
+        assert!(!device.capabilities.touch);
+    }
+}
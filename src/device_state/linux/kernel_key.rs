@@ -1,6 +1,8 @@
-/// A non-exhaustive list of keycodes from Linux. Only the ones that this library currently supports
-/// is currently listed in this file; other keycodes will need to be added later as needed.
-/// Reference: https://github.com/torvalds/linux/blob/master/include/uapi/linux/input-event-codes.h
+use keymap::Key;
+
+// A non-exhaustive list of keycodes from Linux. Only the ones that this library currently supports
+// is currently listed in this file; other keycodes will need to be added later as needed.
+// Reference: https://github.com/torvalds/linux/blob/master/include/uapi/linux/input-event-codes.h
 
 pub const KEY_ESC: u16 = 1;
 pub const KEY_1: u16 = 2;
@@ -70,6 +72,8 @@ pub const KEY_F7: u16 = 65;
 pub const KEY_F8: u16 = 66;
 pub const KEY_F9: u16 = 67;
 pub const KEY_F10: u16 = 68;
+pub const KEY_NUMLOCK: u16 = 69;
+pub const KEY_SCROLLLOCK: u16 = 70;
 pub const KEY_KP7: u16 = 71;
 pub const KEY_KP8: u16 = 72;
 pub const KEY_KP9: u16 = 73;
@@ -82,11 +86,13 @@ pub const KEY_KP1: u16 = 79;
 pub const KEY_KP2: u16 = 80;
 pub const KEY_KP3: u16 = 81;
 pub const KEY_KP0: u16 = 82;
+pub const KEY_KPDOT: u16 = 83;
 pub const KEY_F11: u16 = 87;
 pub const KEY_F12: u16 = 88;
 pub const KEY_KPENTER: u16 = 96;
 pub const KEY_RIGHTCTRL: u16 = 97;
 pub const KEY_KPSLASH: u16 = 98;
+pub const KEY_SYSRQ: u16 = 99;
 pub const KEY_RIGHTALT: u16 = 100;
 pub const KEY_HOME: u16 = 102;
 pub const KEY_UP: u16 = 103;
@@ -98,5 +104,139 @@ pub const KEY_DOWN: u16 = 108;
 pub const KEY_PAGEDOWN: u16 = 109;
 pub const KEY_INSERT: u16 = 110;
 pub const KEY_DELETE: u16 = 111;
+pub const KEY_PAUSE: u16 = 119;
 pub const KEY_LEFTMETA: u16 = 125;
 pub const KEY_RIGHTMETA: u16 = 126;
+pub const KEY_COMPOSE: u16 = 127;
+pub const KEY_F13: u16 = 183;
+pub const KEY_F14: u16 = 184;
+pub const KEY_F15: u16 = 185;
+pub const KEY_F16: u16 = 186;
+pub const KEY_F17: u16 = 187;
+pub const KEY_F18: u16 = 188;
+pub const KEY_F19: u16 = 189;
+pub const KEY_F20: u16 = 190;
+pub const KEY_F21: u16 = 191;
+pub const KEY_F22: u16 = 192;
+pub const KEY_F23: u16 = 193;
+pub const KEY_F24: u16 = 194;
+
+/// Kernel key code of every `Key`.
+pub const KEY_CODES: &[(Key, u32)] = &[
+    (Key::Key0, KEY_0 as u32),
+    (Key::Key1, KEY_1 as u32),
+    (Key::Key2, KEY_2 as u32),
+    (Key::Key3, KEY_3 as u32),
+    (Key::Key4, KEY_4 as u32),
+    (Key::Key5, KEY_5 as u32),
+    (Key::Key6, KEY_6 as u32),
+    (Key::Key7, KEY_7 as u32),
+    (Key::Key8, KEY_8 as u32),
+    (Key::Key9, KEY_9 as u32),
+    (Key::A, KEY_A as u32),
+    (Key::B, KEY_B as u32),
+    (Key::C, KEY_C as u32),
+    (Key::D, KEY_D as u32),
+    (Key::E, KEY_E as u32),
+    (Key::F, KEY_F as u32),
+    (Key::G, KEY_G as u32),
+    (Key::H, KEY_H as u32),
+    (Key::I, KEY_I as u32),
+    (Key::J, KEY_J as u32),
+    (Key::K, KEY_K as u32),
+    (Key::L, KEY_L as u32),
+    (Key::M, KEY_M as u32),
+    (Key::N, KEY_N as u32),
+    (Key::O, KEY_O as u32),
+    (Key::P, KEY_P as u32),
+    (Key::Q, KEY_Q as u32),
+    (Key::R, KEY_R as u32),
+    (Key::S, KEY_S as u32),
+    (Key::T, KEY_T as u32),
+    (Key::U, KEY_U as u32),
+    (Key::V, KEY_V as u32),
+    (Key::W, KEY_W as u32),
+    (Key::X, KEY_X as u32),
+    (Key::Y, KEY_Y as u32),
+    (Key::Z, KEY_Z as u32),
+    (Key::F1, KEY_F1 as u32),
+    (Key::F2, KEY_F2 as u32),
+    (Key::F3, KEY_F3 as u32),
+    (Key::F4, KEY_F4 as u32),
+    (Key::F5, KEY_F5 as u32),
+    (Key::F6, KEY_F6 as u32),
+    (Key::F7, KEY_F7 as u32),
+    (Key::F8, KEY_F8 as u32),
+    (Key::F9, KEY_F9 as u32),
+    (Key::F10, KEY_F10 as u32),
+    (Key::F11, KEY_F11 as u32),
+    (Key::F12, KEY_F12 as u32),
+    (Key::F13, KEY_F13 as u32),
+    (Key::F14, KEY_F14 as u32),
+    (Key::F15, KEY_F15 as u32),
+    (Key::F16, KEY_F16 as u32),
+    (Key::F17, KEY_F17 as u32),
+    (Key::F18, KEY_F18 as u32),
+    (Key::F19, KEY_F19 as u32),
+    (Key::F20, KEY_F20 as u32),
+    (Key::F21, KEY_F21 as u32),
+    (Key::F22, KEY_F22 as u32),
+    (Key::F23, KEY_F23 as u32),
+    (Key::F24, KEY_F24 as u32),
+    (Key::Escape, KEY_ESC as u32),
+    (Key::Space, KEY_SPACE as u32),
+    (Key::LControl, KEY_LEFTCTRL as u32),
+    (Key::RControl, KEY_RIGHTCTRL as u32),
+    (Key::LShift, KEY_LEFTSHIFT as u32),
+    (Key::RShift, KEY_RIGHTSHIFT as u32),
+    (Key::LAlt, KEY_LEFTALT as u32),
+    (Key::RAlt, KEY_RIGHTALT as u32),
+    (Key::LMeta, KEY_LEFTMETA as u32),
+    (Key::RMeta, KEY_RIGHTMETA as u32),
+    (Key::Menu, KEY_COMPOSE as u32),
+    (Key::Enter, KEY_ENTER as u32),
+    (Key::Up, KEY_UP as u32),
+    (Key::Down, KEY_DOWN as u32),
+    (Key::Left, KEY_LEFT as u32),
+    (Key::Right, KEY_RIGHT as u32),
+    (Key::Backspace, KEY_BACKSPACE as u32),
+    (Key::CapsLock, KEY_CAPSLOCK as u32),
+    (Key::NumLock, KEY_NUMLOCK as u32),
+    (Key::ScrollLock, KEY_SCROLLLOCK as u32),
+    (Key::Tab, KEY_TAB as u32),
+    (Key::Home, KEY_HOME as u32),
+    (Key::End, KEY_END as u32),
+    (Key::PageUp, KEY_PAGEUP as u32),
+    (Key::PageDown, KEY_PAGEDOWN as u32),
+    (Key::Insert, KEY_INSERT as u32),
+    (Key::Delete, KEY_DELETE as u32),
+    (Key::PrintScreen, KEY_SYSRQ as u32),
+    (Key::Pause, KEY_PAUSE as u32),
+    (Key::Numpad0, KEY_KP0 as u32),
+    (Key::Numpad1, KEY_KP1 as u32),
+    (Key::Numpad2, KEY_KP2 as u32),
+    (Key::Numpad3, KEY_KP3 as u32),
+    (Key::Numpad4, KEY_KP4 as u32),
+    (Key::Numpad5, KEY_KP5 as u32),
+    (Key::Numpad6, KEY_KP6 as u32),
+    (Key::Numpad7, KEY_KP7 as u32),
+    (Key::Numpad8, KEY_KP8 as u32),
+    (Key::Numpad9, KEY_KP9 as u32),
+    (Key::NumpadSubtract, KEY_KPMINUS as u32),
+    (Key::NumpadAdd, KEY_KPPLUS as u32),
+    (Key::NumpadDivide, KEY_KPSLASH as u32),
+    (Key::NumpadMultiply, KEY_KPASTERISK as u32),
+    (Key::NumpadDecimal, KEY_KPDOT as u32),
+    (Key::NumpadEnter, KEY_KPENTER as u32),
+    (Key::Grave, KEY_GRAVE as u32),
+    (Key::Minus, KEY_MINUS as u32),
+    (Key::Equal, KEY_EQUAL as u32),
+    (Key::LeftBracket, KEY_LEFTBRACE as u32),
+    (Key::RightBracket, KEY_RIGHTBRACE as u32),
+    (Key::BackSlash, KEY_BACKSLASH as u32),
+    (Key::Semicolon, KEY_SEMICOLON as u32),
+    (Key::Apostrophe, KEY_APOSTROPHE as u32),
+    (Key::Comma, KEY_COMMA as u32),
+    (Key::Dot, KEY_DOT as u32),
+    (Key::Slash, KEY_SLASH as u32),
+];
@@ -1,19 +1,30 @@
 extern crate x11;
 
 use self::x11::xlib;
-use keymap::Keycode;
-use mouse_state::MouseState;
-use std::os::raw::c_char;
+use device_state::{record_emitted, toggle_lock, InputAction, LockKey, LockState, MouseButton, ReleaseGuard, WindowInfo};
+use std::mem;
+use std::os::raw::c_int;
 use std::ptr;
-use std::slice;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use KeyEvent;
 
+mod evdev;
 mod kernel_key;
+mod screen_saver;
+mod uinput;
+mod unicode;
+mod window;
+mod xkb;
+
+pub(crate) use self::evdev::{input_devices, DeviceMonitor};
+pub(crate) use self::kernel_key::KEY_CODES;
 
 #[derive(Debug, Clone)]
 /// Device state descriptor.
 pub struct DeviceState {
-    xc: Arc<X11Connection>,
+    _reader: Arc<evdev::Reader>,
+    _release: Arc<ReleaseGuard>,
 }
 
 #[derive(Debug)]
@@ -29,190 +40,239 @@ impl Drop for X11Connection {
     }
 }
 
+impl X11Connection {
+    fn open() -> Option<X11Connection> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            None
+        } else {
+            Some(X11Connection { display })
+        }
+    }
+}
+
+// The shared connection is only used behind the mutex below.
+unsafe impl Send for X11Connection {}
+
+lazy_static! {
+    /// Connection to the X server shared by the queries of every thread, such as the polling
+    /// of the focused window.
+    static ref SHARED_CONNECTION: Mutex<Option<X11Connection>> = Mutex::new(X11Connection::open().inspect(|_| unsafe {
+        xlib::XSetErrorHandler(Some(ignore_error));
+    }));
+}
+
+/// Windows can be destroyed between two requests about them: the default error handler
+/// would exit the process then.
+unsafe extern "C" fn ignore_error(_display: *mut xlib::Display, _error: *mut xlib::XErrorEvent) -> c_int {
+    0
+}
+
+fn with_shared_connection<T, F: FnOnce(&X11Connection) -> Option<T>>(query: F) -> Option<T> {
+    query(SHARED_CONNECTION.lock().ok()?.as_ref()?)
+}
+
 impl DeviceState {
     /// Creates a new DeviceState.
+    ///
+    /// Key events are read from the evdev nodes of the keyboards, so the callbacks only see
+    /// them if the process can read `/dev/input/event*`. The keyboards are grabbed and their
+    /// events re-emitted through a uinput virtual device if `/dev/uinput` is writable as well;
+    /// otherwise the callbacks can't block or replace events. Input injected by this crate
+    /// isn't seen by the callbacks on Linux.
+    ///
+    /// Unicode events are typed by mapping keycodes that have no keysym on the X server to
+    /// their characters, and pressing them through uinput. They are dropped on Wayland.
+    ///
+    /// The keyboards are read and grabbed until the last `DeviceState` is dropped.
     pub fn new() -> DeviceState {
-        unsafe {
-            let display = xlib::XOpenDisplay(ptr::null());
-            if display.as_ref().is_none() {
-                panic!("Could not connect to a X display");
-            }
-            DeviceState {
-                xc: Arc::new(X11Connection { display }),
-            }
+        DeviceState {
+            _reader: evdev::Reader::start(),
+            _release: ReleaseGuard::acquire(),
         }
     }
+}
 
-    /// Query the `MouseState`.
-    pub fn query_pointer(&self) -> MouseState {
-        let root;
-        let mut root_x = 0;
-        let mut root_y = 0;
-        let mut win_x = 0;
-        let mut win_y = 0;
-        let mut root_return = 0;
-        let mut child_return = 0;
-        let mut mask_return = 0;
-        unsafe {
-            root = xlib::XDefaultRootWindow(self.xc.display);
-            xlib::XQueryPointer(
-                self.xc.display,
-                root,
-                &mut root_return,
-                &mut child_return,
-                &mut root_x,
-                &mut root_y,
-                &mut win_x,
-                &mut win_y,
-                &mut mask_return,
-            );
+// Reference: https://github.com/torvalds/linux/blob/master/include/uapi/linux/input-event-codes.h
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const BTN_SIDE: u16 = 0x113;
+const BTN_EXTRA: u16 = 0x114;
+
+fn button_code(button: MouseButton) -> u16 {
+    match button {
+        MouseButton::Left => BTN_LEFT,
+        MouseButton::Right => BTN_RIGHT,
+        MouseButton::Middle => BTN_MIDDLE,
+        MouseButton::Back => BTN_SIDE,
+        MouseButton::Forward => BTN_EXTRA,
+    }
+}
+
+/// A release and a press in frames of their own: display servers ignore the repeats of the
+/// kernel (value 2), and repeat held keys with releases and presses themselves.
+fn repeat_events(key: u16) -> Vec<libc::input_event> {
+    vec![
+        uinput::input_event(uinput::EV_KEY, key, 0),
+        uinput::input_event(uinput::EV_SYN, uinput::SYN_REPORT, 0),
+        uinput::input_event(uinput::EV_KEY, key, 1),
+    ]
+}
+
+fn action_events(action: &InputAction) -> Vec<libc::input_event> {
+    match *action {
+        InputAction::KeyDown(key) => vec![uinput::input_event(uinput::EV_KEY, key as u16, 1)],
+        InputAction::KeyUp(key) => vec![uinput::input_event(uinput::EV_KEY, key as u16, 0)],
+        InputAction::KeyRepeat(key) => repeat_events(key as u16),
+        InputAction::MouseMove { dx, dy } => vec![
+            uinput::input_event(uinput::EV_REL, uinput::REL_X, dx),
+            uinput::input_event(uinput::EV_REL, uinput::REL_Y, dy),
+        ],
+        InputAction::MouseDown(button) => vec![uinput::input_event(uinput::EV_KEY, button_code(button), 1)],
+        InputAction::MouseUp(button) => vec![uinput::input_event(uinput::EV_KEY, button_code(button), 0)],
+        InputAction::Scroll { dx, dy } => vec![
+            uinput::input_event(uinput::EV_REL, uinput::REL_HWHEEL, dx),
+            uinput::input_event(uinput::EV_REL, uinput::REL_WHEEL, dy),
+        ],
+    }
+}
+
+/// Injector sink: emits `actions` through the uinput virtual device, one frame per action.
+/// Dropped if `/dev/uinput` isn't writable.
+pub(crate) fn inject(actions: &[InputAction]) {
+    if let Some(ref virtual_device) = *uinput::VIRTUAL_DEVICE {
+        for action in actions {
+            virtual_device.emit(&action_events(action));
         }
-        let button1pressed = mask_return & xlib::Button1Mask > 0;
-        let button2pressed = mask_return & xlib::Button2Mask > 0;
-        let button3pressed = mask_return & xlib::Button3Mask > 0;
-        let button4pressed = mask_return & xlib::Button4Mask > 0;
-        let button5pressed = mask_return & xlib::Button5Mask > 0;
-
-        // Use 1-based indexing here so people can just query the button
-        // number they're interested in directly.
-        let button_pressed = vec![
-            false,
-            button1pressed,
-            button2pressed,
-            button3pressed,
-            button4pressed,
-            button5pressed,
-        ];
-        MouseState {
-            coords: (win_x, win_y),
-            button_pressed,
+    }
+}
+
+/// Window that has the focus, or `None` without an X server or a window manager following
+/// EWMH.
+pub(crate) fn active_window() -> Option<WindowInfo> {
+    with_shared_connection(X11Connection::active_window)
+}
+
+/// Time since the last input the X server got, from the XScreenSaver extension. `None`
+/// without an X server or the extension.
+pub(crate) fn os_idle_time() -> Option<Duration> {
+    with_shared_connection(X11Connection::idle_time)
+}
+
+/// Turns the auto-repeat of the X server on or off, returning whether it was on, or `None`
+/// without an X server. Display servers ignore the repeats of the kernel and repeat held keys
+/// themselves, so blocking the repeats of the keyboards doesn't stop them. Wayland compositors
+/// can't be told.
+pub(crate) fn set_os_auto_repeat(on: bool) -> Option<bool> {
+    with_shared_connection(|connection| unsafe {
+        let mut state: xlib::XKeyboardState = mem::zeroed();
+        xlib::XGetKeyboardControl(connection.display, &mut state);
+        if on {
+            xlib::XAutoRepeatOn(connection.display);
+        } else {
+            xlib::XAutoRepeatOff(connection.display);
         }
+        xlib::XFlush(connection.display);
+        Some(state.global_auto_repeat == xlib::AutoRepeatModeOn)
+    })
+}
+
+/// Which locks are on, from XKB, or from the keyboard LEDs without an X server.
+pub(crate) fn lock_state() -> LockState {
+    xkb::lock_state().or_else(evdev::lock_state).unwrap_or_default()
+}
+
+/// Lock state to poll for changes: from XKB, unless the evdev reader sees the LED events of a
+/// keyboard and reports the changes itself.
+pub(crate) fn polled_lock_state() -> Option<LockState> {
+    if evdev::reads_leds() {
+        None
+    } else {
+        xkb::lock_state()
     }
+}
 
-    /// Query the Keyboard state.
-    pub fn query_keymap(&self) -> Vec<Keycode> {
-        let mut keycodes = vec![];
-        unsafe {
-            let keymap: *mut c_char = [0; 32].as_mut_ptr();
-            xlib::XQueryKeymap(self.xc.display, keymap);
-            for (ix, byte) in slice::from_raw_parts(keymap, 32).iter().enumerate() {
-                for bit in 0_u8..8_u8 {
-                    let bitmask = 1 << bit;
-                    if byte & bitmask != 0 {
-                        //x11 keycode uses kernel keycode with an offset of 8.
-                        let x11_key = ix as u8 * 8 + bit;
-                        let kernel_key = x11_key - 8;
-                        if let Some(k) = self.kernel_key_to_keycode(kernel_key) {
-                            keycodes.push(k)
-                        }
-                    }
-                }
+/// Locks Caps Lock and Num Lock through XKB. Other locks, and every lock without an X server,
+/// are toggled by pressing their key through uinput.
+pub(crate) fn set_lock_state(key: LockKey, on: bool) {
+    if !xkb::lock_modifier(key, on) && lock_state().is_on(key) != on {
+        toggle_lock(key);
+    }
+}
+
+/// Whether Unicode events can be typed: through spare keycodes of the X server, mapped to
+/// their characters. Not on Wayland, nor without uinput.
+pub(crate) fn can_type_unicode() -> bool {
+    unicode::can_type()
+}
+
+/// Emits `events` in order through the uinput virtual device. Unicode events are typed through
+/// a spare keycode of the X server, and dropped if `can_type_unicode` is false.
+pub(crate) fn emit(events: &[KeyEvent]) {
+    record_emitted(events);
+    if let Some(ref virtual_device) = *uinput::VIRTUAL_DEVICE {
+        for event in events {
+            let key_code = match (event.key_code, event.char) {
+                (KeyEvent::UNICODE_KEY_CODE, Some(character)) => match unicode::key_code(character) {
+                    Some(key_code) => key_code,
+                    None => continue,
+                },
+                (KeyEvent::UNICODE_KEY_CODE, None) => continue,
+                (key_code, _) => key_code as u16,
+            };
+            if event.is_pressed && event.is_repeat {
+                virtual_device.emit(&repeat_events(key_code));
+                continue;
             }
+            virtual_device.emit(&[uinput::input_event(uinput::EV_KEY, key_code, event.is_pressed as i32)]);
         }
-        keycodes
     }
+}
 
-    fn kernel_key_to_keycode(&self, kernel_code: u8) -> Option<Keycode> {
-        match kernel_code as u16 {
-            kernel_key::KEY_0 => Some(Keycode::Key0),
-            kernel_key::KEY_1 => Some(Keycode::Key1),
-            kernel_key::KEY_2 => Some(Keycode::Key2),
-            kernel_key::KEY_3 => Some(Keycode::Key3),
-            kernel_key::KEY_4 => Some(Keycode::Key4),
-            kernel_key::KEY_5 => Some(Keycode::Key5),
-            kernel_key::KEY_6 => Some(Keycode::Key6),
-            kernel_key::KEY_7 => Some(Keycode::Key7),
-            kernel_key::KEY_8 => Some(Keycode::Key8),
-            kernel_key::KEY_9 => Some(Keycode::Key9),
-            kernel_key::KEY_A => Some(Keycode::A),
-            kernel_key::KEY_B => Some(Keycode::B),
-            kernel_key::KEY_C => Some(Keycode::C),
-            kernel_key::KEY_D => Some(Keycode::D),
-            kernel_key::KEY_E => Some(Keycode::E),
-            kernel_key::KEY_F => Some(Keycode::F),
-            kernel_key::KEY_G => Some(Keycode::G),
-            kernel_key::KEY_H => Some(Keycode::H),
-            kernel_key::KEY_I => Some(Keycode::I),
-            kernel_key::KEY_J => Some(Keycode::J),
-            kernel_key::KEY_K => Some(Keycode::K),
-            kernel_key::KEY_L => Some(Keycode::L),
-            kernel_key::KEY_M => Some(Keycode::M),
-            kernel_key::KEY_N => Some(Keycode::N),
-            kernel_key::KEY_O => Some(Keycode::O),
-            kernel_key::KEY_P => Some(Keycode::P),
-            kernel_key::KEY_Q => Some(Keycode::Q),
-            kernel_key::KEY_R => Some(Keycode::R),
-            kernel_key::KEY_S => Some(Keycode::S),
-            kernel_key::KEY_T => Some(Keycode::T),
-            kernel_key::KEY_U => Some(Keycode::U),
-            kernel_key::KEY_V => Some(Keycode::V),
-            kernel_key::KEY_W => Some(Keycode::W),
-            kernel_key::KEY_X => Some(Keycode::X),
-            kernel_key::KEY_Y => Some(Keycode::Y),
-            kernel_key::KEY_Z => Some(Keycode::Z),
-            kernel_key::KEY_F1 => Some(Keycode::F1),
-            kernel_key::KEY_F2 => Some(Keycode::F2),
-            kernel_key::KEY_F3 => Some(Keycode::F3),
-            kernel_key::KEY_F4 => Some(Keycode::F4),
-            kernel_key::KEY_F5 => Some(Keycode::F5),
-            kernel_key::KEY_F6 => Some(Keycode::F6),
-            kernel_key::KEY_F7 => Some(Keycode::F7),
-            kernel_key::KEY_F8 => Some(Keycode::F8),
-            kernel_key::KEY_F9 => Some(Keycode::F9),
-            kernel_key::KEY_F10 => Some(Keycode::F10),
-            kernel_key::KEY_F11 => Some(Keycode::F11),
-            kernel_key::KEY_F12 => Some(Keycode::F12),
-            kernel_key::KEY_KP0 => Some(Keycode::Numpad0),
-            kernel_key::KEY_KP1 => Some(Keycode::Numpad1),
-            kernel_key::KEY_KP2 => Some(Keycode::Numpad2),
-            kernel_key::KEY_KP3 => Some(Keycode::Numpad3),
-            kernel_key::KEY_KP4 => Some(Keycode::Numpad4),
-            kernel_key::KEY_KP5 => Some(Keycode::Numpad5),
-            kernel_key::KEY_KP6 => Some(Keycode::Numpad6),
-            kernel_key::KEY_KP7 => Some(Keycode::Numpad7),
-            kernel_key::KEY_KP8 => Some(Keycode::Numpad8),
-            kernel_key::KEY_KP9 => Some(Keycode::Numpad9),
-            kernel_key::KEY_KPENTER => Some(Keycode::Enter),
-            kernel_key::KEY_KPMINUS => Some(Keycode::NumpadSubtract),
-            kernel_key::KEY_KPPLUS => Some(Keycode::NumpadAdd),
-            kernel_key::KEY_KPSLASH => Some(Keycode::NumpadDivide),
-            kernel_key::KEY_KPASTERISK => Some(Keycode::NumpadMultiply),
-            kernel_key::KEY_ESC => Some(Keycode::Escape),
-            kernel_key::KEY_SPACE => Some(Keycode::Space),
-            kernel_key::KEY_LEFTCTRL => Some(Keycode::LControl),
-            kernel_key::KEY_RIGHTCTRL => Some(Keycode::RControl),
-            kernel_key::KEY_LEFTSHIFT => Some(Keycode::LShift),
-            kernel_key::KEY_RIGHTSHIFT => Some(Keycode::RShift),
-            kernel_key::KEY_LEFTALT => Some(Keycode::LAlt),
-            kernel_key::KEY_RIGHTALT => Some(Keycode::RAlt),
-            kernel_key::KEY_LEFTMETA => Some(Keycode::Meta),
-            kernel_key::KEY_RIGHTMETA => Some(Keycode::Meta),
-            kernel_key::KEY_ENTER => Some(Keycode::Enter),
-            kernel_key::KEY_UP => Some(Keycode::Up),
-            kernel_key::KEY_DOWN => Some(Keycode::Down),
-            kernel_key::KEY_LEFT => Some(Keycode::Left),
-            kernel_key::KEY_RIGHT => Some(Keycode::Right),
-            kernel_key::KEY_BACKSPACE => Some(Keycode::Backspace),
-            kernel_key::KEY_CAPSLOCK => Some(Keycode::CapsLock),
-            kernel_key::KEY_TAB => Some(Keycode::Tab),
-            kernel_key::KEY_HOME => Some(Keycode::Home),
-            kernel_key::KEY_END => Some(Keycode::End),
-            kernel_key::KEY_PAGEUP => Some(Keycode::PageUp),
-            kernel_key::KEY_PAGEDOWN => Some(Keycode::PageDown),
-            kernel_key::KEY_INSERT => Some(Keycode::Insert),
-            kernel_key::KEY_DELETE => Some(Keycode::Delete),
-            kernel_key::KEY_GRAVE => Some(Keycode::Grave),
-            kernel_key::KEY_MINUS => Some(Keycode::Minus),
-            kernel_key::KEY_EQUAL => Some(Keycode::Equal),
-            kernel_key::KEY_LEFTBRACE => Some(Keycode::LeftBracket),
-            kernel_key::KEY_RIGHTBRACE => Some(Keycode::RightBracket),
-            kernel_key::KEY_BACKSLASH => Some(Keycode::BackSlash),
-            kernel_key::KEY_SEMICOLON => Some(Keycode::Semicolon),
-            kernel_key::KEY_APOSTROPHE => Some(Keycode::Apostrophe),
-            kernel_key::KEY_COMMA => Some(Keycode::Comma),
-            kernel_key::KEY_DOT => Some(Keycode::Dot),
-            kernel_key::KEY_SLASH => Some(Keycode::Slash),
-            _ => None,
-        }
+/// Emits events that already went through the callbacks, when a pre-filter resumes them. The
+/// callbacks never see input emitted through uinput, so this is plain `emit`.
+pub(crate) fn emit_resumed(events: &[KeyEvent]) {
+    emit(events);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(action: InputAction) -> Vec<(u16, u16, i32)> {
+        action_events(&action)
+            .iter()
+            .map(|event| (event.type_, event.code, event.value))
+            .collect()
+    }
+
+    #[test]
+    fn actions_are_translated_to_input_events() {
+        let a = u32::from(kernel_key::KEY_A);
+        assert_eq!(events(InputAction::KeyDown(a)), vec![(uinput::EV_KEY, kernel_key::KEY_A, 1)]);
+        assert_eq!(events(InputAction::KeyUp(a)), vec![(uinput::EV_KEY, kernel_key::KEY_A, 0)]);
+        assert_eq!(events(InputAction::MouseDown(MouseButton::Right)), vec![(uinput::EV_KEY, BTN_RIGHT, 1)]);
+        assert_eq!(
+            events(InputAction::MouseMove { dx: 3, dy: -2 }),
+            vec![(uinput::EV_REL, uinput::REL_X, 3), (uinput::EV_REL, uinput::REL_Y, -2)]
+        );
+        assert_eq!(
+            events(InputAction::Scroll { dx: 0, dy: 1 }),
+            vec![(uinput::EV_REL, uinput::REL_HWHEEL, 0), (uinput::EV_REL, uinput::REL_WHEEL, 1)]
+        );
+    }
+
+    #[test]
+    fn repeats_are_a_release_and_a_press_in_frames_of_their_own() {
+        let a = u32::from(kernel_key::KEY_A);
+        assert_eq!(
+            events(InputAction::KeyRepeat(a)),
+            vec![
+                (uinput::EV_KEY, kernel_key::KEY_A, 0),
+                (uinput::EV_SYN, uinput::SYN_REPORT, 0),
+                (uinput::EV_KEY, kernel_key::KEY_A, 1),
+            ]
+        );
     }
 }
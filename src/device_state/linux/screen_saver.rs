@@ -0,0 +1,31 @@
+//! Idle time through the XScreenSaver extension of the X server.
+
+use super::x11::{xlib, xss};
+use super::X11Connection;
+use std::os::raw::c_int;
+use std::time::Duration;
+
+impl X11Connection {
+    /// Time since the last input of any device, injected input included.
+    pub(super) fn idle_time(&self) -> Option<Duration> {
+        unsafe {
+            let mut event_base: c_int = 0;
+            let mut error_base: c_int = 0;
+            if xss::XScreenSaverQueryExtension(self.display, &mut event_base, &mut error_base) == 0 {
+                return None;
+            }
+            let info = xss::XScreenSaverAllocInfo();
+            if info.is_null() {
+                return None;
+            }
+            let root = xlib::XDefaultRootWindow(self.display);
+            let idle = if xss::XScreenSaverQueryInfo(self.display, root, info) != 0 {
+                Some(Duration::from_millis((*info).idle as u64))
+            } else {
+                None
+            };
+            xlib::XFree(info as *mut _);
+            idle
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Virtual uinput device, emitting the input that grabbed devices let through along with the
+//! injected one.
+
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const UINPUT_PATH: &str = "/dev/uinput";
+
+/// Name of the virtual device, so that it isn't grabbed itself.
+pub(crate) const VIRTUAL_DEVICE_NAME: &str = "key_director virtual input";
+
+// Reference: https://github.com/torvalds/linux/blob/master/include/uapi/linux/uinput.h
+const UI_DEV_CREATE: u32 = 0x5501;
+const UI_DEV_SETUP: u32 = 0x405c_5503;
+const UI_SET_EVBIT: u32 = 0x4004_5564;
+const UI_SET_KEYBIT: u32 = 0x4004_5565;
+const UI_SET_RELBIT: u32 = 0x4004_5566;
+const UI_SET_LEDBIT: u32 = 0x4004_5569;
+
+// Reference: https://github.com/torvalds/linux/blob/master/include/uapi/linux/input-event-codes.h
+pub(crate) const EV_SYN: u16 = 0x00;
+pub(crate) const EV_KEY: u16 = 0x01;
+pub(crate) const EV_REL: u16 = 0x02;
+pub(crate) const EV_LED: u16 = 0x11;
+pub(crate) const SYN_REPORT: u16 = 0x00;
+pub(crate) const REL_X: u16 = 0x00;
+pub(crate) const REL_Y: u16 = 0x01;
+pub(crate) const REL_HWHEEL: u16 = 0x06;
+pub(crate) const REL_WHEEL: u16 = 0x08;
+const KEY_MAX: u16 = 0x2ff;
+pub(crate) const LED_NUML: u16 = 0x00;
+pub(crate) const LED_CAPSL: u16 = 0x01;
+pub(crate) const LED_SCROLLL: u16 = 0x02;
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// Sends an ioctl without argument or with an integer one.
+unsafe fn ioctl(file: &File, request: u32, argument: libc::c_ulong) -> bool {
+    libc::ioctl(file.as_raw_fd(), request as libc::Ioctl, argument) >= 0
+}
+
+pub(crate) fn input_event(type_: u16, code: u16, value: i32) -> libc::input_event {
+    let mut event: libc::input_event = unsafe { mem::zeroed() };
+    event.type_ = type_;
+    event.code = code;
+    event.value = value;
+    event
+}
+
+/// Virtual device able to emit every key, mouse button and relative motion. It has the lock
+/// LEDs too, so that the OS reports the lock state to it. The kernel destroys it when the
+/// process exits.
+pub(crate) struct VirtualDevice {
+    fd: RawFd,
+    file: Mutex<File>,
+}
+
+impl VirtualDevice {
+    /// Creates the virtual device, or returns `None` if `/dev/uinput` isn't writable.
+    fn create() -> Option<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(UINPUT_PATH)
+            .ok()?;
+
+        let mut setup: libc::uinput_setup = unsafe { mem::zeroed() };
+        setup.id.bustype = BUS_VIRTUAL;
+        for (target, byte) in setup.name.iter_mut().zip(VIRTUAL_DEVICE_NAME.bytes()) {
+            *target = byte as libc::c_char;
+        }
+
+        let created = unsafe {
+            ioctl(&file, UI_SET_EVBIT, EV_KEY as libc::c_ulong)
+                && ioctl(&file, UI_SET_EVBIT, EV_REL as libc::c_ulong)
+                && ioctl(&file, UI_SET_EVBIT, EV_LED as libc::c_ulong)
+                && (1..=KEY_MAX).all(|code| ioctl(&file, UI_SET_KEYBIT, code as libc::c_ulong))
+                && [REL_X, REL_Y, REL_HWHEEL, REL_WHEEL]
+                    .iter()
+                    .all(|code| ioctl(&file, UI_SET_RELBIT, *code as libc::c_ulong))
+                && [LED_NUML, LED_CAPSL, LED_SCROLLL]
+                    .iter()
+                    .all(|code| ioctl(&file, UI_SET_LEDBIT, *code as libc::c_ulong))
+                && libc::ioctl(file.as_raw_fd(), UI_DEV_SETUP as libc::Ioctl, &setup) >= 0
+                && ioctl(&file, UI_DEV_CREATE, 0)
+        };
+        if !created {
+            return None;
+        }
+
+        // Give the display server a moment to pick the device up, or it misses the first events.
+        thread::sleep(Duration::from_millis(200));
+        Some(VirtualDevice {
+            fd: file.as_raw_fd(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Emits `events` as a single frame.
+    pub fn emit(&self, events: &[libc::input_event]) {
+        let mut frame = events.to_vec();
+        frame.push(input_event(EV_SYN, SYN_REPORT, 0));
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                frame.as_ptr() as *const u8,
+                frame.len() * mem::size_of::<libc::input_event>(),
+            )
+        };
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.write_all(bytes);
+        }
+    }
+
+    /// File descriptor to poll for events sent to the device by the OS.
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// The LED events that the OS sent to the device since the last call.
+    pub fn read_leds(&self) -> Vec<libc::input_event> {
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(_) => return Vec::new(),
+        };
+        let mut leds = Vec::new();
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        loop {
+            let bytes = unsafe {
+                slice::from_raw_parts_mut(
+                    &mut event as *mut libc::input_event as *mut u8,
+                    mem::size_of::<libc::input_event>(),
+                )
+            };
+            if file.read_exact(bytes).is_err() {
+                return leds;
+            }
+            if event.type_ == EV_LED {
+                leds.push(event);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// The virtual device, if the process may create one.
+    pub(crate) static ref VIRTUAL_DEVICE: Option<VirtualDevice> = VirtualDevice::create();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_events_are_zeroed_but_for_their_type_code_and_value() {
+        let event = input_event(EV_KEY, 30, 1);
+        assert_eq!((event.type_, event.code, event.value), (EV_KEY, 30, 1));
+        assert_eq!((event.time.tv_sec, event.time.tv_usec), (0, 0));
+    }
+}
@@ -0,0 +1,151 @@
+//! Unicode text typed through the X server, like xdotool does: keycodes that have no keysym
+//! are mapped to the keysyms of the characters, then pressed through uinput.
+
+use super::{uinput, with_shared_connection, xlib, X11Connection};
+use std::collections::VecDeque;
+use std::env;
+use std::os::raw::{c_int, c_ulong};
+use std::slice;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Reference: https://gitlab.freedesktop.org/xorg/proto/xorgproto/-/blob/master/include/X11/keysymdef.h
+const NO_SYMBOL: c_ulong = 0;
+/// Keysyms of the characters outside Latin-1 are this plus their code point.
+const UNICODE_KEYSYM: c_ulong = 0x0100_0000;
+
+/// Offset of X keycodes from evdev key codes.
+const X_KEYCODE_OFFSET: c_int = 8;
+
+/// How long after its last press a keycode may be mapped to another character: the X server
+/// reads uinput on its own, and would type the new character for a press it didn't read yet.
+const REMAP_DELAY: Duration = Duration::from_millis(50);
+
+/// Keysym of `character`.
+fn keysym(character: char) -> c_ulong {
+    match character as c_ulong {
+        code @ 0x20..=0x7e | code @ 0xa0..=0xff => code,
+        code => UNICODE_KEYSYM + code,
+    }
+}
+
+/// A spare keycode and the character it types, if any yet.
+struct Spare {
+    keycode: c_int,
+    character: Option<char>,
+    used_at: Option<Instant>,
+}
+
+/// The spare keycodes, least recently used first.
+struct Spares(VecDeque<Spare>);
+
+impl X11Connection {
+    /// Keycodes of the keyboard mapping without any keysym, which no key types.
+    fn spare_keycodes(&self) -> Spares {
+        let mut spares = VecDeque::new();
+        unsafe {
+            let mut min_keycode: c_int = 0;
+            let mut max_keycode: c_int = 0;
+            xlib::XDisplayKeycodes(self.display, &mut min_keycode, &mut max_keycode);
+            let count = max_keycode - min_keycode + 1;
+            if min_keycode < X_KEYCODE_OFFSET || count <= 0 {
+                return Spares(spares);
+            }
+            let mut keysyms_per_keycode: c_int = 0;
+            let keysyms = xlib::XGetKeyboardMapping(self.display, min_keycode as u8, count, &mut keysyms_per_keycode);
+            if keysyms.is_null() {
+                return Spares(spares);
+            }
+            let per_keycode = keysyms_per_keycode.max(0) as usize;
+            let mapping = slice::from_raw_parts(keysyms, count as usize * per_keycode);
+            for (index, keysyms) in mapping.chunks(per_keycode.max(1)).enumerate() {
+                if keysyms.iter().all(|keysym| *keysym == NO_SYMBOL) {
+                    spares.push_back(Spare {
+                        keycode: min_keycode + index as c_int,
+                        character: None,
+                        used_at: None,
+                    });
+                }
+            }
+            xlib::XFree(keysyms as *mut _);
+        }
+        Spares(spares)
+    }
+
+    /// Make `keycode` type `character`, with or without Shift.
+    fn map_keycode(&self, keycode: c_int, character: char) {
+        let mut keysyms = [keysym(character); 2];
+        unsafe {
+            xlib::XChangeKeyboardMapping(self.display, keycode, keysyms.len() as c_int, keysyms.as_mut_ptr(), 1);
+            xlib::XSync(self.display, xlib::False);
+        }
+    }
+}
+
+impl Spares {
+    /// Spare keycode typing `character`, mapping the least recently used one to it if none
+    /// does yet.
+    fn keycode(&mut self, connection: &X11Connection, character: char) -> Option<c_int> {
+        let index = match self.0.iter().position(|spare| spare.character == Some(character)) {
+            Some(index) => index,
+            None => {
+                let spare = self.0.front_mut()?;
+                if let Some(used_at) = spare.used_at {
+                    thread::sleep(REMAP_DELAY.saturating_sub(used_at.elapsed()));
+                }
+                connection.map_keycode(spare.keycode, character);
+                spare.character = Some(character);
+                0
+            }
+        };
+        let mut spare = self.0.remove(index)?;
+        spare.used_at = Some(Instant::now());
+        let keycode = spare.keycode;
+        self.0.push_back(spare);
+        Some(keycode)
+    }
+}
+
+lazy_static! {
+    /// Spare keycodes of the shared connection, once looked for.
+    static ref SPARES: Mutex<Option<Spares>> = Mutex::new(None);
+}
+
+/// Whether Unicode text can be typed: with an X server having spare keycodes, and uinput to
+/// press them. Wayland compositors keep a keymap of their own, that XWayland can't change.
+pub(super) fn can_type() -> bool {
+    uinput::VIRTUAL_DEVICE.is_some()
+        && env::var_os("WAYLAND_DISPLAY").is_none()
+        && with_spares(|spares, _| Some(!spares.0.is_empty())).unwrap_or(false)
+}
+
+/// Evdev key code typing `character`, if Unicode text can be typed.
+pub(super) fn key_code(character: char) -> Option<u16> {
+    if !can_type() {
+        return None;
+    }
+    let keycode = with_spares(|spares, connection| spares.keycode(connection, character))?;
+    Some((keycode - X_KEYCODE_OFFSET) as u16)
+}
+
+fn with_spares<T, F: FnOnce(&mut Spares, &X11Connection) -> Option<T>>(use_spares: F) -> Option<T> {
+    let mut spares = SPARES.lock().ok()?;
+    with_shared_connection(|connection| {
+        let spares = spares.get_or_insert_with(|| connection.spare_keycodes());
+        use_spares(spares, connection)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latin_1_characters_keep_their_code_point_as_keysym() {
+        assert_eq!(keysym('a'), 0x61);
+        assert_eq!(keysym('é'), 0xe9);
+        assert_eq!(keysym('п'), 0x0100_043f);
+        assert_eq!(keysym('€'), 0x0100_20ac);
+    }
+}
@@ -0,0 +1,124 @@
+//! Focused window through the EWMH properties of the X server.
+
+use super::{xlib, X11Connection};
+use device_state::WindowInfo;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_int, c_long, c_uchar, c_ulong};
+use std::ptr;
+use std::slice;
+
+// Reference: https://gitlab.freedesktop.org/xorg/proto/xorgproto/-/blob/master/include/X11/Xatom.h
+const XA_CARDINAL: xlib::Atom = 6;
+const XA_STRING: xlib::Atom = 31;
+const XA_WINDOW: xlib::Atom = 33;
+
+/// Longest property read, in 32-bit units.
+const MAX_PROPERTY_LENGTH: c_long = 1024;
+
+/// Property `name` of `window`, with its format, if it is set and has the type `kind`.
+unsafe fn property(display: *mut xlib::Display, window: xlib::Window, name: &str, kind: xlib::Atom) -> Option<(c_int, Vec<u8>)> {
+    let name = CString::new(name).expect("Property name contains a nul byte");
+    let atom = xlib::XInternAtom(display, name.as_ptr(), xlib::True);
+    if atom == 0 {
+        return None;
+    }
+
+    let mut actual_kind = 0;
+    let mut format = 0;
+    let mut length: c_ulong = 0;
+    let mut remaining: c_ulong = 0;
+    let mut data: *mut c_uchar = ptr::null_mut();
+    let status = xlib::XGetWindowProperty(
+        display,
+        window,
+        atom,
+        0,
+        MAX_PROPERTY_LENGTH,
+        xlib::False,
+        kind,
+        &mut actual_kind,
+        &mut format,
+        &mut length,
+        &mut remaining,
+        &mut data,
+    );
+    if status != 0 || data.is_null() {
+        return None;
+    }
+    // Items of format 32 are stored as longs on the client side.
+    let size = match format {
+        8 => 1,
+        16 => 2,
+        32 => std::mem::size_of::<c_long>(),
+        _ => 0,
+    };
+    let bytes = slice::from_raw_parts(data, length as usize * size).to_vec();
+    xlib::XFree(data as *mut _);
+    if actual_kind != kind || size == 0 {
+        return None;
+    }
+    Some((format, bytes))
+}
+
+/// First item of a property of format 32.
+unsafe fn property_long(display: *mut xlib::Display, window: xlib::Window, name: &str, kind: xlib::Atom) -> Option<c_ulong> {
+    match property(display, window, name, kind)? {
+        (32, bytes) if bytes.len() >= std::mem::size_of::<c_ulong>() => {
+            Some(ptr::read_unaligned(bytes.as_ptr() as *const c_ulong))
+        }
+        _ => None,
+    }
+}
+
+/// Text property of format 8.
+unsafe fn property_text(display: *mut xlib::Display, window: xlib::Window, name: &str, kind: xlib::Atom) -> Option<String> {
+    match property(display, window, name, kind)? {
+        (8, bytes) => Some(String::from_utf8_lossy(&bytes).into_owned()),
+        _ => None,
+    }
+}
+
+/// Class of `WM_CLASS`, the second of its two strings.
+unsafe fn class(display: *mut xlib::Display, window: xlib::Window) -> String {
+    let mut hint = xlib::XClassHint {
+        res_name: ptr::null_mut(),
+        res_class: ptr::null_mut(),
+    };
+    if xlib::XGetClassHint(display, window, &mut hint) == 0 {
+        return String::new();
+    }
+    let class = if hint.res_class.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(hint.res_class).to_string_lossy().into_owned()
+    };
+    for name in [hint.res_name, hint.res_class].iter().filter(|name| !name.is_null()) {
+        xlib::XFree(*name as *mut _);
+    }
+    class
+}
+
+impl X11Connection {
+    /// Window named by `_NET_ACTIVE_WINDOW` on the root window, which window managers
+    /// following EWMH maintain.
+    pub(super) fn active_window(&self) -> Option<WindowInfo> {
+        unsafe {
+            let root = xlib::XDefaultRootWindow(self.display);
+            let window = property_long(self.display, root, "_NET_ACTIVE_WINDOW", XA_WINDOW)?;
+            if window == 0 {
+                return None;
+            }
+
+            let utf8 = CString::new("UTF8_STRING").expect("Atom name contains a nul byte");
+            let utf8 = xlib::XInternAtom(self.display, utf8.as_ptr(), xlib::False);
+            let title = property_text(self.display, window, "_NET_WM_NAME", utf8)
+                .or_else(|| property_text(self.display, window, "WM_NAME", XA_STRING))
+                .unwrap_or_default();
+            Some(WindowInfo {
+                class: class(self.display, window),
+                title,
+                pid: property_long(self.display, window, "_NET_WM_PID", XA_CARDINAL).map(|pid| pid as u32),
+            })
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Lock state through the XKB extension of the X server.
+
+use super::xlib;
+use device_state::{LockKey, LockState};
+use std::ffi::CString;
+use std::os::raw::{c_int, c_uint, c_ulong};
+use std::ptr;
+use std::sync::Mutex;
+
+// Reference: https://gitlab.freedesktop.org/xorg/proto/xorgproto/-/blob/master/include/X11/extensions/XKB.h
+const XKB_USE_CORE_KBD: c_uint = 0x0100;
+// Reference: https://gitlab.freedesktop.org/xorg/proto/xorgproto/-/blob/master/include/X11/keysymdef.h
+const XK_NUM_LOCK: c_ulong = 0xff7f;
+
+/// Connection to the X server of its own, since the lock state is queried from other threads
+/// than the one of the `DeviceState`.
+struct Display(*mut xlib::Display);
+
+// The connection is only used behind the mutex below.
+unsafe impl Send for Display {}
+
+impl Drop for Display {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.0);
+        }
+    }
+}
+
+lazy_static! {
+    static ref DISPLAY: Mutex<Option<Display>> = Mutex::new(unsafe {
+        let display = xlib::XOpenDisplay(ptr::null());
+        if display.is_null() {
+            None
+        } else {
+            Some(Display(display))
+        }
+    });
+}
+
+fn indicator_name(key: LockKey) -> &'static str {
+    match key {
+        LockKey::CapsLock => "Caps Lock",
+        LockKey::NumLock => "Num Lock",
+        LockKey::ScrollLock => "Scroll Lock",
+    }
+}
+
+/// Whether the indicator of `key` exists and is lit.
+unsafe fn indicator(display: *mut xlib::Display, key: LockKey) -> bool {
+    let name = CString::new(indicator_name(key)).expect("Indicator name contains a nul byte");
+    let atom = xlib::XInternAtom(display, name.as_ptr(), xlib::True);
+    if atom == 0 {
+        return false;
+    }
+    let mut state: c_int = 0;
+    let found = xlib::XkbGetNamedIndicator(
+        display,
+        atom,
+        ptr::null_mut(),
+        &mut state,
+        ptr::null_mut(),
+        ptr::null_mut(),
+    );
+    found != 0 && state != 0
+}
+
+/// Which locks are on, from the indicators of the core keyboard. Returns `None` without an X
+/// server.
+pub(crate) fn lock_state() -> Option<LockState> {
+    let display = DISPLAY.lock().ok()?;
+    let display = display.as_ref()?.0;
+    let mut lock_state = LockState::default();
+    for key in [LockKey::CapsLock, LockKey::NumLock, LockKey::ScrollLock].iter() {
+        lock_state.set(*key, unsafe { indicator(display, *key) });
+    }
+    Some(lock_state)
+}
+
+/// Latch the modifier locked by `key` on or off. Returns `false` if `key` doesn't lock a
+/// modifier, like Scroll Lock usually, or without an X server.
+pub(crate) fn lock_modifier(key: LockKey, on: bool) -> bool {
+    let display = match DISPLAY.lock() {
+        Ok(display) => display,
+        Err(_) => return false,
+    };
+    let display = match display.as_ref() {
+        Some(display) => display.0,
+        None => return false,
+    };
+    unsafe {
+        let mask = match key {
+            LockKey::CapsLock => xlib::LockMask,
+            LockKey::NumLock => xlib::XkbKeysymToModifiers(display, XK_NUM_LOCK),
+            LockKey::ScrollLock => 0,
+        };
+        if mask == 0 {
+            return false;
+        }
+        let locked = xlib::XkbLockModifiers(display, XKB_USE_CORE_KBD, mask, if on { mask } else { 0 }) != 0;
+        xlib::XFlush(display);
+        locked
+    }
+}
@@ -0,0 +1,56 @@
+//! Lock keys and their state.
+
+use super::{InputAction, INJECTOR};
+use serde::{Deserialize, Serialize};
+use Key;
+
+/// Keys toggling a lock, with an LED on most keyboards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LockKey {
+    CapsLock,
+    NumLock,
+    ScrollLock,
+}
+
+impl LockKey {
+    pub fn key(self) -> Key {
+        match self {
+            LockKey::CapsLock => Key::CapsLock,
+            LockKey::NumLock => Key::NumLock,
+            LockKey::ScrollLock => Key::ScrollLock,
+        }
+    }
+}
+
+/// Which locks are on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LockState {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl LockState {
+    pub fn is_on(self, key: LockKey) -> bool {
+        match key {
+            LockKey::CapsLock => self.caps_lock,
+            LockKey::NumLock => self.num_lock,
+            LockKey::ScrollLock => self.scroll_lock,
+        }
+    }
+
+    pub fn set(&mut self, key: LockKey, on: bool) {
+        match key {
+            LockKey::CapsLock => self.caps_lock = on,
+            LockKey::NumLock => self.num_lock = on,
+            LockKey::ScrollLock => self.scroll_lock = on,
+        }
+    }
+}
+
+/// Toggle `key` by queueing a press and a release of it, if the platform has it.
+pub(crate) fn toggle_lock(key: LockKey) {
+    if let Some(code) = key.key().code() {
+        INJECTOR.queue(vec![InputAction::KeyDown(code), InputAction::KeyUp(code)]);
+    }
+}
@@ -0,0 +1,115 @@
+use keymap::Key;
+
+/// Virtual key code of every `Key` that has one, from the `kVK_*` constants of `Events.h`.
+/// The keypad Clear key stands in for NumLock and the Help key for Insert.
+pub const KEY_CODES: &[(Key, u32)] = &[
+    (Key::Key0, 0x1D),
+    (Key::Key1, 0x12),
+    (Key::Key2, 0x13),
+    (Key::Key3, 0x14),
+    (Key::Key4, 0x15),
+    (Key::Key5, 0x17),
+    (Key::Key6, 0x16),
+    (Key::Key7, 0x1A),
+    (Key::Key8, 0x1C),
+    (Key::Key9, 0x19),
+    (Key::A, 0x00),
+    (Key::B, 0x0B),
+    (Key::C, 0x08),
+    (Key::D, 0x02),
+    (Key::E, 0x0E),
+    (Key::F, 0x03),
+    (Key::G, 0x05),
+    (Key::H, 0x04),
+    (Key::I, 0x22),
+    (Key::J, 0x26),
+    (Key::K, 0x28),
+    (Key::L, 0x25),
+    (Key::M, 0x2E),
+    (Key::N, 0x2D),
+    (Key::O, 0x1F),
+    (Key::P, 0x23),
+    (Key::Q, 0x0C),
+    (Key::R, 0x0F),
+    (Key::S, 0x01),
+    (Key::T, 0x11),
+    (Key::U, 0x20),
+    (Key::V, 0x09),
+    (Key::W, 0x0D),
+    (Key::X, 0x07),
+    (Key::Y, 0x10),
+    (Key::Z, 0x06),
+    (Key::F1, 0x7A),
+    (Key::F2, 0x78),
+    (Key::F3, 0x63),
+    (Key::F4, 0x76),
+    (Key::F5, 0x60),
+    (Key::F6, 0x61),
+    (Key::F7, 0x62),
+    (Key::F8, 0x64),
+    (Key::F9, 0x65),
+    (Key::F10, 0x6D),
+    (Key::F11, 0x67),
+    (Key::F12, 0x6F),
+    (Key::F13, 0x69),
+    (Key::F14, 0x6B),
+    (Key::F15, 0x71),
+    (Key::F16, 0x6A),
+    (Key::F17, 0x40),
+    (Key::F18, 0x4F),
+    (Key::F19, 0x50),
+    (Key::F20, 0x5A),
+    (Key::Escape, 0x35),
+    (Key::Space, 0x31),
+    (Key::LControl, 0x3B),
+    (Key::RControl, 0x3E),
+    (Key::LShift, 0x38),
+    (Key::RShift, 0x3C),
+    (Key::LAlt, 0x3A),
+    (Key::RAlt, 0x3D),
+    (Key::LMeta, 0x37),
+    (Key::RMeta, 0x36),
+    (Key::Menu, 0x6E),
+    (Key::Enter, 0x24),
+    (Key::Up, 0x7E),
+    (Key::Down, 0x7D),
+    (Key::Left, 0x7B),
+    (Key::Right, 0x7C),
+    (Key::Backspace, 0x33),
+    (Key::CapsLock, 0x39),
+    (Key::NumLock, 0x47),
+    (Key::Tab, 0x30),
+    (Key::Home, 0x73),
+    (Key::End, 0x77),
+    (Key::PageUp, 0x74),
+    (Key::PageDown, 0x79),
+    (Key::Insert, 0x72),
+    (Key::Delete, 0x75),
+    (Key::Numpad0, 0x52),
+    (Key::Numpad1, 0x53),
+    (Key::Numpad2, 0x54),
+    (Key::Numpad3, 0x55),
+    (Key::Numpad4, 0x56),
+    (Key::Numpad5, 0x57),
+    (Key::Numpad6, 0x58),
+    (Key::Numpad7, 0x59),
+    (Key::Numpad8, 0x5B),
+    (Key::Numpad9, 0x5C),
+    (Key::NumpadSubtract, 0x4E),
+    (Key::NumpadAdd, 0x45),
+    (Key::NumpadDivide, 0x4B),
+    (Key::NumpadMultiply, 0x43),
+    (Key::NumpadDecimal, 0x41),
+    (Key::NumpadEnter, 0x4C),
+    (Key::Grave, 0x32),
+    (Key::Minus, 0x1B),
+    (Key::Equal, 0x18),
+    (Key::LeftBracket, 0x21),
+    (Key::RightBracket, 0x1E),
+    (Key::BackSlash, 0x2A),
+    (Key::Semicolon, 0x29),
+    (Key::Apostrophe, 0x27),
+    (Key::Comma, 0x2B),
+    (Key::Dot, 0x2F),
+    (Key::Slash, 0x2C),
+];
@@ -1,32 +1,71 @@
 use macos_accessibility_client::accessibility::application_is_trusted_with_prompt;
 use cocoa::base::{id, nil};
 use cocoa::foundation::NSAutoreleasePool;
-use core_graphics::event::{CGEvent, CGEventFlags, CGEventType, CGKeyCode, CGEventTap, CGEventTapLocation, CGEventMask, CGEventTapPlacement, CGEventTapOptions};
+use objc::{class, msg_send, sel, sel_impl};
+use core_graphics::event::{CGEvent, CGEventFlags, CGEventType, CGKeyCode, CGEventTap, CGEventTapLocation, CGEventMask, CGEventTapPlacement, CGEventTapOptions, EventField, CGMouseButton, ScrollEventUnit};
+use core_graphics::geometry::CGPoint;
 use core_graphics::event_source::{CGEventSource, CGEventSourceStateID};
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use crate::KeyEvent;
+use std::sync::Arc;
+use crate::{Key, KeyEvent};
+use crate::device_events::{dispatch, Verdict};
+use crate::device_state::{record_emitted, track_key, InputAction, InputDevice, LockKey, LockState, MouseButton, ReleaseGuard, WindowInfo};
 use core_foundation::mach_port::CFMachPort;
 use core_foundation::runloop::{CFRunLoop, kCFRunLoopCommonModes};
 use std::cell::RefCell;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
 use std::time::Duration;
 
+mod keycodes;
+
+pub(crate) use self::keycodes::KEY_CODES;
+
 const K_CG_KEYBOARD_EVENT_KEYCODE: u32 = 9;  // Core Graphics keyboard event keycode constant
+const SIMULATED_EVENT_MARKER: i64 = 1;  // Event source user data of the events we post
+const RESUMED_EVENT_MARKER: i64 = 2;  // Event source user data of the events resumed by pre-filters
+
+// Reference: IOKit/hidsystem/IOHIDParameter.h and IOHIDShared.h
+const K_IO_HID_PARAM_CONNECT_TYPE: u32 = 1;
+const K_IO_HID_CAPS_LOCK_STATE: i32 = 1;
+const K_IO_HID_NUM_LOCK_STATE: i32 = 2;
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    static mach_task_self_: u32;
+    fn IOServiceMatching(name: *const c_char) -> *mut c_void;
+    fn IOServiceGetMatchingService(master_port: u32, matching: *mut c_void) -> u32;
+    fn IOServiceOpen(service: u32, owning_task: u32, connect_type: u32, connect: *mut u32) -> i32;
+    fn IOServiceClose(connect: u32) -> i32;
+    fn IOObjectRelease(object: u32) -> i32;
+    fn IOHIDGetModifierLockState(connect: u32, selector: i32, state: *mut bool) -> i32;
+    fn IOHIDSetModifierLockState(connect: u32, selector: i32, state: bool) -> i32;
+}
+
+// Reference: CoreGraphics/CGEventSource.h and CGEventTypes.h
+const K_CG_EVENT_SOURCE_STATE_HID_SYSTEM_STATE: i32 = 1;
+const K_CG_ANY_INPUT_EVENT_TYPE: u32 = !0;
 
-lazy_static! {
-    static ref GLOBAL_CALLBACKS: Mutex<Vec<Box<dyn Fn(&KeyEvent) -> bool + Send + Sync>>> = Mutex::new(Vec::new());
-    static ref CURRENT_KEYS: Mutex<HashMap<u32, KeyEvent>> = Mutex::new(HashMap::new());
-    static ref SIMULATION_FLAG: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGEventSourceSecondsSinceLastEventType(state: i32, event_type: u32) -> f64;
 }
 
+/// Modifier flags of the last event dispatched by the event tap. The events replacing it, or
+/// resumed by pre-filters, carry them on, so that e.g. a key remapped while Command is held
+/// still makes a shortcut.
+static ORIGINAL_FLAGS: AtomicU64 = AtomicU64::new(0);
+
 thread_local! {
     static EVENT_TAP: RefCell<Option<CGEventTap<'static>>> = RefCell::new(None);
+    // Buttons held by injected input, to post drags instead of moves.
+    static INJECTED_BUTTONS: RefCell<Vec<MouseButton>> = RefCell::new(Vec::new());
 }
 
 pub struct DeviceState {
     initialized: Arc<AtomicBool>,
+    _release: Arc<ReleaseGuard>,
 }
 
 impl DeviceState {
@@ -51,21 +90,21 @@ impl DeviceState {
                     vec![CGEventType::KeyDown, CGEventType::KeyUp],
                     move |proxy, event_type, event| unsafe {
                         println!("Received event: {:?}", event_type);
-                        if let Some(key_event) = handle_keyboard_event(event_type, event) {
+                        if let Some(mut key_event) = handle_keyboard_event(event_type, event) {
                             println!("Processed key event: {:?}", key_event);
-                            if let Ok(mut current_keys) = CURRENT_KEYS.lock() {
-                                if key_event.is_pressed {
-                                    current_keys.insert(key_event.key_code, key_event.clone());
-                                } else {
-                                    current_keys.remove(&key_event.key_code);
-                                }
-                            }
+                            track_key(&mut key_event);
+                            ORIGINAL_FLAGS.store(event.get_flags().bits(), Ordering::SeqCst);
                             
-                            if let Ok(callbacks) = GLOBAL_CALLBACKS.lock() {
-                                for callback in callbacks.iter() {
-                                    if !callback(&key_event) {
-                                        return None;
-                                    }
+                            match dispatch(&key_event) {
+                                Verdict::Pass => {}
+                                Verdict::Block => return None,
+                                Verdict::Replace(events) => {
+                                    emit(&events);
+                                    return None;
+                                }
+                                Verdict::Modify(event) => {
+                                    emit(&[event]);
+                                    return None;
                                 }
                             }
                         }
@@ -96,7 +135,10 @@ impl DeviceState {
             thread::sleep(Duration::from_millis(10));
         }
         
-        DeviceState { initialized }
+        DeviceState {
+            initialized,
+            _release: ReleaseGuard::acquire(),
+        }
     }
 
     /// returns `None` if app doesn't accessibility permissions.
@@ -107,106 +149,374 @@ impl DeviceState {
             None
         }
     }
+}
 
-    pub fn get_keys(&self) -> Vec<KeyEvent> {
-        if let Ok(current_keys) = CURRENT_KEYS.lock() {
-            current_keys.values().cloned().collect()
-        } else {
-            Vec::new()
-        }
+impl Drop for DeviceState {
+    fn drop(&mut self) {
+        EVENT_TAP.with(|tap| {
+            if let Some(tap) = tap.borrow_mut().take() {
+                tap.enable();
+            }
+        });
     }
+}
 
-    pub fn add_callback<F>(&self, callback: F)
-    where
-        F: Fn(&KeyEvent) -> bool + Send + Sync + 'static,
-    {
-        if let Ok(mut callbacks) = GLOBAL_CALLBACKS.lock() {
-            callbacks.push(Box::new(callback));
+unsafe extern "C" fn event_callback(_proxy: *const std::ffi::c_void, event_type: CGEventType, event: &CGEvent) -> Option<CGEvent> {
+    // Resumed events went through the callbacks before being posted.
+    if event.get_integer_value_field(EventField::EVENT_SOURCE_USER_DATA) == RESUMED_EVENT_MARKER {
+        return Some(event.clone());
+    }
+    if let Some(mut key_event) = handle_keyboard_event(event_type, event) {
+        track_key(&mut key_event);
+        ORIGINAL_FLAGS.store(event.get_flags().bits(), Ordering::SeqCst);
+        
+        match dispatch(&key_event) {
+            Verdict::Pass => {}
+            Verdict::Block => return None,
+            Verdict::Replace(events) => {
+                emit(&events);
+                return None;
+            }
+            Verdict::Modify(event) => {
+                emit(&[event]);
+                return None;
+            }
         }
     }
+    Some(event.clone())
+}
 
-    pub fn press(&self, keys: Vec<u32>) {
-        let source = CGEventSource::new(CGEventSourceStateID::Private)
-            .expect("Failed to create event source");
-            
-        for key in keys {
-            let down_event = CGEvent::new_keyboard_event(
-                source.clone(),
-                key as CGKeyCode,
-                true
-            ).expect("Failed to create key down event");
-            
-            down_event.set_flags(CGEventFlags::empty());
-            down_event.post(CGEventTapLocation::HID);
-        }
+/// Unicode events are typed as the string of their event.
+pub(crate) fn can_type_unicode() -> bool {
+    true
+}
+
+/// Flag of the modifier with this key code, if it is one.
+fn modifier_flag(key_code: u32) -> Option<CGEventFlags> {
+    match Key::from_code(key_code)? {
+        Key::LShift | Key::RShift => Some(CGEventFlags::CGEventFlagShift),
+        Key::LControl | Key::RControl => Some(CGEventFlags::CGEventFlagControl),
+        Key::LAlt | Key::RAlt => Some(CGEventFlags::CGEventFlagAlternate),
+        Key::LMeta | Key::RMeta => Some(CGEventFlags::CGEventFlagCommand),
+        _ => None,
     }
+}
 
-    pub fn release(&self, keys: Vec<u32>) {
-        let source = CGEventSource::new(CGEventSourceStateID::Private)
-            .expect("Failed to create event source");
-            
-        for key in keys {
-            let up_event = CGEvent::new_keyboard_event(
-                source.clone(),
-                key as CGKeyCode,
-                false
-            ).expect("Failed to create key up event");
-            
-            up_event.set_flags(CGEventFlags::empty());
-            up_event.post(CGEventTapLocation::HID);
+/// Posts `events` in order, marked as simulated, with the modifier flags of the event they
+/// replace. Unicode events are typed as their character, without modifiers.
+pub(crate) fn emit(events: &[KeyEvent]) {
+    post_events(events, SIMULATED_EVENT_MARKER);
+}
+
+/// Posts events that already went through the callbacks, when a pre-filter resumes them. The
+/// event tap lets them through without dispatching them again.
+pub(crate) fn emit_resumed(events: &[KeyEvent]) {
+    post_events(events, RESUMED_EVENT_MARKER);
+}
+
+fn post_events(events: &[KeyEvent], marker: i64) {
+    record_emitted(events);
+    let source = match CGEventSource::new(CGEventSourceStateID::Private) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+
+    // Modifiers pressed or released by the events themselves change the flags of the next ones.
+    let modifiers = CGEventFlags::CGEventFlagShift
+        | CGEventFlags::CGEventFlagControl
+        | CGEventFlags::CGEventFlagAlternate
+        | CGEventFlags::CGEventFlagCommand
+        | CGEventFlags::CGEventFlagAlphaShift;
+    let mut flags = CGEventFlags::from_bits_truncate(ORIGINAL_FLAGS.load(Ordering::SeqCst)) & modifiers;
+    for key_event in events {
+        let is_unicode = key_event.key_code == KeyEvent::UNICODE_KEY_CODE;
+        if let Some(flag) = modifier_flag(key_event.key_code) {
+            flags.set(flag, key_event.is_pressed);
+        }
+        let key_code = if is_unicode { 0 } else { key_event.key_code as CGKeyCode };
+        let event = match CGEvent::new_keyboard_event(source.clone(), key_code, key_event.is_pressed) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+
+        if is_unicode {
+            match key_event.char {
+                Some(character) => event.set_string(&character.to_string()),
+                None => continue,
+            }
         }
+        event.set_flags(if is_unicode { CGEventFlags::empty() } else { flags });
+        if key_event.is_pressed && key_event.is_repeat {
+            event.set_integer_value_field(EventField::KEYBOARD_EVENT_AUTOREPEAT, 1);
+        }
+        event.set_integer_value_field(EventField::EVENT_SOURCE_USER_DATA, marker);
+        event.post(CGEventTapLocation::HID);
     }
 }
 
-impl Drop for DeviceState {
-    fn drop(&mut self) {
-        EVENT_TAP.with(|tap| {
-            if let Some(tap) = tap.borrow_mut().take() {
-                tap.enable();
-            }
-        });
+fn mouse_event_types(button: MouseButton) -> (CGEventType, CGEventType, CGEventType, CGMouseButton) {
+    match button {
+        MouseButton::Left => (
+            CGEventType::LeftMouseDown,
+            CGEventType::LeftMouseUp,
+            CGEventType::LeftMouseDragged,
+            CGMouseButton::Left,
+        ),
+        MouseButton::Right => (
+            CGEventType::RightMouseDown,
+            CGEventType::RightMouseUp,
+            CGEventType::RightMouseDragged,
+            CGMouseButton::Right,
+        ),
+        _ => (
+            CGEventType::OtherMouseDown,
+            CGEventType::OtherMouseUp,
+            CGEventType::OtherMouseDragged,
+            CGMouseButton::Center,
+        ),
     }
 }
 
-unsafe extern "C" fn event_callback(_proxy: *const std::ffi::c_void, event_type: CGEventType, event: &CGEvent) -> Option<CGEvent> {
-    if let Some(key_event) = handle_keyboard_event(event_type, event) {
-        if let Ok(mut current_keys) = CURRENT_KEYS.lock() {
-            if key_event.is_pressed {
-                current_keys.insert(key_event.key_code, key_event.clone());
-            } else {
-                current_keys.remove(&key_event.key_code);
+fn post_mouse_event(source: &CGEventSource, event_type: CGEventType, position: CGPoint, button: MouseButton) {
+    let (_, _, _, cg_button) = mouse_event_types(button);
+    if let Ok(event) = CGEvent::new_mouse_event(source.clone(), event_type, position, cg_button) {
+        let button_number = match button {
+            MouseButton::Left => 0,
+            MouseButton::Right => 1,
+            MouseButton::Middle => 2,
+            MouseButton::Back => 3,
+            MouseButton::Forward => 4,
+        };
+        event.set_integer_value_field(EventField::MOUSE_EVENT_BUTTON_NUMBER, button_number);
+        event.set_integer_value_field(EventField::EVENT_SOURCE_USER_DATA, SIMULATED_EVENT_MARKER);
+        event.post(CGEventTapLocation::HID);
+    }
+}
+
+/// Injector sink: posts `actions` in order, marked as simulated.
+pub(crate) fn inject(actions: &[InputAction]) {
+    let source = match CGEventSource::new(CGEventSourceStateID::Private) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+
+    for action in actions {
+        match *action {
+            InputAction::KeyDown(key) | InputAction::KeyUp(key) | InputAction::KeyRepeat(key) => {
+                let is_pressed = !matches!(action, InputAction::KeyUp(_));
+                if let Ok(event) = CGEvent::new_keyboard_event(source.clone(), key as CGKeyCode, is_pressed) {
+                    event.set_flags(CGEventFlags::empty());
+                    if matches!(action, InputAction::KeyRepeat(_)) {
+                        event.set_integer_value_field(EventField::KEYBOARD_EVENT_AUTOREPEAT, 1);
+                    }
+                    event.set_integer_value_field(EventField::EVENT_SOURCE_USER_DATA, SIMULATED_EVENT_MARKER);
+                    event.post(CGEventTapLocation::HID);
+                }
             }
-        }
-        
-        if let Ok(callbacks) = GLOBAL_CALLBACKS.lock() {
-            for callback in callbacks.iter() {
-                if !callback(&key_event) {
-                    return None;
+            InputAction::MouseMove { dx, dy } => {
+                let current = match CGEvent::new(source.clone()) {
+                    Ok(event) => event.location(),
+                    Err(_) => continue,
+                };
+                let position = CGPoint::new(current.x + dx as f64, current.y + dy as f64);
+                let held = INJECTED_BUTTONS.with(|buttons| buttons.borrow().first().cloned());
+                match held {
+                    Some(button) => post_mouse_event(&source, mouse_event_types(button).2, position, button),
+                    None => post_mouse_event(&source, CGEventType::MouseMoved, position, MouseButton::Left),
+                }
+            }
+            InputAction::MouseDown(button) | InputAction::MouseUp(button) => {
+                let is_pressed = matches!(action, InputAction::MouseDown(_));
+                let position = match CGEvent::new(source.clone()) {
+                    Ok(event) => event.location(),
+                    Err(_) => continue,
+                };
+                INJECTED_BUTTONS.with(|buttons| {
+                    let mut buttons = buttons.borrow_mut();
+                    buttons.retain(|held| *held != button);
+                    if is_pressed {
+                        buttons.push(button);
+                    }
+                });
+                let (down, up, _, _) = mouse_event_types(button);
+                post_mouse_event(&source, if is_pressed { down } else { up }, position, button);
+            }
+            InputAction::Scroll { dx, dy } => {
+                // The second wheel scrolls left for positive values.
+                if let Ok(event) = CGEvent::new_scroll_event(source.clone(), ScrollEventUnit::LINE, 2, dy, -dx, 0) {
+                    event.set_integer_value_field(EventField::EVENT_SOURCE_USER_DATA, SIMULATED_EVENT_MARKER);
+                    event.post(CGEventTapLocation::HID);
                 }
             }
         }
     }
-    Some(event.clone())
 }
 
 fn handle_keyboard_event(event_type: CGEventType, event: &CGEvent) -> Option<KeyEvent> {
     match event_type {
         CGEventType::KeyDown | CGEventType::KeyUp => {
             let key_code = event.get_integer_value_field(K_CG_KEYBOARD_EVENT_KEYCODE) as u32;
-            let flags = event.get_flags();
+            let user_data = event.get_integer_value_field(EventField::EVENT_SOURCE_USER_DATA);
             
-            Some(KeyEvent::new(
+            let mut key_event = KeyEvent::new(
                 None, // TODO: Implement character conversion
                 key_code,
                 key_code, // Using keycode as scancode for now
                 matches!(event_type, CGEventType::KeyDown),
-                flags.contains(CGEventFlags::CGEventFlagNull)
-            ))
+                user_data == SIMULATED_EVENT_MARKER
+            );
+            key_event.is_repeat = event.get_integer_value_field(EventField::KEYBOARD_EVENT_AUTOREPEAT) != 0;
+            Some(key_event)
         }
         _ => None
     }
 }
 
+/// Connection to the HID system, which holds the modifier locks.
+struct HidSystem(u32);
+
+impl HidSystem {
+    fn open() -> Option<Self> {
+        unsafe {
+            let matching = IOServiceMatching(b"IOHIDSystem\0".as_ptr() as *const c_char);
+            let service = IOServiceGetMatchingService(0, matching);
+            if service == 0 {
+                return None;
+            }
+            let mut connect = 0;
+            let result = IOServiceOpen(service, mach_task_self_, K_IO_HID_PARAM_CONNECT_TYPE, &mut connect);
+            IOObjectRelease(service);
+            if result == 0 {
+                Some(HidSystem(connect))
+            } else {
+                None
+            }
+        }
+    }
+
+    fn get(&self, selector: i32) -> bool {
+        let mut state = false;
+        unsafe { IOHIDGetModifierLockState(self.0, selector, &mut state) == 0 && state }
+    }
+
+    fn set(&self, selector: i32, on: bool) {
+        unsafe {
+            IOHIDSetModifierLockState(self.0, selector, on);
+        }
+    }
+}
+
+impl Drop for HidSystem {
+    fn drop(&mut self) {
+        unsafe {
+            IOServiceClose(self.0);
+        }
+    }
+}
+
+fn lock_selector(key: LockKey) -> Option<i32> {
+    match key {
+        LockKey::CapsLock => Some(K_IO_HID_CAPS_LOCK_STATE),
+        LockKey::NumLock => Some(K_IO_HID_NUM_LOCK_STATE),
+        LockKey::ScrollLock => None,
+    }
+}
+
+/// The event tap sees the repeats of the OS, so blocking them is enough: there is nothing to
+/// turn off.
+pub(crate) fn set_os_auto_repeat(_on: bool) -> Option<bool> {
+    None
+}
+
+/// Which locks are on, from the HID system. Macs have no Scroll Lock.
+pub(crate) fn lock_state() -> LockState {
+    match HidSystem::open() {
+        Some(hid_system) => LockState {
+            caps_lock: hid_system.get(K_IO_HID_CAPS_LOCK_STATE),
+            num_lock: hid_system.get(K_IO_HID_NUM_LOCK_STATE),
+            scroll_lock: false,
+        },
+        None => LockState::default(),
+    }
+}
+
+/// Lock state to poll for changes, which the HID system doesn't report.
+pub(crate) fn polled_lock_state() -> Option<LockState> {
+    Some(lock_state())
+}
+
+pub(crate) fn set_lock_state(key: LockKey, on: bool) {
+    if let (Some(selector), Some(hid_system)) = (lock_selector(key), HidSystem::open()) {
+        hid_system.set(selector, on);
+    }
+}
+
+unsafe fn ns_string(string: id) -> String {
+    if string == nil {
+        return String::new();
+    }
+    let utf8: *const c_char = msg_send![string, UTF8String];
+    if utf8.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(utf8).to_string_lossy().into_owned()
+    }
+}
+
+/// Frontmost application. Window titles need the Accessibility API, so the title is the name
+/// of the application.
+pub(crate) fn active_window() -> Option<WindowInfo> {
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        let application: id = msg_send![workspace, frontmostApplication];
+        let window = if application == nil {
+            None
+        } else {
+            let bundle_identifier: id = msg_send![application, bundleIdentifier];
+            let name: id = msg_send![application, localizedName];
+            let pid: i32 = msg_send![application, processIdentifier];
+            Some(WindowInfo {
+                class: ns_string(bundle_identifier),
+                title: ns_string(name),
+                pid: Some(pid as u32).filter(|_| pid > 0),
+            })
+        };
+        let _: () = msg_send![pool, drain];
+        window
+    }
+}
+
+/// Time since the last input of the HID system, injected input included.
+pub(crate) fn os_idle_time() -> Option<Duration> {
+    let seconds = unsafe {
+        CGEventSourceSecondsSinceLastEventType(K_CG_EVENT_SOURCE_STATE_HID_SYSTEM_STATE, K_CG_ANY_INPUT_EVENT_TYPE)
+    };
+    if seconds.is_finite() && seconds >= 0.0 {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+/// Device enumeration isn't supported on macOS yet.
+pub(crate) fn input_devices() -> Vec<InputDevice> {
+    Vec::new()
+}
+
+/// Device changes aren't observed on macOS, so the device list is polled.
+pub(crate) struct DeviceMonitor;
+
+impl DeviceMonitor {
+    pub fn new() -> Self {
+        DeviceMonitor
+    }
+
+    pub fn wait(&self, timeout: Duration) {
+        thread::sleep(timeout);
+    }
+}
+
 /// Returns true if the Accessibility permissions necessary for this library to work are granted
 /// to this process
 ///
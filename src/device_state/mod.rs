@@ -1,22 +1,319 @@
 //! DeviceState implementation.
 
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use device_events::{DeviceProfile, HandlerId, HandlerInfo, KeyBinding, Stage, Verdict, GLOBAL_CALLBACKS};
+use keymap::DeviceKey;
+use {Key, KeyEvent, Modifiers};
+
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
 pub use self::linux::DeviceState;
+#[cfg(target_os = "linux")]
+pub(crate) use self::linux::{input_devices, DeviceMonitor, KEY_CODES};
+#[cfg(target_os = "linux")]
+pub(crate) use self::linux::{
+    active_window, can_type_unicode, emit_resumed, inject, lock_state, os_idle_time, polled_lock_state, set_lock_state,
+    set_os_auto_repeat,
+};
 
 #[cfg(target_os = "windows")]
 mod windows;
 #[cfg(target_os = "windows")]
 pub use self::windows::DeviceState;
+#[cfg(target_os = "windows")]
+pub(crate) use self::windows::{input_devices, DeviceMonitor, KEY_CODES};
+#[cfg(target_os = "windows")]
+pub(crate) use self::windows::{
+    active_window, can_type_unicode, emit_resumed, inject, lock_state, os_idle_time, polled_lock_state, set_lock_state,
+    set_os_auto_repeat,
+};
 
 #[cfg(target_os = "macos")]
 mod macos;
 #[cfg(target_os = "macos")]
 pub use self::macos::DeviceState;
+#[cfg(target_os = "macos")]
+pub(crate) use self::macos::{input_devices, DeviceMonitor, KEY_CODES};
+#[cfg(target_os = "macos")]
+pub(crate) use self::macos::{
+    active_window, can_type_unicode, emit_resumed, inject, lock_state, os_idle_time, polled_lock_state, set_lock_state,
+    set_os_auto_repeat,
+};
+
+mod idle;
+mod injector;
+mod input_device;
+mod lock_state;
+mod window;
+pub use self::injector::*;
+pub use self::input_device::*;
+pub use self::lock_state::{LockKey, LockState};
+pub use self::window::*;
+pub(crate) use self::lock_state::toggle_lock;
+pub(crate) use self::idle::{idle_time, record_injection};
+
+lazy_static! {
+    pub(crate) static ref INJECTOR: Injector = Injector::new(inject_input);
+
+    /// Guard shared by every `DeviceState` alive, if any.
+    static ref RELEASE_GUARD: Mutex<Weak<ReleaseGuard>> = Mutex::new(Weak::new());
+
+    /// Keys held down, by device and key code, so that a device can only release its own keys.
+    static ref CURRENT_KEYS: Mutex<HashMap<DeviceKey, KeyEvent>> = Mutex::new(HashMap::new());
+}
+
+/// Releases the input injected by this crate when the last `DeviceState` is dropped, clones
+/// included.
+#[derive(Debug)]
+pub(crate) struct ReleaseGuard;
+
+impl ReleaseGuard {
+    /// Guard of the `DeviceState`s alive, created if there are none. The first call also makes
+    /// every panic release the injected input, see `Injector::release_all_on_panic`.
+    pub(crate) fn acquire() -> Arc<ReleaseGuard> {
+        INJECTOR.release_all_on_panic();
+        let mut guard = RELEASE_GUARD.lock().expect("Couldn't lock the release guard");
+        guard.upgrade().unwrap_or_else(|| {
+            let acquired = Arc::new(ReleaseGuard);
+            *guard = Arc::downgrade(&acquired);
+            acquired
+        })
+    }
+}
+
+impl Drop for ReleaseGuard {
+    fn drop(&mut self) {
+        INJECTOR.release_all();
+    }
+}
+
+/// Injector sink: injects `actions`, recording that the input they cause isn't the user's.
+fn inject_input(actions: &[InputAction]) {
+    inject(actions);
+    record_injection();
+}
+
+/// Record a key event before the callbacks see it, flagging the presses of keys that are held
+/// down already as repeats.
+pub(crate) fn track_key(event: &mut KeyEvent) {
+    if !event.is_simulated {
+        idle::record_input();
+    }
+    if let Ok(mut current_keys) = CURRENT_KEYS.lock() {
+        if event.is_pressed {
+            event.is_repeat = event.is_repeat || current_keys.contains_key(&event.device_key());
+            current_keys.insert(event.device_key(), event.clone());
+        } else {
+            current_keys.remove(&event.device_key());
+        }
+    }
+}
+
+/// Record events that a backend emitted directly, so that they are released like queued input.
+pub(crate) fn record_emitted(events: &[KeyEvent]) {
+    record_injection();
+    let actions: Vec<InputAction> = events
+        .iter()
+        .filter(|event| event.key_code != KeyEvent::UNICODE_KEY_CODE)
+        .map(|event| {
+            if event.is_pressed {
+                InputAction::KeyDown(event.key_code)
+            } else {
+                InputAction::KeyUp(event.key_code)
+            }
+        })
+        .collect();
+    INJECTOR.record(&actions);
+}
 
 impl Default for DeviceState {
     fn default() -> Self {
         Self::new()
     }
 }
+
+impl DeviceState {
+    /// Keys currently held down, once per device holding them.
+    pub fn get_keys(&self) -> Vec<KeyEvent> {
+        match CURRENT_KEYS.lock() {
+            Ok(current_keys) => current_keys.values().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Register a blocking key callback. Returning `false` from the callback blocks the event.
+    ///
+    /// The callback is added to the `Stage::Normal` stage with priority 0, after every callback
+    /// registered before it.
+    pub fn add_callback<F>(&self, callback: F) -> HandlerId
+    where
+        F: Fn(&KeyEvent) -> bool + Send + Sync + 'static,
+    {
+        self.add_named_callback("unnamed", Stage::Normal, 0, callback)
+    }
+
+    /// Register a named blocking key callback. Within a stage, callbacks with a higher
+    /// priority run first; callbacks with equal priorities run in registration order.
+    pub fn add_named_callback<F>(&self, name: &str, stage: Stage, priority: i32, callback: F) -> HandlerId
+    where
+        F: Fn(&KeyEvent) -> bool + Send + Sync + 'static,
+    {
+        self.add_handler(name, stage, priority, move |event| Verdict::from(callback(event)))
+    }
+
+    /// Register a named key handler that can pass, block, replace or modify events. It is
+    /// ordered like the callbacks of `add_named_callback`.
+    ///
+    /// Replacement events are emitted by the backend in place of the original one, flagged as
+    /// simulated. Events created with `KeyEvent::unicode` are typed as text.
+    pub fn add_handler<F>(&self, name: &str, stage: Stage, priority: i32, handler: F) -> HandlerId
+    where
+        F: Fn(&KeyEvent) -> Verdict + Send + Sync + 'static,
+    {
+        GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(name, stage, priority, None, Arc::new(handler))
+    }
+
+    /// Register a named key handler like `add_handler`, that only runs for events of the
+    /// devices matching `device`, e.g. to remap the keys of a macro pad but not the ones of the
+    /// main keyboard. Events from devices the backend can't tell apart never match.
+    pub fn add_device_handler<F>(
+        &self,
+        name: &str,
+        stage: Stage,
+        priority: i32,
+        device: DeviceSelector,
+        handler: F,
+    ) -> HandlerId
+    where
+        F: Fn(&KeyEvent) -> Verdict + Send + Sync + 'static,
+    {
+        GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(name, stage, priority, Some(device), Arc::new(handler))
+    }
+
+    /// Apply `profile` to the events of its devices, after the other `Stage::Normal`
+    /// handlers so that hotkeys take precedence over remaps. Removed with `remove_callback`.
+    pub fn add_profile(&self, profile: &DeviceProfile) -> HandlerId {
+        let profile = profile.clone();
+        self.add_device_handler(
+            &profile.name.clone(),
+            Stage::Normal,
+            i32::MIN,
+            profile.device.clone(),
+            move |event| profile.verdict(event),
+        )
+    }
+
+    /// Start building a handler bound to `key`. Bound handlers are only looked up for events of
+    /// their key, so their number doesn't slow down the dispatch of other keys.
+    pub fn on(&self, key: Key) -> KeyBinding {
+        KeyBinding::new(key)
+    }
+
+    /// Remove a callback. Returns `false` if it was already removed.
+    pub fn remove_callback(&self, id: HandlerId) -> bool {
+        GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .remove(id)
+    }
+
+    /// Modifiers currently held down, as seen by the callback chain.
+    pub fn modifiers(&self) -> Modifiers {
+        GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .modifiers()
+    }
+
+    /// List the registered callbacks in dispatch order.
+    pub fn handlers(&self) -> Vec<HandlerInfo> {
+        GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .handlers()
+    }
+
+    /// Press `keys`, by platform key code, after all input injected before. Doesn't wait for
+    /// the keys to be pressed.
+    pub fn press(&self, keys: Vec<u32>) {
+        INJECTOR.queue(keys.into_iter().map(InputAction::KeyDown).collect());
+    }
+
+    /// Release `keys`, by platform key code, after all input injected before. Doesn't wait for
+    /// the keys to be released.
+    pub fn release(&self, keys: Vec<u32>) {
+        INJECTOR.queue(keys.into_iter().map(InputAction::KeyUp).collect());
+    }
+
+    /// Inject `actions` in order, after all input injected before, and wait until they are
+    /// injected. Must not be called from a callback; return a `Verdict` instead.
+    pub fn send(&self, actions: &[InputAction]) {
+        INJECTOR.send(actions);
+    }
+
+    /// Wait until all input injected so far has been sent to the OS.
+    pub fn flush(&self) {
+        INJECTOR.flush();
+    }
+
+    /// Wait until no injected input is pending, for at most `timeout`. Returns `false` on
+    /// timeout.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        INJECTOR.wait_idle(timeout)
+    }
+
+    /// Release every key and mouse button that this crate pressed and didn't release yet,
+    /// discarding input that is still queued. Also done when the last `DeviceState` is
+    /// dropped, and whenever a thread panics, even if the panic is caught.
+    pub fn release_all(&self) {
+        INJECTOR.release_all();
+    }
+
+    /// Release injected keys and buttons automatically once they have been held for `timeout`
+    /// without being pressed again. Disabled with `None`, the default.
+    pub fn set_stuck_key_timeout(&self, timeout: Option<Duration>) {
+        INJECTOR.set_stuck_key_timeout(timeout);
+    }
+
+    /// Which locks are on, as far as the OS knows.
+    pub fn lock_state(&self) -> LockState {
+        lock_state()
+    }
+
+    /// Turn a lock on or off. Doesn't wait for the change: the lock state may only be updated
+    /// once the input injected before is.
+    pub fn set_lock_state(&self, key: LockKey, on: bool) {
+        set_lock_state(key, on);
+    }
+
+    /// Window that has the keyboard focus, if any. On Linux, it is only known under an X
+    /// server with a window manager following EWMH.
+    pub fn active_window(&self) -> Option<WindowInfo> {
+        active_window()
+    }
+
+    /// Time since the last input of the user. Key events are seen by the callbacks; mouse
+    /// input is known from the idle counter of the OS where there is one, see `on_idle`.
+    pub fn idle_time(&self) -> Duration {
+        idle_time()
+    }
+
+    /// Input devices currently attached.
+    pub fn devices(&self) -> Vec<InputDevice> {
+        input_devices()
+    }
+
+    /// Handle on the injection queue, for code that injects input without a `DeviceState`.
+    pub fn injector(&self) -> Injector {
+        INJECTOR.clone()
+    }
+}
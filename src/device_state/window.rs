@@ -0,0 +1,37 @@
+//! Focused window descriptions.
+
+use serde::{Deserialize, Serialize};
+
+/// The window that has the keyboard focus.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WindowInfo {
+    /// Class of the window, telling applications apart: the class of `WM_CLASS` on X11, such
+    /// as `firefox`, the window class name on Windows and the bundle identifier of the
+    /// application on macOS.
+    pub class: String,
+    /// Title of the window. macOS gives the name of the application instead.
+    pub title: String,
+    /// Process owning the window, when known.
+    pub pid: Option<u32>,
+}
+
+/// Selects windows, e.g. to scope handlers to the events typed in a single application.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WindowSelector {
+    /// Windows with this class, ignoring case.
+    Class(String),
+    /// Windows whose title contains this text, ignoring case.
+    Title(String),
+    /// Windows of the process with this id.
+    Pid(u32),
+}
+
+impl WindowSelector {
+    pub fn matches(&self, window: &WindowInfo) -> bool {
+        match *self {
+            WindowSelector::Class(ref class) => window.class.to_lowercase() == class.to_lowercase(),
+            WindowSelector::Title(ref title) => window.title.to_lowercase().contains(&title.to_lowercase()),
+            WindowSelector::Pid(pid) => window.pid == Some(pid),
+        }
+    }
+}
@@ -2,25 +2,33 @@ use windows::Win32::UI::WindowsAndMessaging::{
     SetWindowsHookExW, UnhookWindowsHookEx, CallNextHookEx,
     WH_KEYBOARD_LL, KBDLLHOOKSTRUCT, WM_KEYDOWN, WM_SYSKEYDOWN, HHOOK,
     GetMessageW, TranslateMessage, DispatchMessageW, MSG, GUITHREADINFO,
-    GetGUIThreadInfo, GetWindowThreadProcessId
+    GetGUIThreadInfo, GetWindowThreadProcessId, XBUTTON1, XBUTTON2, GetForegroundWindow,
+    GetWindowTextW, GetClassNameW
 };
 use windows::Win32::UI::Input::KeyboardAndMouse::{
-    GetKeyboardLayout, GetKeyboardState, VK_CAPITAL, GetKeyState,
+    GetKeyboardLayout, GetKeyboardState, VK_CAPITAL, VK_NUMLOCK, VK_SCROLL, GetKeyState,
     ToUnicodeEx, INPUT, INPUT_0, INPUT_KEYBOARD, KEYBDINPUT, KEYEVENTF_KEYUP, SendInput,
-    VIRTUAL_KEY, MapVirtualKeyW, MAP_VIRTUAL_KEY_TYPE, KEYBD_EVENT_FLAGS
+    VIRTUAL_KEY, MapVirtualKeyW, MAP_VIRTUAL_KEY_TYPE, KEYBD_EVENT_FLAGS, KEYEVENTF_UNICODE,
+    INPUT_MOUSE, MOUSEINPUT, MOUSE_EVENT_FLAGS, MOUSEEVENTF_MOVE, MOUSEEVENTF_LEFTDOWN,
+    MOUSEEVENTF_LEFTUP, MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP, MOUSEEVENTF_MIDDLEDOWN,
+    MOUSEEVENTF_MIDDLEUP, MOUSEEVENTF_XDOWN, MOUSEEVENTF_XUP, MOUSEEVENTF_WHEEL, MOUSEEVENTF_HWHEEL,
+    GetLastInputInfo, LASTINPUTINFO
 };
+use windows::Win32::System::SystemInformation::GetTickCount;
 use windows::Win32::Foundation::{LPARAM, WPARAM, LRESULT, HWND};
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
 use crate::KeyEvent;
+use crate::device_events::{dispatch, Verdict};
+use crate::device_state::{record_emitted, toggle_lock, track_key, InputAction, LockKey, LockState, MouseButton, ReleaseGuard, WindowInfo};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use std::cell::RefCell;
 
-lazy_static! {
-    static ref GLOBAL_CALLBACKS: Mutex<Vec<Box<dyn Fn(&KeyEvent) -> bool + Send + Sync>>> = Mutex::new(Vec::new());
-    static ref CURRENT_KEYS: Mutex<HashMap<u32, KeyEvent>> = Mutex::new(HashMap::new());
-    static ref SIMULATION_FLAG: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
-}
+mod keycodes;
+mod raw_input;
+
+pub(crate) use self::keycodes::KEY_CODES;
+pub(crate) use self::raw_input::{input_devices, DeviceMonitor};
 
 // Заменяем static на thread_local
 thread_local! {
@@ -28,11 +36,23 @@ thread_local! {
 }
 
 #[derive(Clone)]
-pub struct DeviceState {}
+pub struct DeviceState {
+    _release: Arc<ReleaseGuard>,
+}
+
+/// `dwExtraInfo` of the input sent by this crate.
+const SIMULATED_EXTRA_INFO: usize = 1;
+/// `dwExtraInfo` of the input resumed by a pre-filter, which went through the callbacks
+/// already.
+const RESUMED_EXTRA_INFO: usize = 2;
 
 unsafe extern "system" fn keyboard_hook_proc(code: i32, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
     if code >= 0 {
         let kbd_struct = *(l_param.0 as *const KBDLLHOOKSTRUCT);
+        // Resumed input went through the callbacks before being sent.
+        if kbd_struct.dwExtraInfo == RESUMED_EXTRA_INFO {
+            return CallNextHookEx(None, code, w_param, l_param);
+        }
         
         let is_pressed = w_param.0 as u32 == WM_KEYDOWN || w_param.0 as u32 == WM_SYSKEYDOWN;
         
@@ -72,35 +92,229 @@ unsafe extern "system" fn keyboard_hook_proc(code: i32, w_param: WPARAM, l_param
             None
         };
 
-        let key_event = KeyEvent::new(
+        let mut key_event = KeyEvent::new(
             character,
             kbd_struct.vkCode,
             kbd_struct.scanCode,
             is_pressed,
-            kbd_struct.dwExtraInfo == 1
+            kbd_struct.dwExtraInfo == SIMULATED_EXTRA_INFO
         );
 
         // Обновляем состояние клавиш
-        if let Ok(mut current_keys) = CURRENT_KEYS.lock() {
-            if is_pressed {
-                current_keys.insert(kbd_struct.vkCode, key_event.clone());
-            } else {
-                current_keys.remove(&kbd_struct.vkCode);
-            }
-        }
+        track_key(&mut key_event);
 
         // Проверяем callbacks для блокировки
-        if let Ok(callbacks) = GLOBAL_CALLBACKS.lock() {
-            for callback in callbacks.iter() {
-                if !callback(&key_event) {
-                    return LRESULT(1);
-                }
+        match dispatch(&key_event) {
+            Verdict::Pass => {}
+            Verdict::Block => return LRESULT(1),
+            Verdict::Replace(events) => {
+                emit(&events);
+                return LRESULT(1);
+            }
+            Verdict::Modify(event) => {
+                emit(&[event]);
+                return LRESULT(1);
             }
         }
     }
     CallNextHookEx(None, code, w_param, l_param)
 }
 
+fn keyboard_input(virtual_key: u16, scan_code: u16, flags: KEYBD_EVENT_FLAGS, extra_info: usize) -> INPUT {
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: VIRTUAL_KEY(virtual_key),
+                wScan: scan_code,
+                dwFlags: flags,
+                dwExtraInfo: extra_info,
+                time: 0,
+            },
+        },
+    }
+}
+
+fn virtual_key_input(virtual_key: u32, flags: KEYBD_EVENT_FLAGS, extra_info: usize) -> INPUT {
+    let scan_code = unsafe { MapVirtualKeyW(virtual_key, MAP_VIRTUAL_KEY_TYPE(0)) };
+    keyboard_input(virtual_key as u16, scan_code as u16, flags, extra_info)
+}
+
+/// Inputs reproducing `event`, marked with `extra_info`. Unicode events are typed as their
+/// character.
+fn event_inputs(event: &KeyEvent, extra_info: usize) -> Vec<INPUT> {
+    let flags = if event.is_pressed { KEYBD_EVENT_FLAGS(0) } else { KEYEVENTF_KEYUP };
+    if event.key_code == KeyEvent::UNICODE_KEY_CODE {
+        let mut units = [0u16; 2];
+        return match event.char {
+            Some(character) => character
+                .encode_utf16(&mut units)
+                .iter()
+                .map(|unit| keyboard_input(0, *unit, flags | KEYEVENTF_UNICODE, extra_info))
+                .collect(),
+            None => Vec::new(),
+        };
+    }
+    if event.scan_code == 0 {
+        return vec![virtual_key_input(event.key_code, flags, extra_info)];
+    }
+    vec![keyboard_input(event.key_code as u16, event.scan_code as u16, flags, extra_info)]
+}
+
+fn mouse_input(dx: i32, dy: i32, mouse_data: u32, flags: MOUSE_EVENT_FLAGS) -> INPUT {
+    INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx,
+                dy,
+                mouseData: mouse_data,
+                dwFlags: flags,
+                dwExtraInfo: SIMULATED_EXTRA_INFO,
+                time: 0,
+            },
+        },
+    }
+}
+
+fn button_input(button: MouseButton, is_pressed: bool) -> INPUT {
+    let (down, up, mouse_data) = match button {
+        MouseButton::Left => (MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP, 0),
+        MouseButton::Right => (MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP, 0),
+        MouseButton::Middle => (MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP, 0),
+        MouseButton::Back => (MOUSEEVENTF_XDOWN, MOUSEEVENTF_XUP, XBUTTON1),
+        MouseButton::Forward => (MOUSEEVENTF_XDOWN, MOUSEEVENTF_XUP, XBUTTON2),
+    };
+    mouse_input(0, 0, mouse_data as u32, if is_pressed { down } else { up })
+}
+
+/// Distance of a wheel notch, in the units of `mouseData`.
+const WHEEL_DELTA: i32 = 120;
+
+fn action_inputs(action: &InputAction) -> Vec<INPUT> {
+    match *action {
+        // Repeats are presses of a key that is down already.
+        InputAction::KeyDown(key) | InputAction::KeyRepeat(key) => vec![virtual_key_input(key, KEYBD_EVENT_FLAGS(0), SIMULATED_EXTRA_INFO)],
+        InputAction::KeyUp(key) => vec![virtual_key_input(key, KEYEVENTF_KEYUP, SIMULATED_EXTRA_INFO)],
+        InputAction::MouseMove { dx, dy } => vec![mouse_input(dx, dy, 0, MOUSEEVENTF_MOVE)],
+        InputAction::MouseDown(button) => vec![button_input(button, true)],
+        InputAction::MouseUp(button) => vec![button_input(button, false)],
+        InputAction::Scroll { dx, dy } => {
+            let mut inputs = Vec::new();
+            if dy != 0 {
+                inputs.push(mouse_input(0, 0, (dy * WHEEL_DELTA) as u32, MOUSEEVENTF_WHEEL));
+            }
+            if dx != 0 {
+                inputs.push(mouse_input(0, 0, (dx * WHEEL_DELTA) as u32, MOUSEEVENTF_HWHEEL));
+            }
+            inputs
+        }
+    }
+}
+
+/// Injector sink: sends `actions` with a single `SendInput` call so that nothing can be
+/// interleaved with them.
+pub(crate) fn inject(actions: &[InputAction]) {
+    let inputs: Vec<INPUT> = actions.iter().flat_map(action_inputs).collect();
+    if !inputs.is_empty() {
+        unsafe {
+            SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+        }
+    }
+}
+
+/// Unicode events are typed as their UTF-16 units.
+pub(crate) fn can_type_unicode() -> bool {
+    true
+}
+
+/// Emits `events` synchronously and in order. Safe to call from the hook procedure: the
+/// injected inputs are queued behind the event being processed.
+pub(crate) fn emit(events: &[KeyEvent]) {
+    send_events(events, SIMULATED_EXTRA_INFO);
+}
+
+/// Emits events that already went through the callbacks, when a pre-filter resumes them. The
+/// hook lets them through without dispatching them again.
+pub(crate) fn emit_resumed(events: &[KeyEvent]) {
+    send_events(events, RESUMED_EXTRA_INFO);
+}
+
+fn send_events(events: &[KeyEvent], extra_info: usize) {
+    record_emitted(events);
+    let inputs: Vec<INPUT> = events.iter().flat_map(|event| event_inputs(event, extra_info)).collect();
+    if !inputs.is_empty() {
+        unsafe {
+            SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+        }
+    }
+}
+
+/// The hook sees the repeats of the OS, so blocking them is enough: there is nothing to turn
+/// off.
+pub(crate) fn set_os_auto_repeat(_on: bool) -> Option<bool> {
+    None
+}
+
+/// Which locks are on, from the toggle bit of the lock keys.
+pub(crate) fn lock_state() -> LockState {
+    let is_on = |virtual_key: VIRTUAL_KEY| unsafe { GetKeyState(virtual_key.0 as i32) & 1 != 0 };
+    LockState {
+        caps_lock: is_on(VK_CAPITAL),
+        num_lock: is_on(VK_NUMLOCK),
+        scroll_lock: is_on(VK_SCROLL),
+    }
+}
+
+/// Lock state to poll for changes, which Windows doesn't report.
+pub(crate) fn polled_lock_state() -> Option<LockState> {
+    Some(lock_state())
+}
+
+/// Windows has no API to set the locks, so the lock key is pressed when needed.
+pub(crate) fn set_lock_state(key: LockKey, on: bool) {
+    if lock_state().is_on(key) != on {
+        toggle_lock(key);
+    }
+}
+
+/// Foreground window, or `None` while no window has the focus, e.g. when switching windows.
+pub(crate) fn active_window() -> Option<WindowInfo> {
+    unsafe {
+        let window = GetForegroundWindow();
+        if window.0.is_null() {
+            return None;
+        }
+
+        let mut title = [0u16; 512];
+        let title_length = GetWindowTextW(window, &mut title).max(0) as usize;
+        let mut class = [0u16; 256];
+        let class_length = GetClassNameW(window, &mut class).max(0) as usize;
+        let mut pid = 0u32;
+        GetWindowThreadProcessId(window, Some(&mut pid));
+        Some(WindowInfo {
+            class: String::from_utf16_lossy(&class[..class_length]),
+            title: String::from_utf16_lossy(&title[..title_length]),
+            pid: Some(pid).filter(|pid| *pid != 0),
+        })
+    }
+}
+
+/// Time since the last input of the session, injected input included.
+pub(crate) fn os_idle_time() -> Option<Duration> {
+    let mut info = LASTINPUTINFO {
+        cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+        dwTime: 0,
+    };
+    unsafe {
+        if !GetLastInputInfo(&mut info).as_bool() {
+            return None;
+        }
+        // Both tick counts wrap around after 49 days.
+        Some(Duration::from_millis(GetTickCount().wrapping_sub(info.dwTime) as u64))
+    }
+}
+
 impl DeviceState {
     pub fn new() -> DeviceState {
         thread::spawn(|| {
@@ -125,93 +339,10 @@ impl DeviceState {
             }
         });
 
-        DeviceState {}
-    }
-
-    pub fn get_keys(&self) -> Vec<KeyEvent> {
-        if let Ok(current_keys) = CURRENT_KEYS.lock() {
-            current_keys.values().cloned().collect()
-        } else {
-            Vec::new()
-        }
-    }
-
-    pub fn add_callback<F>(&self, callback: F)
-    where
-        F: Fn(&KeyEvent) -> bool + Send + Sync + 'static,
-    {
-        if let Ok(mut callbacks) = GLOBAL_CALLBACKS.lock() {
-            callbacks.push(Box::new(callback));
+        DeviceState {
+            _release: ReleaseGuard::acquire(),
         }
     }
-
-    pub fn press(&self, keys: Vec<u32>) {
-        thread::spawn(move || {
-            unsafe {
-                let mut inputs = Vec::with_capacity(keys.len() * 2);
-                
-                let mut simulation_flag = SIMULATION_FLAG.lock().unwrap();
-                *simulation_flag = true;
-                
-                // Первый цикл - нажатия клавиш (KEYDOWN)
-                for key in &keys {
-                    let scan_code = MapVirtualKeyW(*key, MAP_VIRTUAL_KEY_TYPE(0));
-
-                    let input = INPUT {
-                        r#type: INPUT_KEYBOARD,
-                        Anonymous: INPUT_0 {
-                            ki: KEYBDINPUT {
-                                wVk: VIRTUAL_KEY(*key as u16),
-                                wScan: scan_code as u16,
-                                dwFlags: KEYBD_EVENT_FLAGS(0), // KEYDOWN
-                                dwExtraInfo: 1,
-                                time: 0,
-                            },
-                        },
-                    };
-
-                    inputs.push(input);
-                }
-                
-                SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
-            }
-        });
-    }
-    pub fn release(&self, keys: Vec<u32>) {
-        thread::spawn(move || {
-            unsafe {
-                let mut inputs = Vec::with_capacity(keys.len() * 2);
-                
-                let mut simulation_flag = SIMULATION_FLAG.lock().unwrap();
-                *simulation_flag = true;
-                
-                for key in &keys {
-                    let scan_code = MapVirtualKeyW(*key, MAP_VIRTUAL_KEY_TYPE(0));
-                    
-                    if let Ok(mut current_keys) = CURRENT_KEYS.lock() {
-                        current_keys.remove(key);
-                    }
-
-                    let input = INPUT {
-                        r#type: INPUT_KEYBOARD,
-                        Anonymous: INPUT_0 {
-                            ki: KEYBDINPUT {
-                                wVk: VIRTUAL_KEY(*key as u16),
-                                wScan: scan_code as u16,
-                                dwFlags: KEYEVENTF_KEYUP, // Добавляем флаг KEYUP
-                                dwExtraInfo: 1,
-                                time: 0,
-                            },
-                        },
-                    };
-
-                    inputs.push(input);
-                }
-                
-                SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
-            }
-        });
-    }
 }
 
 impl Drop for DeviceState {
@@ -0,0 +1,140 @@
+//! Raw Input devices.
+
+use crate::device_state::{Bus, Capabilities, InputDevice};
+use std::thread;
+use std::time::Duration;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::UI::Input::{
+    GetRawInputDeviceInfoW, GetRawInputDeviceList, RAWINPUTDEVICELIST, RID_DEVICE_INFO,
+    RIDI_DEVICEINFO, RIDI_DEVICENAME, RIM_TYPEHID, RIM_TYPEKEYBOARD, RIM_TYPEMOUSE,
+};
+
+fn device_name(device: HANDLE) -> Option<String> {
+    let mut size = 0u32;
+    unsafe {
+        GetRawInputDeviceInfoW(Some(device), RIDI_DEVICENAME, None, &mut size);
+        let mut name = vec![0u16; size as usize];
+        let copied = GetRawInputDeviceInfoW(
+            Some(device),
+            RIDI_DEVICENAME,
+            Some(name.as_mut_ptr() as *mut _),
+            &mut size,
+        );
+        if copied == u32::MAX {
+            return None;
+        }
+        name.truncate(copied as usize);
+        Some(String::from_utf16_lossy(&name).trim_end_matches('\0').to_string())
+    }
+}
+
+fn device_info(device: HANDLE) -> Option<RID_DEVICE_INFO> {
+    let mut info = RID_DEVICE_INFO {
+        cbSize: std::mem::size_of::<RID_DEVICE_INFO>() as u32,
+        ..Default::default()
+    };
+    let mut size = info.cbSize;
+    let copied = unsafe {
+        GetRawInputDeviceInfoW(
+            Some(device),
+            RIDI_DEVICEINFO,
+            Some(&mut info as *mut RID_DEVICE_INFO as *mut _),
+            &mut size,
+        )
+    };
+    if copied == u32::MAX {
+        None
+    } else {
+        Some(info)
+    }
+}
+
+/// Reads a hexadecimal id such as `VID_046D` from a device path.
+fn path_id(path: &str, prefix: &str) -> u16 {
+    path.to_uppercase()
+        .find(prefix)
+        .and_then(|start| path.get(start + prefix.len()..start + prefix.len() + 4))
+        .and_then(|id| u16::from_str_radix(id, 16).ok())
+        .unwrap_or(0)
+}
+
+fn bus(path: &str) -> Bus {
+    let path = path.to_uppercase();
+    if path.contains("{00001124-0000-1000-8000-00805F9B34FB}") || path.contains("BTH") {
+        Bus::Bluetooth
+    } else if path.contains("VID_") {
+        Bus::Usb
+    } else if path.contains("ACPI") {
+        Bus::Internal
+    } else if path.contains("ROOT#") {
+        Bus::Virtual
+    } else {
+        Bus::Unknown
+    }
+}
+
+fn read_device(entry: &RAWINPUTDEVICELIST) -> Option<InputDevice> {
+    let path = device_name(entry.hDevice)?;
+    let info = device_info(entry.hDevice)?;
+
+    let (vendor, product) = if info.dwType == RIM_TYPEHID {
+        let hid = unsafe { info.Anonymous.hid };
+        (hid.dwVendorId as u16, hid.dwProductId as u16)
+    } else {
+        (path_id(&path, "VID_"), path_id(&path, "PID_"))
+    };
+    let keyboard = info.dwType == RIM_TYPEKEYBOARD;
+    let capabilities = Capabilities {
+        keyboard,
+        mouse: info.dwType == RIM_TYPEMOUSE,
+        touch: false,
+        leds: keyboard && unsafe { info.Anonymous.keyboard.dwNumberOfIndicators } > 0,
+    };
+    let kind = match info.dwType {
+        RIM_TYPEKEYBOARD => "Keyboard",
+        RIM_TYPEMOUSE => "Mouse",
+        _ => "HID device",
+    };
+
+    Some(InputDevice {
+        name: format!("{} {:04X}:{:04X}", kind, vendor, product),
+        vendor,
+        product,
+        bus: bus(&path),
+        path: None,
+        capabilities,
+        id: path,
+    })
+}
+
+/// Input devices known to Raw Input. Raw Input doesn't provide product names, so devices are
+/// named after their kind and USB ids.
+pub(crate) fn input_devices() -> Vec<InputDevice> {
+    let entry_size = std::mem::size_of::<RAWINPUTDEVICELIST>() as u32;
+    let mut count = 0u32;
+    unsafe {
+        if GetRawInputDeviceList(None, &mut count, entry_size) == u32::MAX {
+            return Vec::new();
+        }
+        let mut entries = vec![RAWINPUTDEVICELIST::default(); count as usize];
+        let listed = GetRawInputDeviceList(Some(entries.as_mut_ptr()), &mut count, entry_size);
+        if listed == u32::MAX {
+            return Vec::new();
+        }
+        entries.truncate(listed as usize);
+        entries.iter().filter_map(read_device).collect()
+    }
+}
+
+/// Raw Input only notifies device changes to windows, so the device list is polled.
+pub(crate) struct DeviceMonitor;
+
+impl DeviceMonitor {
+    pub fn new() -> Self {
+        DeviceMonitor
+    }
+
+    pub fn wait(&self, timeout: Duration) {
+        thread::sleep(timeout);
+    }
+}
@@ -0,0 +1,215 @@
+//! Bounce keys: ignoring keys pressed again right after being released.
+
+use device_events::{HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use keymap::DeviceKey;
+use KeyEvent;
+
+/// Priority of the bounce keys filter among the pre-filters, after the debounce filter.
+const PRIORITY: i32 = 950;
+
+/// Configuration of a `BounceKeys` filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BounceKeysConfig {
+    /// How long after its release a key is ignored.
+    pub delay: Duration,
+}
+
+impl BounceKeysConfig {
+    pub fn new(delay: Duration) -> Self {
+        BounceKeysConfig { delay }
+    }
+}
+
+#[derive(Default)]
+struct KeyState {
+    /// When the key was last released, if that release was let through.
+    released_at: Option<Instant>,
+    /// Whether the current keystroke is ignored.
+    ignoring: bool,
+}
+
+struct State {
+    config: BounceKeysConfig,
+    enabled: bool,
+    keys: HashMap<DeviceKey, KeyState>,
+}
+
+/// Ignores whole keystrokes of a key pressed again within a delay after its release, for
+/// users whose fingers tend to hit a key twice. Keys are tracked separately on each device.
+/// Simulated events are left alone.
+///
+/// Unlike `Debounce`, which cleans up faulty switches within a few milliseconds, the delay is
+/// meant to be long, e.g. half a second. The filter can be combined with `SlowKeys` and
+/// `StickyKeys`.
+///
+/// ```no_run
+/// use key_director::{BounceKeys, BounceKeysConfig, DeviceState};
+/// use std::time::Duration;
+///
+/// let _device_state = DeviceState::new();
+/// let bounce_keys = BounceKeys::install(BounceKeysConfig::new(Duration::from_millis(500)));
+/// bounce_keys.set_enabled(false);
+/// ```
+///
+/// The filter is removed when dropped.
+pub struct BounceKeys {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+}
+
+impl BounceKeys {
+    /// Install the filter, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: BounceKeysConfig) -> BounceKeys {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: true,
+            keys: HashMap::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "bounce keys",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match handler_state.lock() {
+                    Ok(mut state) => filter(&mut state, event, Instant::now()),
+                    Err(_) => Verdict::Pass,
+                }),
+            );
+        BounceKeys { id, state }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the filter without removing it. Keys being ignored are still ignored
+    /// until released.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.lock().enabled = enabled;
+    }
+
+    pub fn config(&self) -> BounceKeysConfig {
+        self.lock().config.clone()
+    }
+
+    pub fn set_config(&self, config: BounceKeysConfig) {
+        self.lock().config = config;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the bounce keys state")
+    }
+}
+
+impl Drop for BounceKeys {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(state: &mut State, event: &KeyEvent, now: Instant) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    let enabled = state.enabled;
+    let delay = state.config.delay;
+    let key_state = state.keys.entry(event.device_key()).or_default();
+
+    if !event.is_pressed {
+        if key_state.ignoring {
+            key_state.ignoring = false;
+            return Verdict::Block;
+        }
+        key_state.released_at = Some(now);
+        return Verdict::Pass;
+    }
+    if key_state.ignoring {
+        return Verdict::Block;
+    }
+    match key_state.released_at {
+        Some(released_at) if enabled && now.duration_since(released_at) < delay => {
+            key_state.ignoring = true;
+            Verdict::Block
+        }
+        _ => Verdict::Pass,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures;
+    use Key;
+
+    const DELAY: Duration = Duration::from_millis(100);
+
+    fn key(is_pressed: bool) -> KeyEvent {
+        fixtures::key(Key::A, is_pressed)
+    }
+
+    fn state() -> State {
+        State {
+            config: BounceKeysConfig::new(DELAY),
+            enabled: true,
+            keys: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn keystrokes_within_the_delay_are_ignored_whole() {
+        let mut state = state();
+        let start = Instant::now();
+        assert_eq!(filter(&mut state, &key(true), start), Verdict::Pass);
+        assert_eq!(filter(&mut state, &key(false), start), Verdict::Pass);
+
+        let bounce = start + DELAY - Duration::from_millis(1);
+        assert_eq!(filter(&mut state, &key(true), bounce), Verdict::Block);
+        // Auto-repeats and the release of the ignored keystroke are ignored too, whenever
+        // they come.
+        assert_eq!(filter(&mut state, &key(true), start + DELAY * 2), Verdict::Block);
+        assert_eq!(filter(&mut state, &key(false), start + DELAY * 2), Verdict::Block);
+    }
+
+    #[test]
+    fn the_delay_counts_from_the_last_release_let_through() {
+        let mut state = state();
+        let start = Instant::now();
+        assert_eq!(filter(&mut state, &key(false), start), Verdict::Pass);
+        let bounce = start + DELAY / 2;
+        assert_eq!(filter(&mut state, &key(true), bounce), Verdict::Block);
+        assert_eq!(filter(&mut state, &key(false), bounce), Verdict::Block);
+
+        assert_eq!(filter(&mut state, &key(true), start + DELAY), Verdict::Pass);
+    }
+
+    #[test]
+    fn keys_pressed_at_the_end_of_the_delay_pass() {
+        let mut state = state();
+        let start = Instant::now();
+        assert_eq!(filter(&mut state, &key(false), start), Verdict::Pass);
+        assert_eq!(filter(&mut state, &key(true), start + DELAY), Verdict::Pass);
+    }
+
+    #[test]
+    fn disabled_filters_and_simulated_events_pass() {
+        let mut state = state();
+        let start = Instant::now();
+        assert_eq!(filter(&mut state, &key(false), start), Verdict::Pass);
+        let simulated = KeyEvent::new(None, Key::A.code().unwrap(), 0, true, true);
+        assert_eq!(filter(&mut state, &simulated, start), Verdict::Pass);
+
+        state.enabled = false;
+        assert_eq!(filter(&mut state, &key(true), start), Verdict::Pass);
+    }
+}
@@ -0,0 +1,345 @@
+//! Caps Word: letters typed in capitals until the end of the word.
+
+use device_events::{held_modifiers, resume_replacement, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use {Key, KeyEvent, Modifiers};
+
+/// Priority of Caps Word among the pre-filters, after the one-shot modifiers.
+const PRIORITY: i32 = 910;
+
+const LETTERS: [Key; 26] = [
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+];
+
+const TERMINATORS: [Key; 25] = [
+    Key::Space, Key::Enter, Key::NumpadEnter, Key::Tab, Key::Escape,
+    Key::Grave, Key::Equal, Key::LeftBracket, Key::RightBracket, Key::BackSlash, Key::Semicolon,
+    Key::Apostrophe, Key::Comma, Key::Dot, Key::Slash,
+    Key::Up, Key::Down, Key::Left, Key::Right, Key::Home, Key::End, Key::PageUp, Key::PageDown,
+    Key::NumpadDecimal, Key::CapsLock,
+];
+
+/// Configuration of a `CapsWord` filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapsWordConfig {
+    /// Key turning Caps Word on, or off before the end of the word. Its own events are
+    /// swallowed.
+    pub toggle_key: Option<Key>,
+    /// Keys typed with Shift while Caps Word is on.
+    pub shifted: BTreeSet<Key>,
+    /// Keys ending the word. They are typed as usual, like the keys in neither set.
+    pub terminators: BTreeSet<Key>,
+    /// How long Caps Word stays on without a key pressed, if limited.
+    pub timeout: Option<Duration>,
+}
+
+impl CapsWordConfig {
+    /// Shift the letters and `-`, for identifiers like `MAX_LENGTH`, until Space, Enter, Tab,
+    /// Escape, punctuation or a navigation key, with a 5 s timeout. Digits and Backspace don't
+    /// end the word.
+    pub fn new() -> Self {
+        let mut shifted: BTreeSet<Key> = LETTERS.iter().cloned().collect();
+        shifted.insert(Key::Minus);
+        CapsWordConfig {
+            toggle_key: None,
+            shifted,
+            terminators: TERMINATORS.iter().cloned().collect(),
+            timeout: Some(Duration::from_secs(5)),
+        }
+    }
+
+    pub fn toggle_key(mut self, key: Key) -> Self {
+        self.toggle_key = Some(key);
+        self
+    }
+
+    pub fn shifted(mut self, keys: &[Key]) -> Self {
+        self.shifted = keys.iter().cloned().collect();
+        self
+    }
+
+    pub fn terminators(mut self, keys: &[Key]) -> Self {
+        self.terminators = keys.iter().cloned().collect();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Default for CapsWordConfig {
+    fn default() -> Self {
+        CapsWordConfig::new()
+    }
+}
+
+struct State {
+    config: CapsWordConfig,
+    /// Last key press while Caps Word is on, if it is.
+    active_since: Option<Instant>,
+    /// Codes of the toggle key pressed, swallowed until their release.
+    swallowed: HashSet<u32>,
+}
+
+impl State {
+    fn is_active(&self, now: Instant) -> bool {
+        match (self.active_since, self.config.timeout) {
+            (Some(since), Some(timeout)) => now.saturating_duration_since(since) <= timeout,
+            (active_since, _) => active_since.is_some(),
+        }
+    }
+}
+
+/// Types the letters of a word in capitals, like Caps Word in QMK: turned on by the toggle key
+/// or `CapsWord::activate`, it shifts each letter until a terminator is pressed, and turns off
+/// by itself. Unlike Caps Lock, it never outlives the word.
+///
+/// Each shifted key is wrapped in injected Shift presses, auto-repeats included, and left alone
+/// while Shift is held. Shortcuts with Control, Alt or Meta end the word, while the modifiers
+/// themselves don't. Simulated events are left alone.
+///
+/// ```no_run
+/// use key_director::{CapsWord, CapsWordConfig, DeviceState, Key};
+///
+/// let _device_state = DeviceState::new();
+/// let caps_word = CapsWord::install(CapsWordConfig::new().toggle_key(Key::CapsLock));
+/// caps_word.activate();
+/// ```
+///
+/// The filter is removed when dropped.
+pub struct CapsWord {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+}
+
+impl CapsWord {
+    /// Install the filter, off, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: CapsWordConfig) -> CapsWord {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            active_since: None,
+            swallowed: HashSet::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "caps word",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| {
+                    let verdict = match handler_state.lock() {
+                        Ok(mut state) => filter(&mut state, event, Instant::now(), held_modifiers),
+                        Err(_) => Verdict::Pass,
+                    };
+                    resume_replacement(event, verdict)
+                }),
+            );
+        CapsWord { id, state }
+    }
+
+    /// Whether the word being typed is in capitals.
+    pub fn is_active(&self) -> bool {
+        self.lock().is_active(Instant::now())
+    }
+
+    pub fn activate(&self) {
+        self.lock().active_since = Some(Instant::now());
+    }
+
+    pub fn deactivate(&self) {
+        self.lock().active_since = None;
+    }
+
+    pub fn config(&self) -> CapsWordConfig {
+        self.lock().config.clone()
+    }
+
+    pub fn set_config(&self, config: CapsWordConfig) {
+        self.lock().config = config;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the Caps Word state")
+    }
+}
+
+impl Drop for CapsWord {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+/// Filter `event`, pressed or released at `now` while the modifiers given by `held_modifiers`
+/// are held.
+fn filter<F: FnOnce() -> Modifiers>(state: &mut State, event: &KeyEvent, now: Instant, held_modifiers: F) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    if !event.is_pressed {
+        return if state.swallowed.remove(&event.key_code) {
+            Verdict::Block
+        } else {
+            Verdict::Pass
+        };
+    }
+    // Auto-repeats of the toggle key.
+    if state.swallowed.contains(&event.key_code) {
+        return Verdict::Block;
+    }
+
+    let key = Key::from_code(event.key_code);
+    if key.is_some() && key == state.config.toggle_key {
+        state.swallowed.insert(event.key_code);
+        state.active_since = if state.is_active(now) { None } else { Some(now) };
+        return Verdict::Block;
+    }
+    if !state.is_active(now) {
+        state.active_since = None;
+        return Verdict::Pass;
+    }
+    let key = match key {
+        Some(key) if key.modifier().is_some() => return Verdict::Pass,
+        Some(key) => key,
+        None => return Verdict::Pass,
+    };
+
+    let modifiers = held_modifiers();
+    let shortcut = modifiers.contains(Modifiers::CTRL) || modifiers.contains(Modifiers::ALT) || modifiers.contains(Modifiers::META);
+    if shortcut || state.config.terminators.contains(&key) {
+        state.active_since = None;
+        return Verdict::Pass;
+    }
+    state.active_since = Some(now);
+    if !state.config.shifted.contains(&key) || modifiers.contains(Modifiers::SHIFT) {
+        return Verdict::Pass;
+    }
+    let shift = match Key::LShift.code() {
+        Some(shift) => shift,
+        None => return Verdict::Pass,
+    };
+    Verdict::Replace(vec![
+        KeyEvent::new(None, shift, 0, true, true),
+        event.clone(),
+        KeyEvent::new(None, shift, 0, false, true),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::key;
+
+    fn shifted(key_: Key) -> Verdict {
+        let shift = Key::LShift.code().unwrap();
+        Verdict::Replace(vec![
+            KeyEvent::new(None, shift, 0, true, true),
+            key(key_, true),
+            KeyEvent::new(None, shift, 0, false, true),
+        ])
+    }
+
+    fn active_state(now: Instant) -> State {
+        State {
+            config: CapsWordConfig::new().toggle_key(Key::CapsLock),
+            active_since: Some(now),
+            swallowed: HashSet::new(),
+        }
+    }
+
+    fn press(state: &mut State, key_: Key, now: Instant, modifiers: Modifiers) -> Verdict {
+        filter(state, &key(key_, true), now, || modifiers)
+    }
+
+    #[test]
+    fn letters_are_shifted_until_a_terminator() {
+        let now = Instant::now();
+        let mut state = active_state(now);
+        assert_eq!(press(&mut state, Key::A, now, Modifiers::NONE), shifted(Key::A));
+        // Auto-repeats are shifted too.
+        assert_eq!(press(&mut state, Key::A, now, Modifiers::NONE), shifted(Key::A));
+        assert_eq!(filter(&mut state, &key(Key::A, false), now, || Modifiers::NONE), Verdict::Pass);
+        assert_eq!(press(&mut state, Key::Minus, now, Modifiers::NONE), shifted(Key::Minus));
+        // Digits and Backspace neither are shifted nor end the word.
+        assert_eq!(press(&mut state, Key::Key1, now, Modifiers::NONE), Verdict::Pass);
+        assert_eq!(press(&mut state, Key::Backspace, now, Modifiers::NONE), Verdict::Pass);
+        assert!(state.is_active(now));
+
+        assert_eq!(press(&mut state, Key::Space, now, Modifiers::NONE), Verdict::Pass);
+        assert!(!state.is_active(now));
+        assert_eq!(press(&mut state, Key::B, now, Modifiers::NONE), Verdict::Pass);
+    }
+
+    #[test]
+    fn letters_typed_with_shift_are_left_alone() {
+        let now = Instant::now();
+        let mut state = active_state(now);
+        assert_eq!(press(&mut state, Key::LShift, now, Modifiers::NONE), Verdict::Pass);
+        assert_eq!(press(&mut state, Key::A, now, Modifiers::SHIFT), Verdict::Pass);
+        assert!(state.is_active(now));
+        assert_eq!(press(&mut state, Key::B, now, Modifiers::NONE), shifted(Key::B));
+    }
+
+    #[test]
+    fn shortcuts_end_the_word_but_modifiers_dont() {
+        let now = Instant::now();
+        let mut state = active_state(now);
+        assert_eq!(press(&mut state, Key::LControl, now, Modifiers::NONE), Verdict::Pass);
+        assert!(state.is_active(now));
+        assert_eq!(press(&mut state, Key::C, now, Modifiers::CTRL), Verdict::Pass);
+        assert!(!state.is_active(now));
+    }
+
+    #[test]
+    fn the_word_times_out_without_key_presses() {
+        let start = Instant::now();
+        let mut state = active_state(start);
+        let timeout = state.config.timeout.unwrap();
+        // Each key press restarts the timeout.
+        assert_eq!(press(&mut state, Key::A, start + timeout, Modifiers::NONE), shifted(Key::A));
+        assert!(state.is_active(start + timeout * 2));
+        assert!(!state.is_active(start + timeout * 2 + Duration::from_millis(1)));
+        assert_eq!(press(&mut state, Key::B, start + timeout * 3, Modifiers::NONE), Verdict::Pass);
+        assert_eq!(state.active_since, None);
+
+        state.config = state.config.clone().timeout(None);
+        state.active_since = Some(start);
+        assert!(state.is_active(start + timeout * 100));
+    }
+
+    #[test]
+    fn the_toggle_key_is_swallowed_and_toggles() {
+        let now = Instant::now();
+        let mut state = active_state(now);
+        state.active_since = None;
+        assert_eq!(press(&mut state, Key::CapsLock, now, Modifiers::NONE), Verdict::Block);
+        assert!(state.is_active(now));
+        assert_eq!(press(&mut state, Key::CapsLock, now, Modifiers::NONE), Verdict::Block);
+        assert_eq!(filter(&mut state, &key(Key::CapsLock, false), now, || Modifiers::NONE), Verdict::Block);
+        assert_eq!(press(&mut state, Key::A, now, Modifiers::NONE), shifted(Key::A));
+
+        assert_eq!(press(&mut state, Key::CapsLock, now, Modifiers::NONE), Verdict::Block);
+        assert!(!state.is_active(now));
+        assert_eq!(filter(&mut state, &key(Key::CapsLock, false), now, || Modifiers::NONE), Verdict::Block);
+        assert_eq!(filter(&mut state, &key(Key::CapsLock, false), now, || Modifiers::NONE), Verdict::Pass);
+    }
+
+    #[test]
+    fn simulated_events_pass() {
+        let now = Instant::now();
+        let mut state = active_state(now);
+        let press = KeyEvent::new(None, Key::A.code().unwrap(), 0, true, true);
+        assert_eq!(filter(&mut state, &press, now, || Modifiers::NONE), Verdict::Pass);
+    }
+}
@@ -0,0 +1,554 @@
+//! Combos: keys pressed together producing another key.
+
+use super::timer::Timer;
+use device_events::{resume, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use {Key, KeyEvent};
+
+/// Priority of the combos among the pre-filters, between the turbo filter and the mouse keys.
+const PRIORITY: i32 = 750;
+
+/// Keys producing `output` when pressed together.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Combo {
+    pub keys: BTreeSet<Key>,
+    /// Keys emitted in place of the combo: pressed in order, released in reverse order.
+    pub output: Vec<Key>,
+}
+
+impl Combo {
+    pub fn new(keys: &[Key], output: &[Key]) -> Self {
+        let keys: BTreeSet<Key> = keys.iter().cloned().collect();
+        assert!(keys.len() >= 2, "A combo needs at least two different keys");
+        Combo {
+            keys,
+            output: output.to_vec(),
+        }
+    }
+}
+
+/// Configuration of a `Combos` filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CombosConfig {
+    pub combos: Vec<Combo>,
+    /// How long after the first key of a combo the other keys may be pressed.
+    pub term: Duration,
+}
+
+impl CombosConfig {
+    pub fn new(term: Duration) -> Self {
+        CombosConfig {
+            combos: Vec::new(),
+            term,
+        }
+    }
+
+    /// Emit `output` when `keys` are pressed together.
+    pub fn combo(mut self, keys: &[Key], output: &[Key]) -> Self {
+        self.combos.push(Combo::new(keys, output));
+        self
+    }
+}
+
+/// A combo that fired, until all its keys are released.
+struct Active {
+    /// Codes of the combo keys still held.
+    held: HashSet<u32>,
+    output: Vec<u32>,
+    /// Whether the output was released, on the release of the first combo key.
+    released: bool,
+}
+
+struct State {
+    config: CombosConfig,
+    enabled: bool,
+    id: Option<HandlerId>,
+    /// Physical events held back until it is known whether they make a combo, with the instant
+    /// they came.
+    pending: VecDeque<(Instant, KeyEvent)>,
+    /// Events decided, waiting to be resumed in order, with the instant they came.
+    ready: VecDeque<(Instant, KeyEvent)>,
+    /// Whether an event taken from `ready` is being resumed.
+    resuming: bool,
+    /// While `ready` is being resumed, the instant the first event resumed came and the instant
+    /// it was resumed.
+    replay: Option<(Instant, Instant)>,
+    active: Vec<Active>,
+}
+
+impl State {
+    fn is_combo_key(&self, key: Key) -> bool {
+        self.enabled && self.config.combos.iter().any(|combo| combo.keys.contains(&key))
+    }
+
+    /// Whether some combo has all of `keys`, and more if `longer`.
+    fn is_within_combo(&self, keys: &BTreeSet<Key>, longer: bool) -> bool {
+        self.config
+            .combos
+            .iter()
+            .any(|combo| keys.is_subset(&combo.keys) && (!longer || combo.keys.len() > keys.len()))
+    }
+
+    /// The combo starting with `first` pressed among `keys`: the longest, or the first
+    /// configured of the longest.
+    fn best_combo(&self, first: Key, keys: &BTreeSet<Key>) -> Option<Combo> {
+        self.config
+            .combos
+            .iter()
+            .filter(|combo| combo.keys.contains(&first) && combo.keys.is_subset(keys))
+            .fold(None, |best: Option<&Combo>, combo| match best {
+                Some(best) if best.keys.len() >= combo.keys.len() => Some(best),
+                _ => Some(combo),
+            })
+            .cloned()
+    }
+
+    /// Handle the release of a combo key that came at `at`, returning whether it was one.
+    fn release_combo_key(&mut self, at: Instant, code: u32) -> bool {
+        let index = match self.active.iter().position(|active| active.held.contains(&code)) {
+            Some(index) => index,
+            None => return false,
+        };
+        let active = &mut self.active[index];
+        active.held.remove(&code);
+        if !active.released {
+            active.released = true;
+            let releases = active.output.iter().rev().map(|code| (at, KeyEvent::new(None, *code, 0, false, true)));
+            self.ready.extend(releases);
+        }
+        if active.held.is_empty() {
+            self.active.remove(index);
+        }
+        true
+    }
+
+    /// Fire `combo`, completed at `at` by the keys of `codes`.
+    fn fire(&mut self, combo: &Combo, at: Instant, codes: HashSet<u32>) {
+        let output: Vec<u32> = combo.output.iter().filter_map(|key| key.code()).collect();
+        self.ready
+            .extend(output.iter().map(|code| (at, KeyEvent::new(None, *code, 0, true, true))));
+        self.active.push(Active {
+            held: codes,
+            output,
+            released: false,
+        });
+    }
+
+    /// Move the pending events that are decided to `ready`, firing the combos pressed.
+    /// Returns when the filter must look again if it waits for a longer combo.
+    fn resolve(&mut self, now: Instant) -> Option<Instant> {
+        while let Some((at, head)) = self.pending.front().cloned() {
+            let key = Key::from_code(head.key_code);
+            if !head.is_pressed {
+                self.pending.pop_front();
+                if !self.release_combo_key(at, head.key_code) {
+                    self.ready.push_back((at, head));
+                }
+                continue;
+            }
+            if self.active.iter().any(|active| active.held.contains(&head.key_code)) {
+                // Auto-repeat of a combo key.
+                self.pending.pop_front();
+                continue;
+            }
+            let key = match key {
+                Some(key) if self.is_combo_key(key) => key,
+                _ => {
+                    self.pending.pop_front();
+                    self.ready.push_back((at, head));
+                    continue;
+                }
+            };
+
+            // Keys pressed since the head that can still make a combo with it, in order.
+            let mut pressed = vec![(0, head.key_code)];
+            let mut keys: BTreeSet<Key> = Some(key).into_iter().collect();
+            let mut interrupted = false;
+            for (index, (_, event)) in self.pending.iter().enumerate().skip(1) {
+                let key = Key::from_code(event.key_code).filter(|key| event.is_pressed && !keys.contains(key));
+                let mut grown = keys.clone();
+                grown.extend(key);
+                match key {
+                    Some(_) if self.is_within_combo(&grown, false) => {
+                        pressed.push((index, event.key_code));
+                        keys = grown;
+                    }
+                    _ => {
+                        interrupted = true;
+                        break;
+                    }
+                }
+            }
+
+            let deadline = at + self.config.term;
+            if !interrupted && now < deadline && self.is_within_combo(&keys, true) {
+                return Some(deadline);
+            }
+            match self.best_combo(key, &keys) {
+                Some(combo) => {
+                    let consumed: Vec<(usize, u32)> = pressed
+                        .into_iter()
+                        .filter(|&(_, code)| Key::from_code(code).is_some_and(|key| combo.keys.contains(&key)))
+                        .collect();
+                    let mut completed_at = at;
+                    for &(index, _) in consumed.iter().rev() {
+                        if let Some((pressed_at, _)) = self.pending.remove(index) {
+                            completed_at = completed_at.max(pressed_at);
+                        }
+                    }
+                    self.fire(&combo, completed_at, consumed.into_iter().map(|(_, code)| code).collect());
+                }
+                None => {
+                    self.pending.pop_front();
+                    self.ready.push_back((at, head));
+                }
+            }
+        }
+        None
+    }
+
+    /// Give up on the combos being pressed, letting their keys through.
+    fn flush(&mut self) {
+        let pending: Vec<(Instant, KeyEvent)> = self.pending.drain(..).collect();
+        self.ready.extend(pending);
+    }
+
+    /// When to resume the event of `ready` that came at `at`: as long after the first event
+    /// of the replay was resumed as it came after it.
+    fn due(&mut self, at: Instant, now: Instant) -> Instant {
+        let (first, resumed) = *self.replay.get_or_insert((at, now));
+        resumed + at.saturating_duration_since(first)
+    }
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Turns keys pressed together into other keys, like the combos of QMK: e.g. J and K pressed
+/// within 30 ms emit Escape. Presses of combo keys are held back for at most the term, until
+/// it is known whether they make a combo; the events that don't are let through in their
+/// original order and with the same gaps between them, so normal typing is only delayed, never
+/// reordered nor bunched up. Simulated events are left alone.
+///
+/// Overlapping combos follow these rules:
+///
+/// - A combo fires once all its keys are pressed within the term of the first, without any
+///   other key pressed nor any key released in between.
+/// - The longest combo wins: while the keys pressed so far can still complete a longer combo,
+///   the filter waits for it until the term is over, then fires the longest combo among the
+///   keys pressed that has the first of them. Among combos of the same length, the first
+///   configured wins. When there is none, the first key is let through and the others are
+///   considered again.
+/// - The keys pressed in the window that aren't part of the combo fired are let through after
+///   its output, in order, and may start another combo.
+/// - The output is held until the first of the combo keys is released. The releases of the
+///   combo keys are swallowed.
+///
+/// ```no_run
+/// use key_director::{Combos, CombosConfig, DeviceState, Key};
+/// use std::time::Duration;
+///
+/// let _device_state = DeviceState::new();
+/// let config = CombosConfig::new(Duration::from_millis(30))
+///     .combo(&[Key::J, Key::K], &[Key::Escape])
+///     .combo(&[Key::J, Key::K, Key::L], &[Key::LControl, Key::Z]);
+/// let _combos = Combos::install(config);
+/// ```
+///
+/// The keys it holds back are let through when it is dropped.
+pub struct Combos {
+    id: HandlerId,
+    state: Shared,
+    timer: Arc<Timer>,
+}
+
+impl Combos {
+    /// Install the filter, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: CombosConfig) -> Combos {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: true,
+            id: None,
+            pending: VecDeque::new(),
+            ready: VecDeque::new(),
+            resuming: false,
+            replay: None,
+            active: Vec::new(),
+        }));
+        let timer = Arc::new(Timer::new());
+
+        let handler_state = Arc::downgrade(&state);
+        let handler_timer = Arc::downgrade(&timer);
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "combos",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match (handler_state.upgrade(), handler_timer.upgrade()) {
+                    (Some(state), Some(timer)) => filter(&state, &timer, event),
+                    _ => Verdict::Pass,
+                }),
+            );
+        state.lock().expect("Couldn't lock the combos state").id = Some(id);
+
+        Combos {
+            id,
+            state,
+            timer,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the filter without removing it. The keys held back when it gets
+    /// disabled are let through, and the combos held down still release their output.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.lock();
+        state.enabled = enabled;
+        if !enabled && !state.pending.is_empty() {
+            state.flush();
+            let shared = Arc::downgrade(&self.state);
+            let timer = Arc::downgrade(&self.timer);
+            self.timer.schedule(Instant::now(), move || drain(&shared, &timer));
+        }
+    }
+
+    pub fn config(&self) -> CombosConfig {
+        self.lock().config.clone()
+    }
+
+    /// Change the configuration, for the keys pressed from now on.
+    pub fn set_config(&self, config: CombosConfig) {
+        self.lock().config = config;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the combos state")
+    }
+}
+
+impl Drop for Combos {
+    /// Resumes the events held back before removing the filter: `resume` finds the handlers
+    /// after it from its place in the chain.
+    fn drop(&mut self) {
+        let events: Vec<KeyEvent> = match self.state.lock() {
+            Ok(mut state) => {
+                // Keys pressed meanwhile go through untouched.
+                state.enabled = false;
+                state.flush();
+                for active in state.active.drain(..).collect::<Vec<_>>() {
+                    if !active.released {
+                        let releases = active.output.iter().rev().map(|code| KeyEvent::new(None, *code, 0, false, true));
+                        state.ready.extend(releases.map(|release| (Instant::now(), release)));
+                    }
+                }
+                state.ready.drain(..).map(|(_, event)| event).collect()
+            }
+            Err(_) => Vec::new(),
+        };
+        for event in events.iter() {
+            resume(event, self.id);
+        }
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(shared: &Shared, timer: &Arc<Timer>, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return Verdict::Pass,
+    };
+    let idle = state.pending.is_empty() && state.ready.is_empty() && !state.resuming;
+    let now = Instant::now();
+    state.pending.push_back((now, event.clone()));
+    let deadline = state.resolve(now);
+    if idle && state.pending.is_empty() && state.ready.len() == 1 && state.ready[0].1 == *event {
+        // Nothing held back: no need to delay the event.
+        state.ready.clear();
+        return Verdict::Pass;
+    }
+
+    if let Some(deadline) = deadline {
+        let weak_state = Arc::downgrade(shared);
+        let weak_timer = Arc::downgrade(timer);
+        timer.schedule(deadline, move || expire(&weak_state, &weak_timer));
+    }
+    if !state.ready.is_empty() && state.replay.is_none() {
+        // Otherwise, the replay going on resumes them.
+        let weak_state = Arc::downgrade(shared);
+        let weak_timer = Arc::downgrade(timer);
+        timer.schedule(now, move || drain(&weak_state, &weak_timer));
+    }
+    Verdict::Block
+}
+
+/// Decide on the combo whose term is over.
+fn expire(shared: &Weak<Mutex<State>>, timer: &Weak<Timer>) {
+    let replaying = match shared.upgrade() {
+        Some(state) => match state.lock() {
+            Ok(mut state) => {
+                state.resolve(Instant::now());
+                state.replay.is_some()
+            }
+            Err(_) => return,
+        },
+        None => return,
+    };
+    if !replaying {
+        drain(shared, timer);
+    }
+}
+
+/// Resume the events decided, in order and with their original gaps, through the handlers
+/// after the filter. Stops until the next is due, scheduling the rest on `timer`.
+fn drain(shared: &Weak<Mutex<State>>, timer: &Weak<Timer>) {
+    let state = match shared.upgrade() {
+        Some(state) => state,
+        None => return,
+    };
+    loop {
+        let (event, id) = {
+            let mut state = match state.lock() {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+            let now = Instant::now();
+            let due = match state.ready.front() {
+                Some(&(at, _)) => state.due(at, now),
+                None => {
+                    state.resuming = false;
+                    state.replay = None;
+                    return;
+                }
+            };
+            if due > now {
+                state.resuming = false;
+                if let Some(strong_timer) = timer.upgrade() {
+                    let (shared, timer) = (shared.clone(), timer.clone());
+                    strong_timer.schedule(due, move || drain(&shared, &timer));
+                }
+                return;
+            }
+            let event = state.ready.pop_front().map(|(_, event)| event);
+            state.resuming = event.is_some();
+            match (event, state.id) {
+                (Some(event), Some(id)) => (event, id),
+                _ => return,
+            }
+        };
+        resume(&event, id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::{key, millis};
+
+    fn state() -> State {
+        State {
+            config: CombosConfig::new(millis(30)).combo(&[Key::J, Key::K], &[Key::Escape]),
+            enabled: true,
+            id: None,
+            pending: VecDeque::new(),
+            ready: VecDeque::new(),
+            resuming: false,
+            replay: None,
+            active: Vec::new(),
+        }
+    }
+
+    /// Push the physical `event` that came at `at`, as the filter does.
+    fn push(state: &mut State, at: Instant, event: KeyEvent) -> Option<Instant> {
+        state.pending.push_back((at, event));
+        state.resolve(at)
+    }
+
+    /// The events of `ready`, with when they are resumed by a replay starting at `now`.
+    fn replay(state: &mut State, now: Instant) -> Vec<(Duration, KeyEvent)> {
+        let ready: Vec<(Instant, KeyEvent)> = state.ready.drain(..).collect();
+        ready
+            .into_iter()
+            .map(|(at, event)| (state.due(at, now) - now, event))
+            .collect()
+    }
+
+    #[test]
+    fn keys_held_back_keep_their_gaps() {
+        let mut state = state();
+        let start = Instant::now();
+        assert_eq!(push(&mut state, start, key(Key::J, true)), Some(start + millis(30)));
+        assert_eq!(push(&mut state, start + millis(12), key(Key::A, true)), None);
+        push(&mut state, start + millis(20), key(Key::A, false));
+
+        let replayed = replay(&mut state, start + millis(12));
+        assert_eq!(
+            replayed,
+            vec![
+                (millis(0), key(Key::J, true)),
+                (millis(12), key(Key::A, true)),
+                (millis(20), key(Key::A, false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_held_back_until_the_term_keep_their_gaps() {
+        let mut state = state();
+        let start = Instant::now();
+        push(&mut state, start, key(Key::J, true));
+        assert!(state.ready.is_empty());
+        state.resolve(start + millis(30));
+
+        let replayed = replay(&mut state, start + millis(30));
+        assert_eq!(replayed, vec![(millis(0), key(Key::J, true))]);
+    }
+
+    #[test]
+    fn keys_after_a_combo_follow_its_output() {
+        let mut state = state();
+        let start = Instant::now();
+        push(&mut state, start, key(Key::J, true));
+        push(&mut state, start + millis(10), key(Key::K, true));
+        push(&mut state, start + millis(25), key(Key::A, true));
+        push(&mut state, start + millis(40), key(Key::J, false));
+        push(&mut state, start + millis(45), key(Key::K, false));
+
+        let escape = Key::Escape.code().unwrap();
+        let replayed = replay(&mut state, start + millis(10));
+        assert_eq!(
+            replayed,
+            vec![
+                (millis(0), KeyEvent::new(None, escape, 0, true, true)),
+                (millis(15), key(Key::A, true)),
+                (millis(30), KeyEvent::new(None, escape, 0, false, true)),
+            ]
+        );
+        assert!(state.active.is_empty());
+    }
+
+    #[test]
+    fn replays_never_reorder_events() {
+        let mut state = state();
+        let start = Instant::now();
+        state.ready.push_back((start + millis(10), key(Key::A, true)));
+        state.ready.push_back((start, key(Key::B, true)));
+        state.ready.push_back((start + millis(15), key(Key::C, true)));
+
+        let replayed: Vec<Duration> = replay(&mut state, start).into_iter().map(|(due, _)| due).collect();
+        // Events due already are resumed right away, after the previous ones.
+        assert_eq!(replayed, vec![millis(0), millis(0), millis(5)]);
+    }
+}
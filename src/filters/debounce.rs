@@ -0,0 +1,382 @@
+//! Debouncing of chattering key switches.
+
+use super::timer::Timer;
+use device_events::{resume, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use keymap::DeviceKey;
+use {Key, KeyEvent};
+
+/// Priority of the debounce filter among the pre-filters. It comes before the other filters of
+/// the crate, so that they only see clean input.
+const PRIORITY: i32 = 1000;
+
+/// How a `Debounce` filter tells chatter from real key transitions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DebounceAlgorithm {
+    /// Let a transition through at once, then ignore the key until the window is over. Adds no
+    /// latency, but lets single noise spikes through.
+    Eager,
+    /// Let a transition through once the key has been stable for the window. Filters noise
+    /// spikes out, at the cost of delaying every transition by the window.
+    Deferred,
+}
+
+/// Configuration of a `Debounce` filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebounceConfig {
+    pub algorithm: DebounceAlgorithm,
+    pub window: Duration,
+    /// Windows replacing `window` for some keys, e.g. for a single worn switch.
+    #[serde(default)]
+    pub key_windows: BTreeMap<Key, Duration>,
+}
+
+impl DebounceConfig {
+    pub fn new(algorithm: DebounceAlgorithm, window: Duration) -> Self {
+        DebounceConfig {
+            algorithm,
+            window,
+            key_windows: BTreeMap::new(),
+        }
+    }
+
+    /// Debounce `key` with `window` instead of the default one.
+    pub fn key_window(mut self, key: Key, window: Duration) -> Self {
+        self.key_windows.insert(key, window);
+        self
+    }
+
+    fn window_for(&self, key: Option<Key>) -> Duration {
+        key.and_then(|key| self.key_windows.get(&key))
+            .cloned()
+            .unwrap_or(self.window)
+    }
+}
+
+/// Counters of a `Debounce` filter.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebounceStats {
+    /// Key events swallowed as chatter.
+    pub suppressed: u64,
+    /// Key events swallowed as chatter, for the keys that have a `Key`.
+    pub by_key: BTreeMap<Key, u64>,
+}
+
+impl DebounceStats {
+    fn suppress(&mut self, key: Option<Key>) {
+        self.suppressed += 1;
+        if let Some(key) = key {
+            *self.by_key.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    /// Stop counting an event that was let through after all.
+    fn unsuppress(&mut self, key: Option<Key>) {
+        self.suppressed = self.suppressed.saturating_sub(1);
+        if let Some(count) = key.and_then(|key| self.by_key.get_mut(&key)) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// Debouncing state of a physical key.
+struct KeyState {
+    /// Whether the handlers after the filter consider the key pressed.
+    reported: bool,
+    /// When the last transition was let through.
+    reported_at: Option<Instant>,
+    /// Last event of the key, which may not have been let through yet.
+    last: KeyEvent,
+    last_at: Instant,
+}
+
+struct State {
+    config: DebounceConfig,
+    id: Option<HandlerId>,
+    keys: HashMap<DeviceKey, KeyState>,
+    stats: DebounceStats,
+}
+
+/// What the filter does with an event.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pass,
+    /// Swallow the event, and look at the key again `at` that instant, with its `window`.
+    Check { at: Instant, window: Duration },
+}
+
+impl State {
+    /// Filter a physical `event` that came at `now`.
+    fn filter(&mut self, event: &KeyEvent, now: Instant) -> Outcome {
+        if event.is_simulated {
+            return Outcome::Pass;
+        }
+        let key = Key::from_code(event.key_code);
+        let window = self.config.window_for(key);
+        let algorithm = self.config.algorithm;
+        let key_state = self.keys.entry(event.device_key()).or_insert_with(|| KeyState {
+            reported: !event.is_pressed,
+            reported_at: None,
+            last: event.clone(),
+            last_at: now,
+        });
+        let was_pressed = key_state.last.is_pressed;
+        key_state.last = event.clone();
+        key_state.last_at = now;
+
+        // Auto-repeats of a key that is stable.
+        if event.is_pressed == key_state.reported && was_pressed == key_state.reported {
+            return Outcome::Pass;
+        }
+
+        let check_at = match algorithm {
+            DebounceAlgorithm::Eager => {
+                let window_end = key_state.reported_at.map(|reported_at| reported_at + window);
+                match window_end {
+                    Some(window_end) if now < window_end => window_end,
+                    _ => {
+                        key_state.reported = event.is_pressed;
+                        key_state.reported_at = Some(now);
+                        return Outcome::Pass;
+                    }
+                }
+            }
+            DebounceAlgorithm::Deferred => now + window,
+        };
+        self.stats.suppress(key);
+        Outcome::Check { at: check_at, window }
+    }
+
+    /// The last event of a key, to let through at `now` if the key has been stable for
+    /// `window` in a state the handlers after the filter don't know of yet.
+    fn let_through(&mut self, device_key: DeviceKey, window: Duration, now: Instant) -> Option<KeyEvent> {
+        let algorithm = self.config.algorithm;
+        let key_state = self.keys.get_mut(&device_key)?;
+        let is_stable = algorithm == DebounceAlgorithm::Eager || key_state.last_at + window <= now;
+        if !is_stable || key_state.last.is_pressed == key_state.reported {
+            return None;
+        }
+
+        key_state.reported = key_state.last.is_pressed;
+        key_state.reported_at = Some(now);
+        let event = key_state.last.clone();
+        self.stats.unsuppress(Key::from_code(event.key_code));
+        Some(event)
+    }
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Swallows the repeated transitions of chattering keys, ahead of the user callbacks. Keys are
+/// debounced separately on each device. Simulated events are left alone.
+///
+/// ```no_run
+/// use key_director::{Debounce, DebounceAlgorithm, DebounceConfig, DeviceState, Key};
+/// use std::time::Duration;
+///
+/// let _device_state = DeviceState::new();
+/// let config = DebounceConfig::new(DebounceAlgorithm::Eager, Duration::from_millis(8))
+///     .key_window(Key::Space, Duration::from_millis(30));
+/// let debounce = Debounce::install(config);
+/// println!("{} events suppressed", debounce.stats().suppressed);
+/// ```
+///
+/// The filter is removed when dropped.
+pub struct Debounce {
+    id: HandlerId,
+    state: Shared,
+    _timer: Arc<Timer>,
+}
+
+impl Debounce {
+    /// Install the filter in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: DebounceConfig) -> Debounce {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            id: None,
+            keys: HashMap::new(),
+            stats: DebounceStats::default(),
+        }));
+        let timer = Arc::new(Timer::new());
+
+        let handler_state = Arc::downgrade(&state);
+        let handler_timer = Arc::downgrade(&timer);
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "debounce",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match (handler_state.upgrade(), handler_timer.upgrade()) {
+                    (Some(state), Some(timer)) => filter(&state, &timer, event),
+                    _ => Verdict::Pass,
+                }),
+            );
+        state.lock().expect("Couldn't lock the debounce state").id = Some(id);
+
+        Debounce {
+            id,
+            state,
+            _timer: timer,
+        }
+    }
+
+    pub fn stats(&self) -> DebounceStats {
+        self.state.lock().expect("Couldn't lock the debounce state").stats.clone()
+    }
+
+    pub fn reset_stats(&self) {
+        self.state.lock().expect("Couldn't lock the debounce state").stats = DebounceStats::default();
+    }
+}
+
+impl Drop for Debounce {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(shared: &Shared, timer: &Timer, event: &KeyEvent) -> Verdict {
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return Verdict::Pass,
+    };
+    if state.id.is_none() {
+        return Verdict::Pass;
+    }
+    match state.filter(event, Instant::now()) {
+        Outcome::Pass => Verdict::Pass,
+        Outcome::Check { at, window } => {
+            let weak_state = Arc::downgrade(shared);
+            let device_key = event.device_key();
+            timer.schedule(at, move || let_through(&weak_state, device_key, window));
+            Verdict::Block
+        }
+    }
+}
+
+/// Let the last event of a key through if it is stable, see `State::let_through`.
+fn let_through(shared: &Weak<Mutex<State>>, device_key: DeviceKey, window: Duration) {
+    let shared = match shared.upgrade() {
+        Some(shared) => shared,
+        None => return,
+    };
+    let (event, id) = {
+        let mut state = match shared.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        match (state.let_through(device_key, window, Instant::now()), state.id) {
+            (Some(event), Some(id)) => (event, id),
+            _ => return,
+        }
+    };
+    resume(&event, id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::{key, millis};
+
+    const WINDOW: Duration = Duration::from_millis(8);
+
+    fn state(algorithm: DebounceAlgorithm) -> State {
+        State {
+            config: DebounceConfig::new(algorithm, WINDOW).key_window(Key::Space, millis(30)),
+            id: None,
+            keys: HashMap::new(),
+            stats: DebounceStats::default(),
+        }
+    }
+
+    #[test]
+    fn eager_lets_transitions_through_at_once_and_swallows_the_chatter() {
+        let start = Instant::now();
+        let mut state = state(DebounceAlgorithm::Eager);
+        let a = key(Key::A, true).device_key();
+        assert_eq!(state.filter(&key(Key::A, true), start), Outcome::Pass);
+        let check = Outcome::Check {
+            at: start + WINDOW,
+            window: WINDOW,
+        };
+        assert_eq!(state.filter(&key(Key::A, false), start + millis(2)), check);
+        assert_eq!(state.filter(&key(Key::A, true), start + millis(3)), check);
+        // The key ends the window as it was reported.
+        assert_eq!(state.let_through(a, WINDOW, start + WINDOW), None);
+        assert_eq!(state.stats.suppressed, 2);
+        assert_eq!(state.stats.by_key.get(&Key::A), Some(&2));
+
+        assert_eq!(state.filter(&key(Key::A, false), start + millis(20)), Outcome::Pass);
+    }
+
+    #[test]
+    fn eager_lets_a_transition_in_the_window_through_once_it_is_over() {
+        let start = Instant::now();
+        let mut state = state(DebounceAlgorithm::Eager);
+        let a = key(Key::A, true).device_key();
+        assert_eq!(state.filter(&key(Key::A, true), start), Outcome::Pass);
+        assert_ne!(state.filter(&key(Key::A, false), start + millis(5)), Outcome::Pass);
+        assert_eq!(state.let_through(a, WINDOW, start + WINDOW), Some(key(Key::A, false)));
+        assert_eq!(state.stats.suppressed, 0);
+        assert_eq!(state.let_through(a, WINDOW, start + WINDOW), None);
+    }
+
+    #[test]
+    fn deferred_lets_transitions_through_once_stable() {
+        let start = Instant::now();
+        let mut state = state(DebounceAlgorithm::Deferred);
+        let a = key(Key::A, true).device_key();
+        assert_eq!(
+            state.filter(&key(Key::A, true), start),
+            Outcome::Check {
+                at: start + WINDOW,
+                window: WINDOW,
+            }
+        );
+        assert_eq!(state.let_through(a, WINDOW, start + WINDOW - millis(1)), None);
+        assert_eq!(state.let_through(a, WINDOW, start + WINDOW), Some(key(Key::A, true)));
+        // Auto-repeats of the stable key go through.
+        assert_eq!(state.filter(&key(Key::A, true), start + millis(500)), Outcome::Pass);
+    }
+
+    #[test]
+    fn deferred_filters_noise_spikes_out() {
+        let start = Instant::now();
+        let mut state = state(DebounceAlgorithm::Deferred);
+        let a = key(Key::A, true).device_key();
+        assert_ne!(state.filter(&key(Key::A, true), start), Outcome::Pass);
+        assert_ne!(state.filter(&key(Key::A, false), start + millis(1)), Outcome::Pass);
+        assert_eq!(state.let_through(a, WINDOW, start + WINDOW), None);
+        assert_eq!(state.let_through(a, WINDOW, start + millis(1) + WINDOW), None);
+        assert_eq!(state.stats.suppressed, 2);
+    }
+
+    #[test]
+    fn keys_can_have_windows_of_their_own() {
+        let start = Instant::now();
+        let mut state = state(DebounceAlgorithm::Deferred);
+        assert_eq!(
+            state.filter(&key(Key::Space, true), start),
+            Outcome::Check {
+                at: start + millis(30),
+                window: millis(30),
+            }
+        );
+    }
+
+    #[test]
+    fn simulated_events_pass() {
+        let mut state = state(DebounceAlgorithm::Deferred);
+        let press = KeyEvent::new(None, Key::A.code().unwrap(), 0, true, true);
+        assert_eq!(state.filter(&press, Instant::now()), Outcome::Pass);
+        assert!(state.keys.is_empty());
+    }
+}
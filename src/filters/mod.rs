@@ -0,0 +1,43 @@
+//! Input filters running in the `Stage::PreFilter` stage of the callback chain, cleaning up
+//! the physical input before the user callbacks see it.
+
+mod bounce_keys;
+mod caps_word;
+mod combos;
+mod debounce;
+mod mouse_keys;
+mod one_shot;
+mod repeater;
+mod slow_keys;
+mod socd;
+mod sticky_keys;
+mod timer;
+mod turbo;
+
+pub use self::bounce_keys::*;
+pub use self::caps_word::*;
+pub use self::combos::*;
+pub use self::debounce::*;
+pub use self::mouse_keys::*;
+pub use self::one_shot::*;
+pub use self::repeater::*;
+pub use self::slow_keys::*;
+pub use self::socd::*;
+pub use self::sticky_keys::*;
+pub use self::turbo::*;
+
+/// Event and time factories shared by the tests of the filters.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use std::time::Duration;
+    use {Key, KeyEvent};
+
+    /// A physical press or release of `key`, without a character.
+    pub fn key(key: Key, is_pressed: bool) -> KeyEvent {
+        KeyEvent::new(None, key.code().unwrap(), 0, is_pressed, false)
+    }
+
+    pub fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+}
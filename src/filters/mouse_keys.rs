@@ -0,0 +1,635 @@
+//! Mouse keys: pointer movement, clicks and scrolling from the keyboard.
+
+use super::timer::Timer;
+use device_events::{HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::{InputAction, MouseButton, INJECTOR};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use {Key, KeyEvent};
+
+/// Priority of the mouse keys among the pre-filters, after the turbo filter.
+const PRIORITY: i32 = 700;
+
+/// What a key does in mouse keys mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MouseKeyAction {
+    /// Move the pointer while the key is held, towards the right for a positive `dx` and
+    /// downwards for a positive `dy`. The directions of the keys held together add up.
+    Move { dx: i32, dy: i32 },
+    /// Hold the button while the key is held, to click or drag.
+    Button(MouseButton),
+    /// Press the button, or release it if the key pressed it before, to drag without holding
+    /// the key.
+    ToggleButton(MouseButton),
+    /// Scroll while the key is held, right for a positive `dx` and up for a positive `dy`.
+    Scroll { dx: i32, dy: i32 },
+}
+
+/// Speed of the pointer along the time a movement key is held: from `initial_speed` it
+/// reaches `max_speed` after `time_to_max`, following a power `curve`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Acceleration {
+    /// Pixels per second when a key is pressed.
+    pub initial_speed: f64,
+    /// Pixels per second once a key is held for `time_to_max`.
+    pub max_speed: f64,
+    pub time_to_max: Duration,
+    /// Exponent of the curve: 1 accelerates evenly, more starts slower and less faster.
+    pub curve: f64,
+}
+
+impl Acceleration {
+    /// Accelerate evenly from `initial_speed` to `max_speed`, in pixels per second.
+    pub fn new(initial_speed: f64, max_speed: f64, time_to_max: Duration) -> Self {
+        assert!(
+            initial_speed >= 0.0 && max_speed >= initial_speed,
+            "Mouse keys speeds must be positive and increasing"
+        );
+        Acceleration {
+            initial_speed,
+            max_speed,
+            time_to_max,
+            curve: 1.0,
+        }
+    }
+
+    pub fn curve(mut self, curve: f64) -> Self {
+        assert!(curve > 0.0, "Mouse keys acceleration curve must be positive");
+        self.curve = curve;
+        self
+    }
+
+    /// Fraction of the way to `time_to_max` after `held`, between 0 and 1.
+    fn progress(&self, held: Duration) -> f64 {
+        if self.time_to_max.is_zero() {
+            1.0
+        } else {
+            (held.as_secs_f64() / self.time_to_max.as_secs_f64()).min(1.0)
+        }
+    }
+
+    /// Pixels per second after the keys have been held for `held`.
+    pub fn speed(&self, held: Duration) -> f64 {
+        let range = self.max_speed - self.initial_speed;
+        self.initial_speed + range * self.progress(held).powf(self.curve)
+    }
+
+    /// Pixels covered after the keys have been held for `held`: the integral of `speed`, so
+    /// that the movement doesn't depend on how often it is sampled.
+    pub fn distance(&self, held: Duration) -> f64 {
+        let seconds = held.as_secs_f64();
+        let accelerating = seconds.min(self.time_to_max.as_secs_f64());
+        let range = self.max_speed - self.initial_speed;
+        let mut distance = self.initial_speed * accelerating
+            + range * accelerating * self.progress(held).powf(self.curve) / (self.curve + 1.0);
+        if seconds > accelerating {
+            distance += self.max_speed * (seconds - accelerating);
+        }
+        distance
+    }
+
+    /// Pixels covered during the tick of `period` starting once the keys have been held for
+    /// `held`.
+    fn tick_distance(&self, held: Duration, period: Duration) -> f64 {
+        self.distance(held + period) - self.distance(held)
+    }
+}
+
+/// Whole pixels or notches to inject for moving by `amount` along the unit vector
+/// `direction`, if any. The fractions left are kept in `remainder` for the next ticks.
+fn step(direction: (f64, f64), amount: f64, remainder: &mut (f64, f64)) -> Option<(i32, i32)> {
+    let (dx, dy) = (remainder.0 + direction.0 * amount, remainder.1 + direction.1 * amount);
+    *remainder = (dx.fract(), dy.fract());
+    if dx.trunc() != 0.0 || dy.trunc() != 0.0 {
+        Some((dx.trunc() as i32, dy.trunc() as i32))
+    } else {
+        None
+    }
+}
+
+impl Default for Acceleration {
+    /// From 100 to 1500 pixels per second in a second, starting slowly for precision.
+    fn default() -> Self {
+        Acceleration::new(100.0, 1500.0, Duration::from_secs(1)).curve(2.0)
+    }
+}
+
+/// Configuration of a `MouseKeys` mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MouseKeysConfig {
+    pub keys: BTreeMap<Key, MouseKeyAction>,
+    pub acceleration: Acceleration,
+    /// Wheel notches per second while a scroll key is held.
+    pub scroll_rate: f64,
+    /// Pointer and wheel updates per second.
+    pub tick_rate: f64,
+    /// Key turning the mode on and off. Its own events are swallowed.
+    pub toggle_key: Option<Key>,
+    /// Key that must be held for the keys to drive the mouse, like a layer. Its own events are
+    /// swallowed.
+    pub layer_key: Option<Key>,
+}
+
+impl MouseKeysConfig {
+    /// No keys, with the default acceleration, 10 notches per second and 60 ticks per second.
+    pub fn new() -> Self {
+        MouseKeysConfig {
+            keys: BTreeMap::new(),
+            acceleration: Acceleration::default(),
+            scroll_rate: 10.0,
+            tick_rate: 60.0,
+            toggle_key: None,
+            layer_key: None,
+        }
+    }
+
+    /// The numpad: the digits around 5 move, 5 holds the left button, 0 toggles it for
+    /// dragging, `+` and `*` hold the right and middle buttons, and `/` and `-` scroll up and
+    /// down. Num Lock turns the mode on and off.
+    pub fn numpad() -> Self {
+        MouseKeysConfig::new()
+            .key(Key::Numpad8, MouseKeyAction::Move { dx: 0, dy: -1 })
+            .key(Key::Numpad2, MouseKeyAction::Move { dx: 0, dy: 1 })
+            .key(Key::Numpad4, MouseKeyAction::Move { dx: -1, dy: 0 })
+            .key(Key::Numpad6, MouseKeyAction::Move { dx: 1, dy: 0 })
+            .key(Key::Numpad7, MouseKeyAction::Move { dx: -1, dy: -1 })
+            .key(Key::Numpad9, MouseKeyAction::Move { dx: 1, dy: -1 })
+            .key(Key::Numpad1, MouseKeyAction::Move { dx: -1, dy: 1 })
+            .key(Key::Numpad3, MouseKeyAction::Move { dx: 1, dy: 1 })
+            .key(Key::Numpad5, MouseKeyAction::Button(MouseButton::Left))
+            .key(Key::Numpad0, MouseKeyAction::ToggleButton(MouseButton::Left))
+            .key(Key::NumpadAdd, MouseKeyAction::Button(MouseButton::Right))
+            .key(Key::NumpadMultiply, MouseKeyAction::Button(MouseButton::Middle))
+            .key(Key::NumpadDivide, MouseKeyAction::Scroll { dx: 0, dy: 1 })
+            .key(Key::NumpadSubtract, MouseKeyAction::Scroll { dx: 0, dy: -1 })
+            .toggle_key(Key::NumLock)
+    }
+
+    /// Vim keys while `layer` is held: HJKL move, U and D scroll up and down, Space holds the
+    /// left button, V toggles it for dragging and `;` holds the right button.
+    pub fn hjkl(layer: Key) -> Self {
+        MouseKeysConfig::new()
+            .key(Key::H, MouseKeyAction::Move { dx: -1, dy: 0 })
+            .key(Key::J, MouseKeyAction::Move { dx: 0, dy: 1 })
+            .key(Key::K, MouseKeyAction::Move { dx: 0, dy: -1 })
+            .key(Key::L, MouseKeyAction::Move { dx: 1, dy: 0 })
+            .key(Key::U, MouseKeyAction::Scroll { dx: 0, dy: 1 })
+            .key(Key::D, MouseKeyAction::Scroll { dx: 0, dy: -1 })
+            .key(Key::Space, MouseKeyAction::Button(MouseButton::Left))
+            .key(Key::V, MouseKeyAction::ToggleButton(MouseButton::Left))
+            .key(Key::Semicolon, MouseKeyAction::Button(MouseButton::Right))
+            .layer_key(layer)
+    }
+
+    pub fn key(mut self, key: Key, action: MouseKeyAction) -> Self {
+        self.keys.insert(key, action);
+        self
+    }
+
+    pub fn acceleration(mut self, acceleration: Acceleration) -> Self {
+        self.acceleration = acceleration;
+        self
+    }
+
+    pub fn scroll_rate(mut self, scroll_rate: f64) -> Self {
+        assert!(scroll_rate > 0.0, "Mouse keys scroll rate must be positive");
+        self.scroll_rate = scroll_rate;
+        self
+    }
+
+    pub fn tick_rate(mut self, tick_rate: f64) -> Self {
+        assert!(tick_rate > 0.0, "Mouse keys tick rate must be positive");
+        self.tick_rate = tick_rate;
+        self
+    }
+
+    pub fn toggle_key(mut self, key: Key) -> Self {
+        self.toggle_key = Some(key);
+        self
+    }
+
+    pub fn layer_key(mut self, key: Key) -> Self {
+        self.layer_key = Some(key);
+        self
+    }
+}
+
+impl Default for MouseKeysConfig {
+    fn default() -> Self {
+        MouseKeysConfig::new()
+    }
+}
+
+struct State {
+    config: MouseKeysConfig,
+    enabled: bool,
+    layer_held: bool,
+    /// Actions of the keys held down, by key code.
+    held: HashMap<u32, MouseKeyAction>,
+    /// Key codes whose events are swallowed until their release, like the toggle key.
+    swallowed: HashSet<u32>,
+    /// Buttons pressed by `MouseKeyAction::ToggleButton`.
+    toggled: HashSet<MouseButton>,
+    /// When movement started, to accelerate.
+    moving_since: Option<Instant>,
+    /// Pointer and wheel movement not injected yet, below a pixel or a notch.
+    pointer_remainder: (f64, f64),
+    scroll_remainder: (f64, f64),
+    ticking: bool,
+    /// Incremented whenever ticking stops, so that the ticks scheduled before are dropped.
+    generation: u64,
+    /// `Injector::epoch` of the last injected input.
+    epoch: u64,
+}
+
+impl State {
+    /// Sum of the directions of the held keys of a kind, made a unit vector.
+    fn direction<F: Fn(&MouseKeyAction) -> Option<(i32, i32)>>(&self, kind: F) -> Option<(f64, f64)> {
+        let (dx, dy) = self
+            .held
+            .values()
+            .filter_map(kind)
+            .fold((0, 0), |(x, y), (dx, dy)| (x + dx, y + dy));
+        let length = ((dx * dx + dy * dy) as f64).sqrt();
+        if length == 0.0 {
+            None
+        } else {
+            Some((dx as f64 / length, dy as f64 / length))
+        }
+    }
+
+    fn pointer_direction(&self) -> Option<(f64, f64)> {
+        self.direction(|action| match *action {
+            MouseKeyAction::Move { dx, dy } => Some((dx, dy)),
+            _ => None,
+        })
+    }
+
+    fn scroll_direction(&self) -> Option<(f64, f64)> {
+        self.direction(|action| match *action {
+            MouseKeyAction::Scroll { dx, dy } => Some((dx, dy)),
+            _ => None,
+        })
+    }
+
+    /// Forget the toggled buttons if `DeviceState::release_all` released them.
+    fn check_epoch(&mut self) {
+        let epoch = INJECTOR.epoch();
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.toggled.clear();
+        }
+    }
+
+    /// Release everything and stop moving.
+    fn reset(&mut self) {
+        let mut releases: Vec<InputAction> = self
+            .held
+            .drain()
+            .filter_map(|(_, action)| match action {
+                MouseKeyAction::Button(button) => Some(InputAction::MouseUp(button)),
+                _ => None,
+            })
+            .collect();
+        releases.extend(self.toggled.drain().map(InputAction::MouseUp));
+        if !releases.is_empty() {
+            INJECTOR.queue(releases);
+        }
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.ticking = false;
+        self.generation += 1;
+        self.moving_since = None;
+        self.pointer_remainder = (0.0, 0.0);
+        self.scroll_remainder = (0.0, 0.0);
+    }
+
+    fn period(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.config.tick_rate)
+    }
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Drives the pointer from the keyboard: movement with acceleration, clicks, drags and
+/// scrolling, injected at a steady tick rate while keys are held. The keys driving the mouse
+/// are swallowed; the others are left alone, as are simulated events.
+///
+/// ```no_run
+/// use key_director::{Acceleration, DeviceState, Key, MouseKeys, MouseKeysConfig};
+/// use std::time::Duration;
+///
+/// let _device_state = DeviceState::new();
+/// let config = MouseKeysConfig::hjkl(Key::CapsLock)
+///     .acceleration(Acceleration::new(200.0, 2000.0, Duration::from_millis(800)).curve(1.5))
+///     .tick_rate(120.0);
+/// let _mouse_keys = MouseKeys::install(config);
+/// ```
+///
+/// The buttons held by the mode are released when it is turned off or dropped.
+pub struct MouseKeys {
+    id: HandlerId,
+    state: Shared,
+    _timer: Arc<Timer>,
+}
+
+impl MouseKeys {
+    /// Install the mode, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: MouseKeysConfig) -> MouseKeys {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: true,
+            layer_held: false,
+            held: HashMap::new(),
+            swallowed: HashSet::new(),
+            toggled: HashSet::new(),
+            moving_since: None,
+            pointer_remainder: (0.0, 0.0),
+            scroll_remainder: (0.0, 0.0),
+            ticking: false,
+            generation: 0,
+            epoch: INJECTOR.epoch(),
+        }));
+        let timer = Arc::new(Timer::new());
+
+        let handler_state = Arc::downgrade(&state);
+        let handler_timer = Arc::downgrade(&timer);
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "mouse keys",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match (handler_state.upgrade(), handler_timer.upgrade()) {
+                    (Some(state), Some(timer)) => filter(&state, &timer, event),
+                    _ => Verdict::Pass,
+                }),
+            );
+
+        MouseKeys {
+            id,
+            state,
+            _timer: timer,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.lock();
+        state.enabled = enabled;
+        if !enabled {
+            state.reset();
+        }
+    }
+
+    pub fn config(&self) -> MouseKeysConfig {
+        self.lock().config.clone()
+    }
+
+    /// Change the configuration, releasing everything held by the previous one.
+    pub fn set_config(&self, config: MouseKeysConfig) {
+        let mut state = self.lock();
+        state.reset();
+        state.config = config;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the mouse keys state")
+    }
+}
+
+impl Drop for MouseKeys {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+        if let Ok(mut state) = self.state.lock() {
+            state.reset();
+        }
+    }
+}
+
+fn filter(shared: &Shared, timer: &Arc<Timer>, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return Verdict::Pass,
+    };
+    state.check_epoch();
+
+    if !event.is_pressed {
+        if state.swallowed.remove(&event.key_code) {
+            return Verdict::Block;
+        }
+        let key = Key::from_code(event.key_code);
+        if key.is_some() && key == state.config.layer_key {
+            state.layer_held = false;
+            return Verdict::Block;
+        }
+        return match state.held.remove(&event.key_code) {
+            Some(MouseKeyAction::Button(button)) => {
+                INJECTOR.queue(vec![InputAction::MouseUp(button)]);
+                Verdict::Block
+            }
+            Some(_) => {
+                if state.pointer_direction().is_none() {
+                    state.moving_since = None;
+                    state.pointer_remainder = (0.0, 0.0);
+                }
+                if state.scroll_direction().is_none() {
+                    state.scroll_remainder = (0.0, 0.0);
+                }
+                Verdict::Block
+            }
+            None => Verdict::Pass,
+        };
+    }
+
+    if state.held.contains_key(&event.key_code) {
+        // Auto-repeat of a key driving the mouse.
+        return Verdict::Block;
+    }
+    let key = match Key::from_code(event.key_code) {
+        Some(key) => key,
+        None => return Verdict::Pass,
+    };
+    if Some(key) == state.config.toggle_key {
+        state.swallowed.insert(event.key_code);
+        state.enabled = !state.enabled;
+        if !state.enabled {
+            state.reset();
+        }
+        return Verdict::Block;
+    }
+    if Some(key) == state.config.layer_key {
+        state.layer_held = true;
+        return Verdict::Block;
+    }
+    if !state.enabled || (state.config.layer_key.is_some() && !state.layer_held) {
+        return Verdict::Pass;
+    }
+    let action = match state.config.keys.get(&key) {
+        Some(action) => *action,
+        None => return Verdict::Pass,
+    };
+
+    state.held.insert(event.key_code, action);
+    match action {
+        MouseKeyAction::Button(button) => {
+            INJECTOR.queue(vec![InputAction::MouseDown(button)]);
+        }
+        MouseKeyAction::ToggleButton(button) => {
+            if state.toggled.remove(&button) {
+                INJECTOR.queue(vec![InputAction::MouseUp(button)]);
+            } else {
+                state.toggled.insert(button);
+                INJECTOR.queue(vec![InputAction::MouseDown(button)]);
+            }
+        }
+        MouseKeyAction::Move { .. } | MouseKeyAction::Scroll { .. } => {
+            if state.moving_since.is_none() && state.pointer_direction().is_some() {
+                state.moving_since = Some(Instant::now());
+            }
+            if !state.ticking {
+                state.ticking = true;
+                schedule_tick(shared, timer, Instant::now(), state.generation);
+            }
+        }
+    }
+    Verdict::Block
+}
+
+fn schedule_tick(shared: &Shared, timer: &Arc<Timer>, at: Instant, generation: u64) {
+    let weak_state = Arc::downgrade(shared);
+    let weak_timer = Arc::downgrade(timer);
+    timer.schedule(at, move || tick(&weak_state, &weak_timer, at, generation));
+}
+
+/// Move the pointer and the wheels for the tick at `at` and schedule the next one, as long as
+/// ticking hasn't stopped since `generation` and keys are held.
+fn tick(shared: &Weak<Mutex<State>>, timer: &Weak<Timer>, at: Instant, generation: u64) {
+    let (shared, timer) = match (shared.upgrade(), timer.upgrade()) {
+        (Some(shared), Some(timer)) => (shared, timer),
+        _ => return,
+    };
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return,
+    };
+    if !state.ticking || state.generation != generation {
+        return;
+    }
+    let pointer = state.pointer_direction();
+    let scroll = state.scroll_direction();
+    if pointer.is_none() && scroll.is_none() {
+        state.stop();
+        return;
+    }
+
+    let period = state.period();
+    let mut actions = Vec::new();
+    if let (Some(direction), Some(since)) = (pointer, state.moving_since) {
+        let held = at.saturating_duration_since(since);
+        let distance = state.config.acceleration.tick_distance(held, period);
+        if let Some((dx, dy)) = step(direction, distance, &mut state.pointer_remainder) {
+            actions.push(InputAction::MouseMove { dx, dy });
+        }
+    }
+    if let Some(direction) = scroll {
+        let notches = state.config.scroll_rate * period.as_secs_f64();
+        if let Some((dx, dy)) = step(direction, notches, &mut state.scroll_remainder) {
+            actions.push(InputAction::Scroll { dx, dy });
+        }
+    }
+    if !actions.is_empty() {
+        INJECTOR.queue(actions);
+    }
+    schedule_tick(&shared, &timer, at + period, generation);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::millis;
+
+    fn accelerations() -> Vec<Acceleration> {
+        [0.5, 1.0, 2.0]
+            .iter()
+            .map(|curve| Acceleration::new(100.0, 1500.0, Duration::from_secs(1)).curve(*curve))
+            .collect()
+    }
+
+    /// Pixels moved right by holding a key for `ticks` ticks at `tick_rate`, as `tick` does.
+    fn moved(acceleration: &Acceleration, tick_rate: f64, ticks: u32) -> i32 {
+        let period = Duration::from_secs_f64(1.0 / tick_rate);
+        let mut remainder = (0.0, 0.0);
+        (0..ticks)
+            .filter_map(|tick| step((1.0, 0.0), acceleration.tick_distance(period * tick, period), &mut remainder))
+            .map(|(dx, _)| dx)
+            .sum()
+    }
+
+    #[test]
+    fn the_speed_increases_from_the_initial_to_the_max_speed() {
+        for acceleration in accelerations() {
+            assert_eq!(acceleration.speed(Duration::ZERO), 100.0);
+            let speeds: Vec<f64> = (0..=120).map(|step| acceleration.speed(millis(step * 10))).collect();
+            assert!(speeds.windows(2).all(|pair| pair[0] < pair[1] || pair[1] == 1500.0));
+            assert_eq!(acceleration.speed(millis(1000)), 1500.0);
+            assert_eq!(acceleration.speed(millis(5000)), 1500.0);
+        }
+    }
+
+    #[test]
+    fn the_distance_is_capped_by_the_max_speed() {
+        for acceleration in accelerations() {
+            let period = millis(10);
+            for step in 0..200 {
+                let held = millis(step * 10);
+                let distance = acceleration.tick_distance(held, period);
+                assert!(distance >= acceleration.speed(held) * 0.01 - 1e-9);
+                assert!(distance <= acceleration.speed(held + period) * 0.01 + 1e-9);
+            }
+            // Past `time_to_max`, it grows at the max speed.
+            assert!((acceleration.tick_distance(millis(1500), millis(500)) - 750.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn without_acceleration_the_speed_is_the_max_speed() {
+        let acceleration = Acceleration::new(100.0, 1500.0, Duration::ZERO);
+        assert_eq!(acceleration.speed(Duration::ZERO), 1500.0);
+        assert!((acceleration.distance(millis(200)) - 300.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn the_distance_covered_doesnt_depend_on_the_tick_rate() {
+        for acceleration in accelerations() {
+            let expected = acceleration.distance(millis(1500));
+            for (tick_rate, ticks) in [(30.0, 45), (60.0, 90), (144.0, 216), (1000.0, 1500)].iter() {
+                let moved = moved(&acceleration, *tick_rate, *ticks);
+                assert!((f64::from(moved) - expected).abs() <= 1.0, "{} pixels at {} Hz", moved, tick_rate);
+            }
+        }
+    }
+
+    #[test]
+    fn fractions_of_pixels_add_up_across_ticks() {
+        let mut remainder = (0.0, 0.0);
+        assert_eq!(step((0.6, -0.8), 1.0, &mut remainder), None);
+        assert_eq!(step((0.6, -0.8), 1.0, &mut remainder), Some((1, -1)));
+        assert_eq!(step((0.6, -0.8), 1.0, &mut remainder), Some((0, -1)));
+        assert!((remainder.0 - 0.8).abs() < 1e-9 && (remainder.1 + 0.4).abs() < 1e-9);
+    }
+}
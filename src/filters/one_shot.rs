@@ -0,0 +1,440 @@
+//! One-shot modifiers: modifiers tapped apply to the next key only.
+
+use super::timer::Timer;
+use device_events::{resume_replacement, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::{InputAction, INJECTOR};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use {Key, KeyEvent, Modifiers};
+
+/// Priority of the one-shot modifiers among the pre-filters, after the sticky keys filter.
+const PRIORITY: i32 = 920;
+
+/// Configuration of a `OneShotModifiers` filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OneShotConfig {
+    /// Modifiers that apply to the next key when tapped.
+    pub modifiers: Modifiers,
+    /// Longest a modifier may be held to count as a tap. Held longer, it is a plain modifier.
+    pub tap_term: Duration,
+    /// How long a tapped modifier waits for the next key before it is dropped, if ever.
+    pub timeout: Option<Duration>,
+}
+
+impl OneShotConfig {
+    /// Make Shift one-shot, with a 200 ms tap term and no timeout.
+    pub fn new() -> Self {
+        OneShotConfig {
+            modifiers: Modifiers::SHIFT,
+            tap_term: Duration::from_millis(200),
+            timeout: None,
+        }
+    }
+
+    pub fn modifiers(mut self, modifiers: Modifiers) -> Self {
+        self.modifiers = modifiers;
+        self
+    }
+
+    pub fn tap_term(mut self, tap_term: Duration) -> Self {
+        self.tap_term = tap_term;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl Default for OneShotConfig {
+    fn default() -> Self {
+        OneShotConfig::new()
+    }
+}
+
+/// A one-shot modifier key physically held.
+struct Held {
+    pressed_at: Instant,
+    /// Whether no other key was pressed since the modifier was.
+    tapped: bool,
+}
+
+struct State {
+    config: OneShotConfig,
+    enabled: bool,
+    /// One-shot modifier keys held, by key code.
+    held: HashMap<u32, Held>,
+    /// Codes of the modifiers tapped, held down until the next key.
+    latched: Vec<u32>,
+    /// Code of the key the modifiers applied to, while it is held, with the modifiers.
+    used: Option<(u32, Vec<u32>)>,
+    /// Incremented whenever the latched modifiers change, so that stale timeouts are dropped.
+    generation: u64,
+}
+
+impl State {
+    fn latched(&self) -> Modifiers {
+        self.latched
+            .iter()
+            .filter_map(|code| Key::from_code(*code)?.modifier())
+            .fold(Modifiers::NONE, |modifiers, modifier| modifiers | modifier)
+    }
+
+    /// Release the modifiers applied to a key.
+    fn release_used(&mut self) -> Vec<KeyEvent> {
+        self.used
+            .take()
+            .map(|(_, codes)| codes)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|code| KeyEvent::new(None, code, 0, false, true))
+            .collect()
+    }
+
+    /// Release every modifier held down by the filter, with injected input.
+    fn release_all(&mut self) {
+        self.generation += 1;
+        let mut releases: Vec<InputAction> = self.latched.drain(..).map(InputAction::KeyUp).collect();
+        releases.extend(self.release_used().into_iter().map(|event| InputAction::KeyUp(event.key_code)));
+        if !releases.is_empty() {
+            INJECTOR.queue(releases);
+        }
+    }
+
+    /// Filter a physical `event` that came at `now`. Blocks only the release of a modifier
+    /// tapped, that gets latched.
+    fn filter(&mut self, event: &KeyEvent, now: Instant) -> Verdict {
+        if event.is_simulated || !self.enabled {
+            return Verdict::Pass;
+        }
+        let code = event.key_code;
+        let key = Key::from_code(code);
+        let one_shot = key
+            .and_then(Key::modifier)
+            .is_some_and(|modifier| self.config.modifiers.contains(modifier));
+
+        if event.is_pressed {
+            if !self.held.contains_key(&code) {
+                for held in self.held.values_mut() {
+                    held.tapped = false;
+                }
+            }
+            let used_by_other = self.used.as_ref().is_some_and(|(used, _)| *used != code);
+            let mut events = if used_by_other { self.release_used() } else { Vec::new() };
+
+            if one_shot {
+                if let Some(index) = self.latched.iter().position(|latched| *latched == code) {
+                    // Tapped again: the modifier is down already, and goes up with this press.
+                    self.latched.remove(index);
+                    self.generation += 1;
+                    self.held.insert(code, Held { pressed_at: now, tapped: false });
+                } else {
+                    self.held.entry(code).or_insert(Held { pressed_at: now, tapped: true });
+                }
+            } else if key.and_then(Key::modifier).is_none() && !self.latched.is_empty() {
+                self.generation += 1;
+                let latched = std::mem::take(&mut self.latched);
+                self.used = Some((code, latched));
+            }
+
+            if events.is_empty() {
+                return Verdict::Pass;
+            }
+            events.push(event.clone());
+            return Verdict::Replace(events);
+        }
+
+        if !one_shot {
+            if self.used.as_ref().is_some_and(|(used, _)| *used == code) {
+                let mut events = vec![event.clone()];
+                events.extend(self.release_used());
+                return Verdict::Replace(events);
+            }
+            return Verdict::Pass;
+        }
+        let tap_term = self.config.tap_term;
+        match self.held.remove(&code) {
+            Some(held) if held.tapped && now.duration_since(held.pressed_at) <= tap_term => {}
+            _ => return Verdict::Pass,
+        }
+        self.latched.push(code);
+        self.generation += 1;
+        Verdict::Block
+    }
+
+    /// The releases of the latched modifiers, dropped if they didn't change since
+    /// `generation`.
+    fn expire(&mut self, generation: u64) -> Vec<InputAction> {
+        if self.generation != generation {
+            return Vec::new();
+        }
+        self.latched.drain(..).map(InputAction::KeyUp).collect()
+    }
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Makes modifiers one-shot, like in QMK: a modifier tapped stays down for the next key only,
+/// e.g. Shift tapped then A types a capital A. Tapping several modifiers stacks them, and other
+/// modifiers pressed in between don't use them, so that a one-shot Shift and a held Control make
+/// Control+Shift+S. The modifiers are released when the key is, or just before the next key
+/// is pressed when typing fast. Auto-repeats of the key stay modified.
+///
+/// A modifier held longer than the tap term, or used in a chord, behaves as usual, and tapping
+/// a latched modifier again cancels it. Unlike `StickyKeys`, latched modifiers can time out, and
+/// never lock. Simulated events are left alone.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Modifiers, OneShotConfig, OneShotModifiers};
+/// use std::time::Duration;
+///
+/// let _device_state = DeviceState::new();
+/// let config = OneShotConfig::new()
+///     .modifiers(Modifiers::SHIFT | Modifiers::CTRL)
+///     .timeout(Duration::from_secs(2));
+/// let one_shot = OneShotModifiers::install(config);
+/// println!("Latched: {:?}", one_shot.latched());
+/// ```
+///
+/// The filter is removed when dropped, releasing the modifiers it holds down.
+pub struct OneShotModifiers {
+    id: HandlerId,
+    state: Shared,
+    _timer: Arc<Timer>,
+}
+
+impl OneShotModifiers {
+    /// Install the filter, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: OneShotConfig) -> OneShotModifiers {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: true,
+            held: HashMap::new(),
+            latched: Vec::new(),
+            used: None,
+            generation: 0,
+        }));
+        let timer = Arc::new(Timer::new());
+
+        let handler_state = Arc::downgrade(&state);
+        let handler_timer = Arc::downgrade(&timer);
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "one-shot modifiers",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match (handler_state.upgrade(), handler_timer.upgrade()) {
+                    (Some(state), Some(timer)) => resume_replacement(event, filter(&state, &timer, event)),
+                    _ => Verdict::Pass,
+                }),
+            );
+
+        OneShotModifiers {
+            id,
+            state,
+            _timer: timer,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the filter without removing it. Disabling it releases the modifiers
+    /// it holds down.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.lock();
+        if !enabled {
+            state.release_all();
+        }
+        state.enabled = enabled;
+    }
+
+    pub fn config(&self) -> OneShotConfig {
+        self.lock().config.clone()
+    }
+
+    /// Change the configuration, releasing the modifiers held down.
+    pub fn set_config(&self, config: OneShotConfig) {
+        let mut state = self.lock();
+        state.release_all();
+        state.config = config;
+    }
+
+    /// Modifiers latched for the next key.
+    pub fn latched(&self) -> Modifiers {
+        self.lock().latched()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the one-shot modifiers state")
+    }
+}
+
+impl Drop for OneShotModifiers {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+        if let Ok(mut state) = self.state.lock() {
+            state.release_all();
+        }
+    }
+}
+
+fn filter(shared: &Shared, timer: &Timer, event: &KeyEvent) -> Verdict {
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return Verdict::Pass,
+    };
+    let now = Instant::now();
+    let verdict = state.filter(event, now);
+    if let (Verdict::Block, Some(timeout)) = (&verdict, state.config.timeout) {
+        // A modifier was tapped, and latched.
+        let weak_state = Arc::downgrade(shared);
+        let generation = state.generation;
+        timer.schedule(now + timeout, move || expire(&weak_state, generation));
+    }
+    verdict
+}
+
+/// Drop the latched modifiers if they didn't change since `generation`.
+fn expire(shared: &Weak<Mutex<State>>, generation: u64) {
+    let shared = match shared.upgrade() {
+        Some(shared) => shared,
+        None => return,
+    };
+    let releases = match shared.lock() {
+        Ok(mut state) => state.expire(generation),
+        Err(_) => return,
+    };
+    if !releases.is_empty() {
+        INJECTOR.queue(releases);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::key;
+
+    fn release_shift() -> KeyEvent {
+        KeyEvent::new(None, Key::LShift.code().unwrap(), 0, false, true)
+    }
+
+    fn state() -> State {
+        State {
+            config: OneShotConfig::new().timeout(Duration::from_secs(2)),
+            enabled: true,
+            held: HashMap::new(),
+            latched: Vec::new(),
+            used: None,
+            generation: 0,
+        }
+    }
+
+    /// Tap `key_` for `held`, starting at `now`.
+    fn tap(state: &mut State, key_: Key, now: Instant, held: Duration) -> Verdict {
+        assert_eq!(state.filter(&key(key_, true), now), Verdict::Pass);
+        state.filter(&key(key_, false), now + held)
+    }
+
+    #[test]
+    fn tapped_modifiers_latch_and_held_ones_dont() {
+        let now = Instant::now();
+        let mut state = state();
+        let tap_term = state.config.tap_term;
+        assert_eq!(tap(&mut state, Key::LShift, now, tap_term), Verdict::Block);
+        assert_eq!(state.latched(), Modifiers::SHIFT);
+
+        let mut state = self::state();
+        assert_eq!(tap(&mut state, Key::LShift, now, tap_term + Duration::from_millis(1)), Verdict::Pass);
+        assert_eq!(state.latched(), Modifiers::NONE);
+
+        // Used in a chord.
+        assert_eq!(state.filter(&key(Key::LShift, true), now), Verdict::Pass);
+        assert_eq!(tap(&mut state, Key::A, now, Duration::ZERO), Verdict::Pass);
+        assert_eq!(state.filter(&key(Key::LShift, false), now), Verdict::Pass);
+        assert_eq!(state.latched(), Modifiers::NONE);
+
+        // Not one-shot.
+        assert_eq!(tap(&mut state, Key::LControl, now, Duration::ZERO), Verdict::Pass);
+        assert_eq!(state.latched(), Modifiers::NONE);
+    }
+
+    #[test]
+    fn the_next_key_and_its_repeats_are_modified() {
+        let now = Instant::now();
+        let mut state = state();
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Block);
+
+        assert_eq!(state.filter(&key(Key::A, true), now), Verdict::Pass);
+        assert_eq!(state.latched(), Modifiers::NONE);
+        assert_eq!(state.filter(&key(Key::A, true), now), Verdict::Pass);
+        assert_eq!(
+            state.filter(&key(Key::A, false), now),
+            Verdict::Replace(vec![key(Key::A, false), release_shift()])
+        );
+        assert_eq!(tap(&mut state, Key::B, now, Duration::ZERO), Verdict::Pass);
+    }
+
+    #[test]
+    fn typing_fast_releases_the_modifiers_before_the_key_after() {
+        let now = Instant::now();
+        let mut state = state();
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Block);
+        assert_eq!(state.filter(&key(Key::A, true), now), Verdict::Pass);
+        assert_eq!(
+            state.filter(&key(Key::B, true), now),
+            Verdict::Replace(vec![release_shift(), key(Key::B, true)])
+        );
+        assert_eq!(state.filter(&key(Key::A, false), now), Verdict::Pass);
+        assert_eq!(state.filter(&key(Key::B, false), now), Verdict::Pass);
+    }
+
+    #[test]
+    fn tapping_a_latched_modifier_again_cancels_it() {
+        let now = Instant::now();
+        let mut state = state();
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Block);
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Pass);
+        assert_eq!(state.latched(), Modifiers::NONE);
+        assert_eq!(tap(&mut state, Key::A, now, Duration::ZERO), Verdict::Pass);
+    }
+
+    #[test]
+    fn latched_modifiers_time_out_unless_they_changed() {
+        let now = Instant::now();
+        let mut state = state();
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Block);
+        let generation = state.generation;
+        assert_eq!(state.expire(generation), vec![InputAction::KeyUp(Key::LShift.code().unwrap())]);
+        assert_eq!(state.latched(), Modifiers::NONE);
+        assert_eq!(tap(&mut state, Key::A, now, Duration::ZERO), Verdict::Pass);
+
+        // The timeout of a modifier used or tapped again since is stale.
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Block);
+        let generation = state.generation;
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Pass);
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Block);
+        assert!(state.expire(generation).is_empty());
+        assert_eq!(state.latched(), Modifiers::SHIFT);
+    }
+
+    #[test]
+    fn simulated_events_and_disabled_filters_pass() {
+        let now = Instant::now();
+        let mut state = state();
+        let release = KeyEvent::new(None, Key::LShift.code().unwrap(), 0, false, true);
+        assert_eq!(state.filter(&release, now), Verdict::Pass);
+        state.enabled = false;
+        assert_eq!(tap(&mut state, Key::LShift, now, Duration::ZERO), Verdict::Pass);
+        assert_eq!(state.latched(), Modifiers::NONE);
+    }
+}
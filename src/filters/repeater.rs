@@ -0,0 +1,483 @@
+//! Software auto-repeat with settings per key.
+
+use super::timer::Timer;
+use device_events::{resume, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::{set_os_auto_repeat, InputAction, INJECTOR};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use keymap::DeviceKey;
+use {Key, KeyEvent};
+
+/// Priority of the repeater among the pre-filters: it comes before the other filters of the
+/// crate, so that they see its repeats instead of the ones of the OS.
+const PRIORITY: i32 = 1100;
+
+/// How often the keys held by injected input are looked for.
+const QUEUED_KEYS_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Range of the repeat rates, in repeats per second.
+const MIN_RATE: f64 = 0.1;
+const MAX_RATE: f64 = 1000.0;
+
+/// Keys that don't repeat unless configured otherwise.
+const NO_REPEAT: [Key; 11] = [
+    Key::LShift,
+    Key::RShift,
+    Key::LControl,
+    Key::RControl,
+    Key::LAlt,
+    Key::RAlt,
+    Key::LMeta,
+    Key::RMeta,
+    Key::CapsLock,
+    Key::NumLock,
+    Key::ScrollLock,
+];
+
+/// How a held key repeats.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RepeatSettings {
+    /// How long the key is held before it starts repeating.
+    pub delay: Duration,
+    /// Repeats per second, between 0.1 and 1000. Rates out of the range, e.g. from a
+    /// deserialized configuration, are brought into it.
+    pub rate: f64,
+}
+
+impl RepeatSettings {
+    pub fn new(delay: Duration, rate: f64) -> Self {
+        assert!(rate > 0.0, "Repeat rate must be positive");
+        RepeatSettings { delay, rate }
+    }
+
+    fn period(&self) -> Duration {
+        // Comparisons are false for NaN.
+        let rate = if self.rate >= MIN_RATE { self.rate.min(MAX_RATE) } else { MIN_RATE };
+        Duration::from_secs_f64(1.0 / rate)
+    }
+}
+
+/// Configuration of a `Repeater`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RepeaterConfig {
+    /// How the keys without settings of their own repeat, if they do.
+    pub default: Option<RepeatSettings>,
+    /// Settings of some keys, `None` for keys that don't repeat.
+    pub keys: BTreeMap<Key, Option<RepeatSettings>>,
+}
+
+impl RepeaterConfig {
+    /// Repeat every key `rate` times per second once held for `delay`, except the modifiers
+    /// and the lock keys.
+    pub fn new(delay: Duration, rate: f64) -> Self {
+        RepeaterConfig {
+            default: Some(RepeatSettings::new(delay, rate)),
+            keys: NO_REPEAT.iter().map(|key| (*key, None)).collect(),
+        }
+    }
+
+    /// Repeat `key` `rate` times per second once held for `delay`.
+    pub fn key(mut self, key: Key, delay: Duration, rate: f64) -> Self {
+        self.keys.insert(key, Some(RepeatSettings::new(delay, rate)));
+        self
+    }
+
+    /// Never repeat `key`.
+    pub fn no_repeat(mut self, key: Key) -> Self {
+        self.keys.insert(key, None);
+        self
+    }
+
+    /// How the key with platform key code `code` repeats, if it does.
+    pub fn settings(&self, code: u32) -> Option<RepeatSettings> {
+        match Key::from_code(code).and_then(|key| self.keys.get(&key)) {
+            Some(settings) => *settings,
+            None => self.default,
+        }
+    }
+}
+
+impl Default for RepeaterConfig {
+    /// A 500 ms delay and 30 repeats per second, close to the defaults of most systems.
+    fn default() -> Self {
+        RepeaterConfig::new(Duration::from_millis(500), 30.0)
+    }
+}
+
+/// A held key that repeats.
+struct Repeating {
+    /// Press repeated through the handlers after the repeater, for physical keys. Keys held by
+    /// injected input are repeated through the injector.
+    press: Option<KeyEvent>,
+    /// Instant of the press, telling it apart from later presses of the key.
+    pressed_at: Instant,
+}
+
+struct State {
+    config: RepeaterConfig,
+    enabled: bool,
+    id: Option<HandlerId>,
+    /// Physical keys held, by device and key code.
+    physical: HashMap<DeviceKey, Repeating>,
+    /// Keys held by queued input, by key code.
+    queued: HashMap<u32, Repeating>,
+    /// Whether the auto-repeat of the OS was on before the repeater turned it off, if it did.
+    os_auto_repeat: Option<bool>,
+    /// Whether the keys held by queued input are polled. Polling stops while disabled.
+    polling: bool,
+}
+
+/// What the repeater does with an event.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pass,
+    Block,
+    /// Let the press through, and repeat it from that instant on.
+    Repeat(Instant),
+}
+
+impl State {
+    /// Filter a physical `event` that came at `now`.
+    fn filter(&mut self, event: &KeyEvent, now: Instant) -> Outcome {
+        if !self.enabled {
+            return Outcome::Pass;
+        }
+        // The repeats of the OS.
+        if event.is_pressed && event.is_repeat {
+            return Outcome::Block;
+        }
+
+        let device_key = event.device_key();
+        if !event.is_pressed {
+            self.physical.remove(&device_key);
+            return Outcome::Pass;
+        }
+        match self.config.settings(event.key_code) {
+            Some(settings) => {
+                let mut press = event.clone();
+                press.is_repeat = true;
+                self.physical.insert(
+                    device_key,
+                    Repeating {
+                        press: Some(press),
+                        pressed_at: now,
+                    },
+                );
+                Outcome::Repeat(now + settings.delay)
+            }
+            None => Outcome::Pass,
+        }
+    }
+
+    /// Let the OS repeat keys again, as it did before.
+    fn restore_os_auto_repeat(&mut self) {
+        if let Some(on) = self.os_auto_repeat.take() {
+            set_os_auto_repeat(on);
+        }
+    }
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Which held key a repeat is for.
+#[derive(Clone)]
+enum Held {
+    Physical(DeviceKey),
+    Queued(u32),
+}
+
+/// Repeats held keys itself instead of the OS, with settings per key: e.g. fast repeats for the
+/// arrows and none for Enter. The repeats of the OS are swallowed, and each repeat goes through
+/// the handlers after the repeater like one of the OS would, flagged with
+/// `KeyEvent::is_repeat`, so that remapped keys repeat the keys they are remapped to. Keys held
+/// by `DeviceState::press` and other queued input repeat too, through the injector.
+///
+/// The X server repeats held keys on its own, so its auto-repeat is turned off while the
+/// repeater is enabled. Wayland compositors can't be told, and keep repeating keys.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Key, Repeater, RepeaterConfig};
+/// use std::time::Duration;
+///
+/// let _device_state = DeviceState::new();
+/// let config = RepeaterConfig::new(Duration::from_millis(400), 25.0)
+///     .key(Key::Left, Duration::from_millis(200), 60.0)
+///     .key(Key::Right, Duration::from_millis(200), 60.0)
+///     .no_repeat(Key::Enter);
+/// let repeater = Repeater::install(config);
+/// repeater.set_config(repeater.config().key(Key::Backspace, Duration::from_millis(300), 40.0));
+/// ```
+///
+/// The repeater is removed when dropped, letting the OS repeat keys again.
+pub struct Repeater {
+    id: HandlerId,
+    state: Shared,
+    timer: Arc<Timer>,
+}
+
+impl Repeater {
+    /// Install the repeater, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: RepeaterConfig) -> Repeater {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: true,
+            id: None,
+            physical: HashMap::new(),
+            queued: HashMap::new(),
+            os_auto_repeat: set_os_auto_repeat(false),
+            polling: true,
+        }));
+        let timer = Arc::new(Timer::new());
+
+        let handler_state = Arc::downgrade(&state);
+        let handler_timer = Arc::downgrade(&timer);
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "repeater",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match (handler_state.upgrade(), handler_timer.upgrade()) {
+                    (Some(state), Some(timer)) => filter(&state, &timer, event),
+                    _ => Verdict::Pass,
+                }),
+            );
+        state.lock().expect("Couldn't lock the repeater state").id = Some(id);
+        poll_queued_keys(&state, &timer, Instant::now());
+
+        Repeater { id, state, timer }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the repeater without removing it. The OS repeats keys while it is
+    /// disabled.
+    pub fn set_enabled(&self, enabled: bool) {
+        let start_polling = {
+            let mut state = self.lock();
+            if enabled == state.enabled {
+                return;
+            }
+            state.enabled = enabled;
+            if enabled {
+                state.os_auto_repeat = set_os_auto_repeat(false);
+                !std::mem::replace(&mut state.polling, true)
+            } else {
+                state.physical.clear();
+                state.queued.clear();
+                state.restore_os_auto_repeat();
+                false
+            }
+        };
+        if start_polling {
+            poll_queued_keys(&self.state, &self.timer, Instant::now());
+        }
+    }
+
+    pub fn config(&self) -> RepeaterConfig {
+        self.lock().config.clone()
+    }
+
+    /// Change the configuration, for the repeats to come, including the ones of keys held.
+    pub fn set_config(&self, config: RepeaterConfig) {
+        self.lock().config = config;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the repeater state")
+    }
+}
+
+impl Drop for Repeater {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+        if let Ok(mut state) = self.state.lock() {
+            state.restore_os_auto_repeat();
+        }
+    }
+}
+
+fn filter(shared: &Shared, timer: &Arc<Timer>, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return Verdict::Pass,
+    };
+    let pressed_at = Instant::now();
+    match state.filter(event, pressed_at) {
+        Outcome::Pass => Verdict::Pass,
+        Outcome::Block => Verdict::Block,
+        Outcome::Repeat(at) => {
+            schedule_repeat(shared, timer, Held::Physical(event.device_key()), pressed_at, at);
+            Verdict::Pass
+        }
+    }
+}
+
+fn schedule_repeat(shared: &Shared, timer: &Arc<Timer>, held: Held, pressed_at: Instant, at: Instant) {
+    let weak_state = Arc::downgrade(shared);
+    let weak_timer = Arc::downgrade(timer);
+    timer.schedule(at, move || repeat(&weak_state, &weak_timer, held, pressed_at, at));
+}
+
+/// Repeat the press of `held` made at `pressed_at` if the key is still held, and schedule the
+/// next repeat with the settings of the moment.
+fn repeat(shared: &Weak<Mutex<State>>, timer: &Weak<Timer>, held: Held, pressed_at: Instant, at: Instant) {
+    let (shared, timer) = match (shared.upgrade(), timer.upgrade()) {
+        (Some(shared), Some(timer)) => (shared, timer),
+        _ => return,
+    };
+    let (press, id, settings) = {
+        let state = match shared.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let (repeating, code) = match held {
+            Held::Physical(ref device_key) => (state.physical.get(device_key), device_key.1),
+            Held::Queued(code) => (state.queued.get(&code), code),
+        };
+        let press = match repeating {
+            Some(repeating) if repeating.pressed_at == pressed_at => repeating.press.clone(),
+            _ => return,
+        };
+        match state.config.settings(code) {
+            Some(settings) => (press, state.id, settings),
+            None => return,
+        }
+    };
+
+    match (press, id, &held) {
+        (Some(press), Some(id), _) => resume(&press, id),
+        (None, _, &Held::Queued(code)) => {
+            INJECTOR.queue(vec![InputAction::KeyRepeat(code)]);
+        }
+        _ => return,
+    }
+    schedule_repeat(&shared, &timer, held, pressed_at, at + settings.period());
+}
+
+/// Follow the keys held by queued input, which repeat like physical keys, until the repeater is
+/// disabled.
+fn poll_queued_keys(shared: &Shared, timer: &Arc<Timer>, at: Instant) {
+    let queued_keys = INJECTOR.queued_keys();
+    {
+        let mut state = match shared.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if !state.enabled {
+            state.polling = false;
+            return;
+        }
+        state
+            .queued
+            .retain(|code, repeating| queued_keys.contains(&(*code, repeating.pressed_at)));
+        for (code, pressed_at) in queued_keys {
+            if state.queued.contains_key(&code) {
+                continue;
+            }
+            state.queued.insert(code, Repeating { press: None, pressed_at });
+            if let Some(settings) = state.config.settings(code) {
+                schedule_repeat(shared, timer, Held::Queued(code), pressed_at, pressed_at + settings.delay);
+            }
+        }
+    }
+
+    let weak_state = Arc::downgrade(shared);
+    let weak_timer = Arc::downgrade(timer);
+    let next = at + QUEUED_KEYS_POLL_INTERVAL;
+    timer.schedule(next, move || {
+        if let (Some(shared), Some(timer)) = (weak_state.upgrade(), weak_timer.upgrade()) {
+            poll_queued_keys(&shared, &timer, next);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::{key, millis};
+
+    fn state() -> State {
+        State {
+            config: RepeaterConfig::new(millis(500), 30.0)
+                .key(Key::Left, millis(200), 60.0)
+                .no_repeat(Key::Enter),
+            enabled: true,
+            id: None,
+            physical: HashMap::new(),
+            queued: HashMap::new(),
+            os_auto_repeat: None,
+            polling: false,
+        }
+    }
+
+    #[test]
+    fn keys_repeat_with_their_own_settings_or_the_default_ones() {
+        let config = state().config;
+        let code = |key: Key| key.code().unwrap();
+        assert_eq!(config.settings(code(Key::Left)), Some(RepeatSettings::new(millis(200), 60.0)));
+        assert_eq!(config.settings(code(Key::A)), Some(RepeatSettings::new(millis(500), 30.0)));
+        assert_eq!(config.settings(code(Key::Enter)), None);
+        assert_eq!(config.settings(code(Key::LShift)), None);
+
+        let config = RepeaterConfig {
+            default: None,
+            keys: BTreeMap::new(),
+        }
+        .key(Key::Left, millis(200), 60.0);
+        assert_eq!(config.settings(code(Key::A)), None);
+        assert!(config.settings(code(Key::Left)).is_some());
+    }
+
+    #[test]
+    fn rates_out_of_range_are_clamped() {
+        let settings = |rate: f64| RepeatSettings { delay: millis(500), rate };
+        assert_eq!(settings(0.0).period(), Duration::from_secs(10));
+        assert_eq!(settings(f64::NAN).period(), Duration::from_secs(10));
+        assert_eq!(settings(f64::INFINITY).period(), millis(1));
+        assert_eq!(settings(50.0).period(), millis(20));
+    }
+
+    #[test]
+    fn the_repeats_of_the_os_are_swallowed() {
+        let start = Instant::now();
+        let mut state = state();
+        assert_eq!(state.filter(&key(Key::A, true), start), Outcome::Repeat(start + millis(500)));
+        let mut repeat = key(Key::A, true);
+        repeat.is_repeat = true;
+        assert_eq!(state.filter(&repeat, start + millis(500)), Outcome::Block);
+        assert_eq!(state.filter(&key(Key::A, false), start + millis(600)), Outcome::Pass);
+        assert!(state.physical.is_empty());
+    }
+
+    #[test]
+    fn keys_that_dont_repeat_pass() {
+        let start = Instant::now();
+        let mut state = state();
+        assert_eq!(state.filter(&key(Key::Enter, true), start), Outcome::Pass);
+        assert!(state.physical.is_empty());
+        assert_eq!(state.filter(&key(Key::Left, true), start), Outcome::Repeat(start + millis(200)));
+        let press = &state.physical[&key(Key::Left, true).device_key()];
+        assert!(press.press.as_ref().unwrap().is_repeat);
+    }
+
+    #[test]
+    fn a_disabled_repeater_lets_everything_through() {
+        let mut state = state();
+        state.enabled = false;
+        let mut repeat = key(Key::A, true);
+        repeat.is_repeat = true;
+        assert_eq!(state.filter(&repeat, Instant::now()), Outcome::Pass);
+    }
+}
@@ -0,0 +1,284 @@
+//! Slow keys: keys only register once held for a while.
+
+use super::timer::Timer;
+use device_events::{resume, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use keymap::DeviceKey;
+use KeyEvent;
+
+/// Priority of the slow keys filter among the pre-filters, after the bounce keys filter.
+const PRIORITY: i32 = 940;
+
+/// Configuration of a `SlowKeys` filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowKeysConfig {
+    /// How long a key must be held before its press registers.
+    pub delay: Duration,
+}
+
+impl SlowKeysConfig {
+    pub fn new(delay: Duration) -> Self {
+        SlowKeysConfig { delay }
+    }
+}
+
+enum KeyState {
+    /// The press is held back until the key has been held long enough.
+    Pending(u64),
+    /// The press was let through.
+    Accepted,
+}
+
+struct State {
+    config: SlowKeysConfig,
+    enabled: bool,
+    id: Option<HandlerId>,
+    keys: HashMap<DeviceKey, KeyState>,
+    /// Number of presses held back so far, telling them apart.
+    presses: u64,
+}
+
+impl State {
+    /// Fate of `event`, holding presses back.
+    fn filter(&mut self, event: &KeyEvent) -> Outcome {
+        if event.is_simulated {
+            return Outcome::Pass;
+        }
+        let device_key = event.device_key();
+
+        if !event.is_pressed {
+            return match self.keys.remove(&device_key) {
+                Some(KeyState::Pending(_)) => Outcome::Block,
+                _ => Outcome::Pass,
+            };
+        }
+        match self.keys.get(&device_key) {
+            Some(KeyState::Accepted) => return Outcome::Pass,
+            Some(KeyState::Pending(_)) => return Outcome::Block,
+            None if !self.enabled => return Outcome::Pass,
+            None => {}
+        }
+
+        self.presses += 1;
+        self.keys.insert(device_key, KeyState::Pending(self.presses));
+        Outcome::Hold(self.presses)
+    }
+
+    /// Whether the press `press` of `device_key` is still held back, and should be let
+    /// through now that its delay elapsed.
+    fn accept(&mut self, device_key: DeviceKey, press: u64) -> bool {
+        match self.keys.get(&device_key) {
+            Some(KeyState::Pending(pending)) if *pending == press => {}
+            _ => return false,
+        }
+        self.keys.insert(device_key, KeyState::Accepted);
+        true
+    }
+}
+
+/// What the filter does with an event.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pass,
+    Block,
+    /// Block the press, and let it through with this number once the delay elapsed.
+    Hold(u64),
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Holds the press of every key back until the key has been held for a delay, so that keys
+/// brushed by accident don't register. Keys released earlier are swallowed entirely. Keys are
+/// tracked separately on each device. Simulated events are left alone.
+///
+/// The filter can be combined with `BounceKeys` and `StickyKeys`.
+///
+/// ```no_run
+/// use key_director::{DeviceState, SlowKeys, SlowKeysConfig};
+/// use std::time::Duration;
+///
+/// let _device_state = DeviceState::new();
+/// let slow_keys = SlowKeys::install(SlowKeysConfig::new(Duration::from_millis(300)));
+/// slow_keys.set_config(SlowKeysConfig::new(Duration::from_millis(500)));
+/// ```
+///
+/// The filter is removed when dropped.
+pub struct SlowKeys {
+    id: HandlerId,
+    state: Shared,
+    _timer: Arc<Timer>,
+}
+
+impl SlowKeys {
+    /// Install the filter, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: SlowKeysConfig) -> SlowKeys {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: true,
+            id: None,
+            keys: HashMap::new(),
+            presses: 0,
+        }));
+        let timer = Arc::new(Timer::new());
+
+        let handler_state = Arc::downgrade(&state);
+        let handler_timer = Arc::downgrade(&timer);
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "slow keys",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match (handler_state.upgrade(), handler_timer.upgrade()) {
+                    (Some(state), Some(timer)) => filter(&state, &timer, event),
+                    _ => Verdict::Pass,
+                }),
+            );
+        state.lock().expect("Couldn't lock the slow keys state").id = Some(id);
+
+        SlowKeys {
+            id,
+            state,
+            _timer: timer,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the filter without removing it. Presses held back when the filter
+    /// gets disabled still wait for their delay.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.lock().enabled = enabled;
+    }
+
+    pub fn config(&self) -> SlowKeysConfig {
+        self.lock().config.clone()
+    }
+
+    /// Change the configuration, for the presses to come.
+    pub fn set_config(&self, config: SlowKeysConfig) {
+        self.lock().config = config;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the slow keys state")
+    }
+}
+
+impl Drop for SlowKeys {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(shared: &Shared, timer: &Timer, event: &KeyEvent) -> Verdict {
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return Verdict::Pass,
+    };
+    let press = match state.filter(event) {
+        Outcome::Pass => return Verdict::Pass,
+        Outcome::Block => return Verdict::Block,
+        Outcome::Hold(press) => press,
+    };
+    let weak_state = Arc::downgrade(shared);
+    let device_key = event.device_key();
+    let event = event.clone();
+    timer.schedule(Instant::now() + state.config.delay, move || {
+        accept(&weak_state, device_key, press, &event)
+    });
+    Verdict::Block
+}
+
+/// Let the press `press` through if its key is still held.
+fn accept(shared: &Weak<Mutex<State>>, device_key: DeviceKey, press: u64, event: &KeyEvent) {
+    let shared = match shared.upgrade() {
+        Some(shared) => shared,
+        None => return,
+    };
+    let id = {
+        let mut state = match shared.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if !state.accept(device_key, press) {
+            return;
+        }
+        match state.id {
+            Some(id) => id,
+            None => return,
+        }
+    };
+    resume(event, id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::key;
+    use Key;
+
+    fn state() -> State {
+        State {
+            config: SlowKeysConfig::new(Duration::from_millis(100)),
+            enabled: true,
+            id: None,
+            keys: HashMap::new(),
+            presses: 0,
+        }
+    }
+
+    #[test]
+    fn presses_held_long_enough_are_let_through() {
+        let mut state = state();
+        let press = key(Key::A, true);
+        assert_eq!(state.filter(&press), Outcome::Hold(1));
+        // Auto-repeats wait for the press.
+        assert_eq!(state.filter(&press), Outcome::Block);
+        assert!(state.accept(press.device_key(), 1));
+        assert_eq!(state.filter(&press), Outcome::Pass);
+        assert_eq!(state.filter(&key(Key::A, false)), Outcome::Pass);
+    }
+
+    #[test]
+    fn keys_released_before_the_delay_are_ignored() {
+        let mut state = state();
+        let press = key(Key::A, true);
+        assert_eq!(state.filter(&press), Outcome::Hold(1));
+        assert_eq!(state.filter(&key(Key::A, false)), Outcome::Block);
+        assert!(!state.accept(press.device_key(), 1));
+
+        // A new press of the key isn't let through by the timer of the old one.
+        assert_eq!(state.filter(&press), Outcome::Hold(2));
+        assert!(!state.accept(press.device_key(), 1));
+        assert!(state.accept(press.device_key(), 2));
+    }
+
+    #[test]
+    fn disabling_lets_keys_held_back_finish() {
+        let mut state = state();
+        let press = key(Key::A, true);
+        assert_eq!(state.filter(&press), Outcome::Hold(1));
+        state.enabled = false;
+        assert_eq!(state.filter(&key(Key::B, true)), Outcome::Pass);
+        assert_eq!(state.filter(&key(Key::A, false)), Outcome::Block);
+        assert_eq!(state.filter(&press), Outcome::Pass);
+    }
+
+    #[test]
+    fn simulated_events_pass() {
+        let mut state = state();
+        let press = KeyEvent::new(None, Key::A.code().unwrap(), 0, true, true);
+        assert_eq!(state.filter(&press), Outcome::Pass);
+        assert!(state.keys.is_empty());
+    }
+}
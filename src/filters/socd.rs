@@ -0,0 +1,228 @@
+//! Simultaneous opposing cardinal direction cleaning.
+
+use device_events::{resume_replacement, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::{InputAction, INJECTOR};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use {Key, KeyEvent};
+
+/// Priority of the SOCD filter among the pre-filters, right after the debounce filter.
+const PRIORITY: i32 = 900;
+
+/// Which key of an opposing pair is active while both are held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SocdPolicy {
+    /// The key pressed last. Releasing it restores the other one.
+    LastInputWins,
+    /// The key pressed first. Releasing it activates the other one.
+    FirstInputWins,
+    /// Neither of them. Releasing one activates the other one.
+    Neutral,
+}
+
+/// Configuration of a `Socd` filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocdConfig {
+    pub policy: SocdPolicy,
+    /// Pairs of opposing keys, such as `(Key::A, Key::D)`. A key only belongs to its first pair.
+    pub pairs: Vec<(Key, Key)>,
+}
+
+impl SocdConfig {
+    pub fn new(policy: SocdPolicy) -> Self {
+        SocdConfig {
+            policy,
+            pairs: Vec::new(),
+        }
+    }
+
+    pub fn pair(mut self, first: Key, second: Key) -> Self {
+        self.pairs.push((first, second));
+        self
+    }
+}
+
+/// State of an opposing pair.
+struct Pair {
+    codes: [u32; 2],
+    /// Whether each key is physically held.
+    held: [bool; 2],
+    /// Whether each key is pressed as far as the OS knows.
+    active: [bool; 2],
+    /// Key of the pair pressed last.
+    last: usize,
+}
+
+impl Pair {
+    fn desired(&self, policy: SocdPolicy) -> [bool; 2] {
+        if !(self.held[0] && self.held[1]) {
+            return self.held;
+        }
+        match policy {
+            SocdPolicy::LastInputWins => [self.last == 0, self.last == 1],
+            SocdPolicy::FirstInputWins => [self.last == 1, self.last == 0],
+            SocdPolicy::Neutral => [false, false],
+        }
+    }
+}
+
+/// Resolves opposing keys held together, e.g. for movement keys in games. The event of a key
+/// is blocked when it shouldn't change the state of the key; the other key of the pair is
+/// pressed or released in its place, releases first. Every key is active only while
+/// physically held, so no direction can get stuck.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Key, Socd, SocdConfig, SocdPolicy};
+///
+/// let _device_state = DeviceState::new();
+/// let _socd = Socd::install(
+///     SocdConfig::new(SocdPolicy::LastInputWins)
+///         .pair(Key::A, Key::D)
+///         .pair(Key::W, Key::S),
+/// );
+/// ```
+///
+/// The filter is removed when dropped.
+pub struct Socd {
+    id: HandlerId,
+}
+
+impl Socd {
+    /// Install the filter in the `Stage::PreFilter` stage of the callback chain. Pairs with a
+    /// key that doesn't exist on the platform are ignored.
+    pub fn install(config: SocdConfig) -> Socd {
+        let pairs: Vec<Pair> = config
+            .pairs
+            .iter()
+            .filter_map(|(first, second)| {
+                Some(Pair {
+                    codes: [first.code()?, second.code()?],
+                    held: [false; 2],
+                    active: [false; 2],
+                    last: 0,
+                })
+            })
+            .collect();
+        let policy = config.policy;
+        let pairs = Mutex::new(pairs);
+
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "socd",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| {
+                    let verdict = match pairs.lock() {
+                        Ok(mut pairs) => filter(&mut pairs, policy, event),
+                        Err(_) => Verdict::Pass,
+                    };
+                    resume_replacement(event, verdict)
+                }),
+            );
+        Socd { id }
+    }
+}
+
+impl Drop for Socd {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(pairs: &mut [Pair], policy: SocdPolicy, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    let (pair, index) = match pairs
+        .iter_mut()
+        .find_map(|pair| Some((pair.codes.iter().position(|code| *code == event.key_code)?, pair)))
+    {
+        Some((index, pair)) => (pair, index),
+        None => return Verdict::Pass,
+    };
+
+    if event.is_pressed && !pair.held[index] {
+        pair.last = index;
+    }
+    pair.held[index] = event.is_pressed;
+    let desired = pair.desired(policy);
+    // Presses of a key that stays active are auto-repeats, and go through.
+    let pass = if event.is_pressed { desired[index] } else { pair.active[index] };
+    pair.active[index] = desired[index];
+
+    let other = 1 - index;
+    if desired[other] == pair.active[other] {
+        if !pass {
+            return Verdict::Block;
+        }
+        if !event.is_pressed {
+            // The press may have been emitted: let the injector know the key got released.
+            INJECTOR.record(&[InputAction::KeyUp(pair.codes[index])]);
+        }
+        return Verdict::Pass;
+    }
+
+    // The other key changes along with the event, in the same verdict so that the OS never
+    // sees both keys pressed: releases go first.
+    pair.active[other] = desired[other];
+    let other_event = KeyEvent::new(None, pair.codes[other], 0, desired[other], true);
+    Verdict::Replace(match (pass, desired[other]) {
+        (false, _) => vec![other_event],
+        (true, true) => vec![event.clone(), other_event],
+        (true, false) => vec![other_event, event.clone()],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Key, is_pressed: bool, is_simulated: bool) -> KeyEvent {
+        KeyEvent::new(None, key.code().unwrap(), 0, is_pressed, is_simulated)
+    }
+
+    fn pairs() -> Vec<Pair> {
+        vec![Pair {
+            codes: [Key::A.code().unwrap(), Key::D.code().unwrap()],
+            held: [false; 2],
+            active: [false; 2],
+            last: 0,
+        }]
+    }
+
+    #[test]
+    fn last_input_wins_releases_the_other_key_before_pressing() {
+        let mut pairs = pairs();
+        let policy = SocdPolicy::LastInputWins;
+        assert_eq!(filter(&mut pairs, policy, &key(Key::A, true, false)), Verdict::Pass);
+        assert_eq!(
+            filter(&mut pairs, policy, &key(Key::D, true, false)),
+            Verdict::Replace(vec![key(Key::A, false, true), key(Key::D, true, false)])
+        );
+        assert_eq!(
+            filter(&mut pairs, policy, &key(Key::D, false, false)),
+            Verdict::Replace(vec![key(Key::D, false, false), key(Key::A, true, true)])
+        );
+        assert_eq!(filter(&mut pairs, policy, &key(Key::A, false, false)), Verdict::Pass);
+    }
+
+    #[test]
+    fn neutral_releases_both_keys() {
+        let mut pairs = pairs();
+        let policy = SocdPolicy::Neutral;
+        assert_eq!(filter(&mut pairs, policy, &key(Key::A, true, false)), Verdict::Pass);
+        assert_eq!(
+            filter(&mut pairs, policy, &key(Key::D, true, false)),
+            Verdict::Replace(vec![key(Key::A, false, true)])
+        );
+        assert_eq!(
+            filter(&mut pairs, policy, &key(Key::A, false, false)),
+            Verdict::Replace(vec![key(Key::D, true, true)])
+        );
+    }
+}
@@ -0,0 +1,371 @@
+//! Sticky keys: modifiers latched or locked by tapping them.
+
+use device_events::{resume_replacement, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::{InputAction, INJECTOR};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use {Key, KeyEvent, Modifiers};
+
+/// Priority of the sticky keys filter among the pre-filters, after the slow keys filter.
+const PRIORITY: i32 = 930;
+
+/// Configuration of a `StickyKeys` filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StickyKeysConfig {
+    /// Modifiers that stick when tapped.
+    pub modifiers: Modifiers,
+    /// Whether tapping a latched modifier again locks it, until it is tapped once more.
+    pub lock: bool,
+}
+
+impl StickyKeysConfig {
+    /// Make every modifier sticky, with locking.
+    pub fn new() -> Self {
+        StickyKeysConfig {
+            modifiers: Modifiers::SHIFT | Modifiers::CTRL | Modifiers::ALT | Modifiers::META,
+            lock: true,
+        }
+    }
+
+    /// Make only `modifiers` sticky.
+    pub fn modifiers(mut self, modifiers: Modifiers) -> Self {
+        self.modifiers = modifiers;
+        self
+    }
+
+    /// Don't lock modifiers tapped twice: the second tap releases them.
+    pub fn no_lock(mut self) -> Self {
+        self.lock = false;
+        self
+    }
+}
+
+impl Default for StickyKeysConfig {
+    fn default() -> Self {
+        StickyKeysConfig::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Latch {
+    Off,
+    /// Held down for the next key.
+    Latched,
+    /// Held down until tapped again.
+    Locked,
+}
+
+struct ModifierKey {
+    modifier: Modifiers,
+    latch: Latch,
+    /// Whether the key is physically held.
+    held: bool,
+    /// Whether no other key was pressed since the key was.
+    tapped: bool,
+}
+
+struct State {
+    config: StickyKeysConfig,
+    enabled: bool,
+    /// Modifier keys seen so far, by key code.
+    modifiers: HashMap<u32, ModifierKey>,
+    /// Latched modifiers to release along with the key that used them, by key code.
+    used: HashMap<u32, Vec<u32>>,
+}
+
+impl State {
+    fn sticky(&self, latch: Latch) -> Modifiers {
+        let mut modifiers = Modifiers::NONE;
+        for key in self.modifiers.values().filter(|key| key.latch == latch) {
+            modifiers.insert(key.modifier);
+        }
+        modifiers
+    }
+
+    /// Release every latched or locked modifier.
+    fn release_all(&mut self) {
+        let mut releases = Vec::new();
+        for (code, key) in self.modifiers.iter_mut() {
+            if key.latch != Latch::Off && !key.held {
+                releases.push(InputAction::KeyUp(*code));
+            }
+            key.latch = Latch::Off;
+        }
+        releases.extend(self.used.drain().flat_map(|(_, codes)| codes).map(InputAction::KeyUp));
+        if !releases.is_empty() {
+            INJECTOR.queue(releases);
+        }
+    }
+}
+
+/// Makes modifiers stick for users who can't hold several keys at once. Tapping a modifier
+/// latches it: it stays down until the next other key is released. Tapping it again locks it
+/// down until the next tap. Modifiers used in a chord behave as usual. Simulated events are
+/// left alone.
+///
+/// Modifiers are held down by blocking their release, and released along with the key using
+/// them or with injected input. The state is shared by all the devices. The filter can be
+/// combined with `BounceKeys` and `SlowKeys`.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Modifiers, StickyKeys, StickyKeysConfig};
+///
+/// let _device_state = DeviceState::new();
+/// let sticky_keys = StickyKeys::install(
+///     StickyKeysConfig::new().modifiers(Modifiers::SHIFT | Modifiers::CTRL),
+/// );
+/// println!("Latched: {:?}", sticky_keys.latched());
+/// ```
+///
+/// The filter is removed when dropped, releasing the modifiers it holds down.
+pub struct StickyKeys {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+}
+
+impl StickyKeys {
+    /// Install the filter, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: StickyKeysConfig) -> StickyKeys {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: true,
+            modifiers: HashMap::new(),
+            used: HashMap::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "sticky keys",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| {
+                    let verdict = match handler_state.lock() {
+                        Ok(mut state) => filter(&mut state, event),
+                        Err(_) => Verdict::Pass,
+                    };
+                    resume_replacement(event, verdict)
+                }),
+            );
+        StickyKeys { id, state }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the filter without removing it. Disabling it releases the modifiers
+    /// it holds down.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.lock();
+        if !enabled {
+            state.release_all();
+        }
+        state.enabled = enabled;
+    }
+
+    pub fn config(&self) -> StickyKeysConfig {
+        self.lock().config.clone()
+    }
+
+    /// Change the configuration, releasing the modifiers held down.
+    pub fn set_config(&self, config: StickyKeysConfig) {
+        let mut state = self.lock();
+        state.release_all();
+        state.config = config;
+    }
+
+    /// Modifiers latched for the next key.
+    pub fn latched(&self) -> Modifiers {
+        self.lock().sticky(Latch::Latched)
+    }
+
+    /// Modifiers locked down.
+    pub fn locked(&self) -> Modifiers {
+        self.lock().sticky(Latch::Locked)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the sticky keys state")
+    }
+}
+
+impl Drop for StickyKeys {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+        if let Ok(mut state) = self.state.lock() {
+            state.release_all();
+        }
+    }
+}
+
+fn filter(state: &mut State, event: &KeyEvent) -> Verdict {
+    if event.is_simulated || !state.enabled {
+        return Verdict::Pass;
+    }
+    let code = event.key_code;
+    let modifier = Key::from_code(code)
+        .and_then(Key::modifier)
+        .filter(|modifier| state.config.modifiers.contains(*modifier));
+
+    if event.is_pressed {
+        for (modifier_code, key) in state.modifiers.iter_mut() {
+            if key.held && *modifier_code != code {
+                key.tapped = false;
+            }
+        }
+    }
+
+    let modifier = match modifier {
+        Some(modifier) => modifier,
+        None if event.is_pressed => {
+            // Auto-repeats of a key that already used the latched modifiers find none.
+            let mut latched = Vec::new();
+            for (modifier_code, key) in state.modifiers.iter_mut() {
+                if key.latch == Latch::Latched && !key.held {
+                    key.latch = Latch::Off;
+                    latched.push(*modifier_code);
+                }
+            }
+            if !latched.is_empty() {
+                state.used.entry(code).or_default().extend(latched);
+            }
+            return Verdict::Pass;
+        }
+        None => {
+            return match state.used.remove(&code) {
+                Some(codes) => {
+                    let mut events = vec![event.clone()];
+                    events.extend(
+                        codes
+                            .into_iter()
+                            .map(|code| KeyEvent::new(None, code, 0, false, true)),
+                    );
+                    Verdict::Replace(events)
+                }
+                None => Verdict::Pass,
+            };
+        }
+    };
+
+    let lock = state.config.lock;
+    let key = state.modifiers.entry(code).or_insert(ModifierKey {
+        modifier,
+        latch: Latch::Off,
+        held: false,
+        tapped: false,
+    });
+    if event.is_pressed {
+        if !key.held {
+            key.held = true;
+            key.tapped = true;
+        }
+        return Verdict::Pass;
+    }
+
+    key.held = false;
+    if !key.tapped {
+        key.latch = Latch::Off;
+        return Verdict::Pass;
+    }
+    key.latch = match key.latch {
+        Latch::Off => Latch::Latched,
+        Latch::Latched if lock => Latch::Locked,
+        Latch::Latched | Latch::Locked => Latch::Off,
+    };
+    if key.latch == Latch::Off {
+        Verdict::Pass
+    } else {
+        Verdict::Block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::key;
+
+    fn state(config: StickyKeysConfig) -> State {
+        State {
+            config,
+            enabled: true,
+            modifiers: HashMap::new(),
+            used: HashMap::new(),
+        }
+    }
+
+    fn tap(state: &mut State, key_: Key) -> Verdict {
+        assert_eq!(filter(state, &key(key_, true)), Verdict::Pass);
+        filter(state, &key(key_, false))
+    }
+
+    #[test]
+    fn tapped_modifiers_latch_until_the_next_key_is_released() {
+        let mut state = state(StickyKeysConfig::new());
+        assert_eq!(tap(&mut state, Key::LShift), Verdict::Block);
+        assert_eq!(state.sticky(Latch::Latched), Modifiers::SHIFT);
+
+        assert_eq!(filter(&mut state, &key(Key::A, true)), Verdict::Pass);
+        assert_eq!(state.sticky(Latch::Latched), Modifiers::NONE);
+        // The auto-repeat finds the modifier already used.
+        assert_eq!(filter(&mut state, &key(Key::A, true)), Verdict::Pass);
+        assert_eq!(
+            filter(&mut state, &key(Key::A, false)),
+            Verdict::Replace(vec![
+                key(Key::A, false),
+                KeyEvent::new(None, Key::LShift.code().unwrap(), 0, false, true),
+            ])
+        );
+        assert_eq!(filter(&mut state, &key(Key::B, true)), Verdict::Pass);
+        assert_eq!(filter(&mut state, &key(Key::B, false)), Verdict::Pass);
+    }
+
+    #[test]
+    fn tapping_a_latched_modifier_locks_it_then_releases_it() {
+        let mut state = state(StickyKeysConfig::new());
+        assert_eq!(tap(&mut state, Key::LControl), Verdict::Block);
+        assert_eq!(tap(&mut state, Key::LControl), Verdict::Block);
+        assert_eq!(state.sticky(Latch::Locked), Modifiers::CTRL);
+
+        for _ in 0..2 {
+            assert_eq!(tap(&mut state, Key::A), Verdict::Pass);
+        }
+        assert_eq!(state.sticky(Latch::Locked), Modifiers::CTRL);
+        assert_eq!(tap(&mut state, Key::LControl), Verdict::Pass);
+        assert_eq!(state.sticky(Latch::Locked), Modifiers::NONE);
+    }
+
+    #[test]
+    fn without_locking_the_second_tap_releases_the_modifier() {
+        let mut state = state(StickyKeysConfig::new().no_lock());
+        assert_eq!(tap(&mut state, Key::LAlt), Verdict::Block);
+        assert_eq!(tap(&mut state, Key::LAlt), Verdict::Pass);
+        assert_eq!(state.sticky(Latch::Latched), Modifiers::NONE);
+        assert_eq!(state.sticky(Latch::Locked), Modifiers::NONE);
+    }
+
+    #[test]
+    fn modifiers_held_in_a_chord_behave_as_usual() {
+        let mut state = state(StickyKeysConfig::new());
+        assert_eq!(filter(&mut state, &key(Key::LShift, true)), Verdict::Pass);
+        assert_eq!(tap(&mut state, Key::A), Verdict::Pass);
+        assert_eq!(filter(&mut state, &key(Key::LShift, false)), Verdict::Pass);
+        assert_eq!(state.sticky(Latch::Latched), Modifiers::NONE);
+    }
+
+    #[test]
+    fn modifiers_left_out_of_the_config_and_simulated_events_pass() {
+        let mut state = state(StickyKeysConfig::new().modifiers(Modifiers::SHIFT));
+        assert_eq!(tap(&mut state, Key::LControl), Verdict::Pass);
+        let simulated = KeyEvent::new(None, Key::LShift.code().unwrap(), 0, false, true);
+        assert_eq!(filter(&mut state, &key(Key::LShift, true)), Verdict::Pass);
+        assert_eq!(filter(&mut state, &simulated), Verdict::Pass);
+        assert_eq!(state.sticky(Latch::Latched), Modifiers::NONE);
+    }
+}
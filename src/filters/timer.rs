@@ -0,0 +1,82 @@
+//! Background timer of the filters.
+
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Tasks {
+    scheduled: Vec<(Instant, Task)>,
+    stopped: bool,
+}
+
+/// Runs tasks at given instants on a background thread, in chronological order. Tasks still
+/// scheduled when the timer is dropped never run.
+pub(crate) struct Timer {
+    shared: Arc<(Mutex<Tasks>, Condvar)>,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        let shared = Arc::new((Mutex::new(Tasks::default()), Condvar::new()));
+        let worker = shared.clone();
+        thread::spawn(move || {
+            let (ref tasks, ref changed) = *worker;
+            let mut tasks = tasks.lock().unwrap_or_else(PoisonError::into_inner);
+            loop {
+                if tasks.stopped {
+                    return;
+                }
+                let next = tasks
+                    .scheduled
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, (at, _))| *at)
+                    .map(|(index, (at, _))| (index, *at));
+                tasks = match next {
+                    Some((index, at)) if at <= Instant::now() => {
+                        let (_, task) = tasks.scheduled.remove(index);
+                        drop(tasks);
+                        task();
+                        worker.0.lock().unwrap_or_else(PoisonError::into_inner)
+                    }
+                    Some((_, at)) => {
+                        let timeout = at.saturating_duration_since(Instant::now());
+                        changed
+                            .wait_timeout(tasks, timeout)
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .0
+                    }
+                    None => changed.wait(tasks).unwrap_or_else(PoisonError::into_inner),
+                };
+            }
+        });
+        Timer { shared }
+    }
+
+    /// Run `task` at `at`, or as soon as possible if it is already past.
+    pub fn schedule<F>(&self, at: Instant, task: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let (ref tasks, ref changed) = *self.shared;
+        tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .scheduled
+            .push((at, Box::new(task)));
+        changed.notify_all();
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let (ref tasks, ref changed) = *self.shared;
+        let mut tasks = tasks.lock().unwrap_or_else(PoisonError::into_inner);
+        tasks.stopped = true;
+        tasks.scheduled.clear();
+        changed.notify_all();
+    }
+}
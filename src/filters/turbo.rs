@@ -0,0 +1,355 @@
+//! Autofire of a key or mouse button while a key is held.
+
+use super::timer::Timer;
+use device_events::{HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::{InputAction, MouseButton, INJECTOR};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use {Key, KeyEvent};
+
+/// Priority of the turbo filter among the pre-filters, after the filters cleaning up input.
+const PRIORITY: i32 = 800;
+
+/// Range of the rates the filter fires at, in presses per second.
+const MIN_RATE: f64 = 0.1;
+const MAX_RATE: f64 = 1000.0;
+
+/// What a `Turbo` filter fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TurboTarget {
+    Key(Key),
+    Mouse(MouseButton),
+}
+
+impl TurboTarget {
+    fn actions(self) -> Option<(InputAction, InputAction)> {
+        match self {
+            TurboTarget::Key(key) => {
+                let code = key.code()?;
+                Some((InputAction::KeyDown(code), InputAction::KeyUp(code)))
+            }
+            TurboTarget::Mouse(button) => {
+                Some((InputAction::MouseDown(button), InputAction::MouseUp(button)))
+            }
+        }
+    }
+}
+
+/// Configuration of a `Turbo` filter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TurboConfig {
+    /// Physical key firing the target. Its own events are swallowed.
+    pub trigger: Key,
+    pub target: TurboTarget,
+    /// Presses of the target per second, between 0.1 and 1000.
+    pub rate: f64,
+    /// Fraction of each period during which the target is held, between 0 and 1.
+    pub duty_cycle: f64,
+    /// Whether pressing the trigger starts firing until it is pressed again, instead of firing
+    /// while it is held.
+    #[serde(default)]
+    pub toggle: bool,
+}
+
+impl TurboConfig {
+    /// Fire `target` `rate` times per second while `trigger` is held, with a duty cycle of 0.5.
+    pub fn new(trigger: Key, target: TurboTarget, rate: f64) -> Self {
+        assert!(rate > 0.0, "Turbo rate must be positive");
+        TurboConfig {
+            trigger,
+            target,
+            rate,
+            duty_cycle: 0.5,
+            toggle: false,
+        }
+    }
+
+    pub fn duty_cycle(mut self, duty_cycle: f64) -> Self {
+        assert!(
+            duty_cycle > 0.0 && duty_cycle < 1.0,
+            "Turbo duty cycle must be between 0 and 1"
+        );
+        self.duty_cycle = duty_cycle;
+        self
+    }
+
+    pub fn toggle(mut self) -> Self {
+        self.toggle = true;
+        self
+    }
+}
+
+/// When the target is pressed and released.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Timing {
+    period: Duration,
+    hold: Duration,
+}
+
+impl Timing {
+    /// Timing of `rate` presses per second with a `duty_cycle`, brought into their ranges, so
+    /// that deserialized configurations can't stop the timer.
+    fn new(rate: f64, duty_cycle: f64) -> Self {
+        // Comparisons are false for NaN.
+        let rate = if rate >= MIN_RATE { rate.min(MAX_RATE) } else { MIN_RATE };
+        let duty_cycle = if duty_cycle >= 0.0 { duty_cycle.min(1.0) } else { 0.0 };
+        let period = Duration::from_secs_f64(1.0 / rate);
+        Timing {
+            period,
+            hold: period.mul_f64(duty_cycle),
+        }
+    }
+
+    /// When to release the target pressed `at` that instant.
+    fn release_at(&self, pressed_at: Instant) -> Instant {
+        pressed_at + self.hold
+    }
+
+    /// When to press the target again after pressing it `at` that instant. Pulses are timed
+    /// from when they were due rather than from when they ran, so the rate doesn't drift.
+    fn next_press_at(&self, pressed_at: Instant) -> Instant {
+        pressed_at + self.period
+    }
+}
+
+struct State {
+    /// Key code of the trigger, if the filter can do anything.
+    trigger: Option<u32>,
+    press: InputAction,
+    release: InputAction,
+    timing: Timing,
+    toggle: bool,
+    /// Whether the trigger is physically held.
+    held: bool,
+    /// Whether the target is being fired.
+    firing: bool,
+    /// Whether the target is pressed by the last injected input.
+    target_down: bool,
+    /// Incremented whenever firing stops, so that the pulses scheduled before are dropped.
+    generation: u64,
+    /// `Injector::epoch` when firing started.
+    epoch: u64,
+}
+
+impl State {
+    fn start(&mut self, shared: &Shared, timer: &Arc<Timer>) {
+        self.firing = true;
+        self.epoch = INJECTOR.epoch();
+        schedule_pulse(shared, timer, Instant::now(), self.generation);
+    }
+
+    fn stop(&mut self) {
+        self.firing = false;
+        self.generation += 1;
+        if self.target_down {
+            self.target_down = false;
+            INJECTOR.queue(vec![self.release.clone()]);
+        }
+    }
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Fires a key or mouse button while a key is held, by injecting presses and releases of the
+/// target. Simulated events are left alone, so the injected input never retriggers the filter,
+/// even when the target is the trigger itself.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Key, MouseButton, Turbo, TurboConfig, TurboTarget};
+///
+/// let _device_state = DeviceState::new();
+/// let _turbo = Turbo::install(
+///     TurboConfig::new(Key::F, TurboTarget::Mouse(MouseButton::Left), 15.0).duty_cycle(0.3),
+/// );
+/// ```
+///
+/// Firing stops, releasing the target, as soon as the trigger is released (or pressed again
+/// in toggle mode), when the filter is dropped, and on `DeviceState::release_all`, which is
+/// also called when the `DeviceState` is dropped.
+pub struct Turbo {
+    id: HandlerId,
+    state: Shared,
+    _timer: Arc<Timer>,
+}
+
+impl Turbo {
+    /// Install the filter in the `Stage::PreFilter` stage of the callback chain. A filter whose
+    /// trigger or target key doesn't exist on the platform does nothing. A rate or duty cycle
+    /// out of its range, e.g. from a deserialized configuration, is brought into it.
+    pub fn install(config: TurboConfig) -> Turbo {
+        let actions = config.target.actions();
+        let trigger = actions.as_ref().and(config.trigger.code());
+        let (press, release) =
+            actions.unwrap_or((InputAction::KeyDown(0), InputAction::KeyUp(0)));
+        let state = Arc::new(Mutex::new(State {
+            trigger,
+            press,
+            release,
+            timing: Timing::new(config.rate, config.duty_cycle),
+            toggle: config.toggle,
+            held: false,
+            firing: false,
+            target_down: false,
+            generation: 0,
+            epoch: 0,
+        }));
+        let timer = Arc::new(Timer::new());
+
+        let handler_state = Arc::downgrade(&state);
+        let handler_timer = Arc::downgrade(&timer);
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "turbo",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match (handler_state.upgrade(), handler_timer.upgrade()) {
+                    (Some(state), Some(timer)) => filter(&state, &timer, event),
+                    _ => Verdict::Pass,
+                }),
+            );
+
+        Turbo {
+            id,
+            state,
+            _timer: timer,
+        }
+    }
+
+    pub fn is_firing(&self) -> bool {
+        self.state.lock().map(|state| state.firing).unwrap_or(false)
+    }
+}
+
+impl Drop for Turbo {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+        if let Ok(mut state) = self.state.lock() {
+            state.stop();
+        }
+    }
+}
+
+fn filter(shared: &Shared, timer: &Arc<Timer>, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return Verdict::Pass,
+    };
+    if Some(event.key_code) != state.trigger {
+        return Verdict::Pass;
+    }
+
+    let was_held = state.held;
+    state.held = event.is_pressed;
+    if state.firing && state.epoch != INJECTOR.epoch() {
+        // Everything injected was released in the meantime.
+        state.target_down = false;
+        state.stop();
+    }
+    match (event.is_pressed, was_held) {
+        // Auto-repeat of the trigger.
+        (true, true) => {}
+        (true, false) if state.toggle && state.firing => state.stop(),
+        (true, false) => state.start(shared, timer),
+        (false, _) if !state.toggle => state.stop(),
+        (false, _) => {}
+    }
+    Verdict::Block
+}
+
+fn schedule_pulse(shared: &Shared, timer: &Arc<Timer>, at: Instant, generation: u64) {
+    let weak_state = Arc::downgrade(shared);
+    let weak_timer = Arc::downgrade(timer);
+    timer.schedule(at, move || pulse(&weak_state, &weak_timer, at, generation, true));
+}
+
+/// Press the target at `at` if `down`, else release it, and schedule the next pulse, as long
+/// as firing hasn't stopped since `generation`.
+fn pulse(
+    shared: &Weak<Mutex<State>>,
+    timer: &Weak<Timer>,
+    at: Instant,
+    generation: u64,
+    down: bool,
+) {
+    let (shared, timer) = match (shared.upgrade(), timer.upgrade()) {
+        (Some(shared), Some(timer)) => (shared, timer),
+        _ => return,
+    };
+    let mut state = match shared.lock() {
+        Ok(state) => state,
+        Err(_) => return,
+    };
+    if !state.firing || state.generation != generation {
+        return;
+    }
+    if state.epoch != INJECTOR.epoch() {
+        state.target_down = false;
+        state.stop();
+        return;
+    }
+
+    if down {
+        state.target_down = true;
+        INJECTOR.queue(vec![state.press.clone()]);
+        let release_at = state.timing.release_at(at);
+        let weak_state = Arc::downgrade(&shared);
+        let weak_timer = Arc::downgrade(&timer);
+        timer.schedule(release_at, move || {
+            pulse(&weak_state, &weak_timer, at, generation, false)
+        });
+    } else {
+        state.target_down = false;
+        INJECTOR.queue(vec![state.release.clone()]);
+        let next_press_at = state.timing.next_press_at(at);
+        schedule_pulse(&shared, &timer, next_press_at, generation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::millis;
+
+    #[test]
+    fn the_rate_and_duty_cycle_set_the_period_and_hold() {
+        let timing = Timing::new(10.0, 0.3);
+        assert_eq!(timing.period, millis(100));
+        assert_eq!(timing.hold, millis(30));
+    }
+
+    #[test]
+    fn rates_out_of_range_are_clamped() {
+        assert_eq!(Timing::new(0.0, 0.5).period, Duration::from_secs(10));
+        assert_eq!(Timing::new(-5.0, 0.5).period, Duration::from_secs(10));
+        assert_eq!(Timing::new(f64::NAN, 0.5).period, Duration::from_secs(10));
+        assert_eq!(Timing::new(f64::INFINITY, 0.5).period, millis(1));
+    }
+
+    #[test]
+    fn duty_cycles_out_of_range_are_clamped() {
+        assert_eq!(Timing::new(10.0, -1.0).hold, Duration::from_secs(0));
+        assert_eq!(Timing::new(10.0, 2.0).hold, millis(100));
+        assert_eq!(Timing::new(10.0, f64::NAN).hold, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn pulses_are_timed_from_when_they_were_due() {
+        let timing = Timing::new(20.0, 0.25);
+        let start = Instant::now();
+        let mut pressed_at = start;
+        for _ in 0..3 {
+            assert_eq!(timing.release_at(pressed_at) - pressed_at, millis(12) + Duration::from_micros(500));
+            pressed_at = timing.next_press_at(pressed_at);
+        }
+        assert_eq!(pressed_at - start, millis(150));
+    }
+}
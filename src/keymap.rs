@@ -1,4 +1,16 @@
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::ops::{BitOr, BitOrAssign};
+use std::sync::Arc;
+use device_state::{InputDevice, KEY_CODES};
+
+lazy_static! {
+    /// `KEY_CODES` indexed both ways, since filters convert key codes on every event. Built in
+    /// reverse so that the first entry of a key or code listed twice wins.
+    static ref CODES_BY_KEY: HashMap<Key, u32> = KEY_CODES.iter().rev().cloned().collect();
+    static ref KEYS_BY_CODE: HashMap<u32, Key> =
+        KEY_CODES.iter().rev().map(|(key, code)| (*code, *key)).collect();
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct KeyEvent {
@@ -6,17 +18,322 @@ pub struct KeyEvent {
     pub key_code: u32,
     pub scan_code: u32,
     pub is_pressed: bool,
-    pub is_simulated: bool
+    pub is_simulated: bool,
+    /// Device the event came from, when the backend knows it. Only the evdev backend on Linux
+    /// does for now: the low-level hooks of Windows and macOS don't tell devices apart.
+    #[serde(default)]
+    pub device: Option<Arc<InputDevice>>,
+    /// Whether the press repeats the one of a key held down, from the auto-repeat of the OS or
+    /// of a `Repeater`.
+    #[serde(default)]
+    pub is_repeat: bool
 }
 
 impl KeyEvent {
+    /// Key code of events that carry nothing but a character, such as the ones created by
+    /// `KeyEvent::unicode`. Backends type them as text instead of pressing a key.
+    pub const UNICODE_KEY_CODE: u32 = u32::MAX;
+
     pub fn new(character: Option<char>, key_code: u32, scan_code: u32, is_pressed: bool, is_simulated: bool) -> Self {
         KeyEvent {
             char: character,
             key_code,
             scan_code,
             is_pressed,
-            is_simulated
+            is_simulated,
+            device: None,
+            is_repeat: false
         }
     }
+
+    /// Event typing `character` regardless of the keyboard layout, flagged as simulated.
+    pub fn unicode(character: char, is_pressed: bool) -> Self {
+        KeyEvent::new(Some(character), Self::UNICODE_KEY_CODE, 0, is_pressed, true)
+    }
+
+    /// Identifies the physical key of the event: its key code on its device.
+    pub(crate) fn device_key(&self) -> DeviceKey {
+        (self.device.as_ref().map(|device| device.number()), self.key_code)
+    }
+}
+
+/// Physical key: the number of its device, see `InputDevice::number`, and its key code.
+pub(crate) type DeviceKey = (Option<u32>, u32);
+
+/// Platform independent key identifiers. Convert them to the platform key codes carried by
+/// `KeyEvent::key_code` with `Key::code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Key {
+    Key0,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+    I,
+    J,
+    K,
+    L,
+    M,
+    N,
+    O,
+    P,
+    Q,
+    R,
+    S,
+    T,
+    U,
+    V,
+    W,
+    X,
+    Y,
+    Z,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    F13,
+    F14,
+    F15,
+    F16,
+    F17,
+    F18,
+    F19,
+    F20,
+    F21,
+    F22,
+    F23,
+    F24,
+    Escape,
+    Space,
+    LControl,
+    RControl,
+    LShift,
+    RShift,
+    LAlt,
+    RAlt,
+    LMeta,
+    RMeta,
+    Menu,
+    Enter,
+    Up,
+    Down,
+    Left,
+    Right,
+    Backspace,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    Tab,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    PrintScreen,
+    Pause,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+    NumpadSubtract,
+    NumpadAdd,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadDecimal,
+    NumpadEnter,
+    Grave,
+    Minus,
+    Equal,
+    LeftBracket,
+    RightBracket,
+    BackSlash,
+    Semicolon,
+    Apostrophe,
+    Comma,
+    Dot,
+    Slash,
+}
+
+impl Key {
+    /// Platform key code of this key, or `None` if the platform has no such key.
+    pub fn code(self) -> Option<u32> {
+        CODES_BY_KEY.get(&self).cloned()
+    }
+
+    /// Key with the given platform key code.
+    pub fn from_code(code: u32) -> Option<Key> {
+        KEYS_BY_CODE.get(&code).cloned()
+    }
+
+    /// Modifier held down by this key, if it is a modifier key.
+    pub fn modifier(self) -> Option<Modifiers> {
+        match self {
+            Key::LShift | Key::RShift => Some(Modifiers::SHIFT),
+            Key::LControl | Key::RControl => Some(Modifiers::CTRL),
+            Key::LAlt | Key::RAlt => Some(Modifiers::ALT),
+            Key::LMeta | Key::RMeta => Some(Modifiers::META),
+            _ => None,
+        }
+    }
+
+    /// Character typed by this key on a US QWERTY layout, with or without Shift. Serves when
+    /// the backend doesn't translate key events to characters.
+    pub fn us_char(self, shift: bool) -> Option<char> {
+        US_LAYOUT
+            .iter()
+            .find(|(key, _, _)| *key == self)
+            .map(|(_, plain, shifted)| if shift { *shifted } else { *plain })
+    }
+
+    /// Key typing `character` on a US QWERTY layout, and whether with Shift. Keys of the main
+    /// block are preferred over the numpad.
+    pub fn from_us_char(character: char) -> Option<(Key, bool)> {
+        US_LAYOUT.iter().find_map(|(key, plain, shifted)| {
+            if *plain == character {
+                Some((*key, false))
+            } else if *shifted == character {
+                Some((*key, true))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Characters of the keys on a US QWERTY layout, without and with Shift.
+const US_LAYOUT: &[(Key, char, char)] = &[
+    (Key::Key0, '0', ')'),
+    (Key::Key1, '1', '!'),
+    (Key::Key2, '2', '@'),
+    (Key::Key3, '3', '#'),
+    (Key::Key4, '4', '$'),
+    (Key::Key5, '5', '%'),
+    (Key::Key6, '6', '^'),
+    (Key::Key7, '7', '&'),
+    (Key::Key8, '8', '*'),
+    (Key::Key9, '9', '('),
+    (Key::A, 'a', 'A'),
+    (Key::B, 'b', 'B'),
+    (Key::C, 'c', 'C'),
+    (Key::D, 'd', 'D'),
+    (Key::E, 'e', 'E'),
+    (Key::F, 'f', 'F'),
+    (Key::G, 'g', 'G'),
+    (Key::H, 'h', 'H'),
+    (Key::I, 'i', 'I'),
+    (Key::J, 'j', 'J'),
+    (Key::K, 'k', 'K'),
+    (Key::L, 'l', 'L'),
+    (Key::M, 'm', 'M'),
+    (Key::N, 'n', 'N'),
+    (Key::O, 'o', 'O'),
+    (Key::P, 'p', 'P'),
+    (Key::Q, 'q', 'Q'),
+    (Key::R, 'r', 'R'),
+    (Key::S, 's', 'S'),
+    (Key::T, 't', 'T'),
+    (Key::U, 'u', 'U'),
+    (Key::V, 'v', 'V'),
+    (Key::W, 'w', 'W'),
+    (Key::X, 'x', 'X'),
+    (Key::Y, 'y', 'Y'),
+    (Key::Z, 'z', 'Z'),
+    (Key::Space, ' ', ' '),
+    (Key::Grave, '`', '~'),
+    (Key::Minus, '-', '_'),
+    (Key::Equal, '=', '+'),
+    (Key::LeftBracket, '[', '{'),
+    (Key::RightBracket, ']', '}'),
+    (Key::BackSlash, '\\', '|'),
+    (Key::Semicolon, ';', ':'),
+    (Key::Apostrophe, '\'', '"'),
+    (Key::Comma, ',', '<'),
+    (Key::Dot, '.', '>'),
+    (Key::Slash, '/', '?'),
+    (Key::Numpad0, '0', '0'),
+    (Key::Numpad1, '1', '1'),
+    (Key::Numpad2, '2', '2'),
+    (Key::Numpad3, '3', '3'),
+    (Key::Numpad4, '4', '4'),
+    (Key::Numpad5, '5', '5'),
+    (Key::Numpad6, '6', '6'),
+    (Key::Numpad7, '7', '7'),
+    (Key::Numpad8, '8', '8'),
+    (Key::Numpad9, '9', '9'),
+    (Key::NumpadSubtract, '-', '-'),
+    (Key::NumpadAdd, '+', '+'),
+    (Key::NumpadDivide, '/', '/'),
+    (Key::NumpadMultiply, '*', '*'),
+    (Key::NumpadDecimal, '.', '.'),
+];
+
+/// Set of modifiers, regardless of which side of the keyboard they are held on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(1);
+    pub const CTRL: Modifiers = Modifiers(1 << 1);
+    pub const ALT: Modifiers = Modifiers(1 << 2);
+    pub const META: Modifiers = Modifiers(1 << 3);
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every modifier of `other` is also in `self`.
+    pub fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Modifiers) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Modifiers) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Modifiers {
+    fn bitor_assign(&mut self, other: Modifiers) {
+        self.insert(other);
+    }
 }
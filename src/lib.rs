@@ -1,38 +1,29 @@
-//! A simple library for querying mouse and keyboard state without requiring
+//! A simple library for querying keyboard state and filtering key events without requiring
 //! an active window. Currently works in Windows, Linux, and macOS.
 //!
 //! ```no_run
-//! use device_query::{DeviceQuery, DeviceState, MouseState, Keycode};
+//! use key_director::{DeviceQuery, DeviceState, Key};
 //!
 //! let device_state = DeviceState::new();
 //!
-//! let mouse: MouseState = device_state.get_mouse();
-//! println!("Current Mouse Coordinates: {:?}", mouse.coords);
-//!
-//! let keys: Vec<Keycode> = device_state.get_keys();
-//! println!("Is A pressed? {}", keys.contains(&Keycode::A));
+//! let keys = device_state.get_keys();
+//! let a = Key::A.code();
+//! println!("Is A pressed? {}", keys.iter().any(|key| Some(key.key_code) == a));
 //! ```
 //!
 //! It's also possible to listen for events.
 //! ```no_run
-//!  use device_query::{DeviceEvents, DeviceState};
+//!  use key_director::{DeviceEvents, DeviceState};
 //!
 //!  let device_state = DeviceState::new();
 //!
-//!  let _guard = device_state.on_mouse_move(|position| {
-//!     println!("Mouse position: {:#?}", position);
-//!  });
-//!  let _guard = device_state.on_mouse_down(|button| {
-//!     println!("Mouse button down: {:#?}", button);
-//!  });
-//!  let _guard = device_state.on_mouse_up(|button| {
-//!     println!("Mouse button up: {:#?}", button);
-//!  });
 //!  let _guard = device_state.on_key_down(|key| {
 //!     println!("Keyboard key down: {:#?}", key);
+//!     true
 //!  });
 //!  let _guard = device_state.on_key_up(|key| {
 //!     println!("Keyboard key up: {:#?}", key);
+//!     true
 //!  });
 //!
 //!  loop {}
@@ -41,6 +32,12 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate serde;
+extern crate serde_json;
+extern crate chrono;
+extern crate arboard;
+
+#[cfg(target_os = "linux")]
+extern crate libc;
 
 #[cfg(target_os = "windows")]
 extern crate windows;
@@ -63,9 +60,15 @@ extern crate objc;
 pub mod device_events;
 pub mod device_query;
 pub mod device_state;
+pub mod filters;
 pub mod keymap;
+pub mod recording;
+pub mod text;
 
 pub use device_events::*;
 pub use device_query::*;
 pub use device_state::*;
+pub use filters::*;
 pub use keymap::*;
+pub use recording::*;
+pub use text::*;
@@ -0,0 +1,521 @@
+//! Recording file formats.
+//!
+//! JSON Lines recordings hold the `RecordingHeader` on their first line, then one
+//! `RecordedEvent` per line.
+//!
+//! Binary recordings start with `KDREC`, the version as a little endian `u32` and the platform.
+//! Then each event is made of:
+//!
+//! - the microseconds elapsed since the previous event,
+//! - the kind of input, as a byte: 0 for keys,
+//! - flags, as a byte: pressed, simulated, with a character, with a device, repeat,
+//! - the key code and the scan code,
+//! - the character, if flagged,
+//! - the index of the device among the ones seen so far, if flagged. An index that wasn't
+//!   seen yet is followed by the device: id, name, vendor, product, bus as a byte, whether a
+//!   path follows as a byte, the path and the capabilities as a byte.
+//!
+//! Integers are unsigned LEB128 varints unless stated otherwise. Strings are their length
+//! followed by their UTF-8 bytes.
+
+use super::{RecordedEvent, RecordedInput, RecordingFormat, RecordingHeader, RECORDING_VERSION};
+use serde_json;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::Arc;
+use {Bus, Capabilities, InputDevice, KeyEvent};
+
+const MAGIC: &[u8] = b"KDREC";
+
+const KIND_KEY: u8 = 0;
+
+const FLAG_PRESSED: u8 = 1;
+const FLAG_SIMULATED: u8 = 1 << 1;
+const FLAG_CHAR: u8 = 1 << 2;
+const FLAG_DEVICE: u8 = 1 << 3;
+const FLAG_REPEAT: u8 = 1 << 4;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    write_varint(out, string.len() as u64);
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn bus_byte(bus: Bus) -> u8 {
+    match bus {
+        Bus::Usb => 0,
+        Bus::Bluetooth => 1,
+        Bus::Internal => 2,
+        Bus::Virtual => 3,
+        Bus::Unknown => 4,
+    }
+}
+
+fn byte_bus(byte: u8) -> Bus {
+    match byte {
+        0 => Bus::Usb,
+        1 => Bus::Bluetooth,
+        2 => Bus::Internal,
+        3 => Bus::Virtual,
+        _ => Bus::Unknown,
+    }
+}
+
+fn capabilities_byte(capabilities: Capabilities) -> u8 {
+    capabilities.keyboard as u8
+        | (capabilities.mouse as u8) << 1
+        | (capabilities.touch as u8) << 2
+        | (capabilities.leds as u8) << 3
+}
+
+fn byte_capabilities(byte: u8) -> Capabilities {
+    Capabilities {
+        keyboard: byte & 1 != 0,
+        mouse: byte & (1 << 1) != 0,
+        touch: byte & (1 << 2) != 0,
+        leds: byte & (1 << 3) != 0,
+    }
+}
+
+fn write_device(out: &mut Vec<u8>, device: &InputDevice) {
+    write_string(out, &device.id);
+    write_string(out, &device.name);
+    write_varint(out, device.vendor as u64);
+    write_varint(out, device.product as u64);
+    out.push(bus_byte(device.bus));
+    match device.path {
+        Some(ref path) => {
+            out.push(1);
+            write_string(out, path);
+        }
+        None => out.push(0),
+    }
+    out.push(capabilities_byte(device.capabilities));
+}
+
+fn read_byte<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut byte = [0u8];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_varint<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_byte(input)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint too long"))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let value = read_varint(input)?;
+    if value > u32::MAX as u64 {
+        return Err(invalid_data("integer out of range"));
+    }
+    Ok(value as u32)
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let value = read_varint(input)?;
+    if value > u16::MAX as u64 {
+        return Err(invalid_data("integer out of range"));
+    }
+    Ok(value as u16)
+}
+
+fn read_string<R: Read>(input: &mut R) -> io::Result<String> {
+    let len = read_varint(input)?;
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated string"));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("string isn't UTF-8"))
+}
+
+fn read_device<R: Read>(input: &mut R) -> io::Result<InputDevice> {
+    Ok(InputDevice {
+        id: read_string(input)?,
+        name: read_string(input)?,
+        vendor: read_u16(input)?,
+        product: read_u16(input)?,
+        bus: byte_bus(read_byte(input)?),
+        path: match read_byte(input)? {
+            0 => None,
+            _ => Some(read_string(input)?),
+        },
+        capabilities: byte_capabilities(read_byte(input)?),
+    })
+}
+
+/// Writes recorded events to `W` in one of the recording formats.
+pub struct RecordingWriter<W: Write> {
+    output: W,
+    format: RecordingFormat,
+    last_micros: u64,
+    /// Devices already written to a binary recording, by index.
+    devices: Vec<Arc<InputDevice>>,
+}
+
+impl<W: Write> RecordingWriter<W> {
+    /// Writes the header of a recording made on this machine to `output`.
+    pub fn new(mut output: W, format: RecordingFormat) -> io::Result<Self> {
+        let header = RecordingHeader::current();
+        match format {
+            RecordingFormat::JsonLines => {
+                serde_json::to_writer(&mut output, &header)?;
+                output.write_all(b"\n")?;
+            }
+            RecordingFormat::Binary => {
+                let mut bytes = MAGIC.to_vec();
+                bytes.extend_from_slice(&header.version.to_le_bytes());
+                write_string(&mut bytes, &header.platform);
+                output.write_all(&bytes)?;
+            }
+        }
+        Ok(RecordingWriter {
+            output,
+            format,
+            last_micros: 0,
+            devices: Vec::new(),
+        })
+    }
+
+    /// Appends `event`. Events must be written in chronological order.
+    pub fn write(&mut self, event: &RecordedEvent) -> io::Result<()> {
+        match self.format {
+            RecordingFormat::JsonLines => {
+                serde_json::to_writer(&mut self.output, event)?;
+                self.output.write_all(b"\n")
+            }
+            RecordingFormat::Binary => {
+                let bytes = self.encode(event);
+                self.output.write_all(&bytes)
+            }
+        }
+    }
+
+    fn encode(&mut self, event: &RecordedEvent) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, event.micros.saturating_sub(self.last_micros));
+        self.last_micros = self.last_micros.max(event.micros);
+
+        let RecordedInput::Key(ref key_event) = event.input;
+        let mut flags = 0;
+        if key_event.is_pressed {
+            flags |= FLAG_PRESSED;
+        }
+        if key_event.is_simulated {
+            flags |= FLAG_SIMULATED;
+        }
+        if key_event.char.is_some() {
+            flags |= FLAG_CHAR;
+        }
+        if key_event.device.is_some() {
+            flags |= FLAG_DEVICE;
+        }
+        if key_event.is_repeat {
+            flags |= FLAG_REPEAT;
+        }
+        bytes.push(KIND_KEY);
+        bytes.push(flags);
+        write_varint(&mut bytes, key_event.key_code as u64);
+        write_varint(&mut bytes, key_event.scan_code as u64);
+        if let Some(character) = key_event.char {
+            write_varint(&mut bytes, character as u64);
+        }
+        if let Some(ref device) = key_event.device {
+            match self.devices.iter().position(|known| known == device) {
+                Some(index) => write_varint(&mut bytes, index as u64),
+                None => {
+                    write_varint(&mut bytes, self.devices.len() as u64);
+                    write_device(&mut bytes, device);
+                    self.devices.push(device.clone());
+                }
+            }
+        }
+        bytes
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Reads the events of a recording in any of the recording formats, detected from its start.
+pub struct RecordingReader<R: BufRead> {
+    input: R,
+    format: RecordingFormat,
+    header: RecordingHeader,
+    last_micros: u64,
+    devices: Vec<Arc<InputDevice>>,
+}
+
+impl<R: BufRead> RecordingReader<R> {
+    /// Reads the header of the recording. Fails if it was made with another version of the
+    /// format.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let is_binary = input.fill_buf()?.starts_with(MAGIC);
+        let (format, header) = if is_binary {
+            input.consume(MAGIC.len());
+            let mut version = [0u8; 4];
+            input.read_exact(&mut version)?;
+            let header = RecordingHeader {
+                version: u32::from_le_bytes(version),
+                platform: read_string(&mut input)?,
+            };
+            (RecordingFormat::Binary, header)
+        } else {
+            let mut line = String::new();
+            input.read_line(&mut line)?;
+            (RecordingFormat::JsonLines, serde_json::from_str(&line)?)
+        };
+
+        if header.version != RECORDING_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported recording version {}, expected {}",
+                header.version, RECORDING_VERSION
+            )));
+        }
+        Ok(RecordingReader {
+            input,
+            format,
+            header,
+            last_micros: 0,
+            devices: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    pub fn format(&self) -> RecordingFormat {
+        self.format
+    }
+
+    /// Reads the next event, or returns `None` at the end of the recording.
+    pub fn read(&mut self) -> io::Result<Option<RecordedEvent>> {
+        match self.format {
+            RecordingFormat::JsonLines => {
+                let mut line = String::new();
+                loop {
+                    if self.input.read_line(&mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    if !line.trim().is_empty() {
+                        return Ok(Some(serde_json::from_str(&line)?));
+                    }
+                    line.clear();
+                }
+            }
+            RecordingFormat::Binary => {
+                if self.input.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                self.decode().map(Some)
+            }
+        }
+    }
+
+    fn decode(&mut self) -> io::Result<RecordedEvent> {
+        let input = &mut self.input;
+        self.last_micros = self.last_micros.saturating_add(read_varint(input)?);
+        if read_byte(input)? != KIND_KEY {
+            return Err(invalid_data("unknown kind of input"));
+        }
+
+        let flags = read_byte(input)?;
+        let key_code = read_u32(input)?;
+        let scan_code = read_u32(input)?;
+        let character = if flags & FLAG_CHAR != 0 {
+            let code = read_u32(input)?;
+            Some(std::char::from_u32(code).ok_or_else(|| invalid_data("invalid character"))?)
+        } else {
+            None
+        };
+        let mut key_event = KeyEvent::new(
+            character,
+            key_code,
+            scan_code,
+            flags & FLAG_PRESSED != 0,
+            flags & FLAG_SIMULATED != 0,
+        );
+        key_event.is_repeat = flags & FLAG_REPEAT != 0;
+        if flags & FLAG_DEVICE != 0 {
+            let index = read_varint(input)? as usize;
+            if index == self.devices.len() {
+                self.devices.push(Arc::new(read_device(input)?));
+            }
+            let device = self.devices.get(index).ok_or_else(|| invalid_data("unknown device"))?;
+            key_event.device = Some(device.clone());
+        }
+
+        Ok(RecordedEvent {
+            micros: self.last_micros,
+            input: RecordedInput::Key(key_event),
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for RecordingReader<R> {
+    type Item = io::Result<RecordedEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str) -> Arc<InputDevice> {
+        Arc::new(InputDevice {
+            id: id.to_string(),
+            name: format!("{} keyboard", id),
+            vendor: 0x046d,
+            product: 0xc31c,
+            bus: Bus::Usb,
+            path: Some(format!("usb-{}", id)),
+            capabilities: Capabilities {
+                keyboard: true,
+                leds: true,
+                ..Capabilities::default()
+            },
+        })
+    }
+
+    fn key(micros: u64, key_code: u32, is_pressed: bool, device: Option<&Arc<InputDevice>>) -> RecordedEvent {
+        let mut key_event = KeyEvent::new(None, key_code, key_code / 2, is_pressed, false);
+        key_event.device = device.cloned();
+        RecordedEvent {
+            micros,
+            input: RecordedInput::Key(key_event),
+        }
+    }
+
+    fn events() -> Vec<RecordedEvent> {
+        let first = device("first");
+        let second = device("second");
+        let mut repeat = key(400, 30, true, Some(&first));
+        let RecordedInput::Key(ref mut key_event) = repeat.input;
+        key_event.is_repeat = true;
+        let mut simulated = key(u32::MAX as u64 + 1, u32::MAX, true, None);
+        let RecordedInput::Key(ref mut key_event) = simulated.input;
+        key_event.char = Some('ж');
+        key_event.is_simulated = true;
+        vec![
+            key(0, 30, true, Some(&first)),
+            key(127, 31, true, Some(&second)),
+            key(128, 31, false, Some(&second)),
+            repeat,
+            key(16_384, 30, false, Some(&first)),
+            simulated,
+        ]
+    }
+
+    fn write(format: RecordingFormat, events: &[RecordedEvent]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut writer = RecordingWriter::new(&mut bytes, format).unwrap();
+            for event in events {
+                writer.write(event).unwrap();
+            }
+        }
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> io::Result<Vec<RecordedEvent>> {
+        RecordingReader::new(bytes)?.collect()
+    }
+
+    #[test]
+    fn recordings_round_trip() {
+        for format in [RecordingFormat::JsonLines, RecordingFormat::Binary] {
+            let bytes = write(format, &events());
+            let reader = RecordingReader::new(&bytes[..]).unwrap();
+            assert_eq!(reader.format(), format);
+            assert_eq!(*reader.header(), RecordingHeader::current());
+            assert_eq!(read(&bytes).unwrap(), events());
+        }
+    }
+
+    #[test]
+    fn varints_round_trip_at_their_edges() {
+        for value in [0, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, u64::MAX] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            assert_eq!(read_varint(&mut &bytes[..]).unwrap(), value);
+        }
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 0x80);
+        assert_eq!(bytes, [0x80, 0x01]);
+    }
+
+    #[test]
+    fn overlong_varints_and_out_of_range_integers_are_rejected() {
+        assert!(read_varint(&mut &[0xff; 10][..]).is_err());
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, u32::MAX as u64 + 1);
+        assert!(read_u32(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn devices_are_written_once_then_referred_to() {
+        let first = device("first");
+        let with_first = |micros| key(micros, 30, true, Some(&first));
+        let once = write(RecordingFormat::Binary, &[with_first(0)]);
+        let twice = write(RecordingFormat::Binary, &[with_first(0), with_first(1)]);
+        // Delta, kind, flags, key code, scan code and the device index.
+        assert_eq!(twice.len() - once.len(), 6);
+
+        let read_back = read(&twice).unwrap();
+        match (&read_back[0].input, &read_back[1].input) {
+            (RecordedInput::Key(first), RecordedInput::Key(second)) => {
+                assert!(Arc::ptr_eq(first.device.as_ref().unwrap(), second.device.as_ref().unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_device_references_are_rejected() {
+        let mut bytes = write(RecordingFormat::Binary, &[]);
+        bytes.extend_from_slice(&[0, KIND_KEY, FLAG_DEVICE, 30, 15, 1]);
+        assert_eq!(read(&bytes).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_recordings_are_rejected() {
+        let events = events();
+        let bytes = write(RecordingFormat::Binary, &events);
+        let boundaries: Vec<usize> = (0..=events.len())
+            .map(|count| write(RecordingFormat::Binary, &events[..count]).len())
+            .collect();
+        for len in boundaries[0]..bytes.len() {
+            match boundaries.iter().position(|boundary| *boundary == len) {
+                Some(count) => assert_eq!(read(&bytes[..len]).unwrap(), events[..count]),
+                None => assert_eq!(read(&bytes[..len]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof),
+            }
+        }
+        assert!(RecordingReader::new(&bytes[..MAGIC.len() + 2]).is_err());
+    }
+}
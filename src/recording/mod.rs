@@ -0,0 +1,70 @@
+//! Recording of input to files, and replay of the recordings.
+//!
+//! ```no_run
+//! use key_director::{Player, Recorder, RecordingFormat};
+//! use std::thread;
+//! use std::time::Duration;
+//!
+//! let recorder = Recorder::create("typing.kdrec", RecordingFormat::Binary).unwrap();
+//! thread::sleep(Duration::from_secs(10));
+//! recorder.stop().unwrap();
+//!
+//! let player = Player::open("typing.kdrec").unwrap();
+//! println!("{:?}", player.simulate());
+//! ```
+
+use serde::{Deserialize, Serialize};
+use KeyEvent;
+
+mod format;
+mod player;
+mod recorder;
+
+pub use self::format::*;
+pub use self::player::*;
+pub use self::recorder::*;
+
+/// Version of the recording formats written by this crate. Recordings of other versions can't be
+/// read.
+pub const RECORDING_VERSION: u32 = 1;
+
+/// File formats of recordings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RecordingFormat {
+    /// One JSON object per line, readable and easy to edit into test fixtures.
+    JsonLines,
+    /// Compact binary encoding, for long sessions.
+    Binary,
+}
+
+/// First record of every recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub version: u32,
+    /// OS the recording was made on, as in `std::env::consts::OS`. Key codes are platform
+    /// specific, so recordings are only replayed on the same OS.
+    pub platform: String,
+}
+
+impl RecordingHeader {
+    /// Header of recordings made on this machine.
+    pub fn current() -> Self {
+        RecordingHeader {
+            version: RECORDING_VERSION,
+            platform: std::env::consts::OS.to_string(),
+        }
+    }
+}
+
+/// Input captured in a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RecordedInput {
+    Key(KeyEvent),
+}
+
+/// Input with the time it was captured at, relative to the start of the recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub micros: u64,
+    pub input: RecordedInput,
+}
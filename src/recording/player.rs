@@ -0,0 +1,194 @@
+//! Replay of recordings.
+
+use super::{RecordedEvent, RecordedInput, RecordingReader};
+use device_events::{Simulation, Verdict};
+use device_state::{InputAction, Injector};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use KeyEvent;
+
+/// Replays recorded events, either through the injector like real input or through the
+/// callbacks only, to check what they make of it.
+#[derive(Debug, Clone)]
+pub struct Player {
+    events: Vec<RecordedEvent>,
+    speed: f64,
+    include_simulated: bool,
+}
+
+impl Player {
+    pub fn new(events: Vec<RecordedEvent>) -> Self {
+        Player {
+            events,
+            speed: 1.0,
+            include_simulated: false,
+        }
+    }
+
+    /// Read a whole recording. Fails if it was made on another OS, since key codes differ
+    /// between platforms.
+    pub fn read<R: BufRead>(input: R) -> io::Result<Player> {
+        let reader = RecordingReader::new(input)?;
+        if reader.header().platform != std::env::consts::OS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("recording made on {}", reader.header().platform),
+            ));
+        }
+        Ok(Player::new(reader.collect::<io::Result<_>>()?))
+    }
+
+    /// Read a whole recording file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Player> {
+        Player::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Replay `speed` times faster than recorded. Defaults to 1.
+    pub fn speed(mut self, speed: f64) -> Self {
+        assert!(speed > 0.0, "Replay speed must be positive");
+        self.speed = speed;
+        self
+    }
+
+    /// Also play the simulated events of the recording. They are skipped by default: they were
+    /// produced by the handlers of the recorded session, which produce them again when the
+    /// physical events are played.
+    pub fn include_simulated(mut self, include_simulated: bool) -> Self {
+        self.include_simulated = include_simulated;
+        self
+    }
+
+    pub fn events(&self) -> &[RecordedEvent] {
+        &self.events
+    }
+
+    /// Queue the events on `injector` with their recorded timing, blocking until the last one
+    /// is queued. Only keys are injected: events carrying nothing but a character are skipped,
+    /// like simulated events unless `include_simulated` is set.
+    pub fn play(&self, injector: &Injector) {
+        let start = Instant::now();
+        for event in self.played() {
+            let RecordedInput::Key(ref key_event) = event.input;
+            if key_event.key_code == KeyEvent::UNICODE_KEY_CODE {
+                continue;
+            }
+
+            let due = Duration::from_secs_f64(event.micros as f64 / 1_000_000.0 / self.speed);
+            if let Some(delay) = due.checked_sub(start.elapsed()) {
+                thread::sleep(delay);
+            }
+            injector.queue(vec![if key_event.is_pressed && key_event.is_repeat {
+                InputAction::KeyRepeat(key_event.key_code)
+            } else if key_event.is_pressed {
+                InputAction::KeyDown(key_event.key_code)
+            } else {
+                InputAction::KeyUp(key_event.key_code)
+            }]);
+        }
+    }
+
+    /// Run the events through the callbacks as if they came from the OS, without timing, and
+    /// return the events that would have reached it, so that recordings of real typing can
+    /// serve as regression fixtures for handlers. Simulated events are skipped unless
+    /// `include_simulated` is set.
+    ///
+    /// The events go through a copy of the callback chain, with modifiers of its own, and the
+    /// input the handlers queue on the injector meanwhile is returned as simulated key events
+    /// instead of being injected. Handlers still update their own state, and input that
+    /// filters queue or resume later from their own threads, like deferred debouncing or
+    /// one-shot timeouts, isn't simulated: leave such filters out of the chain under test.
+    pub fn simulate(&self) -> Vec<KeyEvent> {
+        let simulation = Simulation::new();
+        let mut output = Vec::new();
+        for event in self.played() {
+            let RecordedInput::Key(ref key_event) = event.input;
+            let (verdict, injected) = Injector::capture(|| simulation.dispatch(key_event));
+            match verdict {
+                Verdict::Pass => output.push(key_event.clone()),
+                Verdict::Block => {}
+                Verdict::Replace(events) => output.extend(events),
+                Verdict::Modify(new_event) => output.push(new_event),
+            }
+            output.extend(injected.into_iter().filter_map(|action| match action {
+                InputAction::KeyDown(code) => Some(KeyEvent::new(None, code, 0, true, true)),
+                InputAction::KeyUp(code) => Some(KeyEvent::new(None, code, 0, false, true)),
+                InputAction::KeyRepeat(code) => {
+                    let mut repeat = KeyEvent::new(None, code, 0, true, true);
+                    repeat.is_repeat = true;
+                    Some(repeat)
+                }
+                _ => None,
+            }));
+        }
+        output
+    }
+
+    fn played(&self) -> impl Iterator<Item = &RecordedEvent> {
+        let include_simulated = self.include_simulated;
+        self.events.iter().filter(move |event| {
+            let RecordedInput::Key(ref key_event) = event.input;
+            include_simulated || !key_event.is_simulated
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_events::{Stage, GLOBAL_CALLBACKS};
+    use device_state::INJECTOR;
+    use std::sync::Arc;
+    use {Key, Modifiers};
+
+    const TRIGGER: u32 = 10_000;
+    const INJECTED: u32 = 10_001;
+
+    fn recorded(micros: u64, key_event: KeyEvent) -> RecordedEvent {
+        RecordedEvent {
+            micros,
+            input: RecordedInput::Key(key_event),
+        }
+    }
+
+    #[test]
+    fn simulation_captures_injected_input_and_leaves_the_chain_alone() {
+        let id = GLOBAL_CALLBACKS.lock().unwrap().insert(
+            "injecting",
+            Stage::Normal,
+            0,
+            None,
+            Arc::new(|event: &KeyEvent| {
+                if event.key_code != TRIGGER {
+                    return Verdict::Pass;
+                }
+                INJECTOR.queue(vec![InputAction::KeyDown(INJECTED)]);
+                Verdict::Block
+            }),
+        );
+        let shift = Key::LShift.code().unwrap();
+        let player = Player::new(vec![
+            recorded(0, KeyEvent::new(None, shift, 0, true, false)),
+            recorded(1, KeyEvent::new(None, TRIGGER, 0, true, false)),
+            recorded(2, KeyEvent::new(None, INJECTED, 0, true, true)),
+        ]);
+
+        let output = player.simulate();
+        let with_simulated = player.clone().include_simulated(true).simulate();
+        GLOBAL_CALLBACKS.lock().unwrap().remove(id);
+
+        assert_eq!(
+            output,
+            vec![
+                KeyEvent::new(None, shift, 0, true, false),
+                KeyEvent::new(None, INJECTED, 0, true, true),
+            ]
+        );
+        assert_eq!(GLOBAL_CALLBACKS.lock().unwrap().modifiers(), Modifiers::NONE);
+        assert!(INJECTOR.queued_keys().is_empty());
+        assert_eq!(with_simulated.len(), 3);
+        assert_eq!(with_simulated[2], KeyEvent::new(None, INJECTED, 0, true, true));
+    }
+}
@@ -0,0 +1,88 @@
+//! Live recording of the key events.
+
+use super::{RecordedEvent, RecordedInput, RecordingFormat, RecordingWriter};
+use device_events::{HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+struct Recording {
+    writer: RecordingWriter<Box<dyn Write + Send>>,
+    start: Instant,
+    /// First error hit while writing, reported by `Recorder::stop`.
+    error: Option<io::Error>,
+}
+
+/// Records every key event, including simulated ones, before any other handler can change
+/// it. Recording stops when the recorder is stopped or dropped.
+pub struct Recorder {
+    id: HandlerId,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl Recorder {
+    /// Start recording to `output`.
+    pub fn start<W>(output: W, format: RecordingFormat) -> io::Result<Recorder>
+    where
+        W: Write + Send + 'static,
+    {
+        let recording = Arc::new(Mutex::new(Recording {
+            writer: RecordingWriter::new(Box::new(output) as Box<dyn Write + Send>, format)?,
+            start: Instant::now(),
+            error: None,
+        }));
+
+        let handler_recording = recording.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "recorder",
+                Stage::PreFilter,
+                i32::MAX,
+                None,
+                Arc::new(move |event| {
+                    if let Ok(mut recording) = handler_recording.lock() {
+                        let event = RecordedEvent {
+                            micros: recording.start.elapsed().as_micros() as u64,
+                            input: RecordedInput::Key(event.clone()),
+                        };
+                        if recording.error.is_none() {
+                            recording.error = recording.writer.write(&event).err();
+                        }
+                    }
+                    Verdict::Pass
+                }),
+            );
+        Ok(Recorder { id, recording })
+    }
+
+    /// Start recording to a new file at `path`, replacing any existing one.
+    pub fn create<P: AsRef<Path>>(path: P, format: RecordingFormat) -> io::Result<Recorder> {
+        Recorder::start(BufWriter::new(File::create(path)?), format)
+    }
+
+    /// Stop recording and flush the output. Returns the first error hit while recording.
+    pub fn stop(self) -> io::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+        let mut recording = self.recording.lock().expect("Couldn't lock the recording");
+        if let Some(error) = recording.error.take() {
+            return Err(error);
+        }
+        recording.writer.flush()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
@@ -0,0 +1,559 @@
+//! Compose key and dead keys.
+
+use super::keysym::keysym_char;
+use super::{is_shortcut, type_text, typed_char};
+use device_events::{resume_replacement, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::can_type_unicode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use {Key, KeyEvent};
+
+/// Priority of the compose engine among the pre-filters, after the filters of the crate.
+const PRIORITY: i32 = 500;
+
+/// Directory of the system compose files, `%S` in include directives.
+const SYSTEM_DIR: &str = "/usr/share/X11/locale";
+
+/// How deep included compose files may nest.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Parses the quoted string at the start of `text`, with its escapes. Octal and hexadecimal
+/// escapes stand for bytes, e.g. `"\342\206\222"` for the UTF-8 encoding of `→`.
+fn parse_string(text: &str) -> Option<String> {
+    let mut chars = text.strip_prefix('"')?.chars().peekable();
+    let mut bytes = Vec::new();
+    while let Some(character) = chars.next() {
+        let escaped = match character {
+            '"' => return String::from_utf8(bytes).ok(),
+            '\\' => chars.next()?,
+            _ => {
+                bytes.extend_from_slice(character.encode_utf8(&mut [0; 4]).as_bytes());
+                continue;
+            }
+        };
+        let (radix, max_digits, mut digits) = match escaped {
+            'x' | 'X' => (16, 2, String::new()),
+            '0'..='7' => (8, 3, escaped.to_string()),
+            _ => {
+                bytes.extend_from_slice(escaped.encode_utf8(&mut [0; 4]).as_bytes());
+                continue;
+            }
+        };
+        while let Some(digit) = chars.peek().filter(|digit| digits.len() < max_digits && digit.is_digit(radix)) {
+            digits.push(*digit);
+            chars.next();
+        }
+        bytes.push(u8::from_str_radix(&digits, radix).ok()?);
+    }
+    None
+}
+
+/// Locale of the process, as the environment tells the C library.
+fn locale() -> String {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|variable| env::var(variable).ok())
+        .find(|locale| !locale.is_empty())
+        .unwrap_or_else(|| "en_US.UTF-8".to_string())
+}
+
+/// Compose file of the locale of the process, `%L` in include directives.
+fn locale_compose_file() -> PathBuf {
+    let locale = locale();
+    let compose_dir = fs::read_to_string(Path::new(SYSTEM_DIR).join("compose.dir")).unwrap_or_default();
+    let file = compose_dir
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?.trim_end_matches(':'), fields.next()?))
+        })
+        .find(|(_, name)| *name == locale)
+        .map_or("en_US.UTF-8/Compose", |(file, _)| file)
+        .to_string();
+    Path::new(SYSTEM_DIR).join(file)
+}
+
+/// Sequences of characters typed after the compose key, with the text they produce.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComposeTable {
+    sequences: BTreeMap<String, String>,
+}
+
+/// Outcome of looking a sequence up.
+enum Lookup<'a> {
+    Complete(&'a str),
+    Prefix,
+    Unknown,
+}
+
+impl ComposeTable {
+    pub fn new() -> Self {
+        ComposeTable::default()
+    }
+
+    /// Make the characters of `sequence`, typed after the compose key, produce `output`.
+    pub fn sequence(mut self, sequence: &str, output: &str) -> Self {
+        self.insert(sequence, output);
+        self
+    }
+
+    pub fn insert(&mut self, sequence: &str, output: &str) {
+        self.sequences.insert(sequence.to_string(), output.to_string());
+    }
+
+    /// Text produced by `sequence`, if it is complete.
+    pub fn get(&self, sequence: &str) -> Option<&str> {
+        self.sequences.get(sequence).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sequences.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty()
+    }
+
+    /// Parse the X11 `Compose` file format, e.g. `<Multi_key> <apostrophe> <e> : "é" eacute`.
+    ///
+    /// Only the sequences starting with `<Multi_key>` are kept, since dead keys are configured
+    /// on `ComposeConfig` and reuse them. Lines with modifiers, with keysyms that don't type a
+    /// character or that can't be parsed are skipped, and so are include directives.
+    pub fn parse(text: &str) -> ComposeTable {
+        let mut table = ComposeTable::new();
+        for line in text.lines() {
+            if let Some((sequence, output)) = parse_line(line) {
+                table.sequences.insert(sequence, output);
+            }
+        }
+        table
+    }
+
+    /// Load a file in the X11 `Compose` format, like `parse`, following its include
+    /// directives. `%H` stands for the home directory, `%S` for the directory of the system
+    /// compose files and `%L` for the compose file of the locale in include paths.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ComposeTable> {
+        let mut table = ComposeTable::new();
+        table.load_into(path.as_ref(), 0)?;
+        Ok(table)
+    }
+
+    /// Load the system compose file of the locale of the process, e.g.
+    /// `/usr/share/X11/locale/en_US.UTF-8/Compose`.
+    pub fn system() -> io::Result<ComposeTable> {
+        ComposeTable::load(locale_compose_file())
+    }
+
+    fn load_into(&mut self, path: &Path, depth: usize) -> io::Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "compose files include each other"));
+        }
+        for line in fs::read_to_string(path)?.lines() {
+            match line.trim().strip_prefix("include") {
+                Some(include) => {
+                    let include = match parse_string(include.trim()) {
+                        Some(include) => include,
+                        None => continue,
+                    };
+                    let home = env::var("HOME").unwrap_or_default();
+                    let included = if include == "%L" {
+                        locale_compose_file()
+                    } else {
+                        PathBuf::from(include.replace("%H", &home).replace("%S", SYSTEM_DIR))
+                    };
+                    self.load_into(&included, depth + 1)?;
+                }
+                None => {
+                    if let Some((sequence, output)) = parse_line(line) {
+                        self.sequences.insert(sequence, output);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, sequence: &str) -> Lookup<'_> {
+        if let Some(output) = self.get(sequence) {
+            return Lookup::Complete(output);
+        }
+        match self.sequences.range(sequence.to_string()..).next() {
+            Some((longer, _)) if longer.starts_with(sequence) => Lookup::Prefix,
+            _ => Lookup::Unknown,
+        }
+    }
+}
+
+/// Parses a sequence line of a compose file into the characters after `<Multi_key>` and the
+/// output.
+fn parse_line(line: &str) -> Option<(String, String)> {
+    let mut keysyms = Vec::new();
+    let mut rest = line.trim();
+    while let Some(event) = rest.strip_prefix('<') {
+        let end = event.find('>')?;
+        keysyms.push(&event[..end]);
+        rest = event[end + 1..].trim_start();
+    }
+    let output = rest.strip_prefix(':')?.trim();
+    if keysyms.first() != Some(&"Multi_key") {
+        return None;
+    }
+    let sequence = keysyms[1..].iter().map(|keysym| keysym_char(keysym)).collect::<Option<String>>()?;
+
+    let output = if output.starts_with('"') {
+        parse_string(output)?
+    } else {
+        keysym_char(output.split_whitespace().next()?)?.to_string()
+    };
+    if sequence.is_empty() || output.is_empty() {
+        return None;
+    }
+    Some((sequence, output))
+}
+
+/// Configuration of a `Compose` engine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComposeConfig {
+    /// Key starting a sequence.
+    pub key: Key,
+    pub table: ComposeTable,
+    /// How long a sequence may wait for its next key before being cancelled.
+    pub timeout: Duration,
+    /// Characters starting a sequence on their own, like on the US International layout. They
+    /// are typed as usual when followed by a sequence that doesn't exist, or by a space.
+    #[serde(default)]
+    pub dead_keys: BTreeSet<char>,
+}
+
+impl ComposeConfig {
+    /// Compose with `key` and `table`, cancelling sequences after 5 seconds.
+    pub fn new(key: Key, table: ComposeTable) -> Self {
+        ComposeConfig {
+            key,
+            table,
+            timeout: Duration::from_secs(5),
+            dead_keys: BTreeSet::new(),
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Make `character` a dead key.
+    pub fn dead_key(mut self, character: char) -> Self {
+        self.dead_keys.insert(character);
+        self
+    }
+}
+
+/// Sequence being typed.
+struct Sequence {
+    characters: String,
+    /// Whether it was started by a dead key rather than the compose key.
+    dead: bool,
+    last_at: Instant,
+}
+
+struct State {
+    config: ComposeConfig,
+    sequence: Option<Sequence>,
+    /// Key codes whose press was swallowed, so that their release is too.
+    swallowed: HashSet<u32>,
+}
+
+impl State {
+    /// Cancel the sequence, returning the events typing the dead key that started it, if any.
+    fn cancel(&mut self) -> Vec<KeyEvent> {
+        match self.sequence.take() {
+            Some(ref sequence) if sequence.dead => type_text(&sequence.characters),
+            _ => Vec::new(),
+        }
+    }
+
+    fn swallow(&mut self, event: &KeyEvent) -> Verdict {
+        self.swallowed.insert(event.key_code);
+        Verdict::Block
+    }
+}
+
+/// Software compose key: the compose key followed by a sequence of the table types the text
+/// of the sequence instead, e.g. Right Alt, `'` and `e` type `é`. The keys of the sequence are
+/// swallowed. Dead keys start sequences on their own.
+///
+/// A sequence is cancelled by Escape, by a key that doesn't type a character, by a shortcut
+/// with Ctrl, Alt or Meta, which goes through, by a sequence missing from the table and by its
+/// timeout. Simulated events are left alone. Where the
+/// output can't be typed, like on Wayland, no sequence starts and the keys go through.
+///
+/// ```no_run
+/// use key_director::{Compose, ComposeConfig, ComposeTable, DeviceState, Key};
+///
+/// let _device_state = DeviceState::new();
+/// let table = ComposeTable::system()
+///     .unwrap_or_default()
+///     .sequence("'e", "é")
+///     .sequence("oo", "°");
+/// let _compose = Compose::install(ComposeConfig::new(Key::RAlt, table).dead_key('`'));
+/// ```
+///
+/// The engine is removed when dropped.
+pub struct Compose {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+}
+
+impl Compose {
+    /// Install the engine in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: ComposeConfig) -> Compose {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            sequence: None,
+            swallowed: HashSet::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "compose",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| {
+                    let verdict = match handler_state.lock() {
+                        Ok(mut state) => filter(&mut state, event),
+                        Err(_) => Verdict::Pass,
+                    };
+                    resume_replacement(event, verdict)
+                }),
+            );
+        Compose { id, state }
+    }
+
+    /// Whether a sequence is being typed.
+    pub fn is_composing(&self) -> bool {
+        self.state
+            .lock()
+            .map(|state| state.sequence.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Cancel the sequence being typed, without typing anything.
+    pub fn cancel(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.sequence = None;
+        }
+    }
+}
+
+impl Drop for Compose {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(state: &mut State, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    if !event.is_pressed {
+        return if state.swallowed.remove(&event.key_code) {
+            Verdict::Block
+        } else {
+            Verdict::Pass
+        };
+    }
+    // Auto-repeats of swallowed keys.
+    if state.swallowed.contains(&event.key_code) {
+        return Verdict::Block;
+    }
+
+    let timeout = state.config.timeout;
+    let mut typed = match state.sequence {
+        Some(ref sequence) if sequence.last_at.elapsed() > timeout => state.cancel(),
+        _ => Vec::new(),
+    };
+    let verdict = compose(state, event, &mut typed);
+    if typed.is_empty() {
+        return verdict;
+    }
+    match verdict {
+        Verdict::Pass => typed.push(event.clone()),
+        Verdict::Block => {}
+        Verdict::Replace(events) => typed.extend(events),
+        Verdict::Modify(new_event) => typed.push(new_event),
+    }
+    Verdict::Replace(typed)
+}
+
+/// Handle a press, adding the events to type before the outcome to `typed`.
+fn compose(state: &mut State, event: &KeyEvent, typed: &mut Vec<KeyEvent>) -> Verdict {
+    let key = Key::from_code(event.key_code);
+    if key.and_then(Key::modifier).is_some() && key != Some(state.config.key) {
+        return Verdict::Pass;
+    }
+    if is_shortcut() {
+        typed.extend(state.cancel());
+        return Verdict::Pass;
+    }
+    if state.sequence.is_none() && !can_type_unicode() {
+        return Verdict::Pass;
+    }
+    if key == Some(state.config.key) {
+        typed.extend(state.cancel());
+        state.sequence = Some(Sequence {
+            characters: String::new(),
+            dead: false,
+            last_at: Instant::now(),
+        });
+        return state.swallow(event);
+    }
+
+    let character = typed_char(event);
+    let mut sequence = match state.sequence.take() {
+        Some(sequence) => sequence,
+        None => {
+            return match character {
+                Some(character) if state.config.dead_keys.contains(&character) => {
+                    state.sequence = Some(Sequence {
+                        characters: character.to_string(),
+                        dead: true,
+                        last_at: Instant::now(),
+                    });
+                    state.swallow(event)
+                }
+                _ => Verdict::Pass,
+            };
+        }
+    };
+
+    let character = match character {
+        Some(character) if key != Some(Key::Escape) => character,
+        _ => {
+            state.sequence = Some(sequence);
+            typed.extend(state.cancel());
+            return if key == Some(Key::Escape) {
+                state.swallow(event)
+            } else {
+                Verdict::Pass
+            };
+        }
+    };
+    if sequence.dead && character == ' ' {
+        typed.extend(type_text(&sequence.characters));
+        return state.swallow(event);
+    }
+
+    sequence.characters.push(character);
+    sequence.last_at = Instant::now();
+    match state.config.table.lookup(&sequence.characters) {
+        Lookup::Complete(output) => {
+            typed.extend(type_text(output));
+            state.swallow(event)
+        }
+        Lookup::Prefix => {
+            state.sequence = Some(sequence);
+            state.swallow(event)
+        }
+        Lookup::Unknown if sequence.dead => {
+            typed.extend(type_text(&sequence.characters));
+            state.swallow(event)
+        }
+        Lookup::Unknown => state.swallow(event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn strings_are_parsed_with_their_escapes() {
+        assert_eq!(parse_string("\"é\" eacute"), Some("é".to_string()));
+        assert_eq!(parse_string(r#""a\"b\\c""#), Some("a\"b\\c".to_string()));
+        assert_eq!(parse_string(r#""\101\x42\X43""#), Some("ABC".to_string()));
+        assert_eq!(parse_string(r#""\342\206\222\xe2\x86\x92""#), Some("→→".to_string()));
+        assert_eq!(parse_string(r#""\1011""#), Some("A1".to_string()));
+        assert_eq!(parse_string(r#""\377""#), None);
+        assert_eq!(parse_string(r#""unterminated"#), None);
+        assert_eq!(parse_string("no quotes"), None);
+    }
+
+    #[test]
+    fn sequence_lines_are_parsed() {
+        assert_eq!(
+            parse_line(r#"<Multi_key> <apostrophe> <e> : "é" eacute"#),
+            Some(("'e".to_string(), "é".to_string()))
+        );
+        // Output given by its keysym only.
+        assert_eq!(parse_line("<Multi_key> <o> <o> : degree"), Some(("oo".to_string(), "°".to_string())));
+        assert_eq!(
+            parse_line(r#"  <Multi_key> <minus> <greater>	: "\342\206\222""#),
+            Some(("->".to_string(), "→".to_string()))
+        );
+    }
+
+    #[test]
+    fn lines_that_cant_be_used_are_skipped() {
+        assert_eq!(parse_line(r#"<dead_acute> <e> : "é" eacute"#), None);
+        assert_eq!(parse_line(r#"<Multi_key> <Left> <e> : "é""#), None);
+        assert_eq!(parse_line(r#"<Multi_key> : "é""#), None);
+        assert_eq!(parse_line("# <Multi_key> <o> <o> : degree"), None);
+        assert_eq!(parse_line(r#"include "%L""#), None);
+        assert_eq!(parse_line(""), None);
+    }
+
+    #[test]
+    fn sequences_are_looked_up_by_prefix() {
+        let table = ComposeTable::parse(
+            r#"
+            <Multi_key> <o> <o> : degree
+            <Multi_key> <minus> <minus> <minus> : "—" emdash
+            "#,
+        );
+        assert_eq!(table.len(), 2);
+        assert!(matches!(table.lookup("oo"), Lookup::Complete("°")));
+        assert!(matches!(table.lookup("o"), Lookup::Prefix));
+        assert!(matches!(table.lookup("--"), Lookup::Prefix));
+        assert!(matches!(table.lookup("---"), Lookup::Complete("—")));
+        assert!(matches!(table.lookup("ox"), Lookup::Unknown));
+        assert!(matches!(table.lookup("p"), Lookup::Unknown));
+    }
+
+    #[test]
+    fn included_files_are_loaded() {
+        let dir = env::temp_dir().join(format!("key_director-compose-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("Compose");
+        let included = dir.join("Included");
+        fs::write(
+            &main,
+            format!("include \"{}\"\n<Multi_key> <o> <o> : \"o\"\n", included.display()),
+        )
+        .unwrap();
+        fs::write(&included, "<Multi_key> <o> <o> : degree\n<Multi_key> <a> <a> : aring\n").unwrap();
+
+        let table = ComposeTable::load(&main).unwrap();
+        assert_eq!(table.get("aa"), Some("å"));
+        // Lines after the include directive override the included ones.
+        assert_eq!(table.get("oo"), Some("o"));
+
+        fs::write(&included, format!("include \"{}\"\n", main.display())).unwrap();
+        assert_eq!(ComposeTable::load(&main).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(ComposeTable::load(&main).is_err());
+    }
+}
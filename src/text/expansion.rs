@@ -0,0 +1,520 @@
+//! Text expansion: typed triggers replaced with longer text.
+
+use super::{is_shortcut, type_text, typed_char};
+use chrono::Local;
+use device_events::{resume_replacement, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::can_type_unicode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use {Key, KeyEvent};
+
+/// Priority of the text expansion engine among the pre-filters, after the compose engine.
+const PRIORITY: i32 = 450;
+
+/// How the case of a typed trigger is matched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CaseRule {
+    /// The trigger must be typed with the exact same case.
+    #[default]
+    Exact,
+    /// The trigger may be typed in any case. The replacement is typed as is.
+    Ignore,
+    /// The trigger may be typed in any case, and the replacement follows it: capitalized when
+    /// the trigger is, in upper case when the trigger is.
+    Follow,
+}
+
+/// A trigger and the text replacing it.
+///
+/// The replacement may hold placeholders:
+/// - `{date}` and `{time}`: the local date and time, also with a `strftime` format like
+///   `{date:%d.%m.%Y}`.
+/// - `{cursor}`: where to leave the cursor, moved there with the Left arrow.
+/// - `{{` and `}}`: literal braces.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Snippet {
+    pub trigger: String,
+    pub replacement: String,
+    /// Whether the trigger must be a whole word: it then only expands once followed by a
+    /// character that isn't alphanumeric, or by Enter or Tab, which are kept.
+    #[serde(default)]
+    pub word: bool,
+    #[serde(default)]
+    pub case: CaseRule,
+}
+
+impl Snippet {
+    /// Replace `trigger` with `replacement` as soon as it is typed, with the exact same case.
+    pub fn new(trigger: &str, replacement: &str) -> Self {
+        Snippet {
+            trigger: trigger.to_string(),
+            replacement: replacement.to_string(),
+            word: false,
+            case: CaseRule::Exact,
+        }
+    }
+
+    /// Only expand the trigger as a whole word.
+    pub fn word(mut self) -> Self {
+        self.word = true;
+        self
+    }
+
+    pub fn case(mut self, case: CaseRule) -> Self {
+        self.case = case;
+        self
+    }
+
+    /// Whether `typed` is the trigger.
+    fn matches(&self, typed: &[char]) -> bool {
+        let trigger: Vec<char> = self.trigger.chars().collect();
+        if trigger.len() != typed.len() {
+            return false;
+        }
+        match self.case {
+            CaseRule::Exact => trigger == typed,
+            CaseRule::Ignore | CaseRule::Follow => trigger
+                .iter()
+                .zip(typed)
+                .all(|(expected, typed)| expected.to_lowercase().eq(typed.to_lowercase())),
+        }
+    }
+
+    /// Text replacing the trigger typed as `typed`, and how many characters of it come after
+    /// the cursor.
+    pub fn expand(&self, typed: &str) -> (String, usize) {
+        let (text, after_cursor) = expand_placeholders(&self.replacement);
+        if self.case != CaseRule::Follow {
+            return (text, after_cursor);
+        }
+        let letters: Vec<char> = typed.chars().filter(|character| character.is_alphabetic()).collect();
+        if letters.len() > 1 && letters.iter().all(|letter| letter.is_uppercase()) {
+            return (text.to_uppercase(), after_cursor);
+        }
+        let trigger_capitalized = self
+            .trigger
+            .chars()
+            .find(|character| character.is_alphabetic())
+            .is_some_and(char::is_uppercase);
+        if letters.first().is_some_and(|letter| letter.is_uppercase()) && !trigger_capitalized {
+            return (capitalize(&text), after_cursor);
+        }
+        (text, after_cursor)
+    }
+}
+
+/// `text` with its first letter in upper case.
+fn capitalize(text: &str) -> String {
+    match text.char_indices().find(|(_, character)| character.is_alphabetic()) {
+        Some((index, letter)) => {
+            let mut capitalized = text[..index].to_string();
+            capitalized.extend(letter.to_uppercase());
+            capitalized.push_str(&text[index + letter.len_utf8()..]);
+            capitalized
+        }
+        None => text.to_string(),
+    }
+}
+
+/// `template` with its placeholders replaced, and how many characters come after the cursor
+/// placeholder. Unknown placeholders are left as is.
+fn expand_placeholders(template: &str) -> (String, usize) {
+    let now = Local::now();
+    let mut text = String::new();
+    let mut cursor = None;
+    let mut rest = template;
+    while let Some(index) = rest.find(['{', '}']) {
+        text.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            text.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        let end = match rest.find('}').filter(|_| rest.starts_with('{')) {
+            Some(end) => end,
+            None => {
+                text.push_str(&rest[..1]);
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let placeholder = &rest[1..end];
+        let (name, format) = match placeholder.find(':') {
+            Some(colon) => (&placeholder[..colon], Some(&placeholder[colon + 1..])),
+            None => (placeholder, None),
+        };
+        let formatted = match name {
+            "cursor" if format.is_none() => {
+                cursor = Some(text.chars().count());
+                Some(String::new())
+            }
+            "date" => format_time(&now, format.unwrap_or("%Y-%m-%d")),
+            "time" => format_time(&now, format.unwrap_or("%H:%M")),
+            _ => None,
+        };
+        match formatted {
+            Some(formatted) => text.push_str(&formatted),
+            None => text.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    let after_cursor = cursor.map_or(0, |cursor| text.chars().count() - cursor);
+    (text, after_cursor)
+}
+
+/// `now` formatted with `format`, or `None` if the format is invalid.
+fn format_time(now: &chrono::DateTime<Local>, format: &str) -> Option<String> {
+    let mut formatted = String::new();
+    write!(formatted, "{}", now.format(format)).ok()?;
+    Some(formatted)
+}
+
+/// Configuration of a `TextExpansion` engine.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExpansionConfig {
+    pub snippets: Vec<Snippet>,
+}
+
+impl ExpansionConfig {
+    pub fn new() -> Self {
+        ExpansionConfig::default()
+    }
+
+    pub fn snippet(mut self, snippet: Snippet) -> Self {
+        self.snippets.push(snippet);
+        self
+    }
+}
+
+struct State {
+    config: ExpansionConfig,
+    enabled: bool,
+    /// Characters typed since the buffer was last cleared, at most as many as the longest
+    /// trigger and the character before it.
+    buffer: Vec<char>,
+    /// Key codes whose press was swallowed, so that their release is too.
+    swallowed: HashSet<u32>,
+}
+
+impl State {
+    /// Snippet of the longest trigger ending the buffer, among the whole words if `word`.
+    fn find(&self, word: bool) -> Option<(&Snippet, usize)> {
+        self.config
+            .snippets
+            .iter()
+            .filter(|snippet| snippet.word == word && !snippet.trigger.is_empty())
+            .filter_map(|snippet| {
+                let length = snippet.trigger.chars().count();
+                let start = self.buffer.len().checked_sub(length)?;
+                if !snippet.matches(&self.buffer[start..]) {
+                    return None;
+                }
+                if word && start > 0 && self.buffer[start - 1].is_alphanumeric() {
+                    return None;
+                }
+                Some((snippet, length))
+            })
+            .max_by_key(|(_, length)| *length)
+    }
+
+    /// Events erasing the last `length` characters of the buffer and typing the replacement
+    /// of `snippet` instead. `erased` of them were typed already. Clears the buffer. Returns
+    /// `None`, leaving the trigger alone, if the replacement can't be typed.
+    fn expand(&mut self, snippet: &Snippet, length: usize, erased: usize) -> Option<Vec<KeyEvent>> {
+        let typed: String = self.buffer[self.buffer.len() - length..].iter().collect();
+        let (text, after_cursor) = snippet.expand(&typed);
+        if !text.is_empty() && !can_type_unicode() {
+            return None;
+        }
+        self.buffer.clear();
+
+        let mut events = Vec::new();
+        if let Some(code) = Key::Backspace.code() {
+            events.extend(taps(code, erased));
+        }
+        events.extend(type_text(&text));
+        if let Some(code) = Key::Left.code() {
+            events.extend(taps(code, after_cursor));
+        }
+        Some(events)
+    }
+
+    fn push(&mut self, character: char) {
+        self.buffer.push(character);
+        let capacity = self
+            .config
+            .snippets
+            .iter()
+            .map(|snippet| snippet.trigger.chars().count() + 1)
+            .max()
+            .unwrap_or(0);
+        if self.buffer.len() > capacity {
+            let excess = self.buffer.len() - capacity;
+            self.buffer.drain(..excess);
+        }
+    }
+}
+
+/// Events pressing and releasing the key `code` `count` times.
+fn taps(code: u32, count: usize) -> Vec<KeyEvent> {
+    (0..count)
+        .flat_map(|_| vec![KeyEvent::new(None, code, 0, true, true), KeyEvent::new(None, code, 0, false, true)])
+        .collect()
+}
+
+/// Replaces typed triggers with longer text system-wide: the trigger is erased with Backspace
+/// and the replacement typed instead. Triggers like `;addr` expand as soon as typed, whole
+/// word triggers once the word ends.
+///
+/// The typed characters are kept in a rolling buffer, edited by Backspace and cleared by keys
+/// that don't type text, like arrows, and by shortcuts with Ctrl, Alt or Meta, which go
+/// through. Clicks moving the cursor go unseen, so a trigger typed across them still expands.
+/// Simulated events are left alone. Triggers stay as typed where the replacement can't be,
+/// like on Wayland.
+///
+/// ```no_run
+/// use key_director::{CaseRule, DeviceState, ExpansionConfig, Snippet, TextExpansion};
+///
+/// let _device_state = DeviceState::new();
+/// let _expansion = TextExpansion::install(
+///     ExpansionConfig::new()
+///         .snippet(Snippet::new(";addr", "221B Baker Street, London"))
+///         .snippet(Snippet::new(":shrug:", "¯\\_(ツ)_/¯"))
+///         .snippet(Snippet::new("brb", "be right back").word().case(CaseRule::Follow))
+///         .snippet(Snippet::new(";today", "{date:%d.%m.%Y}"))
+///         .snippet(Snippet::new(";fn", "fn {cursor}() {}")),
+/// );
+/// ```
+///
+/// The engine is removed when dropped.
+pub struct TextExpansion {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+}
+
+impl TextExpansion {
+    /// Install the engine, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: ExpansionConfig) -> TextExpansion {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: true,
+            buffer: Vec::new(),
+            swallowed: HashSet::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "text expansion",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| {
+                    let verdict = match handler_state.lock() {
+                        Ok(mut state) => filter(&mut state, event),
+                        Err(_) => Verdict::Pass,
+                    };
+                    resume_replacement(event, verdict)
+                }),
+            );
+        TextExpansion { id, state }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the engine without removing it.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.lock();
+        state.enabled = enabled;
+        state.buffer.clear();
+    }
+
+    pub fn config(&self) -> ExpansionConfig {
+        self.lock().config.clone()
+    }
+
+    pub fn set_config(&self, config: ExpansionConfig) {
+        let mut state = self.lock();
+        state.config = config;
+        state.buffer.clear();
+    }
+
+    /// Forget the characters typed so far, e.g. when the focus changes.
+    pub fn clear(&self) {
+        self.lock().buffer.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the text expansion state")
+    }
+}
+
+impl Drop for TextExpansion {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(state: &mut State, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    if !event.is_pressed {
+        return if state.swallowed.remove(&event.key_code) {
+            Verdict::Block
+        } else {
+            Verdict::Pass
+        };
+    }
+    if state.swallowed.contains(&event.key_code) {
+        return Verdict::Block;
+    }
+    if !state.enabled {
+        return Verdict::Pass;
+    }
+
+    let key = Key::from_code(event.key_code);
+    if key.and_then(Key::modifier).is_none() && is_shortcut() {
+        state.buffer.clear();
+        return Verdict::Pass;
+    }
+    if key == Some(Key::Backspace) {
+        state.buffer.pop();
+        return Verdict::Pass;
+    }
+    let character = typed_char(event);
+    let ends_word = match character {
+        Some(character) => !character.is_alphanumeric(),
+        None => matches!(key, Some(Key::Enter) | Some(Key::NumpadEnter) | Some(Key::Tab)),
+    };
+
+    let mut events = Vec::new();
+    if ends_word {
+        if let Some((snippet, length)) = state.find(true).map(|(snippet, length)| (snippet.clone(), length)) {
+            events = state.expand(&snippet, length, length).unwrap_or_default();
+        }
+    }
+
+    let character = match character {
+        Some(character) => character,
+        None => {
+            if key.and_then(Key::modifier).is_none() {
+                state.buffer.clear();
+            }
+            return if events.is_empty() {
+                Verdict::Pass
+            } else {
+                events.push(event.clone());
+                Verdict::Replace(events)
+            };
+        }
+    };
+    state.push(character);
+
+    if let Some((snippet, length)) = state.find(false).map(|(snippet, length)| (snippet.clone(), length)) {
+        // The last character of the trigger is the one being typed, swallowed instead.
+        if let Some(expanded) = state.expand(&snippet, length, length - 1) {
+            events.extend(expanded);
+            state.swallowed.insert(event.key_code);
+            return Verdict::Replace(events);
+        }
+    }
+    if events.is_empty() {
+        Verdict::Pass
+    } else {
+        events.push(event.clone());
+        Verdict::Replace(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(snippets: Vec<Snippet>) -> State {
+        State {
+            config: ExpansionConfig { snippets },
+            enabled: true,
+            buffer: Vec::new(),
+            swallowed: HashSet::new(),
+        }
+    }
+
+    fn typed(state: &mut State, text: &str) {
+        text.chars().for_each(|character| state.push(character));
+    }
+
+    #[test]
+    fn braces_are_escaped_by_doubling_them() {
+        assert_eq!(expand_placeholders("{{cursor}} }}{{"), ("{cursor} }{".to_string(), 0));
+        assert_eq!(expand_placeholders("a } b { c"), ("a } b { c".to_string(), 0));
+    }
+
+    #[test]
+    fn the_cursor_placeholder_counts_the_characters_after_it() {
+        assert_eq!(expand_placeholders("fn {cursor}() {}"), ("fn () {}".to_string(), 5));
+        assert_eq!(expand_placeholders("«{cursor}»"), ("«»".to_string(), 1));
+        assert_eq!(expand_placeholders("{cursor:x}"), ("{cursor:x}".to_string(), 0));
+    }
+
+    #[test]
+    fn dates_are_formatted_and_invalid_formats_left_as_is() {
+        let (year, _) = expand_placeholders("{date:%Y}");
+        assert_eq!(year, Local::now().format("%Y").to_string());
+        assert_eq!(expand_placeholders("{date:%Q}"), ("{date:%Q}".to_string(), 0));
+        assert_eq!(expand_placeholders("{unknown}"), ("{unknown}".to_string(), 0));
+    }
+
+    #[test]
+    fn triggers_match_with_the_case_rule() {
+        let chars = |text: &str| text.chars().collect::<Vec<char>>();
+        let exact = Snippet::new("brb", "be right back");
+        assert!(exact.matches(&chars("brb")));
+        assert!(!exact.matches(&chars("Brb")));
+        assert!(!exact.matches(&chars("brbb")));
+        let ignore = exact.clone().case(CaseRule::Ignore);
+        assert!(ignore.matches(&chars("BrB")));
+        assert_eq!(ignore.expand("BRB").0, "be right back");
+    }
+
+    #[test]
+    fn the_replacement_follows_the_case_of_the_trigger() {
+        let snippet = Snippet::new("brb", "be right back").case(CaseRule::Follow);
+        assert_eq!(snippet.expand("brb").0, "be right back");
+        assert_eq!(snippet.expand("Brb").0, "Be right back");
+        assert_eq!(snippet.expand("BRB").0, "BE RIGHT BACK");
+        let capitalized = Snippet::new("Ty", "thank you").case(CaseRule::Follow);
+        assert_eq!(capitalized.expand("Ty").0, "thank you");
+    }
+
+    #[test]
+    fn whole_word_triggers_only_match_after_a_word_boundary() {
+        let mut state = state(vec![Snippet::new("brb", "be right back").word()]);
+        typed(&mut state, "abrb");
+        assert!(state.find(true).is_none());
+        typed(&mut state, " brb");
+        assert_eq!(state.find(true).map(|(_, length)| length), Some(3));
+        assert!(state.find(false).is_none());
+        state.buffer.clear();
+        typed(&mut state, "brb");
+        assert!(state.find(true).is_some());
+    }
+
+    #[test]
+    fn the_longest_trigger_wins_and_the_buffer_keeps_only_what_it_needs() {
+        let mut state = state(vec![Snippet::new("a", "1"), Snippet::new(";ab", "2"), Snippet::new("b", "3")]);
+        typed(&mut state, "xyz;ab");
+        assert_eq!(state.buffer, vec!['z', ';', 'a', 'b']);
+        assert_eq!(state.find(false).map(|(snippet, _)| snippet.replacement.as_str()), Some("2"));
+    }
+}
@@ -0,0 +1,131 @@
+//! X11 keysym names.
+
+/// Names of the keysyms typing ASCII characters other than letters and digits, and a few more.
+const KEYSYMS: &[(&str, char)] = &[
+    ("space", ' '),
+    ("exclam", '!'),
+    ("quotedbl", '"'),
+    ("numbersign", '#'),
+    ("dollar", '$'),
+    ("percent", '%'),
+    ("ampersand", '&'),
+    ("apostrophe", '\''),
+    ("parenleft", '('),
+    ("parenright", ')'),
+    ("asterisk", '*'),
+    ("plus", '+'),
+    ("comma", ','),
+    ("minus", '-'),
+    ("period", '.'),
+    ("slash", '/'),
+    ("colon", ':'),
+    ("semicolon", ';'),
+    ("less", '<'),
+    ("equal", '='),
+    ("greater", '>'),
+    ("question", '?'),
+    ("at", '@'),
+    ("bracketleft", '['),
+    ("backslash", '\\'),
+    ("bracketright", ']'),
+    ("asciicircum", '^'),
+    ("underscore", '_'),
+    ("grave", '`'),
+    ("braceleft", '{'),
+    ("bar", '|'),
+    ("braceright", '}'),
+    ("asciitilde", '~'),
+    ("numerosign", '№'),
+];
+
+/// Names of the keysyms typing the Latin-1 characters from U+00A0 on, in order.
+const LATIN1_KEYSYMS: &[&str] = &[
+    "nobreakspace", "exclamdown", "cent", "sterling", "currency", "yen", "brokenbar", "section",
+    "diaeresis", "copyright", "ordfeminine", "guillemotleft", "notsign", "hyphen", "registered",
+    "macron", "degree", "plusminus", "twosuperior", "threesuperior", "acute", "mu", "paragraph",
+    "periodcentered", "cedilla", "onesuperior", "masculine", "guillemotright", "onequarter",
+    "onehalf", "threequarters", "questiondown", "Agrave", "Aacute", "Acircumflex", "Atilde",
+    "Adiaeresis", "Aring", "AE", "Ccedilla", "Egrave", "Eacute", "Ecircumflex", "Ediaeresis",
+    "Igrave", "Iacute", "Icircumflex", "Idiaeresis", "ETH", "Ntilde", "Ograve", "Oacute",
+    "Ocircumflex", "Otilde", "Odiaeresis", "multiply", "Oslash", "Ugrave", "Uacute", "Ucircumflex",
+    "Udiaeresis", "Yacute", "THORN", "ssharp", "agrave", "aacute", "acircumflex", "atilde",
+    "adiaeresis", "aring", "ae", "ccedilla", "egrave", "eacute", "ecircumflex", "ediaeresis",
+    "igrave", "iacute", "icircumflex", "idiaeresis", "eth", "ntilde", "ograve", "oacute",
+    "ocircumflex", "otilde", "odiaeresis", "division", "oslash", "ugrave", "uacute", "ucircumflex",
+    "udiaeresis", "yacute", "thorn", "ydiaeresis",
+];
+
+/// Letters of the `Cyrillic_` keysyms, in lower case. Upper case letters have their names in
+/// upper case.
+const CYRILLIC_KEYSYMS: &[(&str, char)] = &[
+    ("a", 'а'),
+    ("be", 'б'),
+    ("ve", 'в'),
+    ("ghe", 'г'),
+    ("de", 'д'),
+    ("ie", 'е'),
+    ("io", 'ё'),
+    ("zhe", 'ж'),
+    ("ze", 'з'),
+    ("i", 'и'),
+    ("shorti", 'й'),
+    ("ka", 'к'),
+    ("el", 'л'),
+    ("em", 'м'),
+    ("en", 'н'),
+    ("o", 'о'),
+    ("pe", 'п'),
+    ("er", 'р'),
+    ("es", 'с'),
+    ("te", 'т'),
+    ("u", 'у'),
+    ("ef", 'ф'),
+    ("ha", 'х'),
+    ("tse", 'ц'),
+    ("che", 'ч'),
+    ("sha", 'ш'),
+    ("shcha", 'щ'),
+    ("hardsign", 'ъ'),
+    ("yeru", 'ы'),
+    ("softsign", 'ь'),
+    ("e", 'э'),
+    ("yu", 'ю'),
+    ("ya", 'я'),
+];
+
+/// Character typed by the keysym `name`: letters, digits, ASCII and Latin-1 symbols, Cyrillic
+/// letters, and Unicode keysyms, named `Uxxxx` or numbered `0x100xxxxx`.
+pub(crate) fn keysym_char(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    if let (Some(character), None) = (chars.next(), chars.next()) {
+        return Some(character).filter(char::is_ascii_alphanumeric);
+    }
+    let unicode = match name.strip_prefix("0x") {
+        // Keysyms of Unicode characters are their code point plus 0x1000000.
+        Some(hex) => u32::from_str_radix(hex, 16)
+            .ok()
+            .and_then(|keysym| keysym.checked_sub(0x0100_0000)),
+        None => name.strip_prefix('U').and_then(|hex| u32::from_str_radix(hex, 16).ok()),
+    };
+    if let Some(character) = unicode.and_then(std::char::from_u32) {
+        return Some(character);
+    }
+    if let Some(letter) = name.strip_prefix("Cyrillic_") {
+        return CYRILLIC_KEYSYMS.iter().find_map(|(name, character)| {
+            if letter == *name {
+                Some(*character)
+            } else if letter == name.to_uppercase() {
+                character.to_uppercase().next()
+            } else {
+                None
+            }
+        });
+    }
+    if let Some(index) = LATIN1_KEYSYMS.iter().position(|keysym| *keysym == name) {
+        return std::char::from_u32(0xa0 + index as u32);
+    }
+    KEYSYMS
+        .iter()
+        .find(|(keysym, _)| *keysym == name)
+        .map(|(_, character)| *character)
+}
@@ -0,0 +1,839 @@
+//! Keyboard layouts emulated in software.
+
+use super::keysym::keysym_char;
+use super::type_text;
+use device_events::{HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::{can_type_unicode, lock_state};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use {Key, KeyEvent};
+
+/// Priority of the layout emulation among the pre-filters, after the filters of the crate and
+/// before the other text features.
+const PRIORITY: i32 = 600;
+
+/// Keys of the character block with their XKB names and their PC scan codes, row by row.
+const POSITIONS: &[(Key, &str, u32)] = &[
+    (Key::Grave, "TLDE", 0x29),
+    (Key::Key1, "AE01", 0x02),
+    (Key::Key2, "AE02", 0x03),
+    (Key::Key3, "AE03", 0x04),
+    (Key::Key4, "AE04", 0x05),
+    (Key::Key5, "AE05", 0x06),
+    (Key::Key6, "AE06", 0x07),
+    (Key::Key7, "AE07", 0x08),
+    (Key::Key8, "AE08", 0x09),
+    (Key::Key9, "AE09", 0x0a),
+    (Key::Key0, "AE10", 0x0b),
+    (Key::Minus, "AE11", 0x0c),
+    (Key::Equal, "AE12", 0x0d),
+    (Key::Q, "AD01", 0x10),
+    (Key::W, "AD02", 0x11),
+    (Key::E, "AD03", 0x12),
+    (Key::R, "AD04", 0x13),
+    (Key::T, "AD05", 0x14),
+    (Key::Y, "AD06", 0x15),
+    (Key::U, "AD07", 0x16),
+    (Key::I, "AD08", 0x17),
+    (Key::O, "AD09", 0x18),
+    (Key::P, "AD10", 0x19),
+    (Key::LeftBracket, "AD11", 0x1a),
+    (Key::RightBracket, "AD12", 0x1b),
+    (Key::BackSlash, "BKSL", 0x2b),
+    (Key::A, "AC01", 0x1e),
+    (Key::S, "AC02", 0x1f),
+    (Key::D, "AC03", 0x20),
+    (Key::F, "AC04", 0x21),
+    (Key::G, "AC05", 0x22),
+    (Key::H, "AC06", 0x23),
+    (Key::J, "AC07", 0x24),
+    (Key::K, "AC08", 0x25),
+    (Key::L, "AC09", 0x26),
+    (Key::Semicolon, "AC10", 0x27),
+    (Key::Apostrophe, "AC11", 0x28),
+    (Key::Z, "AB01", 0x2c),
+    (Key::X, "AB02", 0x2d),
+    (Key::C, "AB03", 0x2e),
+    (Key::V, "AB04", 0x2f),
+    (Key::B, "AB05", 0x30),
+    (Key::N, "AB06", 0x31),
+    (Key::M, "AB07", 0x32),
+    (Key::Comma, "AB08", 0x33),
+    (Key::Dot, "AB09", 0x34),
+    (Key::Slash, "AB10", 0x35),
+    (Key::Space, "SPCE", 0x39),
+];
+
+/// Shift states of the columns of KLC files, as combinations of Shift (1), Ctrl (2) and Alt (4).
+const KLC_SHIFT: u32 = 1;
+const KLC_ALT_GR: u32 = 6;
+
+/// How deep `xkb_symbols` sections may include each other.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Characters typed by a key at each level: alone, with Shift, with AltGr, and with both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyLevels {
+    pub base: Option<char>,
+    pub shift: Option<char>,
+    #[serde(default)]
+    pub alt_gr: Option<char>,
+    #[serde(default)]
+    pub shift_alt_gr: Option<char>,
+}
+
+impl KeyLevels {
+    pub fn new(base: char, shift: char) -> Self {
+        KeyLevels {
+            base: Some(base),
+            shift: Some(shift),
+            alt_gr: None,
+            shift_alt_gr: None,
+        }
+    }
+
+    pub fn alt_gr(mut self, alt_gr: char) -> Self {
+        self.alt_gr = Some(alt_gr);
+        self
+    }
+
+    pub fn shift_alt_gr(mut self, shift_alt_gr: char) -> Self {
+        self.shift_alt_gr = Some(shift_alt_gr);
+        self
+    }
+
+    /// Character typed with or without Shift and AltGr.
+    pub fn get(&self, shift: bool, alt_gr: bool) -> Option<char> {
+        match (shift, alt_gr) {
+            (false, false) => self.base,
+            (true, false) => self.shift,
+            (false, true) => self.alt_gr,
+            (true, true) => self.shift_alt_gr,
+        }
+    }
+
+    /// Character typed with or without Shift and AltGr, Caps Lock inverting Shift for the
+    /// keys typing letters.
+    fn typed(&self, shift: bool, caps_lock: bool, alt_gr: bool) -> Option<char> {
+        self.get(shift != (caps_lock && self.is_letter()), alt_gr)
+    }
+
+    /// Whether Caps Lock applies to the key: it types a letter, in upper case with Shift.
+    fn is_letter(&self) -> bool {
+        match (self.base, self.shift) {
+            (Some(base), Some(shift)) => base.is_lowercase() && base.to_uppercase().eq(Some(shift)),
+            _ => false,
+        }
+    }
+
+    fn from_levels(levels: &[Option<char>]) -> Self {
+        let level = |index: usize| levels.get(index).copied().flatten();
+        KeyLevels {
+            base: level(0),
+            shift: level(1),
+            alt_gr: level(2),
+            shift_alt_gr: level(3),
+        }
+    }
+}
+
+/// A positional keyboard layout: the characters typed by each key of the character block.
+///
+/// Layouts can be built in code, loaded from a simple layout file, from the `xkb_symbols` of
+/// an XKB layout or from a KLC file of the Microsoft Keyboard Layout Creator. Keys are named
+/// by where they are on a US QWERTY keyboard, and levels missing from the layout type nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Layout {
+    pub name: String,
+    pub keys: BTreeMap<Key, KeyLevels>,
+}
+
+impl Layout {
+    pub fn new(name: &str) -> Self {
+        Layout {
+            name: name.to_string(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Make `key` type `levels`.
+    pub fn key(mut self, key: Key, levels: KeyLevels) -> Self {
+        self.keys.insert(key, levels);
+        self
+    }
+
+    /// Layout typing the characters of `base` and `shift` on the keys of the character block,
+    /// row by row, without the space bar.
+    fn from_rows(name: &str, base: &str, shift: &str) -> Self {
+        let keys = POSITIONS
+            .iter()
+            .zip(base.chars().zip(shift.chars()))
+            .map(|((key, _, _), (base, shift))| (*key, KeyLevels::new(base, shift)))
+            .collect();
+        Layout {
+            name: name.to_string(),
+            keys,
+        }
+    }
+
+    /// The US Dvorak layout.
+    pub fn dvorak() -> Self {
+        Layout::from_rows(
+            "Dvorak",
+            "`1234567890[]',.pyfgcrl/=\\aoeuidhtns-;qjkxbmwvz",
+            "~!@#$%^&*(){}\"<>PYFGCRL?+|AOEUIDHTNS_:QJKXBMWVZ",
+        )
+    }
+
+    /// The Colemak layout.
+    pub fn colemak() -> Self {
+        Layout::from_rows(
+            "Colemak",
+            "`1234567890-=qwfpgjluy;[]\\arstdhneio'zxcvbkm,./",
+            "~!@#$%^&*()_+QWFPGJLUY:{}|ARSTDHNEIO\"ZXCVBKM<>?",
+        )
+    }
+
+    /// Parse a simple layout file: a `name` line, then a line per key with the character
+    /// typed by the key on US QWERTY, followed by the characters it types alone, with Shift,
+    /// with AltGr and with both. Trailing levels may be left out. Characters are written as is,
+    /// as `U+XXXX`, or as `space`, and `none` stands for no character. Lines starting with `#`
+    /// are comments.
+    ///
+    /// ```text
+    /// name Dvorak
+    /// q ' "
+    /// w , <
+    /// e . > € ¢
+    /// ```
+    pub fn parse(text: &str) -> io::Result<Layout> {
+        let mut layout = Layout::new("");
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("name ") {
+                layout.name = name.trim().to_string();
+                continue;
+            }
+            let error = |message: &str| invalid_data(format!("line {}: {}", number + 1, message));
+            let mut fields = line.split_whitespace();
+            let position = fields.next().unwrap_or_default();
+            let key = parse_char(position)
+                .and_then(|character| Key::from_us_char(character?))
+                .filter(|(_, shift)| !shift)
+                .map(|(key, _)| key)
+                .ok_or_else(|| error(&format!("no key types {} on US QWERTY", position)))?;
+            let levels = fields
+                .map(|field| parse_char(field).ok_or_else(|| error(&format!("invalid character {}", field))))
+                .collect::<io::Result<Vec<_>>>()?;
+            if levels.is_empty() || levels.len() > 4 {
+                return Err(error("expected 1 to 4 characters"));
+            }
+            layout.keys.insert(key, KeyLevels::from_levels(&levels));
+        }
+        Ok(layout)
+    }
+
+    /// Parse the `xkb_symbols` section `variant` of an XKB symbols file, or its first one, like
+    /// `/usr/share/X11/xkb/symbols/us`. Only the keys of the first group are read, and only the
+    /// include statements naming sections of the same file are followed. Keysyms that don't
+    /// type a character, like dead keys, type nothing.
+    pub fn from_xkb(text: &str, variant: Option<&str>) -> io::Result<Layout> {
+        let text: String = text
+            .lines()
+            .map(|line| line.find("//").map_or(line, |comment| &line[..comment]))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut layout = Layout::new("");
+        layout.name = xkb_keys(&text, variant, &mut layout.keys, 0)?;
+        Ok(layout)
+    }
+
+    /// Parse a KLC file of the Microsoft Keyboard Layout Creator. Only the levels alone, with
+    /// Shift, with AltGr and with both are read, and dead keys type their own character.
+    pub fn from_klc(text: &str) -> io::Result<Layout> {
+        let mut layout = Layout::new("");
+        let mut shift_states: Vec<u32> = Vec::new();
+        let mut section = "";
+        for line in text.lines() {
+            let line = line.find("//").map_or(line, |comment| &line[..comment]).trim();
+            let mut fields = line.split_whitespace();
+            let first = match fields.next() {
+                Some(first) => first,
+                None => continue,
+            };
+            if first.len() > 2 && first.chars().all(|character| character.is_ascii_uppercase() || character == '_') {
+                section = match first {
+                    "KBD" => {
+                        let description = line.split_once('"').map_or("", |(_, description)| description);
+                        layout.name = description.trim_end_matches('"').to_string();
+                        ""
+                    }
+                    "SHIFTSTATE" | "LAYOUT" => first,
+                    _ => "",
+                };
+                continue;
+            }
+            match section {
+                "SHIFTSTATE" => shift_states.push(
+                    first
+                        .parse()
+                        .map_err(|_| invalid_data(format!("invalid shift state {}", first)))?,
+                ),
+                "LAYOUT" => {
+                    let scan_code = u32::from_str_radix(first, 16)
+                        .map_err(|_| invalid_data(format!("invalid scan code {}", first)))?;
+                    let key = match POSITIONS.iter().find(|(_, _, code)| *code == scan_code) {
+                        Some((key, _, _)) => *key,
+                        None => continue,
+                    };
+                    // Skip the virtual key and the Caps Lock behaviour.
+                    let characters: Vec<Option<char>> = fields.skip(2).map(klc_char).collect();
+                    let level = |state: u32| {
+                        shift_states
+                            .iter()
+                            .position(|shift_state| *shift_state == state)
+                            .and_then(|column| characters.get(column).copied().flatten())
+                    };
+                    layout.keys.insert(
+                        key,
+                        KeyLevels {
+                            base: level(0),
+                            shift: level(KLC_SHIFT),
+                            alt_gr: level(KLC_ALT_GR),
+                            shift_alt_gr: level(KLC_ALT_GR | KLC_SHIFT),
+                        },
+                    );
+                }
+                _ => {}
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Load a layout file: a KLC file if its extension is `.klc`, XKB symbols if it has an
+    /// `xkb_symbols` section, and a simple layout file otherwise. KLC files are usually saved
+    /// in UTF-16.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Layout> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        let text = match bytes.get(..2) {
+            Some([0xff, 0xfe]) => {
+                let units: Vec<u16> = bytes[2..]
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .collect();
+                String::from_utf16(&units).map_err(|error| invalid_data(error.to_string()))?
+            }
+            _ => String::from_utf8(bytes).map_err(|error| invalid_data(error.to_string()))?,
+        };
+        let is_klc = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("klc"));
+        if is_klc {
+            Layout::from_klc(&text)
+        } else if text.contains("xkb_symbols") {
+            Layout::from_xkb(&text, None)
+        } else {
+            Layout::parse(&text)
+        }
+    }
+}
+
+/// Character written as `field` in a simple layout file, `None` for `none`. Returns `None` if
+/// the field is invalid.
+fn parse_char(field: &str) -> Option<Option<char>> {
+    match field {
+        "none" => return Some(None),
+        "space" => return Some(Some(' ')),
+        _ => {}
+    }
+    if let Some(hex) = field.strip_prefix("U+") {
+        return u32::from_str_radix(hex, 16).ok().and_then(std::char::from_u32).map(Some);
+    }
+    let mut chars = field.chars();
+    match (chars.next(), chars.next()) {
+        (Some(character), None) => Some(Some(character)),
+        _ => None,
+    }
+}
+
+/// Character written as `field` in the layout of a KLC file: a character as is or as 4 hex
+/// digits, dead when followed by `@`, or `-1` for none.
+fn klc_char(field: &str) -> Option<char> {
+    let field = field.trim_end_matches('@');
+    let mut chars = field.chars();
+    match (chars.next(), chars.next()) {
+        (Some(character), None) => Some(character),
+        _ if field.len() == 4 => u32::from_str_radix(field, 16).ok().and_then(std::char::from_u32),
+        _ => None,
+    }
+}
+
+/// Name and body of the `xkb_symbols` section named `variant`, or of the first one. Text
+/// without sections is a body of its own.
+fn xkb_section<'a>(text: &'a str, variant: Option<&str>) -> Option<(&'a str, &'a str)> {
+    if !text.contains("xkb_symbols") {
+        return Some(("xkb", text));
+    }
+    let mut rest = text;
+    while let Some(start) = rest.find("xkb_symbols") {
+        rest = &rest[start + "xkb_symbols".len()..];
+        let name = rest.split('"').nth(1).unwrap_or_default();
+        let open = rest.find('{')?;
+        let mut depth = 0;
+        let mut close = None;
+        for (index, character) in rest[open..].char_indices() {
+            match character {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(open + index);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let close = close?;
+        if variant.is_none_or(|variant| variant == name) {
+            return Some((name, &rest[open + 1..close]));
+        }
+        rest = &rest[close..];
+    }
+    None
+}
+
+/// Read the keys of the `xkb_symbols` section `variant` of `text`, or of its first one, into
+/// `keys`, returning the name of the section.
+fn xkb_keys(text: &str, variant: Option<&str>, keys: &mut BTreeMap<Key, KeyLevels>, depth: usize) -> io::Result<String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(invalid_data("xkb_symbols sections include each other".to_string()));
+    }
+    let (section, body) = xkb_section(text, variant)
+        .ok_or_else(|| invalid_data(format!("no xkb_symbols {}", variant.unwrap_or("section"))))?;
+
+    let mut rest = body;
+    loop {
+        let key = rest.find("key <");
+        let include = rest.find("include \"").filter(|include| key.is_none_or(|key| *include < key));
+        match (include, key) {
+            (Some(include), _) => {
+                rest = &rest[include + "include \"".len()..];
+                let end = rest.find('"').ok_or_else(|| invalid_data("unterminated include".to_string()))?;
+                // Only `file(section)` with a section of this file can be followed.
+                let included = rest[..end]
+                    .split_once('(')
+                    .map(|(_, section)| section.trim_end_matches(')'))
+                    .filter(|section| xkb_section(text, Some(section)).is_some());
+                if let Some(included) = included {
+                    xkb_keys(text, Some(included), keys, depth + 1)?;
+                }
+                rest = &rest[end..];
+            }
+            (None, Some(start)) => {
+                rest = &rest[start + "key <".len()..];
+                let name_end = rest.find('>').ok_or_else(|| invalid_data("unterminated key name".to_string()))?;
+                let name = &rest[..name_end];
+                let definition = match (rest.find('{'), rest.find("};")) {
+                    (Some(open), Some(close)) if open < close => &rest[open + 1..close],
+                    _ => return Err(invalid_data(format!("invalid definition of key <{}>", name))),
+                };
+                rest = &rest[name_end..];
+
+                let key = match POSITIONS.iter().find(|(_, xkb_name, _)| *xkb_name == name) {
+                    Some((key, _, _)) => *key,
+                    None => continue,
+                };
+                if let Some(symbols) = xkb_symbols(definition) {
+                    let levels: Vec<Option<char>> = symbols.split(',').map(|keysym| keysym_char(keysym.trim())).collect();
+                    keys.insert(key, KeyLevels::from_levels(&levels));
+                }
+            }
+            (None, None) => break,
+        }
+    }
+    Ok(xkb_group_name(body).unwrap_or_else(|| section.to_string()))
+}
+
+/// Keysyms of the first group in a key definition, e.g. `q, Q` in `{ [ q, Q ] }` or in
+/// `{ type[Group1] = "ALPHABETIC", symbols[Group1] = [ q, Q ] }`.
+fn xkb_symbols(definition: &str) -> Option<&str> {
+    let mut offset = 0;
+    while let Some(open) = definition[offset..].find('[') {
+        let open = offset + open;
+        let close = open + definition[open..].find(']')?;
+        let is_index = definition[..open]
+            .chars()
+            .next_back()
+            .is_some_and(|character| character.is_alphanumeric());
+        if !is_index {
+            return Some(&definition[open + 1..close]);
+        }
+        offset = close + 1;
+    }
+    None
+}
+
+/// Name given to the first group of a section, e.g. `name[Group1]= "English (Dvorak)";`.
+fn xkb_group_name(body: &str) -> Option<String> {
+    let start = body.find("name[")?;
+    let rest = &body[start..];
+    let end = rest.find(';')?;
+    rest[..end].split('"').nth(1).map(str::to_string)
+}
+
+struct State {
+    layout: Layout,
+    enabled: bool,
+    /// Modifier keys held down, by key code.
+    modifiers: HashSet<u32>,
+    /// Key codes whose press was replaced, so that their release is swallowed.
+    replaced: HashSet<u32>,
+}
+
+impl State {
+    fn holds(&self, keys: &[Key]) -> bool {
+        keys.iter()
+            .filter_map(|key| key.code())
+            .any(|code| self.modifiers.contains(&code))
+    }
+}
+
+/// Emulates a keyboard layout in software on top of a US QWERTY layout of the OS, e.g. to type
+/// on Dvorak on a shared machine without changing its settings. Every key of the layout types
+/// its character at the level of Shift and AltGr (Right Alt), and Caps Lock applies to the
+/// keys typing letters.
+///
+/// Characters the OS layout has are typed with its keys, the others as Unicode text. Where text
+/// can't be typed, like on Wayland, those keys type what the OS layout has on them instead.
+/// Keys held with Ctrl, Alt or Meta keep their meaning on the OS layout, so that shortcuts
+/// don't move. The other text features don't see the characters typed by the layout.
+/// Simulated events are left alone.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Layout, LayoutEmulation};
+///
+/// let _device_state = DeviceState::new();
+/// let layout = Layout::load("my_layout.txt").unwrap_or_else(|_| Layout::colemak());
+/// let _emulation = LayoutEmulation::install(layout);
+/// ```
+///
+/// The emulation is removed when dropped.
+pub struct LayoutEmulation {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+}
+
+impl LayoutEmulation {
+    /// Install the emulation, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(layout: Layout) -> LayoutEmulation {
+        let state = Arc::new(Mutex::new(State {
+            layout,
+            enabled: true,
+            modifiers: HashSet::new(),
+            replaced: HashSet::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "layout emulation",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| match handler_state.lock() {
+                    Ok(mut state) => filter(&mut state, event),
+                    Err(_) => Verdict::Pass,
+                }),
+            );
+        LayoutEmulation { id, state }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the emulation without removing it, e.g. from a hotkey.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.lock().enabled = enabled;
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.lock().layout.clone()
+    }
+
+    pub fn set_layout(&self, layout: Layout) {
+        self.lock().layout = layout;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the layout emulation state")
+    }
+}
+
+impl Drop for LayoutEmulation {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(state: &mut State, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    let key = Key::from_code(event.key_code);
+    if key.and_then(Key::modifier).is_some() {
+        if event.is_pressed {
+            state.modifiers.insert(event.key_code);
+        } else {
+            state.modifiers.remove(&event.key_code);
+        }
+        return Verdict::Pass;
+    }
+    if !event.is_pressed {
+        return if state.replaced.remove(&event.key_code) {
+            Verdict::Block
+        } else {
+            Verdict::Pass
+        };
+    }
+    if !state.enabled {
+        return Verdict::Pass;
+    }
+    let levels = match key.and_then(|key| state.layout.keys.get(&key)) {
+        Some(levels) => *levels,
+        None => return Verdict::Pass,
+    };
+
+    let alt_gr = state.holds(&[Key::RAlt]);
+    // AltGr is reported as Ctrl and Alt on Windows.
+    if state.holds(&[Key::LAlt, Key::LMeta, Key::RMeta]) || (state.holds(&[Key::LControl, Key::RControl]) && !alt_gr) {
+        return Verdict::Pass;
+    }
+    let caps_lock = lock_state().caps_lock;
+    let shift = state.holds(&[Key::LShift, Key::RShift]);
+    state.replaced.insert(event.key_code);
+    let character = match levels.typed(shift, caps_lock, alt_gr) {
+        Some(character) => character,
+        None => return Verdict::Block,
+    };
+
+    // Modifiers changing the level on the OS layout, released while the character is typed.
+    let mut held: Vec<u32> = [Key::LShift, Key::RShift, Key::RAlt, Key::LControl, Key::RControl]
+        .iter()
+        .filter_map(|key| key.code())
+        .filter(|code| state.modifiers.contains(code))
+        .collect();
+    let typed = match Key::from_us_char(character).and_then(|(key, shift)| Some((key, key.code()?, shift))) {
+        Some((key, code, shift)) => {
+            let caps_lock = caps_lock && key.us_char(false).is_some_and(char::is_alphabetic);
+            let shift_codes: Vec<u32> = [Key::LShift, Key::RShift].iter().filter_map(|key| key.code()).collect();
+            match Key::LShift.code() {
+                // Shift alone is held and needed.
+                Some(_) if shift != caps_lock && !held.is_empty() && held.iter().all(|code| shift_codes.contains(code)) => {
+                    held.clear();
+                    tap(code)
+                }
+                Some(shift_code) if shift != caps_lock => {
+                    let mut typed = vec![KeyEvent::new(None, shift_code, 0, true, true)];
+                    typed.extend(tap(code));
+                    typed.push(KeyEvent::new(None, shift_code, 0, false, true));
+                    typed
+                }
+                _ => tap(code),
+            }
+        }
+        None if can_type_unicode() => type_text(&character.to_string()),
+        // The key types its character on the OS layout rather than nothing.
+        None => {
+            state.replaced.remove(&event.key_code);
+            return Verdict::Pass;
+        }
+    };
+
+    let mut events: Vec<KeyEvent> = held.iter().map(|code| KeyEvent::new(None, *code, 0, false, true)).collect();
+    events.extend(typed);
+    events.extend(held.iter().rev().map(|code| KeyEvent::new(None, *code, 0, true, true)));
+    Verdict::Replace(events)
+}
+
+/// Events pressing and releasing the key `code`.
+fn tap(code: u32) -> Vec<KeyEvent> {
+    vec![KeyEvent::new(None, code, 0, true, true), KeyEvent::new(None, code, 0, false, true)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_are_picked_by_shift_caps_lock_and_alt_gr() {
+        let e = KeyLevels::new('e', 'E').alt_gr('€').shift_alt_gr('¢');
+        assert_eq!(e.typed(false, false, false), Some('e'));
+        assert_eq!(e.typed(true, false, false), Some('E'));
+        assert_eq!(e.typed(false, false, true), Some('€'));
+        assert_eq!(e.typed(true, false, true), Some('¢'));
+        // Caps Lock inverts Shift on letters only.
+        assert_eq!(e.typed(false, true, false), Some('E'));
+        assert_eq!(e.typed(true, true, false), Some('e'));
+        let one = KeyLevels::new('1', '!');
+        assert_eq!(one.typed(false, true, false), Some('1'));
+        assert_eq!(one.typed(false, false, true), None);
+    }
+
+    #[test]
+    fn simple_layout_files_are_parsed() {
+        let layout = Layout::parse(
+            "# Comment\n\
+             name My layout\n\
+             q ' \"\n\
+             e . > € U+00A2\n\
+             ; s S none ß\n\
+             \n\
+             / space\n",
+        )
+        .unwrap();
+        assert_eq!(layout.name, "My layout");
+        assert_eq!(layout.keys[&Key::Q], KeyLevels::new('\'', '"'));
+        assert_eq!(layout.keys[&Key::E], KeyLevels::new('.', '>').alt_gr('€').shift_alt_gr('¢'));
+        assert_eq!(
+            layout.keys[&Key::Semicolon],
+            KeyLevels {
+                base: Some('s'),
+                shift: Some('S'),
+                alt_gr: None,
+                shift_alt_gr: Some('ß'),
+            }
+        );
+        assert_eq!(layout.keys[&Key::Slash].base, Some(' '));
+        assert_eq!(layout.keys[&Key::Slash].shift, None);
+    }
+
+    #[test]
+    fn invalid_simple_layout_lines_are_reported() {
+        let error = |text: &str| Layout::parse(text).unwrap_err().to_string();
+        assert_eq!(error("name x\nQ a A"), "line 2: no key types Q on US QWERTY");
+        assert_eq!(error("q ab"), "line 1: invalid character ab");
+        assert_eq!(error("q U+D800"), "line 1: invalid character U+D800");
+        assert_eq!(error("q"), "line 1: expected 1 to 4 characters");
+        assert_eq!(error("q a b c d e"), "line 1: expected 1 to 4 characters");
+    }
+
+    const XKB: &str = r#"
+        default partial alphanumeric_keys
+        xkb_symbols "basic" {
+            name[Group1]= "Test";
+            key <AD01> { [ q, Q ] };
+            key <AD03> { [ e, E, EuroSign, cent ] }; // Not a Latin-1 keysym.
+            key <AC01> { type[Group1] = "FOUR_LEVEL", symbols[Group1] = [ a, A, aring, Aring ] };
+            key <AC11> { [ dead_acute, quotedbl ] };
+            key <ESC> { [ Escape ] };
+        };
+
+        partial alphanumeric_keys
+        xkb_symbols "cyrillic" {
+            include "test(basic)"
+            include "pc(other)"
+            key <AD01> { [ Cyrillic_shorti, Cyrillic_SHORTI ] };
+        };
+
+        xkb_symbols "loop" {
+            include "test(loop)"
+        };
+    "#;
+
+    #[test]
+    fn xkb_symbols_are_parsed() {
+        let layout = Layout::from_xkb(XKB, None).unwrap();
+        assert_eq!(layout.name, "Test");
+        assert_eq!(layout.keys[&Key::Q], KeyLevels::new('q', 'Q'));
+        assert_eq!(
+            layout.keys[&Key::E],
+            KeyLevels {
+                base: Some('e'),
+                shift: Some('E'),
+                alt_gr: None,
+                shift_alt_gr: Some('¢'),
+            }
+        );
+        assert_eq!(layout.keys[&Key::A], KeyLevels::new('a', 'A').alt_gr('å').shift_alt_gr('Å'));
+        assert_eq!(layout.keys[&Key::Apostrophe].base, None);
+        assert_eq!(layout.keys[&Key::Apostrophe].shift, Some('"'));
+        assert_eq!(layout.keys.len(), 4);
+    }
+
+    #[test]
+    fn xkb_variants_include_sections_of_the_same_file() {
+        let layout = Layout::from_xkb(XKB, Some("cyrillic")).unwrap();
+        assert_eq!(layout.name, "cyrillic");
+        assert_eq!(layout.keys[&Key::Q], KeyLevels::new('й', 'Й'));
+        assert_eq!(layout.keys[&Key::E].base, Some('e'));
+
+        assert_eq!(
+            Layout::from_xkb(XKB, Some("missing")).unwrap_err().to_string(),
+            "no xkb_symbols missing"
+        );
+        assert_eq!(
+            Layout::from_xkb(XKB, Some("loop")).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn klc_files_are_parsed() {
+        let layout = Layout::from_klc(
+            "KBD\tTEST\t\"Test layout\"\n\
+             \n\
+             SHIFTSTATE\n\
+             \n\
+             0\t//Column 4\n\
+             1\t//Column 5 : Shft\n\
+             2\t//Column 6 :       Ctrl\n\
+             6\t//Column 7 :       Ctrl Alt\n\
+             7\t//Column 8 : Shft  Ctrl Alt\n\
+             \n\
+             LAYOUT\t\t;an extra '@' at the end is a dead key\n\
+             \n\
+             //SC\tVK_\t\tCap\t0\t1\t2\t6\t7\n\
+             10\tQ\t\t1\tq\tQ\t-1\t-1\t-1\n\
+             12\tE\t\t1\te\tE\t-1\t20ac\t00a2\n\
+             28\tOEM_7\t\t0\t'@\t\"\t-1\t-1\t-1\n\
+             01\tESCAPE\t\t0\t001b\t001b\t-1\t-1\t-1\n\
+             \n\
+             DEADKEY\t0027\n\
+             \n\
+             0065\t00e9\t// e -> é\n",
+        )
+        .unwrap();
+        assert_eq!(layout.name, "Test layout");
+        assert_eq!(layout.keys[&Key::Q], KeyLevels::new('q', 'Q'));
+        assert_eq!(layout.keys[&Key::E], KeyLevels::new('e', 'E').alt_gr('€').shift_alt_gr('¢'));
+        assert_eq!(layout.keys[&Key::Apostrophe], KeyLevels::new('\'', '"'));
+        assert_eq!(layout.keys.len(), 3);
+    }
+
+    #[test]
+    fn the_builtin_layouts_cover_the_character_block() {
+        let dvorak = Layout::dvorak();
+        assert_eq!(dvorak.keys.len(), POSITIONS.len() - 1);
+        assert_eq!(dvorak.keys[&Key::Q], KeyLevels::new('\'', '"'));
+        assert_eq!(dvorak.keys[&Key::Slash], KeyLevels::new('z', 'Z'));
+        assert_eq!(Layout::colemak().keys[&Key::S], KeyLevels::new('r', 'R'));
+    }
+}
@@ -0,0 +1,880 @@
+//! Correction of words typed on the wrong keyboard layout, between English and Russian.
+
+use super::{type_text, typed_char};
+use arboard::Clipboard;
+use device_events::{resume_replacement, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::{can_type_unicode, InputAction, INJECTOR};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use {Key, KeyEvent};
+
+/// Priority of the layout correction among the pre-filters, after the text expansion engine.
+const PRIORITY: i32 = 400;
+
+/// How long the selection may take to reach the clipboard once copied.
+const COPY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long the converted selection stays in the clipboard to be pasted, before the clipboard
+/// gets its contents back.
+const PASTE_DELAY: Duration = Duration::from_millis(200);
+
+/// Characters typed by the same keys on the US QWERTY and the Russian ЙЦУКЕН layouts, besides
+/// the upper case letters.
+const KEYS: &[(char, char)] = &[
+    ('`', 'ё'),
+    ('q', 'й'),
+    ('w', 'ц'),
+    ('e', 'у'),
+    ('r', 'к'),
+    ('t', 'е'),
+    ('y', 'н'),
+    ('u', 'г'),
+    ('i', 'ш'),
+    ('o', 'щ'),
+    ('p', 'з'),
+    ('[', 'х'),
+    (']', 'ъ'),
+    ('a', 'ф'),
+    ('s', 'ы'),
+    ('d', 'в'),
+    ('f', 'а'),
+    ('g', 'п'),
+    ('h', 'р'),
+    ('j', 'о'),
+    ('k', 'л'),
+    ('l', 'д'),
+    (';', 'ж'),
+    ('\'', 'э'),
+    ('z', 'я'),
+    ('x', 'ч'),
+    ('c', 'с'),
+    ('v', 'м'),
+    ('b', 'и'),
+    ('n', 'т'),
+    ('m', 'ь'),
+    (',', 'б'),
+    ('.', 'ю'),
+    ('/', '.'),
+    ('~', 'Ё'),
+    ('{', 'Х'),
+    ('}', 'Ъ'),
+    (':', 'Ж'),
+    ('"', 'Э'),
+    ('<', 'Б'),
+    ('>', 'Ю'),
+    ('?', ','),
+    ('@', '"'),
+    ('#', '№'),
+    ('$', ';'),
+    ('^', ':'),
+    ('&', '?'),
+    ('|', '/'),
+];
+
+/// Common English words, training the built-in English model and making up its dictionary.
+const ENGLISH_WORDS: &str = "the be to of and a in that have it for not on with he as you do at this \
+    but his by from they we say her she or an will my one all would there their what so up out if \
+    about who get which go me when make can like time no just him know take people into year your \
+    good some could them see other than then now look only come its over think also back after use \
+    two how our work first well way even new want because any these give day most us is are was \
+    were been has had did said made went got thing things very much many more should where here \
+    why still never always every each both few those through down off again before between under \
+    around while last long great little own old right big high different small large next early \
+    young important public bad same able hello thanks please yes sorry today tomorrow yesterday \
+    morning evening night week month world life hand part child eye woman man place case point \
+    government company number group problem fact home water room mother father area money story \
+    question business side kind head house service friend power hour game line end member law car \
+    city community name president team minute idea body information school face others level \
+    office door health person art war history party result change reason research girl guy moment \
+    air teacher force education open close start stop find tell ask seem feel try leave call keep \
+    let begin help talk turn show hear play run move live believe hold bring happen write provide \
+    sit stand lose pay meet include continue set learn lead understand watch follow create speak \
+    read spend grow offer remember love consider appear buy wait serve die send expect build stay \
+    fall cut reach kill remain suggest raise pass sell require report decide pull message email \
+    meeting project code file update check thank";
+
+/// Common Russian words, training the built-in Russian model and making up its dictionary.
+const RUSSIAN_WORDS: &str = "и в не на я быть он с что а по это она этот к но они мы как из у \
+    который то за свой весь год от так о для ты же все тот мочь вы человек такой его сказать \
+    только или еще бы себя один уже до время если сам когда другой вот говорить наш мой знать \
+    стать при чтобы дело жизнь кто первый очень два день ее новый рука даже во со раз где там под \
+    можно ну какой после их работа без самый потом надо хотеть ли слово идти большой должен место \
+    иметь ничто сейчас тут лицо каждый друг нет теперь ни глаз тоже тогда видеть вопрос через да \
+    здесь дом потому сторона какой-то думать сделать страна жить чем мир об последний случай \
+    голова более делать что-то смотреть ребенок просто конечно сила российский конец перед \
+    несколько вид система всегда судьба понять пойти часть спросить город дать также никто \
+    понимать получить отношение лишь второй именно ведь хорошо привет спасибо пожалуйста \
+    здравствуйте пока сегодня завтра вчера утро вечер ночь неделя месяц плохо правда нужно нельзя \
+    давай давайте тебя меня мне тебе нам вам ему ей них нас вас куда откуда зачем почему сколько \
+    много мало больше меньше лучше хуже быстро медленно сразу снова опять здравствуй добрый доброе \
+    вечером утром днем работать писать читать играть знаю думаю хочу могу буду была было были есть \
+    дела делаю сделал пишу звонить позвонить встреча письмо проект файл код проверить вопросы \
+    ответ ответить помощь помочь решить решение задача задачи деньги машина школа семья мама папа";
+
+/// Languages told apart by the layout correction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Language {
+    English,
+    Russian,
+}
+
+impl Language {
+    /// Language `text` was typed in: Russian if it holds Cyrillic letters, English otherwise.
+    pub fn of(text: &str) -> Language {
+        if text.chars().any(|character| matches!(character, 'а'..='я' | 'А'..='Я' | 'ё' | 'Ё')) {
+            Language::Russian
+        } else {
+            Language::English
+        }
+    }
+
+    /// The other language.
+    pub fn other(self) -> Language {
+        match self {
+            Language::English => Language::Russian,
+            Language::Russian => Language::English,
+        }
+    }
+}
+
+/// Character typed by the key of `character` on the layout of the other language.
+fn convert_char(character: char, from: Language) -> char {
+    let convert = |character: char| {
+        KEYS.iter().find_map(|&(english, russian)| match from {
+            Language::English if english == character => Some(russian),
+            Language::Russian if russian == character => Some(english),
+            _ => None,
+        })
+    };
+    if let Some(converted) = convert(character) {
+        return converted;
+    }
+    let mut lower = character.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) if lower != character => convert(lower)
+            .and_then(|converted| converted.to_uppercase().next())
+            .unwrap_or(character),
+        _ => character,
+    }
+}
+
+/// `text` as it would have been typed with the same keys on the layout of the other language,
+/// e.g. `ghbdtn` and `привет`. The language of `text` is told by `Language::of`.
+pub fn convert_layout(text: &str) -> String {
+    let from = Language::of(text);
+    text.chars().map(|character| convert_char(character, from)).collect()
+}
+
+/// `word` in lower case, without the characters that aren't letters at its ends.
+fn trim_word(word: &str) -> String {
+    word.trim_matches(|character: char| !character.is_alphabetic())
+        .to_lowercase()
+}
+
+/// Character trigram model of the words of a language, interpolated with its bigrams and
+/// single characters for the trigrams it hasn't seen.
+#[derive(Debug, Clone, Default)]
+pub struct LayoutModel {
+    trigrams: HashMap<(char, char, char), u32>,
+    bigrams: HashMap<(char, char), u32>,
+    characters: HashMap<char, u32>,
+    /// How often pairs and single characters were followed by a character.
+    pair_contexts: HashMap<(char, char), u32>,
+    character_contexts: HashMap<char, u32>,
+    total: u32,
+}
+
+/// Marks the start of a word in the trigrams.
+const WORD_START: char = '^';
+/// Marks the end of a word in the trigrams.
+const WORD_END: char = '$';
+
+/// Weights of the probabilities of a character after the two previous ones, after the
+/// previous one, and on its own.
+const WEIGHTS: (f64, f64, f64) = (0.5, 0.35, 0.15);
+
+impl LayoutModel {
+    /// Learn the trigrams of the words of `text`, separated by characters that aren't letters.
+    pub fn train(text: &str) -> Self {
+        let mut model = LayoutModel::default();
+        for word in text.split(|character: char| !character.is_alphabetic() && character != '-') {
+            let word = trim_word(word);
+            if word.is_empty() {
+                continue;
+            }
+            for window in padded(&word).windows(3) {
+                let (first, second, character) = (window[0], window[1], window[2]);
+                *model.trigrams.entry((first, second, character)).or_default() += 1;
+                *model.bigrams.entry((second, character)).or_default() += 1;
+                *model.characters.entry(character).or_default() += 1;
+                *model.pair_contexts.entry((first, second)).or_default() += 1;
+                *model.character_contexts.entry(second).or_default() += 1;
+                model.total += 1;
+            }
+        }
+        model
+    }
+
+    /// Probability of `character` after `first` and `second`.
+    fn probability(&self, first: char, second: char, character: char) -> f64 {
+        let ratio = |count: Option<&u32>, context: Option<&u32>| match context {
+            Some(context) => f64::from(count.copied().unwrap_or(0)) / f64::from(*context),
+            None => 0.0,
+        };
+        let trigram = ratio(
+            self.trigrams.get(&(first, second, character)),
+            self.pair_contexts.get(&(first, second)),
+        );
+        let bigram = ratio(self.bigrams.get(&(second, character)), self.character_contexts.get(&second));
+        let count = f64::from(self.characters.get(&character).copied().unwrap_or(0));
+        let single = (count + 1.0) / (f64::from(self.total) + self.characters.len() as f64 + 1.0);
+        WEIGHTS.0 * trigram + WEIGHTS.1 * bigram + WEIGHTS.2 * single
+    }
+
+    /// How plausible `word` is in the language: the mean log-probability of its characters.
+    /// Words without letters get 0, the most plausible score.
+    pub fn score(&self, word: &str) -> f64 {
+        let word = trim_word(word);
+        if word.is_empty() {
+            return 0.0;
+        }
+        let padded = padded(&word);
+        let total: f64 = padded
+            .windows(3)
+            .map(|window| self.probability(window[0], window[1], window[2]).ln())
+            .sum();
+        total / (padded.len() - 2) as f64
+    }
+}
+
+/// `word` padded with the start and end marks.
+fn padded(word: &str) -> Vec<char> {
+    let mut padded = vec![WORD_START, WORD_START];
+    padded.extend(word.chars());
+    padded.push(WORD_END);
+    padded
+}
+
+/// Decides which words were typed on the wrong layout, from the trigram models and the
+/// dictionaries of both languages.
+#[derive(Debug, Clone)]
+pub struct LayoutScorer {
+    english: LayoutModel,
+    russian: LayoutModel,
+    english_words: HashSet<String>,
+    russian_words: HashSet<String>,
+}
+
+impl LayoutScorer {
+    /// Scorer with the built-in models and dictionaries of common words.
+    pub fn new() -> Self {
+        let words = |text: &str| text.split_whitespace().map(str::to_string).collect();
+        LayoutScorer {
+            english: LayoutModel::train(ENGLISH_WORDS),
+            russian: LayoutModel::train(RUSSIAN_WORDS),
+            english_words: words(ENGLISH_WORDS),
+            russian_words: words(RUSSIAN_WORDS),
+        }
+    }
+
+    /// Add `words` to the dictionary of `language`. Words of the dictionaries are never
+    /// corrected, and words converting to them always are.
+    pub fn words<I: IntoIterator<Item = S>, S: AsRef<str>>(mut self, language: Language, words: I) -> Self {
+        let dictionary = match language {
+            Language::English => &mut self.english_words,
+            Language::Russian => &mut self.russian_words,
+        };
+        dictionary.extend(words.into_iter().map(|word| trim_word(word.as_ref())));
+        self
+    }
+
+    pub fn model(&self, language: Language) -> &LayoutModel {
+        match language {
+            Language::English => &self.english,
+            Language::Russian => &self.russian,
+        }
+    }
+
+    pub fn contains(&self, language: Language, word: &str) -> bool {
+        let dictionary = match language {
+            Language::English => &self.english_words,
+            Language::Russian => &self.russian_words,
+        };
+        dictionary.contains(&trim_word(word))
+    }
+
+    /// `word` converted to the other layout if it was typed on the wrong one: if its
+    /// conversion is in the dictionary of the other language, or is more plausible by at
+    /// least `margin` than `word` itself.
+    pub fn correction(&self, word: &str, margin: f64) -> Option<String> {
+        let language = Language::of(word);
+        if trim_word(word).is_empty() || self.contains(language, word) {
+            return None;
+        }
+        let converted = convert_layout(word);
+        if self.contains(language.other(), &converted) {
+            return Some(converted);
+        }
+        let gain = self.model(language.other()).score(&converted) - self.model(language).score(word);
+        if gain >= margin {
+            Some(converted)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for LayoutScorer {
+    fn default() -> Self {
+        LayoutScorer::new()
+    }
+}
+
+/// Configuration of a `LayoutCorrection` engine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayoutCorrectionConfig {
+    /// Whether words are corrected automatically as they end.
+    pub auto: bool,
+    /// Shortest word corrected automatically, in characters.
+    pub min_length: usize,
+    /// How much more plausible a word must be on the other layout to be corrected
+    /// automatically, in mean log-probability per character.
+    pub margin: f64,
+    /// Key converting the last word typed to the other layout, or back.
+    pub word_hotkey: Option<Key>,
+    /// Key converting the selected text to the other layout, through the clipboard.
+    pub selection_hotkey: Option<Key>,
+    /// Keys pressed together to switch the system layout after a conversion, e.g. Alt and
+    /// Shift. The system layout is left alone if empty.
+    #[serde(default)]
+    pub switch_keys: Vec<Key>,
+    /// Words added to the built-in dictionaries.
+    #[serde(default)]
+    pub english_words: BTreeSet<String>,
+    #[serde(default)]
+    pub russian_words: BTreeSet<String>,
+}
+
+impl LayoutCorrectionConfig {
+    /// Correct words of at least 3 characters automatically, without hotkeys.
+    pub fn new() -> Self {
+        LayoutCorrectionConfig {
+            auto: true,
+            min_length: 3,
+            margin: 1.2,
+            word_hotkey: None,
+            selection_hotkey: None,
+            switch_keys: Vec::new(),
+            english_words: BTreeSet::new(),
+            russian_words: BTreeSet::new(),
+        }
+    }
+
+    /// Only convert with the hotkeys.
+    pub fn manual(mut self) -> Self {
+        self.auto = false;
+        self
+    }
+
+    pub fn min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length;
+        self
+    }
+
+    pub fn margin(mut self, margin: f64) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    pub fn word_hotkey(mut self, key: Key) -> Self {
+        self.word_hotkey = Some(key);
+        self
+    }
+
+    pub fn selection_hotkey(mut self, key: Key) -> Self {
+        self.selection_hotkey = Some(key);
+        self
+    }
+
+    pub fn switch_keys(mut self, keys: &[Key]) -> Self {
+        self.switch_keys = keys.to_vec();
+        self
+    }
+
+    /// Add `words` to the dictionary of `language`.
+    pub fn words(mut self, language: Language, words: &[&str]) -> Self {
+        let dictionary = match language {
+            Language::English => &mut self.english_words,
+            Language::Russian => &mut self.russian_words,
+        };
+        dictionary.extend(words.iter().map(|word| word.to_string()));
+        self
+    }
+
+    fn scorer(&self) -> LayoutScorer {
+        LayoutScorer::new()
+            .words(Language::English, &self.english_words)
+            .words(Language::Russian, &self.russian_words)
+    }
+}
+
+impl Default for LayoutCorrectionConfig {
+    fn default() -> Self {
+        LayoutCorrectionConfig::new()
+    }
+}
+
+struct State {
+    config: LayoutCorrectionConfig,
+    scorer: LayoutScorer,
+    enabled: bool,
+    /// Characters of the word being typed.
+    word: Vec<char>,
+    /// Last word typed, as it stands now, and the characters typed after it.
+    last_word: Vec<char>,
+    after_last_word: Vec<char>,
+    /// Key codes whose press was swallowed, so that their release is too.
+    swallowed: HashSet<u32>,
+}
+
+impl State {
+    fn clear(&mut self) {
+        self.word.clear();
+        self.last_word.clear();
+        self.after_last_word.clear();
+    }
+
+    /// Key codes pressed together to switch the system layout, if configured.
+    fn switch_codes(&self) -> Vec<u32> {
+        self.config.switch_keys.iter().filter_map(|key| key.code()).collect()
+    }
+
+    /// Events replacing the last `erased` characters typed with `text`, then switching the
+    /// system layout. Returns `None` if `text` can't be typed.
+    fn retype(&self, erased: usize, text: &str) -> Option<Vec<KeyEvent>> {
+        if !text.is_empty() && !can_type_unicode() {
+            return None;
+        }
+        let mut events = Vec::new();
+        if let Some(code) = Key::Backspace.code() {
+            for _ in 0..erased {
+                events.push(KeyEvent::new(None, code, 0, true, true));
+                events.push(KeyEvent::new(None, code, 0, false, true));
+            }
+        }
+        events.extend(type_text(text));
+        let codes = self.switch_codes();
+        events.extend(codes.iter().map(|code| KeyEvent::new(None, *code, 0, true, true)));
+        events.extend(codes.iter().rev().map(|code| KeyEvent::new(None, *code, 0, false, true)));
+        Some(events)
+    }
+
+    /// Convert the word being typed, or else the last word typed, keeping the characters
+    /// typed after it.
+    fn convert_last_word(&mut self) -> Vec<KeyEvent> {
+        if !self.word.is_empty() {
+            let word: String = self.word.iter().collect();
+            let converted = convert_layout(&word);
+            return match self.retype(self.word.len(), &converted) {
+                Some(events) => {
+                    self.word = converted.chars().collect();
+                    events
+                }
+                None => Vec::new(),
+            };
+        }
+        if self.last_word.is_empty() {
+            return Vec::new();
+        }
+        let word: String = self.last_word.iter().collect();
+        let after: String = self.after_last_word.iter().collect();
+        let converted = convert_layout(&word);
+        match self.retype(self.last_word.len() + self.after_last_word.len(), &(converted.clone() + &after)) {
+            Some(events) => {
+                self.last_word = converted.chars().collect();
+                events
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// End the word being typed, correcting it if it was typed on the wrong layout.
+    fn end_word(&mut self) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+        if !self.word.is_empty() {
+            let word: String = self.word.iter().collect();
+            if self.config.auto && self.word.len() >= self.config.min_length {
+                if let Some(converted) = self.scorer.correction(&word, self.config.margin) {
+                    if let Some(retyped) = self.retype(self.word.len(), &converted) {
+                        events = retyped;
+                        self.word = converted.chars().collect();
+                    }
+                }
+            }
+            self.last_word = self.word.split_off(0);
+            self.after_last_word.clear();
+        }
+        events
+    }
+}
+
+/// Corrects words typed on the wrong keyboard layout between English and Russian, like
+/// `ghbdtn` typed for `привет`: when a word ends and is far more plausible on the other
+/// layout, it is erased and typed again as meant. The system layout can be switched along.
+///
+/// A hotkey converts the last word, or back if it was corrected by mistake. Another one
+/// converts the selected text by copying it, and pasting it converted before restoring the
+/// clipboard.
+///
+/// Words are told apart by the trigram models of `LayoutScorer` and its dictionaries of
+/// common words, which can be extended. Words end with Space, Enter or Tab. Keys that don't
+/// type text, like arrows, forget the words typed. Simulated events are left alone. Words stay
+/// as typed where the conversion can't be typed, like on Wayland.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Key, Language, LayoutCorrection, LayoutCorrectionConfig};
+///
+/// let _device_state = DeviceState::new();
+/// let _correction = LayoutCorrection::install(
+///     LayoutCorrectionConfig::new()
+///         .word_hotkey(Key::Pause)
+///         .selection_hotkey(Key::ScrollLock)
+///         .switch_keys(&[Key::LAlt, Key::LShift])
+///         .words(Language::English, &["rustc", "serde"]),
+/// );
+/// ```
+///
+/// The engine is removed when dropped.
+pub struct LayoutCorrection {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+}
+
+impl LayoutCorrection {
+    /// Install the engine, enabled, in the `Stage::PreFilter` stage of the callback chain.
+    pub fn install(config: LayoutCorrectionConfig) -> LayoutCorrection {
+        let state = Arc::new(Mutex::new(State {
+            scorer: config.scorer(),
+            config,
+            enabled: true,
+            word: Vec::new(),
+            last_word: Vec::new(),
+            after_last_word: Vec::new(),
+            swallowed: HashSet::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "layout correction",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| {
+                    let verdict = match handler_state.lock() {
+                        Ok(mut state) => filter(&mut state, event),
+                        Err(_) => Verdict::Pass,
+                    };
+                    resume_replacement(event, verdict)
+                }),
+            );
+        LayoutCorrection { id, state }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Enable or disable the engine without removing it. The hotkeys keep working.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.lock();
+        state.enabled = enabled;
+        state.clear();
+    }
+
+    pub fn config(&self) -> LayoutCorrectionConfig {
+        self.lock().config.clone()
+    }
+
+    pub fn set_config(&self, config: LayoutCorrectionConfig) {
+        let mut state = self.lock();
+        state.scorer = config.scorer();
+        state.config = config;
+        state.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the layout correction state")
+    }
+}
+
+impl Drop for LayoutCorrection {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(state: &mut State, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    if !event.is_pressed {
+        return if state.swallowed.remove(&event.key_code) {
+            Verdict::Block
+        } else {
+            Verdict::Pass
+        };
+    }
+    if state.swallowed.contains(&event.key_code) {
+        return Verdict::Block;
+    }
+
+    let key = Key::from_code(event.key_code);
+    if key.is_some() && key == state.config.word_hotkey {
+        let events = state.convert_last_word();
+        state.swallowed.insert(event.key_code);
+        return if events.is_empty() {
+            Verdict::Block
+        } else {
+            Verdict::Replace(events)
+        };
+    }
+    if key.is_some() && key == state.config.selection_hotkey {
+        state.clear();
+        let switch_codes = state.switch_codes();
+        thread::spawn(move || convert_selection(&switch_codes));
+        state.swallowed.insert(event.key_code);
+        return Verdict::Block;
+    }
+    if !state.enabled {
+        return Verdict::Pass;
+    }
+
+    if key == Some(Key::Backspace) {
+        if state.word.pop().is_none() && state.after_last_word.pop().is_none() {
+            state.last_word.pop();
+            state.word = state.last_word.split_off(0);
+        }
+        return Verdict::Pass;
+    }
+    let character = match typed_char(event) {
+        Some(character) => Some(character),
+        None if matches!(key, Some(Key::Enter) | Some(Key::NumpadEnter) | Some(Key::Tab)) => None,
+        None => {
+            if key.and_then(Key::modifier).is_none() {
+                state.clear();
+            }
+            return Verdict::Pass;
+        }
+    };
+
+    let events = match character {
+        Some(character) if !character.is_whitespace() => {
+            state.word.push(character);
+            return Verdict::Pass;
+        }
+        _ => state.end_word(),
+    };
+    match character {
+        Some(character) => state.after_last_word.push(character),
+        // Enter and Tab move elsewhere.
+        None => state.clear(),
+    }
+    if events.is_empty() {
+        Verdict::Pass
+    } else {
+        let mut events = events;
+        events.push(event.clone());
+        Verdict::Replace(events)
+    }
+}
+
+/// Chord pressing `key` along with the copy and paste modifier of the platform.
+fn shortcut(key: Key) -> Vec<InputAction> {
+    let modifier = if cfg!(target_os = "macos") { Key::LMeta } else { Key::LControl };
+    match (modifier.code(), key.code()) {
+        (Some(modifier), Some(key)) => vec![
+            InputAction::KeyDown(modifier),
+            InputAction::KeyDown(key),
+            InputAction::KeyUp(key),
+            InputAction::KeyUp(modifier),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// Convert the selected text of the focused window: copy it, paste it converted, then put the
+/// contents of the clipboard back. Runs on a thread of its own, since it waits on the window.
+fn convert_selection(switch_codes: &[u32]) {
+    let mut clipboard = match Clipboard::new() {
+        Ok(clipboard) => clipboard,
+        Err(_) => return,
+    };
+    let previous = clipboard.get_text().ok();
+    if clipboard.clear().is_err() {
+        return;
+    }
+    INJECTOR.send(&shortcut(Key::C));
+
+    let copied_by = Instant::now() + COPY_TIMEOUT;
+    let selection = loop {
+        match clipboard.get_text() {
+            Ok(text) if !text.is_empty() => break Some(text),
+            _ if Instant::now() >= copied_by => break None,
+            _ => thread::sleep(Duration::from_millis(20)),
+        }
+    };
+    if let Some(selection) = selection {
+        if clipboard.set_text(convert_layout(&selection)).is_ok() {
+            INJECTOR.send(&shortcut(Key::V));
+            let mut switch: Vec<InputAction> = switch_codes.iter().map(|code| InputAction::KeyDown(*code)).collect();
+            switch.extend(switch_codes.iter().rev().map(|code| InputAction::KeyUp(*code)));
+            INJECTOR.send(&switch);
+            thread::sleep(PASTE_DELAY);
+        }
+    }
+    match previous {
+        Some(previous) => {
+            let _ = clipboard.set_text(previous);
+        }
+        None => {
+            let _ = clipboard.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::key;
+
+    fn typed(character: char) -> KeyEvent {
+        KeyEvent::new(Some(character), Key::A.code().unwrap(), 0, true, false)
+    }
+
+    fn state(config: LayoutCorrectionConfig) -> State {
+        State {
+            scorer: config.scorer(),
+            config,
+            enabled: true,
+            word: Vec::new(),
+            last_word: Vec::new(),
+            after_last_word: Vec::new(),
+            swallowed: HashSet::new(),
+        }
+    }
+
+    fn type_str(state: &mut State, text: &str) {
+        for character in text.chars() {
+            assert_eq!(filter(state, &typed(character)), Verdict::Pass);
+        }
+    }
+
+    fn text(characters: &[char]) -> String {
+        characters.iter().collect()
+    }
+
+    #[test]
+    fn conversion_round_trips_between_the_layouts() {
+        assert_eq!(convert_layout("ghbdtn"), "привет");
+        assert_eq!(convert_layout("привет"), "ghbdtn");
+        assert_eq!(convert_layout("Ghbdtn"), "Привет");
+        assert_eq!(convert_layout("Привет"), "Ghbdtn");
+        assert_eq!(convert_layout("ult ns?"), "где ты,");
+        assert_eq!(convert_layout(&convert_layout("ghbdtn, vbh!")), "ghbdtn, vbh!");
+        // Characters typed by the same key on both layouts stay.
+        assert_eq!(convert_layout("123"), "123");
+    }
+
+    #[test]
+    fn the_language_is_told_by_cyrillic_letters() {
+        assert_eq!(Language::of("ghbdtn"), Language::English);
+        assert_eq!(Language::of("приvet"), Language::Russian);
+        assert_eq!(Language::of("Ёж"), Language::Russian);
+    }
+
+    #[test]
+    fn scoring_prefers_the_layout_a_word_was_meant_for() {
+        let scorer = LayoutScorer::new();
+        let english = scorer.model(Language::English);
+        let russian = scorer.model(Language::Russian);
+        // Neither word is in the dictionaries.
+        assert!(russian.score("стол") > english.score("cnjk"));
+        assert!(english.score("table") > russian.score("ефиду"));
+        assert!(english.score("strength") > english.score("cnhtyui"));
+
+        assert_eq!(scorer.correction("ghbdtn", 1.2), Some("привет".to_string()));
+        assert_eq!(scorer.correction("руддщ", 1.2), Some("hello".to_string()));
+        assert_eq!(scorer.correction("cnjk", 1.0), Some("стол".to_string()));
+        assert_eq!(scorer.correction("hello", 1.2), None);
+        assert_eq!(scorer.correction("привет", 1.2), None);
+        assert_eq!(scorer.correction("table", 1.2), None);
+    }
+
+    #[test]
+    fn dictionary_words_are_never_corrected() {
+        let scorer = LayoutScorer::new().words(Language::English, ["cnjk"]);
+        assert_eq!(scorer.correction("cnjk", 0.0), None);
+        assert_eq!(scorer.correction("Cnjk,", 0.0), None);
+    }
+
+    #[test]
+    fn words_end_with_whitespace_and_keep_their_punctuation() {
+        let mut state = state(LayoutCorrectionConfig::new());
+        type_str(&mut state, "hello,");
+        assert_eq!(text(&state.word), "hello,");
+        assert_eq!(filter(&mut state, &typed(' ')), Verdict::Pass);
+        assert_eq!(text(&state.word), "");
+        assert_eq!(text(&state.last_word), "hello,");
+        assert_eq!(text(&state.after_last_word), " ");
+
+        type_str(&mut state, "wo");
+        assert_eq!(text(&state.word), "wo");
+        assert_eq!(text(&state.last_word), "hello,");
+    }
+
+    #[test]
+    fn backspace_goes_back_into_the_last_word() {
+        let mut state = state(LayoutCorrectionConfig::new());
+        type_str(&mut state, "hello ");
+        assert_eq!(filter(&mut state, &key(Key::Backspace, true)), Verdict::Pass);
+        assert_eq!(text(&state.after_last_word), "");
+        assert_eq!(text(&state.last_word), "hello");
+        assert_eq!(filter(&mut state, &key(Key::Backspace, true)), Verdict::Pass);
+        assert_eq!(text(&state.word), "hell");
+        assert_eq!(text(&state.last_word), "");
+    }
+
+    #[test]
+    fn keys_moving_elsewhere_forget_the_words() {
+        let mut state = state(LayoutCorrectionConfig::new());
+        type_str(&mut state, "hello wo");
+        assert_eq!(filter(&mut state, &key(Key::LShift, true)), Verdict::Pass);
+        assert_eq!(text(&state.word), "wo");
+        assert_eq!(filter(&mut state, &key(Key::Left, true)), Verdict::Pass);
+        assert!(state.word.is_empty() && state.last_word.is_empty());
+
+        type_str(&mut state, "hello");
+        assert_eq!(filter(&mut state, &key(Key::Enter, true)), Verdict::Pass);
+        assert!(state.word.is_empty() && state.last_word.is_empty());
+    }
+
+    #[test]
+    fn short_words_and_disabled_engines_are_left_alone() {
+        let mut short = state(LayoutCorrectionConfig::new().min_length(7));
+        type_str(&mut short, "ghbdtn ");
+        assert_eq!(text(&short.last_word), "ghbdtn");
+
+        let mut disabled = state(LayoutCorrectionConfig::new());
+        disabled.enabled = false;
+        type_str(&mut disabled, "ghbdtn ");
+        assert!(disabled.last_word.is_empty());
+    }
+}
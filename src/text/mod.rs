@@ -0,0 +1,45 @@
+//! Text input features, running in the `Stage::PreFilter` stage of the callback chain after
+//! the filters, and typing their output as Unicode text.
+//!
+//! Characters are typed through `KeyEvent::unicode`. On Linux that needs an X server and a
+//! writable `/dev/uinput`: without them, as on Wayland, the features leave the keys alone
+//! rather than swallow them.
+
+mod compose;
+mod expansion;
+mod keysym;
+mod layout;
+mod layout_correction;
+mod transliteration;
+
+pub use self::compose::*;
+pub use self::expansion::*;
+pub use self::layout::*;
+pub use self::layout_correction::*;
+pub use self::transliteration::*;
+
+use device_events::held_modifiers;
+use {Key, KeyEvent, Modifiers};
+
+/// Character typed by the press `event`, or `None` for keys that don't type text. Backends
+/// that don't translate key events fall back to a US QWERTY layout.
+pub(crate) fn typed_char(event: &KeyEvent) -> Option<char> {
+    if let Some(character) = event.char {
+        return Some(character).filter(|character| !character.is_control());
+    }
+    let shift = held_modifiers().contains(Modifiers::SHIFT);
+    Key::from_code(event.key_code)?.us_char(shift)
+}
+
+/// Whether Ctrl, Alt or Meta is held, making a press a shortcut rather than text.
+pub(crate) fn is_shortcut() -> bool {
+    let modifiers = held_modifiers();
+    modifiers.contains(Modifiers::CTRL) || modifiers.contains(Modifiers::ALT) || modifiers.contains(Modifiers::META)
+}
+
+/// Events typing `text`.
+pub(crate) fn type_text(text: &str) -> Vec<KeyEvent> {
+    text.chars()
+        .flat_map(|character| vec![KeyEvent::unicode(character, true), KeyEvent::unicode(character, false)])
+        .collect()
+}
@@ -0,0 +1,504 @@
+//! Transliteration of Latin keys to Cyrillic text.
+
+use super::{is_shortcut, type_text, typed_char};
+use device_events::{resume_replacement, HandlerId, Stage, Verdict, GLOBAL_CALLBACKS};
+use device_state::can_type_unicode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, MutexGuard};
+use {Key, KeyEvent};
+
+/// Priority of the transliteration among the pre-filters, after the layout correction.
+const PRIORITY: i32 = 350;
+
+/// Rules of GOST 7.79-2000 system B, with `c` alone for `ц` too.
+const GOST_RULES: &[(&str, &str)] = &[
+    ("a", "а"),
+    ("b", "б"),
+    ("v", "в"),
+    ("g", "г"),
+    ("d", "д"),
+    ("e", "е"),
+    ("yo", "ё"),
+    ("zh", "ж"),
+    ("z", "з"),
+    ("i", "и"),
+    ("j", "й"),
+    ("k", "к"),
+    ("l", "л"),
+    ("m", "м"),
+    ("n", "н"),
+    ("o", "о"),
+    ("p", "п"),
+    ("r", "р"),
+    ("s", "с"),
+    ("t", "т"),
+    ("u", "у"),
+    ("f", "ф"),
+    ("x", "х"),
+    ("cz", "ц"),
+    ("c", "ц"),
+    ("ch", "ч"),
+    ("sh", "ш"),
+    ("shh", "щ"),
+    ("``", "ъ"),
+    ("y`", "ы"),
+    ("`", "ь"),
+    ("e`", "э"),
+    ("yu", "ю"),
+    ("ya", "я"),
+];
+
+/// Rules of ISO 9, one Latin character for each Cyrillic one.
+const ISO9_RULES: &[(&str, &str)] = &[
+    ("a", "а"),
+    ("b", "б"),
+    ("v", "в"),
+    ("g", "г"),
+    ("d", "д"),
+    ("e", "е"),
+    ("ë", "ё"),
+    ("ž", "ж"),
+    ("z", "з"),
+    ("i", "и"),
+    ("j", "й"),
+    ("k", "к"),
+    ("l", "л"),
+    ("m", "м"),
+    ("n", "н"),
+    ("o", "о"),
+    ("p", "п"),
+    ("r", "р"),
+    ("s", "с"),
+    ("t", "т"),
+    ("u", "у"),
+    ("f", "ф"),
+    ("h", "х"),
+    ("c", "ц"),
+    ("č", "ч"),
+    ("š", "ш"),
+    ("ŝ", "щ"),
+    ("ʺ", "ъ"),
+    ("y", "ы"),
+    ("ʹ", "ь"),
+    ("è", "э"),
+    ("û", "ю"),
+    ("â", "я"),
+];
+
+fn lowercase(character: char) -> char {
+    let mut lower = character.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => lower,
+        _ => character,
+    }
+}
+
+/// Cyrillic text of a rule, in the case of the Latin characters `typed` for it: capitalized
+/// when they are, in upper case when they all are.
+fn follow_case(typed: &[char], cyrillic: &str) -> String {
+    match typed.first() {
+        Some(first) if first.is_uppercase() => {
+            if typed.len() > 1 && typed.iter().all(|character| !character.is_lowercase()) {
+                return cyrillic.to_uppercase();
+            }
+            let mut characters = cyrillic.chars();
+            match characters.next() {
+                Some(first) => first.to_uppercase().chain(characters).collect(),
+                None => String::new(),
+            }
+        }
+        _ => cyrillic.to_string(),
+    }
+}
+
+/// Rules replacing sequences of Latin characters with Cyrillic text, like `sh` with `ш`.
+/// Sequences are matched regardless of case, and the output follows the case of the input.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransliterationScheme {
+    pub name: String,
+    /// Cyrillic text of each sequence, in lower case.
+    pub rules: BTreeMap<String, String>,
+}
+
+impl TransliterationScheme {
+    pub fn new(name: &str) -> Self {
+        TransliterationScheme {
+            name: name.to_string(),
+            rules: BTreeMap::new(),
+        }
+    }
+
+    /// Replace `latin` with `cyrillic`.
+    pub fn rule(mut self, latin: &str, cyrillic: &str) -> Self {
+        self.rules.insert(latin.to_lowercase(), cyrillic.to_string());
+        self
+    }
+
+    fn from_rules(name: &str, rules: &[(&str, &str)]) -> Self {
+        rules
+            .iter()
+            .fold(TransliterationScheme::new(name), |scheme, (latin, cyrillic)| scheme.rule(latin, cyrillic))
+    }
+
+    /// GOST 7.79-2000 system B, in ASCII: `zh` for `ж`, `shh` for `щ`, `` ` `` for `ь`,
+    /// `y` followed by `` ` `` for `ы`, and so on.
+    pub fn gost() -> Self {
+        TransliterationScheme::from_rules("GOST 7.79-2000 B", GOST_RULES)
+    }
+
+    /// ISO 9, with diacritics: `ž` for `ж`, `ŝ` for `щ`, and so on. Meant to be typed along with
+    /// dead keys or a compose key.
+    pub fn iso9() -> Self {
+        TransliterationScheme::from_rules("ISO 9", ISO9_RULES)
+    }
+
+    /// Transliterate `text` at once.
+    pub fn transliterate(&self, text: &str) -> String {
+        let mut pending = Vec::new();
+        let mut output = String::new();
+        for character in text.chars() {
+            pending.push(character);
+            output.push_str(&self.resolve(&mut pending, false).0);
+        }
+        output.push_str(&self.resolve(&mut pending, true).0);
+        output
+    }
+
+    /// Whether `latin` starts a longer sequence.
+    fn is_prefix(&self, latin: &str) -> bool {
+        self.rules
+            .range::<str, _>((Bound::Excluded(latin), Bound::Unbounded))
+            .next()
+            .is_some_and(|(longer, _)| longer.starts_with(latin))
+    }
+
+    /// Length in characters and text of the longest sequence starting `latin`.
+    fn longest_match(&self, latin: &[char]) -> Option<(usize, &str)> {
+        (1..=latin.len()).rev().find_map(|length| {
+            let sequence: String = latin[..length].iter().collect();
+            self.rules.get(&sequence).map(|cyrillic| (length, cyrillic.as_str()))
+        })
+    }
+
+    /// Resolve the characters held back in `pending` as far as they can be: until they start
+    /// a longer sequence, unless `flush`. Characters no sequence starts with are kept as is.
+    /// Returns the text, and whether its last character was such a character, leaving nothing
+    /// held back.
+    fn resolve(&self, pending: &mut Vec<char>, flush: bool) -> (String, bool) {
+        let mut output = String::new();
+        let mut kept = false;
+        while !pending.is_empty() {
+            let latin: Vec<char> = pending.iter().map(|character| lowercase(*character)).collect();
+            if !flush && self.is_prefix(&latin.iter().collect::<String>()) {
+                break;
+            }
+            match self.longest_match(&latin) {
+                Some((length, cyrillic)) => {
+                    output.push_str(&follow_case(&pending[..length], cyrillic));
+                    pending.drain(..length);
+                    kept = false;
+                }
+                None => {
+                    output.push(pending.remove(0));
+                    kept = true;
+                }
+            }
+        }
+        (output, kept && pending.is_empty())
+    }
+}
+
+/// Configuration of a `Transliteration` mode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransliterationConfig {
+    pub scheme: TransliterationScheme,
+    /// Key turning the mode on and off.
+    pub toggle_key: Option<Key>,
+}
+
+impl TransliterationConfig {
+    pub fn new(scheme: TransliterationScheme) -> Self {
+        TransliterationConfig {
+            scheme,
+            toggle_key: None,
+        }
+    }
+
+    pub fn toggle_key(mut self, key: Key) -> Self {
+        self.toggle_key = Some(key);
+        self
+    }
+}
+
+struct State {
+    config: TransliterationConfig,
+    enabled: bool,
+    /// Latin characters held back until the next ones tell which sequence they are.
+    pending: Vec<char>,
+    /// Key codes whose press was swallowed, so that their release is too.
+    swallowed: HashSet<u32>,
+}
+
+impl State {
+    /// Events typing the characters held back, then `event` if any.
+    fn flush(&mut self, event: Option<&KeyEvent>) -> Verdict {
+        let (output, _) = self.config.scheme.resolve(&mut self.pending, true);
+        let mut events = type_text(&output);
+        match event {
+            Some(_) if events.is_empty() => return Verdict::Pass,
+            Some(event) => events.push(event.clone()),
+            None => {}
+        }
+        if events.is_empty() {
+            Verdict::Block
+        } else {
+            Verdict::Replace(events)
+        }
+    }
+}
+
+/// Types Cyrillic text from a Latin layout, following a transliteration scheme: with GOST,
+/// `privet` types `привет`. Characters that may start a longer sequence are held back until
+/// the next key tells, e.g. `s` until it turns out to be `с` or the start of `ш` or `щ`, and
+/// Backspace takes back the last of them. Keys that don't type a character, and shortcuts
+/// with Ctrl, Alt or Meta, type the characters held back first.
+///
+/// The text is typed as Unicode, so the mode stays off where that can't be done, like on
+/// Wayland. Simulated events are left alone.
+///
+/// ```no_run
+/// use key_director::{DeviceState, Key, Transliteration, TransliterationConfig, TransliterationScheme};
+///
+/// let _device_state = DeviceState::new();
+/// let scheme = TransliterationScheme::gost().rule("shch", "щ").rule("w", "щ");
+/// let _transliteration = Transliteration::install(TransliterationConfig::new(scheme).toggle_key(Key::ScrollLock));
+/// ```
+///
+/// The mode is removed when dropped.
+pub struct Transliteration {
+    id: HandlerId,
+    state: Arc<Mutex<State>>,
+}
+
+impl Transliteration {
+    /// Install the mode, enabled if text can be typed, in the `Stage::PreFilter` stage of the
+    /// callback chain.
+    pub fn install(config: TransliterationConfig) -> Transliteration {
+        let state = Arc::new(Mutex::new(State {
+            config,
+            enabled: can_type_unicode(),
+            pending: Vec::new(),
+            swallowed: HashSet::new(),
+        }));
+
+        let handler_state = state.clone();
+        let id = GLOBAL_CALLBACKS
+            .lock()
+            .expect("Couldn't lock GLOBAL_CALLBACKS")
+            .insert(
+                "transliteration",
+                Stage::PreFilter,
+                PRIORITY,
+                None,
+                Arc::new(move |event| {
+                    let verdict = match handler_state.lock() {
+                        Ok(mut state) => filter(&mut state, event),
+                        Err(_) => Verdict::Pass,
+                    };
+                    resume_replacement(event, verdict)
+                }),
+            );
+        Transliteration { id, state }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Turn the mode on or off. Characters held back are dropped. The mode can't be turned on
+    /// where text can't be typed, see `is_enabled`.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.lock();
+        state.enabled = enabled && can_type_unicode();
+        state.pending.clear();
+    }
+
+    pub fn config(&self) -> TransliterationConfig {
+        self.lock().config.clone()
+    }
+
+    /// Change the configuration. Characters held back are dropped.
+    pub fn set_config(&self, config: TransliterationConfig) {
+        let mut state = self.lock();
+        state.config = config;
+        state.pending.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Couldn't lock the transliteration state")
+    }
+}
+
+impl Drop for Transliteration {
+    fn drop(&mut self) {
+        if let Ok(mut chain) = GLOBAL_CALLBACKS.lock() {
+            chain.remove(self.id);
+        }
+    }
+}
+
+fn filter(state: &mut State, event: &KeyEvent) -> Verdict {
+    if event.is_simulated {
+        return Verdict::Pass;
+    }
+    if !event.is_pressed {
+        return if state.swallowed.remove(&event.key_code) {
+            Verdict::Block
+        } else {
+            Verdict::Pass
+        };
+    }
+
+    let key = Key::from_code(event.key_code);
+    if key.is_some() && key == state.config.toggle_key {
+        state.swallowed.insert(event.key_code);
+        state.enabled = !state.enabled && can_type_unicode();
+        return state.flush(None);
+    }
+    if !state.enabled || key.and_then(Key::modifier).is_some() {
+        return Verdict::Pass;
+    }
+    if key == Some(Key::Backspace) && state.pending.pop().is_some() {
+        state.swallowed.insert(event.key_code);
+        return Verdict::Block;
+    }
+    let character = match typed_char(event) {
+        Some(character) if !is_shortcut() => character,
+        _ => return state.flush(Some(event)),
+    };
+
+    state.pending.push(character);
+    let (mut output, kept) = state.config.scheme.resolve(&mut state.pending, false);
+    if kept {
+        // The character of the event has no sequence: let the event type it.
+        output.pop();
+        if output.is_empty() {
+            return Verdict::Pass;
+        }
+        let mut events = type_text(&output);
+        events.push(event.clone());
+        return Verdict::Replace(events);
+    }
+    state.swallowed.insert(event.key_code);
+    if output.is_empty() {
+        Verdict::Block
+    } else {
+        Verdict::Replace(type_text(&output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::fixtures::key;
+    use device_events::{CallbackChain, Simulation};
+
+    /// Text typed by `events`, which must all be Unicode.
+    fn text(events: &[KeyEvent]) -> String {
+        events.iter().filter(|event| event.is_pressed).map(|event| event.char.unwrap()).collect()
+    }
+
+    fn resolve(scheme: &TransliterationScheme, pending: &mut Vec<char>, character: char) -> String {
+        pending.push(character);
+        scheme.resolve(pending, false).0
+    }
+
+    /// Simulation of a chain with the transliteration of `scheme` alone.
+    fn simulation(scheme: TransliterationScheme) -> Simulation {
+        let state = Arc::new(Mutex::new(State {
+            config: TransliterationConfig::new(scheme),
+            enabled: true,
+            pending: Vec::new(),
+            swallowed: HashSet::new(),
+        }));
+        let mut chain = CallbackChain::default();
+        chain.insert(
+            "transliteration",
+            Stage::PreFilter,
+            PRIORITY,
+            None,
+            Arc::new(move |event| filter(&mut state.lock().unwrap(), event)),
+        );
+        Simulation::of(chain)
+    }
+
+    #[test]
+    fn characters_starting_a_sequence_are_held_back() {
+        let scheme = TransliterationScheme::gost();
+        let mut pending = Vec::new();
+        assert_eq!(resolve(&scheme, &mut pending, 's'), "");
+        assert_eq!(resolve(&scheme, &mut pending, 'h'), "");
+        assert_eq!(pending, vec!['s', 'h']);
+        assert_eq!(resolve(&scheme, &mut pending, 'a'), "ша");
+        assert!(pending.is_empty());
+        assert_eq!(scheme.transliterate("privet, Mir"), "привет, Мир");
+        assert_eq!(scheme.transliterate("SHHI"), "ЩИ");
+        assert_eq!(scheme.transliterate("Shhi"), "Щи");
+    }
+
+    #[test]
+    fn an_unfinished_longer_sequence_backtracks_to_the_longest_match() {
+        let scheme = TransliterationScheme::gost().rule("shch", "щ");
+        let mut pending = Vec::new();
+        for character in "shc".chars() {
+            assert_eq!(resolve(&scheme, &mut pending, character), "");
+        }
+        assert_eq!(resolve(&scheme, &mut pending, 'a'), "шца");
+        assert_eq!(scheme.transliterate("shch"), "щ");
+        // Flushing resolves what is held back without waiting.
+        pending.extend("shc".chars());
+        assert_eq!(scheme.resolve(&mut pending, true), ("шц".to_string(), false));
+    }
+
+    #[test]
+    fn characters_without_a_sequence_are_kept() {
+        let scheme = TransliterationScheme::gost();
+        let mut pending = vec!['1'];
+        assert_eq!(scheme.resolve(&mut pending, false), ("1".to_string(), true));
+        let mut pending = vec!['s', '1'];
+        assert_eq!(scheme.resolve(&mut pending, false), ("с1".to_string(), true));
+    }
+
+    #[test]
+    fn backspace_takes_back_a_character_held_back() {
+        let simulation = simulation(TransliterationScheme::gost());
+        assert_eq!(simulation.dispatch(&key(Key::S, true)), Verdict::Block);
+        assert_eq!(simulation.dispatch(&key(Key::S, false)), Verdict::Block);
+        assert_eq!(simulation.dispatch(&key(Key::Backspace, true)), Verdict::Block);
+        assert_eq!(simulation.dispatch(&key(Key::Backspace, false)), Verdict::Block);
+        // Nothing is held back anymore: Backspace erases text as usual.
+        assert_eq!(simulation.dispatch(&key(Key::Backspace, true)), Verdict::Pass);
+        match simulation.dispatch(&key(Key::A, true)) {
+            Verdict::Replace(events) => assert_eq!(text(&events), "а"),
+            verdict => panic!("unexpected {:?}", verdict),
+        }
+    }
+
+    #[test]
+    fn shortcuts_type_the_characters_held_back_first() {
+        let simulation = simulation(TransliterationScheme::gost());
+        assert_eq!(simulation.dispatch(&key(Key::S, true)), Verdict::Block);
+        assert_eq!(simulation.dispatch(&key(Key::LControl, true)), Verdict::Pass);
+        match simulation.dispatch(&key(Key::C, true)) {
+            Verdict::Replace(events) => {
+                let (last, typed) = events.split_last().unwrap();
+                assert_eq!(text(typed), "с");
+                assert_eq!(*last, key(Key::C, true));
+            }
+            verdict => panic!("unexpected {:?}", verdict),
+        }
+        assert_eq!(simulation.dispatch(&key(Key::C, false)), Verdict::Pass);
+    }
+}